@@ -0,0 +1,219 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The ssh algorithms to prefer, for legacy or hardened hosts
+//!
+//! `--ssh-kex`, `--ssh-cipher` and `--ssh-mac` give the key exchange, cipher
+//! and MAC algorithms to offer, most preferred first and separated by commas,
+//! as ssh's `KexAlgorithms`, `Ciphers` and `MACs` do.  A host's own `ssh_kex`,
+//! `ssh_cipher` or `ssh_mac` in the config takes precedence.  Whatever isn't
+//! given is left to libssh2.
+use crate::error::MusshResult;
+use crate::extensions::Extensions;
+use clap::{Arg, ArgMatches};
+use ssh2::{MethodType, Session};
+use std::collections::HashMap;
+
+/// Each category of algorithm: its config key and flag, its name in errors,
+/// and the methods libssh2 applies it to, both directions for a cipher or MAC.
+const CATEGORIES: [(&str, &str, &[MethodType]); 3] = [
+    ("ssh_kex", "key exchange", &[MethodType::Kex]),
+    (
+        "ssh_cipher",
+        "cipher",
+        &[MethodType::CryptCs, MethodType::CryptSc],
+    ),
+    ("ssh_mac", "MAC", &[MethodType::MacCs, MethodType::MacSc]),
+];
+
+/// The `--ssh-kex`, `--ssh-cipher` and `--ssh-mac` args.
+pub(crate) fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("ssh_kex")
+            .long("ssh-kex")
+            .value_name("ALGORITHMS")
+            .help(
+                "The key exchange algorithms to offer, most preferred first, separated by \
+                 commas (a host's own ssh_kex in the config takes precedence)",
+            ),
+        Arg::with_name("ssh_cipher")
+            .long("ssh-cipher")
+            .value_name("ALGORITHMS")
+            .help(
+                "The ciphers to offer, most preferred first, separated by commas (a host's own \
+                 ssh_cipher in the config takes precedence)",
+            ),
+        Arg::with_name("ssh_mac")
+            .long("ssh-mac")
+            .value_name("ALGORITHMS")
+            .help(
+                "The MAC algorithms to offer, most preferred first, separated by commas (a \
+                 host's own ssh_mac in the config takes precedence)",
+            ),
+    ]
+}
+
+/// The algorithms to prefer with one host, by config key, only those given.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Algorithms(HashMap<&'static str, String>);
+
+impl Algorithms {
+    /// Set the preferences on the session, before its handshake.
+    pub(crate) fn apply(&self, session: &Session) -> MusshResult<()> {
+        for (key, _, methods) in &CATEGORIES {
+            if let Some(prefs) = self.0.get(key) {
+                for method in *methods {
+                    session.method_pref(*method, prefs)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The algorithms to prefer with each host, its own or the flags'.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SshAlgorithms {
+    /// The algorithms given with the flags, for hosts without their own.
+    default: Algorithms,
+    /// The `ssh_kex`, `ssh_cipher` and `ssh_mac` of the `[hosts.<name>]`
+    /// tables in the config.
+    hosts: HashMap<String, Algorithms>,
+}
+
+impl SshAlgorithms {
+    /// Parse the algorithms of the hosts in the config, and those of the
+    /// flags, checking that libssh2 supports each of them.
+    pub(crate) fn parse(
+        extensions: &Extensions,
+        matches: Option<&ArgMatches<'_>>,
+    ) -> MusshResult<Self> {
+        let session = Session::new()?;
+        let mut algorithms = Self::default();
+
+        for (key, category, methods) in &CATEGORIES {
+            if let Some(prefs) = matches.and_then(|matches| matches.value_of(key)) {
+                check(&session, category, methods, prefs)?;
+                let _old = algorithms.default.0.insert(key, prefs.to_string());
+            }
+        }
+
+        let default = algorithms.default.clone();
+        for (name, host) in &extensions.hosts {
+            for (key, category, methods) in &CATEGORIES {
+                let Some(prefs) = host.algorithms(key) else {
+                    continue;
+                };
+                check(&session, category, methods, prefs)?;
+                let _old = algorithms
+                    .hosts
+                    .entry(name.clone())
+                    .or_insert_with(|| default.clone())
+                    .0
+                    .insert(key, prefs.to_string());
+            }
+        }
+
+        Ok(algorithms)
+    }
+
+    /// The algorithms to prefer with the given host.
+    pub(crate) fn for_host(&self, name: &str) -> &Algorithms {
+        self.hosts.get(name).unwrap_or(&self.default)
+    }
+}
+
+/// Check that libssh2 supports every algorithm named in `prefs`.
+fn check(
+    session: &Session,
+    category: &str,
+    methods: &[MethodType],
+    prefs: &str,
+) -> MusshResult<()> {
+    for method in methods {
+        let supported = session.supported_algs(*method)?;
+        let unknown: Vec<&str> = prefs
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !supported.contains(name))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(format!(
+                "Unsupported ssh {category} algorithm(s) '{}', expected some of {}",
+                unknown.join(","),
+                supported.join(", ")
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{args, Algorithms, SshAlgorithms};
+    use crate::error::MusshResult;
+    use crate::extensions::Extensions;
+    use clap::App;
+
+    const ALGORITHMS_TOML: &str = r#"[hosts.legacy]
+hostname = "10.0.0.3"
+username = "jozias"
+ssh_kex = "diffie-hellman-group14-sha1"
+[hosts.web]
+hostname = "10.0.0.4"
+username = "jozias"
+"#;
+
+    #[test]
+    fn algorithms() -> MusshResult<()> {
+        let matches = App::new("run").args(&args()).get_matches_from_safe([
+            "run",
+            "--ssh-kex",
+            "diffie-hellman-group14-sha256",
+            "--ssh-cipher",
+            "aes256-ctr,aes128-ctr",
+        ])?;
+        let algorithms = SshAlgorithms::parse(&toml::from_str(ALGORITHMS_TOML)?, Some(&matches))?;
+        let legacy = algorithms.for_host("legacy");
+        assert_eq!(legacy.0["ssh_kex"], "diffie-hellman-group14-sha1");
+        assert_eq!(legacy.0["ssh_cipher"], "aes256-ctr,aes128-ctr");
+        let web = algorithms.for_host("web");
+        assert_eq!(web.0["ssh_kex"], "diffie-hellman-group14-sha256");
+        assert!(!web.0.contains_key("ssh_mac"));
+        assert_eq!(
+            SshAlgorithms::parse(&Extensions::default(), None)?.for_host("web"),
+            &Algorithms::default()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported() -> MusshResult<()> {
+        let matches = App::new("run").args(&args()).get_matches_from_safe([
+            "run",
+            "--ssh-cipher",
+            "aes256-ctr,rot13",
+        ])?;
+        let err = SshAlgorithms::parse(&Extensions::default(), Some(&matches))
+            .err()
+            .ok_or("rot13 was accepted")?;
+        assert!(err
+            .to_string()
+            .contains("Unsupported ssh cipher algorithm(s) 'rot13'"));
+
+        let toml = ALGORITHMS_TOML.replace("diffie-hellman-group14-sha1", "dh-md5");
+        let err = SshAlgorithms::parse(&toml::from_str(&toml)?, None)
+            .err()
+            .ok_or("dh-md5 was accepted")?;
+        assert!(err.to_string().contains("key exchange"));
+        let toml = ALGORITHMS_TOML.replace("\"diffie-hellman-group14-sha1\"", "3");
+        assert!(toml::from_str::<Extensions>(&toml).is_err());
+        Ok(())
+    }
+}
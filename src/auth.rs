@@ -0,0 +1,228 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! ssh authentication
+use crate::algorithms::Algorithms;
+use crate::connect;
+use crate::error::{MusshErrKind, MusshResult};
+use crate::known_hosts::HostKeys;
+use ssh2::Session;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before trying to authenticate again.
+const AUTH_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// How the host is authenticated to, as libmussh will do it: with the host's
+/// pem file if it has one, otherwise with the ssh agent.  `localhost` is run
+/// on without ssh at all.
+///
+/// This names the key file or agent socket used, never a secret.
+pub(crate) fn describe(
+    hostname: &str,
+    username: &str,
+    pem: Option<&str>,
+    agent_sock: Option<&str>,
+) -> String {
+    if hostname == "localhost" {
+        return "none (run locally)".to_string();
+    }
+
+    match (pem, agent_sock) {
+        (Some(pem), _) => format!("pem {pem} as {username}"),
+        (None, Some(sock)) => format!("agent {sock} as {username}"),
+        (None, None) => format!("agent (SSH_AUTH_SOCK is not set) as {username}"),
+    }
+}
+
+/// Connect and authenticate to the host as libmussh does for a run, and open a
+/// session channel, but close it again without running anything.
+///
+/// Connecting fails once `connect_timeout` is up, the `algorithms` are
+/// preferred, and the key of the host is checked against the known hosts.
+/// Authentication is tried again up to `auth_retries` times, i.e. for an agent that was only just
+/// started.  Connection errors are not retried.
+///
+/// `localhost` is run on without ssh, so there is nothing to check.
+pub(crate) fn check(
+    hostname: &str,
+    username: &str,
+    port: Option<u16>,
+    pem: Option<&str>,
+    (connect_timeout, auth_retries): (Duration, usize),
+    host_keys: &HostKeys,
+    algorithms: &Algorithms,
+) -> MusshResult<()> {
+    if hostname == "localhost" {
+        return Ok(());
+    }
+
+    let port = port.unwrap_or(22);
+    let session = handshake(hostname, port, connect_timeout, host_keys, algorithms)?;
+    with_retries(auth_retries, AUTH_RETRY_DELAY, || {
+        authenticate(&session, username, pem)
+    })?;
+
+    let mut channel = session.channel_session()?;
+    channel.close()?;
+    channel.wait_close()?;
+    session.disconnect(None, "mussh --check-auth", None)?;
+    Ok(())
+}
+
+/// Connect and authenticate to the host as libmussh does for a run, for the
+/// subcommands that use the session themselves, i.e. `push`, and for the
+/// session a `run` keeps for the commands of a host.
+pub(crate) fn session(
+    hostname: &str,
+    username: &str,
+    port: Option<u16>,
+    pem: Option<&str>,
+    connect_timeout: Duration,
+    host_keys: &HostKeys,
+    algorithms: &Algorithms,
+) -> MusshResult<Session> {
+    let port = port.unwrap_or(22);
+    let session = handshake(hostname, port, connect_timeout, host_keys, algorithms)?;
+    authenticate(&session, username, pem)?;
+    Ok(session)
+}
+
+/// Connect to the host and open an ssh session with it, each within
+/// `connect_timeout`, preferring the `algorithms` and checking its key against
+/// the known hosts.
+pub(crate) fn handshake(
+    hostname: &str,
+    port: u16,
+    connect_timeout: Duration,
+    host_keys: &HostKeys,
+    algorithms: &Algorithms,
+) -> MusshResult<Session> {
+    let mut session = Session::new()?;
+    algorithms.apply(&session)?;
+    session.set_tcp_stream(connect::connect(hostname, port, connect_timeout)?);
+    session.set_timeout(u32::try_from(connect_timeout.as_millis()).unwrap_or(u32::MAX));
+    session.handshake()?;
+    session.set_timeout(0);
+    host_keys.verify(&session, hostname, port)?;
+    Ok(session)
+}
+
+fn authenticate(session: &Session, username: &str, pem: Option<&str>) -> MusshResult<()> {
+    if let Some(pem) = pem {
+        session.userauth_pubkey_file(username, None, Path::new(pem), None)?;
+    } else {
+        session.userauth_agent(username)?;
+    }
+    if session.authenticated() {
+        Ok(())
+    } else {
+        Err(MusshErrKind::Authentication(username.to_string()).into())
+    }
+}
+
+/// Make `attempt`, and up to `retries` more after `delay` while it fails.
+fn with_retries<F>(retries: usize, delay: Duration, mut attempt: F) -> MusshResult<()>
+where
+    F: FnMut() -> MusshResult<()>,
+{
+    let mut result = attempt();
+    for _ in 0..retries {
+        if result.is_ok() {
+            break;
+        }
+        thread::sleep(delay);
+        result = attempt();
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, describe, with_retries};
+    use crate::algorithms::Algorithms;
+    use crate::error::MusshResult;
+    use crate::known_hosts::{HostKeyCheck, HostKeys};
+    use std::time::Duration;
+
+    #[test]
+    fn methods() {
+        assert_eq!(
+            describe("localhost", "jozias", Some("/keys/m3.pem"), None),
+            "none (run locally)"
+        );
+        assert_eq!(
+            describe(
+                "10.0.0.3",
+                "jozias",
+                Some("/keys/m3.pem"),
+                Some("/tmp/agent.1")
+            ),
+            "pem /keys/m3.pem as jozias"
+        );
+        assert_eq!(
+            describe("10.0.0.4", "deploy", None, Some("/tmp/agent.1")),
+            "agent /tmp/agent.1 as deploy"
+        );
+        assert_eq!(
+            describe("10.0.0.4", "deploy", None, None),
+            "agent (SSH_AUTH_SOCK is not set) as deploy"
+        );
+    }
+
+    #[test]
+    fn checks() -> MusshResult<()> {
+        let (timeout, host_keys) = (Duration::from_secs(1), HostKeys::user(HostKeyCheck::Off)?);
+        let algorithms = Algorithms::default();
+        check(
+            "localhost",
+            "jozias",
+            None,
+            None,
+            (timeout, 0),
+            &host_keys,
+            &algorithms,
+        )?;
+        assert!(check(
+            "127.0.0.1",
+            "jozias",
+            Some(9),
+            None,
+            (timeout, 2),
+            &host_keys,
+            &algorithms
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn retries() {
+        let mut attempts = 0;
+        let result = with_retries(3, Duration::from_millis(1), || {
+            attempts += 1;
+            if attempts < 2 {
+                Err("agent not ready".into())
+            } else {
+                Ok(())
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(attempts, 2);
+
+        attempts = 0;
+        let result = with_retries(2, Duration::from_millis(1), || {
+            attempts += 1;
+            Err("denied".into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Chained commands
+use crate::error::MusshResult;
+use crate::util::shell_quote;
+use std::fmt::Write;
+use toml::Value;
+
+/// Give each `[cmd.<name>]` table with `steps` instead of a `command` the
+/// command that runs them.  libmussh only knows about `command`, so this is
+/// done to the config before it is handed over.
+///
+/// Returns whether there were any chained commands.
+pub(crate) fn expand(config: &mut Value) -> MusshResult<bool> {
+    let mut expanded = false;
+
+    if let Some(cmds) = config.get_mut("cmd").and_then(Value::as_table_mut) {
+        for (name, cmd) in cmds.iter_mut() {
+            let table = match cmd.as_table_mut() {
+                Some(table) if table.contains_key("steps") => table,
+                _ => continue,
+            };
+            if table.contains_key("command") {
+                return Err(format!("Command '{name}' has both a command and steps").into());
+            }
+            let steps = table
+                .get("steps")
+                .and_then(Value::as_array)
+                .map(|steps| steps.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+                .unwrap_or_default()
+                .filter(|steps| !steps.is_empty())
+                .ok_or_else(|| {
+                    format!("The steps of command '{name}' must be a list of commands")
+                })?;
+            let command = script(&steps);
+            let _old = table.insert("command".to_string(), Value::String(command));
+            let _steps = table.remove("steps");
+            expanded = true;
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// The script running the steps in one shell, in order, stopping at the first
+/// one that fails (as with `set -e`).  The failed step is written to stdout,
+/// so it ends up in the host's output.
+fn script(steps: &[&str]) -> String {
+    let mut script = format!(
+        "trap 'mussh_rc=$?; if [ $mussh_rc -ne 0 ]; then echo \"Step $mussh_step of {} failed \
+         with exit code $mussh_rc: $mussh_cmd\"; fi; exit $mussh_rc' EXIT\nset -e\n",
+        steps.len()
+    );
+    for (idx, step) in steps.iter().enumerate() {
+        let _res = writeln!(
+            script,
+            "mussh_step={}; mussh_cmd={}\n{step}",
+            idx + 1,
+            shell_quote(step)
+        );
+    }
+    script
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expand, script};
+    use crate::error::MusshResult;
+    use std::process::Command;
+    use toml::Value;
+
+    fn run(steps: &[&str]) -> MusshResult<(bool, String)> {
+        let output = Command::new("sh").arg("-c").arg(script(steps)).output()?;
+        Ok((
+            output.status.success(),
+            String::from_utf8_lossy(&output.stdout).to_string(),
+        ))
+    }
+
+    #[test]
+    fn steps_share_a_shell() -> MusshResult<()> {
+        let (success, stdout) = run(&["cd /", "x='it''s'", "echo \"$x in $(pwd)\""])?;
+        assert!(success);
+        assert_eq!(stdout, "its in /\n");
+        Ok(())
+    }
+
+    #[test]
+    fn stops_at_the_first_failure() -> MusshResult<()> {
+        let (success, stdout) = run(&["echo one", "exit 3", "echo three"])?;
+        assert!(!success);
+        assert_eq!(stdout, "one\nStep 2 of 3 failed with exit code 3: exit 3\n");
+        Ok(())
+    }
+
+    #[test]
+    fn chains_become_commands() -> MusshResult<()> {
+        let mut config: Value = toml::from_str(
+            "[cmd.ls]\ncommand = \"ls\"\n[cmd.deploy]\nsteps = [\"cd /srv\", \"make\"]\n",
+        )?;
+        assert!(expand(&mut config)?);
+        let deploy = &config["cmd"]["deploy"];
+        assert!(deploy.get("steps").is_none());
+        assert!(deploy["command"].as_str().is_some_and(
+            |command| command.ends_with("cd /srv\nmussh_step=2; mussh_cmd='make'\nmake\n")
+        ));
+        assert_eq!(config["cmd"]["ls"]["command"].as_str(), Some("ls"));
+
+        for bad in &[
+            "[cmd.x]\ncommand = \"ls\"\nsteps = [\"ls\"]\n",
+            "[cmd.x]\nsteps = []\n",
+            "[cmd.x]\nsteps = [1]\n",
+        ] {
+            assert!(expand(&mut toml::from_str(bad)?).is_err());
+        }
+        let mut plain: Value = toml::from_str("[cmd.ls]\ncommand = \"ls\"\n")?;
+        assert!(!expand(&mut plain)?);
+        Ok(())
+    }
+}
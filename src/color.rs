@@ -0,0 +1,95 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Per-host output colors
+use clap::ArgMatches;
+use is_terminal::IsTerminal;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+
+/// The ANSI foreground colors hosts are assigned from.
+const PALETTE: [u8; 12] = [31, 32, 33, 34, 35, 36, 91, 92, 93, 94, 95, 96];
+
+/// Should output be colorized, given the `--color` flag?
+pub(crate) fn use_color(matches: &ArgMatches<'_>) -> bool {
+    match matches.value_of("color") {
+        Some("always") => true,
+        Some("never") => false,
+        _ => io::stdout().is_terminal(),
+    }
+}
+
+/// Assign each host a color from the palette.
+///
+/// A host starts from the slot its name hashes to, so it keeps the same color
+/// from run to run.  If that color is taken by another host in this run, the
+/// next free color is used instead.  Once every color is taken, hosts share
+/// colors.
+pub(crate) fn host_colors(names: &[&str]) -> HashMap<String, u8> {
+    let mut names = names.to_vec();
+    names.sort_unstable();
+    names.dedup();
+
+    let mut colors = HashMap::new();
+    let mut taken = [false; PALETTE.len()];
+
+    for name in names {
+        let start = slot(name);
+        let idx = (0..PALETTE.len())
+            .map(|offset| (start + offset) % PALETTE.len())
+            .find(|idx| !taken[*idx])
+            .unwrap_or(start);
+        taken[idx] = true;
+        let _old = colors.insert(name.to_string(), PALETTE[idx]);
+    }
+
+    colors
+}
+
+/// Wrap the text in the given ANSI color.
+pub(crate) fn paint(color: u8, text: &str) -> String {
+    format!("\x1b[{color}m{text}\x1b[0m")
+}
+
+/// FNV-1a, so the slot doesn't change between builds.
+fn slot(name: &str) -> usize {
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    usize::try_from(hash % PALETTE.len() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{host_colors, PALETTE};
+    use std::collections::HashSet;
+
+    #[test]
+    fn stable_colors() {
+        let first = host_colors(&["web1", "web2", "db1"]);
+        let second = host_colors(&["db1", "web2", "web1"]);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn no_collisions() {
+        let names: Vec<String> = (0..PALETTE.len()).map(|i| format!("m{i}")).collect();
+        let colors = host_colors(&names.iter().map(String::as_str).collect::<Vec<_>>());
+        let unique: HashSet<_> = colors.values().collect();
+        assert_eq!(unique.len(), PALETTE.len());
+    }
+
+    #[test]
+    fn more_hosts_than_colors() {
+        let names: Vec<String> = (0..PALETTE.len() * 2).map(|i| format!("m{i}")).collect();
+        let colors = host_colors(&names.iter().map(String::as_str).collect::<Vec<_>>());
+        assert_eq!(colors.len(), PALETTE.len() * 2);
+        assert!(colors.values().all(|color| PALETTE.contains(color)));
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Editing the config file
+use crate::chain;
+use crate::config_format::ConfigFormat;
+use crate::defaults;
+use crate::error::MusshResult;
+use crate::legacy;
+use libmussh::Config;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml_edit::{Document, Item, RawString, Table, TableLike};
+
+/// The `--config` that reads the config from stdin.
+pub(crate) const STDIN: &str = "-";
+
+/// Load the config file for editing, keeping its comments and layout.  Only a
+/// TOML config can be edited.
+pub(crate) fn load(path: &Path) -> MusshResult<Document> {
+    if path == Path::new(STDIN) {
+        return Err(
+            "The config was read from stdin, so there is nothing to write it back to, use \
+             --config <path> instead"
+                .into(),
+        );
+    }
+    if ConfigFormat::of(path) == ConfigFormat::Yaml {
+        return Err(format!(
+            "{} is YAML, which can't be edited without losing its comments and layout, edit \
+             it by hand instead",
+            path.display()
+        )
+        .into());
+    }
+    fs::read_to_string(path)?
+        .parse::<Document>()
+        .map_err(|e| format!("{}: {e}", path.display()).into())
+}
+
+/// The `hostlist`, `hosts` or `cmd` section of the config, added if it has
+/// none.
+pub(crate) fn section<'a>(
+    document: &'a mut Document,
+    name: &str,
+) -> MusshResult<&'a mut dyn TableLike> {
+    document
+        .entry(name)
+        .or_insert_with(|| {
+            let mut table = Table::new();
+            table.set_implicit(true);
+            Item::Table(table)
+        })
+        .as_table_like_mut()
+        .ok_or_else(|| format!("The {name} of the config are not a table").into())
+}
+
+/// Remove the entry `name` from the `section` of the config.
+///
+/// The comments above its table that are set apart from it by a blank line
+/// aren't about the entry, and nor is a comment at the top of the file, so
+/// they are kept, above the table that follows it.
+///
+/// Returns the entry, if there was one.
+pub(crate) fn remove(
+    document: &mut Document,
+    section: &str,
+    name: &str,
+) -> MusshResult<Option<Item>> {
+    let Some(item) = self::section(document, section)?.remove(name) else {
+        return Ok(None);
+    };
+    let Some((table, position)) = item
+        .as_table()
+        .and_then(|table| Some((table, table.position()?)))
+    else {
+        return Ok(Some(item));
+    };
+    let prefix = table
+        .decor()
+        .prefix()
+        .and_then(RawString::as_str)
+        .unwrap_or_default();
+
+    let mut positions = Vec::new();
+    table_positions(document, &mut positions);
+    let first = positions.iter().all(|other| *other > position);
+    let kept = match prefix.rfind("\n\n") {
+        Some(end) => &prefix[..=end],
+        None if first => prefix,
+        None => "",
+    };
+    if kept.trim().is_empty() {
+        return Ok(Some(item));
+    }
+
+    let next = positions
+        .into_iter()
+        .filter(|other| *other > position)
+        .min();
+    if let Some(next) = next.and_then(|next| table_at(document, next)) {
+        let next_prefix = next
+            .decor()
+            .prefix()
+            .and_then(RawString::as_str)
+            .unwrap_or("\n");
+        let joined = format!("{kept}{next_prefix}");
+        next.decor_mut().set_prefix(joined);
+    } else {
+        let trailing = document.trailing().as_str().unwrap_or_default();
+        let joined = format!("{trailing}{kept}");
+        document.set_trailing(joined);
+    }
+    Ok(Some(item))
+}
+
+/// The positions of the tables in the config, in no particular order.
+fn table_positions(table: &Table, positions: &mut Vec<usize>) {
+    for (_, item) in table {
+        match item {
+            Item::Table(table) => {
+                positions.extend(table.position());
+                table_positions(table, positions);
+            }
+            Item::ArrayOfTables(tables) => {
+                for table in tables {
+                    positions.extend(table.position());
+                    table_positions(table, positions);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The table at `position` in the config.
+fn table_at(table: &mut Table, position: usize) -> Option<&mut Table> {
+    for (_, item) in table.iter_mut() {
+        let tables: Vec<&mut Table> = match item {
+            Item::Table(table) => vec![table],
+            Item::ArrayOfTables(tables) => tables.iter_mut().collect(),
+            _ => continue,
+        };
+        for table in tables {
+            if table.position() == Some(position) {
+                return Some(table);
+            }
+            if let Some(found) = table_at(table, position) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
+
+/// Write the edited config file.
+///
+/// The edited config must still read as a config, so an edit can't leave one
+/// behind that mussh refuses to load.  The previous contents are kept in
+/// `mussh.toml.bak`, and the new contents are written to a temporary file first
+/// and moved into place, so a failed write never leaves a partial config
+/// behind.
+pub(crate) fn save(path: &Path, document: &Document) -> MusshResult<()> {
+    check(path, document)?;
+    if path.exists() {
+        let _bytes = fs::copy(path, with_suffix(path, "bak"))?;
+    }
+    let tmp_path = with_suffix(path, "tmp");
+    fs::write(&tmp_path, document.to_string())?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Read the edited config as `load_config` would, without the environment.
+fn check(path: &Path, document: &Document) -> MusshResult<()> {
+    let unreadable = |e: &dyn std::fmt::Display| format!("Not writing {}: {e}", path.display());
+    let mut value: toml::Value =
+        toml::from_str(&document.to_string()).map_err(|e| unreadable(&e))?;
+    let _legacy = legacy::normalize(&mut value);
+    let _expanded = chain::expand(&mut value)?;
+    let _defaulted = defaults::apply(&mut value)?;
+    let _config: Config = value.try_into().map_err(|e| unreadable(&e))?;
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load, remove, save, STDIN};
+    use crate::error::MusshResult;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use toml_edit::Document;
+
+    #[test]
+    fn save_keeps_a_backup() -> MusshResult<()> {
+        let dir = env::temp_dir().join(format!("mussh-config-file-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("mussh.toml");
+        fs::write(&path, "# hosts\n[hosts]\n")?;
+
+        let mut document = load(&path)?;
+        document["cmd"] = toml_edit::table();
+        save(&path, &document)?;
+
+        let saved = fs::read_to_string(&path)?;
+        let backup = fs::read_to_string(dir.join("mussh.toml.bak"))?;
+        let tmp_exists = dir.join("mussh.toml.tmp").exists();
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(saved, "# hosts\n[hosts]\n\n[cmd]\n");
+        assert_eq!(backup, "# hosts\n[hosts]\n");
+        assert!(!tmp_exists);
+        Ok(())
+    }
+
+    #[test]
+    fn save_refuses_an_unreadable_config() -> MusshResult<()> {
+        let dir = env::temp_dir().join(format!("mussh-config-check-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("mussh.toml");
+        fs::write(&path, "[hosts.m1]\nhostname = \"h\"\nusername = \"u\"\n")?;
+
+        let mut document = load(&path)?;
+        let _old = document["hosts"]["m1"]
+            .as_table_mut()
+            .ok_or("no m1 host")?
+            .remove("username");
+        let error = save(&path, &document).err().map(|e| e.to_string());
+        let unchanged = fs::read_to_string(&path)?;
+        fs::remove_dir_all(&dir)?;
+        assert!(error.is_some_and(|e| e.starts_with("Not writing")));
+        assert!(unchanged.contains("username"));
+        Ok(())
+    }
+
+    const COMMENTED_TOML: &str = r#"# The hosts of the lab
+[hosts.m1]
+hostname = "10.0.0.3"
+username = "jozias"
+
+# Staging
+
+# The second box
+[hosts.m2]
+hostname = "10.0.0.4"
+username = "jozias"
+
+# The last box
+[hosts.m3]
+hostname = "10.0.0.5"
+username = "jozias"
+"#;
+
+    #[test]
+    fn remove_keeps_detached_comments() -> MusshResult<()> {
+        let mut document: Document = COMMENTED_TOML.parse().map_err(|_| "bad toml")?;
+        let _m2 = remove(&mut document, "hosts", "m2")?;
+        let edited = document.to_string();
+        assert!(edited.contains("# Staging\n\n# The last box\n[hosts.m3]"));
+        assert!(!edited.contains("second box"));
+
+        let _m1 = remove(&mut document, "hosts", "m1")?;
+        let _m3 = remove(&mut document, "hosts", "m3")?;
+        assert_eq!(
+            document.to_string(),
+            "# The hosts of the lab\n\n# Staging\n"
+        );
+        assert!(remove(&mut document, "hosts", "m3")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn stdin_config_is_not_editable() {
+        let error = load(Path::new(STDIN)).err().map(|e| e.to_string());
+        assert!(error.is_some_and(|e| e.contains("--config <path>")));
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The formats a config can be written in
+//!
+//! A config is TOML unless its file ends in `.yaml` or `.yml`.  A YAML config
+//! is turned into TOML as it is read, so everything reading the config, i.e.
+//! the tables libmussh doesn't know about, only ever sees TOML.
+use crate::error::{MusshErr, MusshErrKind, MusshResult};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::value::Table;
+
+/// The name of the config file in the config directory, without its extension.
+const CONFIG_FILE_STEM: &str = "mussh";
+
+/// The format of a config file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ConfigFormat {
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// The format of the file, by its extension, TOML for anything but
+    /// `.yaml` and `.yml`.
+    pub(crate) fn of(path: &Path) -> Self {
+        match path.extension().and_then(OsStr::to_str) {
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Toml,
+        }
+    }
+
+    /// Parse the contents of the config file at `path`, reporting errors with
+    /// the file, line and column they occurred at.
+    pub(crate) fn parse(self, path: &Path, contents: &str) -> MusshResult<Table> {
+        match self {
+            Self::Toml => toml::from_str(contents).map_err(|e| -> MusshErr {
+                MusshErrKind::ConfigParse(path.to_path_buf(), e).into()
+            }),
+            // An empty YAML document is null rather than an empty mapping.
+            Self::Yaml if contents.trim().is_empty() => Ok(Table::new()),
+            Self::Yaml => serde_yaml::from_str(contents)
+                .map_err(|e| -> MusshErr { MusshErrKind::YamlParse(path.to_path_buf(), e).into() }),
+        }
+    }
+
+    /// The contents of the config file at `path` as TOML.
+    pub(crate) fn to_toml(self, path: &Path, contents: String) -> MusshResult<String> {
+        match self {
+            Self::Toml => Ok(contents),
+            Self::Yaml => Ok(toml::to_string(&self.parse(path, &contents)?)?),
+        }
+    }
+
+    /// The config document as it would be written in this format.
+    pub(crate) fn write(self, value: &toml::Value) -> MusshResult<String> {
+        match self {
+            Self::Toml => Ok(toml::to_string(value)?),
+            Self::Yaml => serde_yaml::to_string(value)
+                .map_err(|e| format!("Unable to write the config as YAML: {e}").into()),
+        }
+    }
+}
+
+/// Read the config file at `path`, as TOML.
+pub(crate) fn read(path: &Path) -> MusshResult<String> {
+    ConfigFormat::of(path).to_toml(path, fs::read_to_string(path)?)
+}
+
+/// The config file in `dir`: `mussh.toml`, or failing that `mussh.yaml` or
+/// `mussh.yml`.  `mussh.toml` is returned if there is none, so the error for a
+/// missing config names the default.
+pub(crate) fn config_path(dir: &Path) -> PathBuf {
+    let toml = dir.join(CONFIG_FILE_STEM).with_extension("toml");
+    if toml.exists() {
+        return toml;
+    }
+    ["yaml", "yml"]
+        .iter()
+        .map(|extension| dir.join(CONFIG_FILE_STEM).with_extension(extension))
+        .find(|path| path.exists())
+        .unwrap_or(toml)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{config_path, ConfigFormat};
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    const YAML: &str = r"hostlist:
+  web:
+    hostnames: [web1]
+hosts:
+  web1:
+    hostname: 10.0.0.1
+    port: 2222
+    username: jozias
+cmd:
+  ls:
+    command: ls -al
+";
+
+    #[test]
+    fn reads_yaml_as_toml() -> MusshResult<()> {
+        let path = Path::new("mussh.yaml");
+        assert_eq!(ConfigFormat::of(path), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::of(Path::new("mussh.yml")), ConfigFormat::Yaml);
+        assert_eq!(
+            ConfigFormat::of(Path::new("mussh.toml")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(ConfigFormat::of(Path::new("-")), ConfigFormat::Toml);
+
+        let toml = ConfigFormat::Yaml.to_toml(path, YAML.to_string())?;
+        let config: Config = toml::from_str(&toml)?;
+        let value = toml::Value::try_from(&config)?;
+        assert_eq!(
+            toml::from_str::<Config>(&ConfigFormat::Toml.write(&value)?)?,
+            config
+        );
+        let yaml = ConfigFormat::Yaml.write(&value)?;
+        let reread: Config = toml::from_str(&ConfigFormat::Yaml.to_toml(path, yaml)?)?;
+        assert_eq!(reread, config);
+        assert!(toml.contains("port = 2222"));
+        assert_eq!(ConfigFormat::Yaml.to_toml(path, String::new())?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn yaml_errors_have_positions() {
+        let e = ConfigFormat::Yaml
+            .parse(Path::new("mussh.yaml"), "hosts:\n  web: [\n")
+            .err()
+            .map(|e| e.to_string());
+        assert!(e.is_some_and(|e| e.starts_with("mussh.yaml:3:")));
+        let e = ConfigFormat::Yaml
+            .parse(Path::new("mussh.yaml"), "- a list\n")
+            .err()
+            .map(|e| e.to_string());
+        assert!(e.is_some_and(|e| e.starts_with("mussh.yaml")));
+    }
+
+    #[test]
+    fn prefers_toml() -> MusshResult<()> {
+        let dir = env::temp_dir().join(format!("mussh-config-format-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let missing = config_path(&dir);
+        fs::write(dir.join("mussh.yml"), YAML)?;
+        let yaml = config_path(&dir);
+        fs::write(dir.join("mussh.toml"), "")?;
+        let toml = config_path(&dir);
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(missing, dir.join("mussh.toml"));
+        assert_eq!(yaml, dir.join("mussh.yml"));
+        assert_eq!(toml, dir.join("mussh.toml"));
+        Ok(())
+    }
+}
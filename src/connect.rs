@@ -0,0 +1,390 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Connecting to hosts within a timeout
+use crate::error::{MusshErrKind, MusshResult};
+use crate::extensions::Extensions;
+use crate::warnings::Warnings;
+use libmussh::Config;
+use slog::Logger;
+use slog_try::try_trace;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant};
+use toml::Value;
+
+/// How long connecting to a host may take when no timeout is given.
+pub(crate) const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The addresses of the hostnames looked up against the `--resolver`
+/// nameserver, connected to rather than those of the system resolver.  The
+/// hosts keep their hostnames, so their keys are still checked against them.
+static RESOLVED: RwLock<BTreeMap<String, Vec<IpAddr>>> = RwLock::new(BTreeMap::new());
+
+/// How long connecting to each host may take.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ConnectTimeouts {
+    /// The timeout given with `--connect-timeout`, for hosts without their own.
+    default: Duration,
+    /// The `connect_timeout` of the `[hosts.<name>]` tables in the config.
+    hosts: HashMap<String, Duration>,
+}
+
+impl Default for ConnectTimeouts {
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_CONNECT_TIMEOUT,
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+impl ConnectTimeouts {
+    /// Check the `connect_timeout` of the hosts in the config, in seconds.
+    pub(crate) fn parse(extensions: &Extensions, default_secs: Option<usize>) -> MusshResult<Self> {
+        let mut hosts = HashMap::new();
+
+        for (name, host) in &extensions.hosts {
+            match host.connect_timeout {
+                Some(0) => {
+                    return Err(format!(
+                        "The connect_timeout of host '{name}' must be a positive number of seconds"
+                    )
+                    .into())
+                }
+                Some(secs) => {
+                    let _old = hosts.insert(name.clone(), Duration::from_secs(secs));
+                }
+                None => {}
+            }
+        }
+
+        Ok(Self {
+            default: default_secs.map_or(DEFAULT_CONNECT_TIMEOUT, |secs| {
+                Duration::from_secs(u64::try_from(secs).unwrap_or(u64::MAX))
+            }),
+            hosts,
+        })
+    }
+
+    /// How long connecting to the given host may take.
+    pub(crate) fn for_host(&self, name: &str) -> Duration {
+        self.hosts.get(name).copied().unwrap_or(self.default)
+    }
+}
+
+/// An address family to connect to hosts over, with `--ipv4` or `--ipv6`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn includes(self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::V4 => addr.is_ipv4(),
+            Self::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::V4 => write!(f, "IPv4"),
+            Self::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Connect to `hostname:port` within `timeout`.
+///
+/// A hostname that resolves to more than one address has each tried in turn,
+/// in the time left of the timeout, until one connects.  Resolving the
+/// hostname isn't covered by the timeout.
+pub(crate) fn connect(hostname: &str, port: u16, timeout: Duration) -> MusshResult<TcpStream> {
+    connect_addrs(hostname, &socket_addrs(hostname, port)?, timeout).map(|(stream, _)| stream)
+}
+
+/// Connect to `hostname` at the given addresses from now on, in order.
+pub(crate) fn resolve_to(hostname: &str, addrs: Vec<IpAddr>) {
+    if let Ok(mut resolved) = RESOLVED.write() {
+        let _old = resolved.insert(hostname.to_string(), addrs);
+    }
+}
+
+/// The addresses of `hostname`, those it was resolved to with `--resolver`,
+/// or else those of the system resolver.
+fn socket_addrs(hostname: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let resolved = RESOLVED
+        .read()
+        .ok()
+        .and_then(|resolved| resolved.get(hostname).cloned());
+    match resolved {
+        Some(addrs) => Ok(addrs
+            .into_iter()
+            .map(|addr| SocketAddr::new(addr, port))
+            .collect()),
+        None => Ok((hostname, port).to_socket_addrs()?.collect()),
+    }
+}
+
+/// Connect to the first of the addresses of `hostname` that connects within
+/// `timeout`, returning the stream and the address.
+fn connect_addrs(
+    hostname: &str,
+    addrs: &[SocketAddr],
+    timeout: Duration,
+) -> MusshResult<(TcpStream, SocketAddr)> {
+    let deadline = Instant::now() + timeout;
+    let mut last_error = None;
+
+    for addr in addrs {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        match TcpStream::connect_timeout(addr, left) {
+            Ok(stream) => return Ok((stream, *addr)),
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    let timed_out = Instant::now() >= deadline;
+    match last_error {
+        Some(e) if !timed_out => Err(e.into()),
+        None if !timed_out => Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("'{hostname}' doesn't resolve to any address"),
+        )
+        .into()),
+        _ => Err(MusshErrKind::ConnectTimeout(hostname.to_string(), timeout).into()),
+    }
+}
+
+/// Pin the hostname of every remote host to an address of the family, the
+/// first of its addresses that connects within its connect timeout, as
+/// libmussh connects to whatever address the hostname resolves to first.
+/// The hosts are connected to in parallel, and the address each ends up with
+/// is logged at trace level.
+///
+/// Hostnames that are already addresses, and `localhost`, are left alone.  A
+/// hostname without an address of the family is left as is, with a warning.
+/// If none of the addresses connect, the hostname is pinned to the first, to
+/// fail connecting later like any other unreachable host.
+pub(crate) fn pin_family(
+    config: &Config,
+    family: Family,
+    timeouts: &ConnectTimeouts,
+    stdout: Option<&Logger>,
+    warnings: &mut Warnings,
+) -> MusshResult<Config> {
+    let mut value = Value::try_from(config)?;
+    let Some(hosts) = value.get_mut("hosts").and_then(Value::as_table_mut) else {
+        return Ok(value.try_into()?);
+    };
+
+    let pinned: Vec<(String, Result<SocketAddr, String>)> = thread::scope(|scope| {
+        let handles: Vec<_> = hosts
+            .iter()
+            .filter_map(|(name, host)| {
+                let hostname = host.get("hostname").and_then(Value::as_str)?;
+                if !needs_lookup(hostname) {
+                    return None;
+                }
+                let port = host
+                    .get("port")
+                    .and_then(Value::as_integer)
+                    .and_then(|port| u16::try_from(port).ok())
+                    .unwrap_or(22);
+                let timeout = timeouts.for_host(name);
+                let handle = scope.spawn(move || pin(hostname, port, family, timeout));
+                Some((name.clone(), handle))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|(name, handle)| {
+                let pinned = handle
+                    .join()
+                    .unwrap_or_else(|_| Err("the lookup panicked".to_string()));
+                (name, pinned)
+            })
+            .collect()
+    });
+
+    for (name, pinned) in pinned {
+        let Some(host) = hosts.get_mut(&name).and_then(Value::as_table_mut) else {
+            continue;
+        };
+        let hostname = host
+            .get("hostname")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        match pinned {
+            Ok(addr) => {
+                let addr = pinned_hostname(&addr);
+                try_trace!(stdout, "pinned"; "host" => &name, "hostname" => &hostname, "addr" => &addr);
+                let _old = host.insert("hostname".to_string(), Value::String(addr));
+            }
+            Err(e) => warnings.warn(format!(
+                "Not pinning '{hostname}' (host '{name}') to {family}, connecting to any of its \
+                 addresses: {e}"
+            )),
+        }
+    }
+
+    Ok(value.try_into()?)
+}
+
+/// The address of the family `hostname` is to be connected to over.
+fn pin(hostname: &str, port: u16, family: Family, timeout: Duration) -> Result<SocketAddr, String> {
+    let addrs: Vec<SocketAddr> = socket_addrs(hostname, port)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter(|addr| family.includes(addr))
+        .collect();
+    let first = addrs
+        .first()
+        .copied()
+        .ok_or_else(|| format!("it has no {family} address"))?;
+    Ok(connect_addrs(hostname, &addrs, timeout).map_or(first, |(_, addr)| addr))
+}
+
+/// The address as a hostname, keeping the zone of a link-local IPv6 address,
+/// i.e. `fe80::1%2`, which is lost by `IpAddr`.
+fn pinned_hostname(addr: &SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+            format!("{}%{}", addr.ip(), addr.scope_id())
+        }
+        _ => addr.ip().to_string(),
+    }
+}
+
+fn needs_lookup(hostname: &str) -> bool {
+    hostname != "localhost" && hostname.parse::<IpAddr>().is_err()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        connect, pin, pin_family, pinned_hostname, resolve_to, ConnectTimeouts, Family,
+        DEFAULT_CONNECT_TIMEOUT,
+    };
+    use crate::error::MusshResult;
+    use crate::warnings::Warnings;
+    use libmussh::Config;
+    use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener};
+    use std::time::{Duration, Instant};
+
+    const TIMEOUTS_TOML: &str = r#"[hosts.web]
+hostname = "10.0.0.3"
+username = "jozias"
+connect_timeout = 3
+[hosts.db]
+hostname = "10.0.0.4"
+username = "jozias"
+"#;
+
+    #[test]
+    fn timeouts() -> MusshResult<()> {
+        let timeouts = ConnectTimeouts::parse(&toml::from_str(TIMEOUTS_TOML)?, None)?;
+        assert_eq!(timeouts.for_host("web"), Duration::from_secs(3));
+        assert_eq!(timeouts.for_host("db"), DEFAULT_CONNECT_TIMEOUT);
+
+        let timeouts = ConnectTimeouts::parse(&toml::from_str(TIMEOUTS_TOML)?, Some(5))?;
+        assert_eq!(timeouts.for_host("web"), Duration::from_secs(3));
+        assert_eq!(timeouts.for_host("db"), Duration::from_secs(5));
+
+        let invalid = TIMEOUTS_TOML.replace("connect_timeout = 3", "connect_timeout = 0");
+        assert!(ConnectTimeouts::parse(&toml::from_str(&invalid)?, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn connects() -> MusshResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let _stream = connect("localhost", port, Duration::from_secs(1))?;
+        drop(listener);
+
+        // Refused straight away, well within the timeout.
+        let started = Instant::now();
+        assert!(connect("127.0.0.1", port, Duration::from_secs(5)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn resolved() -> MusshResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        assert!(connect("mussh-resolved.invalid", port, Duration::from_secs(1)).is_err());
+
+        // The first address refuses, and the next is tried.
+        resolve_to(
+            "mussh-resolved.invalid",
+            vec![[127, 0, 0, 2].into(), [127, 0, 0, 1].into()],
+        );
+        let _stream = connect("mussh-resolved.invalid", port, Duration::from_secs(1))?;
+        Ok(())
+    }
+
+    #[test]
+    fn families() -> MusshResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            pin("localhost", port, Family::V4, timeout),
+            Ok(SocketAddr::from(([127, 0, 0, 1], port)))
+        );
+        assert!(pin("localhost.invalid", port, Family::V4, timeout).is_err());
+
+        let v6 = SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1),
+            22,
+            0,
+            2,
+        ));
+        assert_eq!(pinned_hostname(&v6), "fe80::1%2");
+        assert_eq!(
+            pinned_hostname(&SocketAddr::from(([10, 0, 0, 1], 22))),
+            "10.0.0.1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pins_hostnames() -> MusshResult<()> {
+        let config: Config = toml::from_str(
+            "[hostlist]\n[hosts.web]\nhostname = \"ip6-localhost.invalid\"\nusername = \"u\"\n\
+             [hosts.db]\nhostname = \"10.0.0.4\"\nusername = \"u\"\n[cmd]\n",
+        )?;
+        let mut warnings = Warnings::default();
+        let pinned = pin_family(
+            &config,
+            Family::V6,
+            &ConnectTimeouts::default(),
+            None,
+            &mut warnings,
+        )?;
+        assert_eq!(pinned, config);
+        assert!(warnings.emit(None, true).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Defaults for the hosts
+//!
+//! A `[defaults]` table gives the `username`, `port`, `pem` and `shell` of the
+//! hosts that don't give their own.  A value given by a host always wins.
+use crate::config_format::ConfigFormat;
+use crate::error::MusshResult;
+use std::path::Path;
+use toml::Value;
+
+/// The name of the table of defaults.
+const DEFAULTS_KEY: &str = "defaults";
+/// The fields of a host that may be defaulted.
+const FIELDS: [&str; 4] = ["username", "port", "pem", "shell"];
+
+/// Fill in the fields the hosts leave out from the `[defaults]` table.
+///
+/// Returns whether the config has a `[defaults]` table.
+pub(crate) fn apply(config: &mut Value) -> MusshResult<bool> {
+    let Some(defaults) = config.get(DEFAULTS_KEY) else {
+        return Ok(false);
+    };
+    let defaults = defaults
+        .as_table()
+        .ok_or("The defaults must be a table of username, port, pem and shell")?
+        .clone();
+
+    for (field, default) in &defaults {
+        let valid = match field.as_str() {
+            "port" => default
+                .as_integer()
+                .is_some_and(|port| (1..=65535).contains(&port)),
+            "username" | "pem" | "shell" => default.as_str().is_some_and(|s| !s.trim().is_empty()),
+            _ => {
+                return Err(format!(
+                    "Unknown default '{field}', expected one of {}",
+                    FIELDS.join(", ")
+                )
+                .into())
+            }
+        };
+        if !valid {
+            let expected = if field == "port" {
+                "a port, 1-65535"
+            } else {
+                "a non-empty string"
+            };
+            return Err(format!("The default {field} must be {expected}").into());
+        }
+    }
+
+    if let Some(hosts) = config.get_mut("hosts").and_then(Value::as_table_mut) {
+        for host in hosts.iter_mut().filter_map(|(_, host)| host.as_table_mut()) {
+            for (field, default) in &defaults {
+                let _ = host.entry(field.clone()).or_insert_with(|| default.clone());
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// The config TOML read from `path` with the defaults filled in, so the fields
+/// only mussh reads, i.e. `shell`, see them too.  A config without a
+/// `[defaults]` table is returned as it is.
+pub(crate) fn with_defaults(path: &Path, contents: String) -> MusshResult<String> {
+    let mut config = Value::Table(ConfigFormat::Toml.parse(path, &contents)?);
+    if apply(&mut config)? {
+        Ok(toml::to_string(&config)?)
+    } else {
+        Ok(contents)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, with_defaults};
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use std::path::Path;
+    use toml::Value;
+
+    const DEFAULTS_TOML: &str = r#"[defaults]
+username = "deploy"
+port = 2222
+pem = "/home/deploy/.ssh/id_ed25519"
+shell = "bash"
+[hostlist.all]
+hostnames = ["web", "db"]
+[hosts.web]
+hostname = "10.0.0.3"
+[hosts.db]
+hostname = "10.0.0.4"
+username = "postgres"
+port = 22
+shell = "zsh"
+[cmd.ls]
+command = "ls"
+"#;
+
+    #[test]
+    fn hosts_fall_back_to_defaults() -> MusshResult<()> {
+        let toml = with_defaults(Path::new("mussh.toml"), DEFAULTS_TOML.to_string())?;
+        let value: Value = toml::from_str(&toml)?;
+        let config: Config = value.clone().try_into()?;
+
+        let web = config.hosts().get("web").ok_or("no web")?;
+        assert_eq!(web.username(), "deploy");
+        assert_eq!(*web.port(), Some(2222));
+        assert_eq!(web.pem().as_deref(), Some("/home/deploy/.ssh/id_ed25519"));
+        let db = config.hosts().get("db").ok_or("no db")?;
+        assert_eq!(db.username(), "postgres");
+        assert_eq!(*db.port(), Some(22));
+        assert_eq!(db.pem().as_deref(), Some("/home/deploy/.ssh/id_ed25519"));
+
+        let shell = |host: &str| value["hosts"][host].get("shell").and_then(Value::as_str);
+        assert_eq!(shell("web"), Some("bash"));
+        assert_eq!(shell("db"), Some("zsh"));
+
+        let plain = "[hosts.web]\nhostname = \"h\"\n".to_string();
+        assert_eq!(
+            with_defaults(Path::new("mussh.toml"), plain.clone())?,
+            plain
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_defaults() -> MusshResult<()> {
+        for (bad, error) in &[
+            ("defaults = 1", "The defaults must be a table"),
+            (
+                "[defaults]\nport = 0",
+                "The default port must be a port, 1-65535",
+            ),
+            (
+                "[defaults]\nusername = \"\"",
+                "The default username must be",
+            ),
+            ("[defaults]\nhostname = \"h\"", "Unknown default 'hostname'"),
+        ] {
+            let mut value: Value = toml::from_str(bad)?;
+            let e = apply(&mut value).err().map(|e| e.to_string());
+            assert!(e.is_some_and(|e| e.starts_with(error)));
+        }
+        Ok(())
+    }
+}
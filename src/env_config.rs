@@ -0,0 +1,183 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Config overrides from the environment
+//!
+//! `MUSSH_HOSTS_<host>_<FIELD>=<value>` sets a field of a host, where the
+//! field is one of `HOSTNAME`, `USERNAME`, `PORT` or `PEM`.  Likewise
+//! `MUSSH_CMD_<cmd>_COMMAND` sets the command of a command, and
+//! `MUSSH_HOSTLIST_<hostlist>_HOSTNAMES` the comma separated hosts of a
+//! hostlist.  Hosts, commands and hostlists that aren't in the config file are
+//! added.
+//!
+//! The overrides are applied on top of the config file (or stdin), and the
+//! run's command line flags, such as `--target` and `--use-ssh-config`, are
+//! applied on top of them.
+use crate::error::MusshResult;
+use toml::value::{Table, Value};
+
+const HOSTS_PREFIX: &str = "MUSSH_HOSTS_";
+const CMD_PREFIX: &str = "MUSSH_CMD_";
+const HOSTLIST_PREFIX: &str = "MUSSH_HOSTLIST_";
+
+/// Are there any config overrides among the variables?
+pub(crate) fn has_overrides<I>(vars: I) -> bool
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    vars.into_iter().any(|(key, _)| section(&key).is_some())
+}
+
+/// Apply the config overrides among the variables to the config.
+///
+/// Returns whether there were any overrides.
+pub(crate) fn apply<I>(config: &mut Value, vars: I) -> MusshResult<bool>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut applied = false;
+
+    for (key, value) in vars {
+        let Some((section, rest)) = section(&key) else {
+            continue;
+        };
+        let (name, field) = rest
+            .rsplit_once('_')
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| format!("{key} doesn't name a {section} entry and field"))?;
+        let (field, value) = field_value(section, field, &value)
+            .ok_or_else(|| format!("Invalid config override {key}={value}"))?;
+
+        let root = config.as_table_mut().ok_or("The config is not a table")?;
+        let entry = root
+            .entry(section)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .and_then(|entries| {
+                entries
+                    .entry(name)
+                    .or_insert_with(|| Value::Table(Table::new()))
+                    .as_table_mut()
+            })
+            .ok_or_else(|| format!("{section}.{name} in the config is not a table"))?;
+        let _old = entry.insert(field.to_string(), value);
+        applied = true;
+    }
+
+    Ok(applied)
+}
+
+/// The config section the variable overrides, and the rest of its name.
+fn section(key: &str) -> Option<(&'static str, &str)> {
+    [
+        ("hosts", HOSTS_PREFIX),
+        ("cmd", CMD_PREFIX),
+        ("hostlist", HOSTLIST_PREFIX),
+    ]
+    .iter()
+    .find_map(|(section, prefix)| key.strip_prefix(prefix).map(|rest| (*section, rest)))
+}
+
+/// The config field and value for the field of a variable, if it's a field of
+/// the section.
+fn field_value(section: &str, field: &str, value: &str) -> Option<(&'static str, Value)> {
+    let string = || Value::String(value.to_string());
+    match (section, field) {
+        ("hosts", "HOSTNAME") => Some(("hostname", string())),
+        ("hosts", "USERNAME") => Some(("username", string())),
+        ("hosts", "PEM") => Some(("pem", string())),
+        ("hosts", "PORT") => value
+            .parse::<u16>()
+            .ok()
+            .map(|port| ("port", Value::Integer(i64::from(port)))),
+        ("cmd", "COMMAND") => Some(("command", string())),
+        ("hostlist", "HOSTNAMES") => Some((
+            "hostnames",
+            Value::Array(
+                value
+                    .split(',')
+                    .map(|host| Value::String(host.trim().to_string()))
+                    .collect(),
+            ),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, has_overrides};
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use toml::Value;
+
+    const ENV_TOML: &str = r#"[hostlist.all]
+hostnames = ["web01"]
+[hosts.web01]
+hostname = "10.0.0.3"
+username = "jozias"
+[cmd.ls]
+command = "ls"
+"#;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_overrides_the_file() -> MusshResult<()> {
+        let mut value: Value = toml::from_str(ENV_TOML)?;
+        let env = vars(&[
+            ("MUSSH_HOSTS_web01_HOSTNAME", "10.0.0.9"),
+            ("MUSSH_HOSTS_web01_PORT", "2222"),
+            ("MUSSH_HOSTS_db_01_HOSTNAME", "10.0.0.4"),
+            ("MUSSH_HOSTS_db_01_USERNAME", "deploy"),
+            ("MUSSH_HOSTLIST_all_HOSTNAMES", "web01, db_01"),
+            ("MUSSH_CMD_ls_COMMAND", "ls -al"),
+            ("PATH", "/bin"),
+        ]);
+        assert!(has_overrides(env.clone()));
+        assert!(apply(&mut value, env)?);
+
+        let config: Config = value.try_into()?;
+        let web01 = config.hosts().get("web01").ok_or("no web01")?;
+        assert_eq!(web01.hostname(), "10.0.0.9");
+        assert_eq!(web01.username(), "jozias");
+        assert_eq!(*web01.port(), Some(2222));
+        let db = config.hosts().get("db_01").ok_or("no db_01")?;
+        assert_eq!(db.username(), "deploy");
+        let all = config.hostlist().get("all").ok_or("no all")?;
+        assert_eq!(
+            all.hostnames(),
+            &vec!["web01".to_string(), "db_01".to_string()]
+        );
+        assert_eq!(
+            config.cmd().get("ls").map(|cmd| cmd.command().as_str()),
+            Some("ls -al")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bad_overrides() -> MusshResult<()> {
+        let mut value: Value = toml::from_str(ENV_TOML)?;
+        assert!(!has_overrides(vars(&[("MUSSH_LOG", "1")])));
+        assert!(!apply(&mut value, vars(&[("MUSSH_LOG", "1")]))?);
+        for bad in &[
+            ("MUSSH_HOSTS_web01_PORT", "ssh"),
+            ("MUSSH_HOSTS_web01_SHELL", "bash"),
+            ("MUSSH_HOSTS_HOSTNAME", "10.0.0.9"),
+            ("MUSSH_CMD_ls_HOSTNAME", "10.0.0.9"),
+        ] {
+            assert!(apply(&mut value, vars(&[*bad])).is_err());
+        }
+        Ok(())
+    }
+}
@@ -9,6 +9,8 @@
 //! Error Handling
 use std::error::Error;
 use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// A result that includes a `mussh::Error`
 pub(crate) type MusshResult<T> = Result<T, MusshErr>;
@@ -20,6 +22,13 @@ pub(crate) struct MusshErr {
     inner: MusshErrKind,
 }
 
+impl MusshErr {
+    /// The kind of error.
+    pub(crate) fn kind(&self) -> &MusshErrKind {
+        &self.inner
+    }
+}
+
 impl Error for MusshErr {
     fn description(&self) -> &str {
         "Mussh Error"
@@ -67,24 +76,60 @@ external_error!(std::io::Error, MusshErrKind::Io);
 external_error!(libmussh::Error, MusshErrKind::Libmussh);
 external_error!(String, MusshErrKind::Str);
 external_error!(rusqlite::Error, MusshErrKind::Rusqlite);
+external_error!(serde_json::Error, MusshErrKind::SerdeJson);
+external_error!(ssh2::Error, MusshErrKind::Ssh2);
+external_error!(toml::de::Error, MusshErrKind::TomlDe);
+external_error!(toml::ser::Error, MusshErrKind::TomlSer);
 
 #[derive(Debug)]
 pub(crate) enum MusshErrKind {
+    AuthFailed(String),
+    Authentication(String),
     Clap(clap::Error),
+    ConfigParse(PathBuf, toml::de::Error),
+    ConnectTimeout(String, Duration),
+    FailFast(String),
+    HostKeyMismatch(String, PathBuf, bool),
+    HostsFailed(usize, i32),
     Io(std::io::Error),
+    Jump(String, String),
     Libmussh(libmussh::Error),
+    MaxRuntime(usize),
+    Proxy(String, String),
     Rusqlite(rusqlite::Error),
+    SerdeJson(serde_json::Error),
+    Ssh2(ssh2::Error),
     Str(String),
+    Strict(Vec<String>),
+    TomlDe(toml::de::Error),
+    TomlSer(toml::ser::Error),
+    YamlParse(PathBuf, serde_yaml::Error),
 }
 
 impl Error for MusshErrKind {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
+            MusshErrKind::AuthFailed(_hostname) => None,
+            MusshErrKind::Authentication(_username) => None,
             MusshErrKind::Clap(inner) => inner.source(),
+            MusshErrKind::ConfigParse(_, inner) => Some(inner),
+            MusshErrKind::ConnectTimeout(_hostname, _timeout) => None,
+            MusshErrKind::FailFast(_hostname) => None,
+            MusshErrKind::HostKeyMismatch(_hostname, _path, _changed) => None,
+            MusshErrKind::HostsFailed(_, _) => None,
             MusshErrKind::Io(inner) => inner.source(),
+            MusshErrKind::Jump(_jump, _message) => None,
             MusshErrKind::Libmussh(inner) => inner.source(),
+            MusshErrKind::MaxRuntime(_hosts) => None,
+            MusshErrKind::Proxy(_addr, _message) => None,
             MusshErrKind::Rusqlite(inner) => inner.source(),
+            MusshErrKind::SerdeJson(inner) => inner.source(),
+            MusshErrKind::Ssh2(inner) => inner.source(),
             MusshErrKind::Str(_inner) => None,
+            MusshErrKind::Strict(_warnings) => None,
+            MusshErrKind::TomlDe(inner) => inner.source(),
+            MusshErrKind::TomlSer(inner) => inner.source(),
+            MusshErrKind::YamlParse(_, inner) => Some(inner),
         }
     }
 }
@@ -93,10 +138,89 @@ impl fmt::Display for MusshErrKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             MusshErrKind::Str(inner) => write!(f, "{inner}"),
+            MusshErrKind::AuthFailed(hostname) => write!(
+                f,
+                "Authentication failed on '{hostname}', stopped the run (--fail-fast-on-auth)"
+            ),
+            MusshErrKind::Authentication(username) => {
+                write!(f, "Unable to authenticate as {username}")
+            }
             MusshErrKind::Clap(inner) => write!(f, "{inner}"),
+            MusshErrKind::ConfigParse(path, inner) => match inner.line_col() {
+                Some((line, col)) => write!(
+                    f,
+                    "{}:{}:{}: {}",
+                    path.display(),
+                    line + 1,
+                    col + 1,
+                    without_position(&inner.to_string())
+                ),
+                None => write!(f, "{}: {inner}", path.display()),
+            },
+            MusshErrKind::ConnectTimeout(hostname, timeout) => write!(
+                f,
+                "Unable to connect to '{hostname}' within {}s",
+                timeout.as_secs()
+            ),
+            MusshErrKind::FailFast(hostname) => write!(
+                f,
+                "A command failed on '{hostname}', stopped the run (--fail-fast)"
+            ),
+            MusshErrKind::HostKeyMismatch(hostname, path, true) => write!(
+                f,
+                "The host key of '{hostname}' has changed since it was added to {}, refusing \
+                 to connect",
+                path.display()
+            ),
+            MusshErrKind::HostKeyMismatch(hostname, path, false) => write!(
+                f,
+                "The host key of '{hostname}' isn't in {}, refusing to connect \
+                 (--accept-new-host-keys adds it)",
+                path.display()
+            ),
+            MusshErrKind::HostsFailed(failed, _) => write!(f, "{failed} host(s) failed"),
             MusshErrKind::Io(inner) => write!(f, "{inner}"),
+            MusshErrKind::Jump(jump, message) => {
+                write!(f, "Unable to use the jump host {jump}: {message}")
+            }
             MusshErrKind::Libmussh(inner) => write!(f, "{inner}"),
+            MusshErrKind::MaxRuntime(hosts) => {
+                write!(f, "{hosts} host(s) timed out by --max-runtime")
+            }
+            MusshErrKind::Proxy(addr, message) => {
+                write!(f, "Unable to use the proxy {addr}: {message}")
+            }
             MusshErrKind::Rusqlite(inner) => write!(f, "{inner}"),
+            MusshErrKind::SerdeJson(inner) => write!(f, "{inner}"),
+            MusshErrKind::Ssh2(inner) => write!(f, "{inner}"),
+            MusshErrKind::Strict(warnings) => {
+                write!(f, "{} warning(s) with --strict:", warnings.len())?;
+                for warning in warnings {
+                    write!(f, "\n  {warning}")?;
+                }
+                Ok(())
+            }
+            MusshErrKind::TomlDe(inner) => write!(f, "{inner}"),
+            MusshErrKind::TomlSer(inner) => write!(f, "{inner}"),
+            MusshErrKind::YamlParse(path, inner) => match inner.location() {
+                Some(location) => write!(
+                    f,
+                    "{}:{}:{}: {}",
+                    path.display(),
+                    location.line(),
+                    location.column(),
+                    without_position(&inner.to_string())
+                ),
+                None => write!(f, "{}: {inner}", path.display()),
+            },
         }
     }
 }
+
+/// toml appends the position to its messages, which is redundant once it has
+/// been moved to the front.
+fn without_position(message: &str) -> &str {
+    message
+        .rsplit_once(" at line ")
+        .map_or(message, |(message, _)| message)
+}
@@ -0,0 +1,152 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Expected command output
+use crate::error::MusshResult;
+use crate::extensions::Extensions;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// The regexes the output of commands must match for them to succeed.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Expectations {
+    /// The regex given with `--expect`, for commands without their own.
+    default: Option<Regex>,
+    /// The `expect` regexes of the `[cmd.<name>]` tables in the config.
+    cmds: HashMap<String, Regex>,
+}
+
+impl Expectations {
+    /// Compile the `expect` regexes of the commands in the config.
+    pub(crate) fn parse(extensions: &Extensions, default: Option<&str>) -> MusshResult<Self> {
+        let mut cmds = HashMap::new();
+
+        for (name, cmd) in &extensions.cmd {
+            if let Some(expect) = &cmd.expect {
+                let _old = cmds.insert(name.clone(), compile(name, expect)?);
+            }
+        }
+
+        Ok(Self {
+            default: default
+                .map(|expect| compile("--expect", expect))
+                .transpose()?,
+            cmds,
+        })
+    }
+
+    /// Is there anything to check?
+    pub(crate) fn is_empty(&self) -> bool {
+        self.default.is_none() && self.cmds.is_empty()
+    }
+
+    /// The regex the output of the given command must match, if any.
+    pub(crate) fn for_cmd(&self, cmd_name: &str) -> Option<&Regex> {
+        self.cmds.get(cmd_name).or(self.default.as_ref())
+    }
+}
+
+fn compile(name: &str, expect: &str) -> MusshResult<Regex> {
+    Regex::new(expect).map_err(|e| format!("Invalid expect regex for '{name}': {e}").into())
+}
+
+/// The error for output that doesn't match the regex, if it doesn't.
+pub(crate) fn check(regex: &Regex, output: &str) -> Option<String> {
+    if regex.is_match(output) {
+        None
+    } else {
+        Some(format!("Output did not match /{regex}/"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, Expectations};
+    use crate::error::MusshResult;
+    use crate::runner::{self, Hooks};
+    use crate::targets;
+    use crate::warnings::Warnings;
+    use indexmap::IndexSet;
+    use libmussh::{Config, Multiplex, RuntimeConfig};
+    use std::sync::Arc;
+
+    const EXPECT_TOML: &str = r#"[hostlist]
+[hosts]
+[cmd.nginx]
+command = "systemctl is-active nginx"
+expect = "^active$"
+[cmd.uptime]
+command = "uptime"
+"#;
+
+    const LOCAL_TOML: &str = r#"[hostlist.all]
+hostnames = ["a"]
+[hosts.a]
+hostname = "localhost"
+username = "jozias"
+[cmd.active]
+command = "echo active"
+expect = "^active$"
+[cmd.inactive]
+command = "echo inactive"
+expect = "^active$"
+"#;
+
+    #[test]
+    fn per_command_regexes() -> MusshResult<()> {
+        let expectations = Expectations::parse(&toml::from_str(EXPECT_TOML)?, Some("up"))?;
+        let nginx = expectations.for_cmd("nginx").ok_or("no nginx regex")?;
+        assert_eq!(nginx.as_str(), "^active$");
+        assert_eq!(check(nginx, "active"), None);
+        assert_eq!(
+            check(nginx, "inactive"),
+            Some("Output did not match /^active$/".to_string())
+        );
+        let uptime = expectations.for_cmd("uptime").ok_or("no uptime regex")?;
+        assert_eq!(uptime.as_str(), "up");
+
+        let expectations = Expectations::parse(&toml::from_str(EXPECT_TOML)?, None)?;
+        assert!(expectations.for_cmd("uptime").is_none());
+        assert!(Expectations::parse(&toml::from_str(EXPECT_TOML)?, Some("(")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_output_fails() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCAL_TOML)?;
+        let mut runtime_config = RuntimeConfig::default();
+        let _ = runtime_config.set_hosts(vec!["all".to_string()].into_iter().collect());
+        let _ = runtime_config.set_cmds(
+            vec!["active".to_string(), "inactive".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        let (_, multiplex_map) =
+            targets::to_host_map(&config, &runtime_config, true, &mut Warnings::default())?;
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_expect(Some(Arc::new(Expectations::parse(
+            &toml::from_str(LOCAL_TOML)?,
+            None,
+        )?)));
+        let results = runner::run(
+            &Multiplex::default(),
+            &IndexSet::new(),
+            multiplex_map,
+            &hooks,
+        );
+
+        let failed: Vec<&str> = results
+            .iter()
+            .filter(|result| !result.success())
+            .map(|result| result.cmd_name().as_str())
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(failed, vec!["inactive"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The config fields only mussh knows about
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+
+/// The fields of the config that mussh adds to libmussh's layout.
+///
+/// libmussh's `Config` drops the keys it doesn't know when it is deserialized,
+/// so these are deserialized from the same loaded document alongside it, once,
+/// and handed to the subcommands that use them.  Their types are checked here,
+/// anything further, like a regex compiling, where each is used.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub(crate) struct Extensions {
+    /// The command run on the hosts when no commands are given.
+    pub(crate) default_cmd: Option<String>,
+    /// The `[options]` table, defaults for the connection flags.
+    pub(crate) options: Options,
+    /// The fields of the `[hosts.<name>]` tables, by host name.
+    pub(crate) hosts: BTreeMap<String, HostExtensions>,
+    /// The fields of the `[cmd.<name>]` tables, by command name.
+    pub(crate) cmd: BTreeMap<String, CmdExtensions>,
+}
+
+/// The defaults of the flags of the same names, for when they aren't given.
+///
+/// A flag given on the command line always wins over its option, and without
+/// either the built-in default applies.  Each is a positive number, and a key
+/// that isn't one of these is an error, as a misspelt option would otherwise
+/// be silently ignored.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Options {
+    /// `--connect-timeout`, in seconds.
+    connect_timeout: Option<NonZeroUsize>,
+    /// `--keepalive`, in seconds.
+    keepalive: Option<NonZeroUsize>,
+    /// `--session-timeout`, in seconds.
+    session_timeout: Option<NonZeroUsize>,
+    /// `--retries`.
+    retries: Option<NonZeroUsize>,
+    /// `--parallel`.
+    parallel: Option<NonZeroUsize>,
+}
+
+impl Options {
+    /// The default of the flag with the given argument name, if there is one.
+    pub(crate) fn get(&self, name: &str) -> Option<usize> {
+        match name {
+            "connect_timeout" => self.connect_timeout,
+            "keepalive" => self.keepalive,
+            "session_timeout" => self.session_timeout,
+            "retries" => self.retries,
+            "parallel" => self.parallel,
+            _ => None,
+        }
+        .map(NonZeroUsize::get)
+    }
+}
+
+/// The fields of a `[hosts.<name>]` table only mussh knows about.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub(crate) struct HostExtensions {
+    /// The tags the host is selected by with `--tag`.
+    pub(crate) tags: Option<Vec<String>>,
+    /// The proxy to connect through, `none` to connect directly.
+    pub(crate) proxy: Option<String>,
+    /// The jump host to connect through.
+    pub(crate) jump: Option<String>,
+    /// How long connecting may take, in seconds.
+    pub(crate) connect_timeout: Option<u64>,
+    /// The shell commands are run with on `localhost`.
+    pub(crate) shell: Option<String>,
+    /// The key exchange algorithms to offer.
+    pub(crate) ssh_kex: Option<String>,
+    /// The ciphers to offer.
+    pub(crate) ssh_cipher: Option<String>,
+    /// The MAC algorithms to offer.
+    pub(crate) ssh_mac: Option<String>,
+}
+
+impl HostExtensions {
+    /// The algorithms of the given `ssh_*` key.
+    pub(crate) fn algorithms(&self, key: &str) -> Option<&str> {
+        match key {
+            "ssh_kex" => self.ssh_kex.as_deref(),
+            "ssh_cipher" => self.ssh_cipher.as_deref(),
+            "ssh_mac" => self.ssh_mac.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// The fields of a `[cmd.<name>]` table only mussh knows about.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub(crate) struct CmdExtensions {
+    /// The exit codes the command succeeds with.
+    pub(crate) success_codes: Option<Vec<u8>>,
+    /// The regex the output of the command must match.
+    pub(crate) expect: Option<String>,
+    /// The environment variables to set for the command.
+    pub(crate) env: Option<BTreeMap<String, String>>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Extensions, HostExtensions};
+    use crate::error::MusshResult;
+
+    const EXTENSIONS_TOML: &str = r#"default_cmd = "uptime"
+[hostlist]
+[hosts.web]
+hostname = "10.0.0.1"
+username = "jozias"
+tags = ["web"]
+connect_timeout = 5
+ssh_mac = "hmac-sha2-256"
+[hosts.db]
+hostname = "10.0.0.2"
+username = "jozias"
+[cmd.uptime]
+command = "uptime"
+success_codes = [0, 1]
+env = { LANG = "C" }
+"#;
+
+    #[test]
+    fn extensions() -> MusshResult<()> {
+        let extensions: Extensions = toml::from_str(EXTENSIONS_TOML)?;
+        assert_eq!(extensions.default_cmd.as_deref(), Some("uptime"));
+        let web = &extensions.hosts["web"];
+        assert_eq!(web.tags, Some(vec!["web".to_string()]));
+        assert_eq!(web.connect_timeout, Some(5));
+        assert_eq!(web.algorithms("ssh_mac"), Some("hmac-sha2-256"));
+        assert_eq!(web.algorithms("ssh_kex"), None);
+        assert_eq!(extensions.hosts["db"], HostExtensions::default());
+        let uptime = &extensions.cmd["uptime"];
+        assert_eq!(uptime.success_codes, Some(vec![0, 1]));
+        assert_eq!(uptime.env.as_ref().map(|env| &env["LANG"][..]), Some("C"));
+        assert_eq!(toml::from_str::<Extensions>("")?, Extensions::default());
+
+        let options =
+            toml::from_str::<Extensions>("[options]\nretries = 3\nkeepalive = 30\n")?.options;
+        assert_eq!(options.get("retries"), Some(3));
+        assert_eq!(options.get("keepalive"), Some(30));
+        assert_eq!(options.get("parallel"), None);
+
+        for bad in [
+            "default_cmd = 1\n",
+            "[hosts.a]\ntags = \"web\"\n",
+            "[hosts.a]\nconnect_timeout = -1\n",
+            "[cmd.x]\nsuccess_codes = [256]\n",
+            "[cmd.x]\nenv = { A = 1 }\n",
+            "[options]\nparallel = 0\n",
+            "[options]\nretry = 3\n",
+        ] {
+            assert!(toml::from_str::<Extensions>(bad).is_err());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Run result formats
+use crate::error::MusshResult;
+use crate::junit;
+use crate::runner::HostRunResult;
+use crate::util::format_duration;
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// The formats `--format` knows by name.
+pub(crate) const FORMATS: [&str; 4] = ["human", "json", "jsonl", "junit"];
+
+/// Formats the results of a run for stdout.
+pub(crate) trait ResultFormatter {
+    /// Format the results.
+    fn format(&self, results: &[HostRunResult]) -> String;
+
+    /// Is each result formatted on its own as soon as it is in, rather than
+    /// all of them once the run is done?  The status lines of the run go to
+    /// stderr with a format that isn't streamed, to keep stdout to the format.
+    fn streams(&self) -> bool {
+        false
+    }
+}
+
+/// A shared formatter.
+pub(crate) type Formatter = Arc<dyn ResultFormatter + Send + Sync>;
+
+/// A line per result, as each comes in.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Human;
+
+impl ResultFormatter for Human {
+    fn format(&self, results: &[HostRunResult]) -> String {
+        let mut output = String::new();
+        for result in results {
+            let duration = format_duration(result.duration());
+            let _res = match result.error() {
+                Some(error) => writeln!(
+                    output,
+                    "'{}' failed on '{}' in {duration}: {error}",
+                    result.cmd_name(),
+                    result.hostname(),
+                ),
+                None => writeln!(
+                    output,
+                    "'{}' run on '{}' in {duration}",
+                    result.cmd_name(),
+                    result.hostname(),
+                ),
+            };
+        }
+        output
+    }
+
+    fn streams(&self) -> bool {
+        true
+    }
+}
+
+/// A JSON array with an object per result.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Json;
+
+impl ResultFormatter for Json {
+    fn format(&self, results: &[HostRunResult]) -> String {
+        let results: Vec<Value> = results
+            .iter()
+            .map(|result| {
+                json!({
+                    "name": result.name(),
+                    "hostname": result.hostname(),
+                    "cmd": result.cmd_name(),
+                    "success": result.success(),
+                    "error": result.error(),
+                    "duration_ms": result.duration().as_millis(),
+                    "started_at": result.started_at(),
+                    "finished_at": result.finished_at(),
+                    "timed_out": result.timed_out(),
+                    "exit_code": result.exit_code(),
+                    "output_hash": result.output_hash(),
+                    "attempts": result.attempts(),
+                })
+            })
+            .collect();
+        format!("{}\n", Value::Array(results))
+    }
+}
+
+/// A JSON object per result, a line each, and then a line with a summary
+/// object, for `jq` and the like.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct JsonLines;
+
+impl ResultFormatter for JsonLines {
+    fn format(&self, results: &[HostRunResult]) -> String {
+        let mut output = String::new();
+        for result in results {
+            let object = json!({
+                "name": result.name(),
+                "hostname": result.hostname(),
+                "cmd_name": result.cmd_name(),
+                "exit_code": result.exit_code(),
+                "duration_ms": result.duration().as_millis(),
+                "success": result.success(),
+                "error": result.error(),
+                "attempts": result.attempts(),
+            });
+            let _res = writeln!(output, "{object}");
+        }
+        let succeeded = results.iter().filter(|result| result.success()).count();
+        let summary = json!({
+            "summary": {
+                "results": results.len(),
+                "succeeded": succeeded,
+                "failed": results.len() - succeeded,
+                "duration_ms": results
+                    .iter()
+                    .map(|result| result.duration().as_millis())
+                    .max()
+                    .unwrap_or_default(),
+            }
+        });
+        let _res = writeln!(output, "{summary}");
+        output
+    }
+}
+
+/// A `JUnit` XML report, as written by `--junit`.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Junit;
+
+impl ResultFormatter for Junit {
+    fn format(&self, results: &[HostRunResult]) -> String {
+        junit::report(results)
+    }
+}
+
+/// The formatter of a `--format`.
+pub(crate) fn named(format: &str) -> MusshResult<Formatter> {
+    match format {
+        "human" => Ok(Arc::new(Human)),
+        "json" => Ok(Arc::new(Json)),
+        "jsonl" => Ok(Arc::new(JsonLines)),
+        "junit" => Ok(Arc::new(Junit)),
+        _ => Err(format!(
+            "Unknown format '{format}', expected one of {}",
+            FORMATS.join(", ")
+        )
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{named, Human, Json, JsonLines, ResultFormatter};
+    use crate::error::MusshResult;
+    use crate::runner::HostRunResult;
+    use serde_json::Value;
+
+    fn results() -> Vec<HostRunResult> {
+        let mut failed = HostRunResult::default();
+        let _ = failed.set_error(Some("Non-zero exit code".to_string()));
+        vec![HostRunResult::default(), failed]
+    }
+
+    #[test]
+    fn formats() -> MusshResult<()> {
+        let human = Human.format(&results());
+        assert_eq!(human.lines().count(), 2);
+        assert!(human.contains("failed on ''") && human.ends_with(": Non-zero exit code\n"));
+
+        let json: Value = serde_json::from_str(&Json.format(&results()))?;
+        assert_eq!(json[0]["success"], Value::Bool(true));
+        assert_eq!(json[1]["error"], Value::from("Non-zero exit code"));
+
+        let jsonl = JsonLines.format(&results());
+        let lines: Vec<Value> = jsonl
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["success"], Value::Bool(true));
+        assert_eq!(lines[1]["success"], Value::Bool(false));
+        assert_eq!(lines[2]["summary"]["failed"], Value::from(1));
+
+        let junit = named("junit")?.format(&results());
+        assert!(junit.contains("failures=\"1\""));
+        assert!(named("human")?.streams());
+        assert!(!named("json")?.streams());
+        assert!(named("yaml").is_err());
+        Ok(())
+    }
+}
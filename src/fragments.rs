@@ -0,0 +1,419 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Fragment configs in `mussh.d`, and the configs a config includes
+use crate::config_file;
+use crate::config_format::ConfigFormat;
+use crate::error::MusshResult;
+use crate::ssh_config;
+use slog::Logger;
+use slog_try::{try_trace, try_warn};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
+
+/// The directory of fragment configs, next to the main config.
+pub(crate) const FRAGMENTS_DIR_NAME: &str = "mussh.d";
+
+/// The key of the config files a config includes, a path or a list of paths,
+/// relative to the config unless absolute, which may have `*` and `?` globs.
+const INCLUDE_KEY: &str = "include";
+
+/// The fragments in `dir`, TOML or YAML, sorted by file name.  Only the files
+/// directly in it are fragments, so a fragment never pulls in another.
+fn fragments(dir: &Path) -> MusshResult<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Some("toml" | "yaml" | "yml") = path.extension().and_then(OsStr::to_str) {
+            paths.push(path);
+        }
+    }
+    paths.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    Ok(paths)
+}
+
+/// Merge `fragment` into `config`.
+///
+/// Each section of the fragment is merged into the same section of the config
+/// by key, and an entry in both is replaced as a whole by the fragment's.
+/// Anything else at the top of the fragment replaces the config's.
+fn merge(config: &mut Table, fragment: Table) {
+    for (key, value) in fragment {
+        match (config.get_mut(&key), value) {
+            (Some(Value::Table(section)), Value::Table(entries)) => section.extend(entries),
+            (_, value) => {
+                let _old = config.insert(key, value);
+            }
+        }
+    }
+}
+
+/// The config files a config includes, merged in by [`with_includes`].
+fn include_paths(
+    config_path: &Path,
+    include: &Value,
+    stderr: Option<&Logger>,
+) -> MusshResult<Vec<PathBuf>> {
+    let invalid = || {
+        format!(
+            "The include of {} must be a path or a list of paths",
+            config_path.display()
+        )
+    };
+    let patterns: Vec<&str> = match include {
+        Value::String(pattern) => vec![pattern.as_str()],
+        Value::Array(patterns) => patterns
+            .iter()
+            .map(|pattern| pattern.as_str().ok_or_else(invalid))
+            .collect::<Result<_, _>>()?,
+        _ => return Err(invalid().into()),
+    };
+
+    let dir = config_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        if !is_glob(pattern) {
+            paths.push(dir.join(pattern));
+            continue;
+        }
+        let matched = glob(&dir.join(pattern));
+        if matched.is_empty() {
+            try_warn!(
+                stderr,
+                "The include '{}' of {} matches no files",
+                pattern,
+                config_path.display()
+            );
+        }
+        paths.extend(matched);
+    }
+    Ok(paths)
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// The files matching `pattern`, where `*` and `?` in any of its components
+/// match as in an ssh pattern, sorted.  As in a shell, a wildcard doesn't
+/// match the start of a hidden file name.
+fn glob(pattern: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let component = component.as_os_str();
+        let Some(component_pattern) = component.to_str().filter(|c| is_glob(c)) else {
+            for path in &mut paths {
+                path.push(component);
+            }
+            continue;
+        };
+        paths = paths
+            .iter()
+            .filter_map(|dir| {
+                let dir = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir.as_path()
+                };
+                fs::read_dir(dir).ok().map(|entries| (dir, entries))
+            })
+            .flat_map(|(dir, entries)| {
+                entries
+                    .flatten()
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .filter(|name| {
+                        (!name.starts_with('.') || component_pattern.starts_with('.'))
+                            && ssh_config::glob_match(component_pattern, name)
+                    })
+                    .map(move |name| dir.join(name))
+            })
+            .collect();
+    }
+    paths.retain(|path| path.is_file());
+    paths.sort();
+    paths
+}
+
+/// The config at `config_path`, with the config files its `include` names
+/// merged in, and theirs in turn.
+///
+/// The included files are merged in the order they're given, the files a
+/// glob matches in file name order, and the config's own entries last, so a
+/// later file replaces a host, hostlist or cmd of the same name in an earlier
+/// one, and the including config has the final say.  `stack` holds the
+/// configs being included, to refuse a config that ends up including itself.
+fn with_includes(
+    config_path: &Path,
+    mut config: Table,
+    stack: &mut Vec<PathBuf>,
+    stderr: Option<&Logger>,
+) -> MusshResult<Table> {
+    let Some(include) = config.remove(INCLUDE_KEY) else {
+        return Ok(config);
+    };
+    let paths = include_paths(config_path, &include, stderr)?;
+    stack.push(fs::canonicalize(config_path).unwrap_or_else(|_| config_path.to_path_buf()));
+
+    let mut merged = Table::new();
+    for path in paths {
+        let contents = fs::read_to_string(&path).map_err(|e| {
+            format!(
+                "Unable to read {}, included by {}: {e}",
+                path.display(),
+                config_path.display()
+            )
+        })?;
+        let canonical = fs::canonicalize(&path)?;
+        if let Some(start) = stack.iter().position(|seen| *seen == canonical) {
+            let cycle: Vec<String> = stack[start..]
+                .iter()
+                .chain(Some(&canonical))
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(format!("The config includes itself: {}", cycle.join(" -> ")).into());
+        }
+        try_trace!(stderr, "Merging included config"; "path" => path.display().to_string());
+        let included = ConfigFormat::of(&path).parse(&path, &contents)?;
+        merge(&mut merged, with_includes(&path, included, stack, stderr)?);
+    }
+
+    let _config_path = stack.pop();
+    merge(&mut merged, config);
+    Ok(merged)
+}
+
+/// The contents of the main config at `config_path`, with the configs it
+/// includes and the fragment configs in the `mussh.d` directory next to it
+/// merged in.  The contents are TOML, whatever the format of the main config.
+///
+/// The fragments are merged in file name order after the main config, so a
+/// host, hostlist or cmd in a fragment replaces the one of the same name in the
+/// main config or an earlier fragment.  A fragment can include configs too.
+/// The overrides in the environment and the run's flags still apply on top.
+///
+/// A config read from stdin has no directory of its own, so the configs it
+/// includes are relative to the current directory, and it has no fragments.
+///
+/// Without any includes or fragments the contents are returned as they are.
+pub(crate) fn with_fragments(
+    config_path: &Path,
+    contents: String,
+    stderr: Option<&Logger>,
+) -> MusshResult<String> {
+    let dir = config_path
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(FRAGMENTS_DIR_NAME);
+    let paths = if config_path != Path::new(config_file::STDIN) && dir.is_dir() {
+        fragments(&dir)?
+    } else {
+        Vec::new()
+    };
+    let config = ConfigFormat::Toml.parse(config_path, &contents)?;
+    if paths.is_empty() && !config.contains_key(INCLUDE_KEY) {
+        return Ok(contents);
+    }
+
+    let mut stack = Vec::new();
+    let mut config = with_includes(config_path, config, &mut stack, stderr)?;
+    for path in paths {
+        try_trace!(stderr, "Merging fragment config"; "path" => path.display().to_string());
+        let fragment = ConfigFormat::of(&path).parse(&path, &fs::read_to_string(&path)?)?;
+        merge(
+            &mut config,
+            with_includes(&path, fragment, &mut stack, stderr)?,
+        );
+    }
+    Ok(toml::to_string(&Value::Table(config))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{glob, with_fragments, FRAGMENTS_DIR_NAME};
+    use crate::config_file::STDIN;
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    const MAIN_TOML: &str = r#"[hostlist.all]
+hostnames = ["web"]
+[hosts.web]
+hostname = "10.0.0.3"
+username = "jozias"
+[cmd.ls]
+command = "ls"
+"#;
+
+    fn config_dir(name: &str, fragments: &[(&str, &str)]) -> MusshResult<PathBuf> {
+        let dir = env::temp_dir().join(format!("mussh-fragments-{name}-{}", std::process::id()));
+        let fragments_dir = dir.join(FRAGMENTS_DIR_NAME);
+        fs::create_dir_all(&fragments_dir)?;
+        for (file_name, contents) in fragments {
+            fs::write(fragments_dir.join(file_name), contents)?;
+        }
+        Ok(dir)
+    }
+
+    #[test]
+    fn later_fragments_override() -> MusshResult<()> {
+        let dir = config_dir(
+            "override",
+            &[
+                (
+                    "20-db.toml",
+                    "[hosts.db]\nhostname = \"10.0.0.5\"\nusername = \"db\"\n",
+                ),
+                (
+                    "10-db.toml",
+                    "[hosts.db]\nhostname = \"10.0.0.4\"\nusername = \"db\"\n\
+                     [hostlist.all]\nhostnames = [\"web\", \"db\"]\n",
+                ),
+                ("30-ops.yml", "cmd:\n  ls:\n    command: ls -al\n"),
+                ("README.md", "not a fragment"),
+            ],
+        )?;
+        let merged = with_fragments(&dir.join("mussh.toml"), MAIN_TOML.to_string(), None);
+        fs::remove_dir_all(&dir)?;
+
+        let config: Config = toml::from_str(&merged?)?;
+        assert_eq!(config.hosts().len(), 2);
+        let db = config.hosts().get("db").ok_or("no db")?;
+        assert_eq!(db.hostname(), "10.0.0.5");
+        let all = config.hostlist().get("all").ok_or("no all")?;
+        assert_eq!(all.hostnames(), &["web", "db"]);
+        let ls = config.cmd().get("ls").ok_or("no ls")?;
+        assert_eq!(ls.command(), "ls -al");
+        Ok(())
+    }
+
+    #[test]
+    fn fragments_do_not_nest() -> MusshResult<()> {
+        let dir = config_dir("nest", &[])?;
+        let nested = dir.join(FRAGMENTS_DIR_NAME).join(FRAGMENTS_DIR_NAME);
+        fs::create_dir_all(&nested)?;
+        fs::write(nested.join("db.toml"), "[hosts.db]\nhostname = \"h\"\n")?;
+        let merged = with_fragments(&dir.join("mussh.toml"), MAIN_TOML.to_string(), None);
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(merged?, MAIN_TOML);
+        Ok(())
+    }
+
+    #[test]
+    fn fragment_parse_errors_name_the_fragment() -> MusshResult<()> {
+        let dir = config_dir("error", &[("db.toml", "[hosts.db\n")])?;
+        let merged = with_fragments(&dir.join("mussh.toml"), MAIN_TOML.to_string(), None);
+        fs::remove_dir_all(&dir)?;
+
+        let error = merged.err().ok_or("expected a parse error")?.to_string();
+        assert!(error.contains(&format!("{FRAGMENTS_DIR_NAME}/db.toml:1:")));
+        Ok(())
+    }
+
+    #[test]
+    fn includes_merge_in_order() -> MusshResult<()> {
+        let dir = config_dir("include", &[])?;
+        fs::create_dir_all(dir.join("hosts"))?;
+        fs::write(
+            dir.join("hosts").join("10-db.toml"),
+            "[hosts.db]\nhostname = \"10.0.0.4\"\nusername = \"db\"\n",
+        )?;
+        fs::write(
+            dir.join("hosts").join("20-db.yaml"),
+            "hosts:\n  db:\n    hostname: 10.0.0.5\n    username: db\n",
+        )?;
+        fs::write(dir.join("hosts").join(".hidden.toml"), "not toml")?;
+        fs::write(
+            dir.join("cmds.toml"),
+            "include = \"groups.toml\"\n[cmd.ls]\ncommand = \"ls -l\"\n",
+        )?;
+        fs::write(
+            dir.join("groups.toml"),
+            "[hostlist.all]\nhostnames = [\"web\", \"db\"]\n",
+        )?;
+        let main =
+            format!("include = [\"hosts/*.toml\", \"hosts/*.yaml\", \"cmds.toml\"]\n{MAIN_TOML}");
+        let merged = with_fragments(&dir.join("mussh.toml"), main, None);
+        let globbed = glob(&dir.join("h*").join("*-db.*"));
+        fs::remove_dir_all(&dir)?;
+
+        let merged = merged?;
+        assert!(!merged.contains("include"));
+        let config: Config = toml::from_str(&merged)?;
+        let db = config.hosts().get("db").ok_or("no db")?;
+        assert_eq!(db.hostname(), "10.0.0.5");
+        // The including config has the final say.
+        let ls = config.cmd().get("ls").ok_or("no ls")?;
+        assert_eq!(ls.command(), "ls");
+        let all = config.hostlist().get("all").ok_or("no all")?;
+        assert_eq!(all.hostnames(), &["web"]);
+        assert_eq!(globbed.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn stdin_includes_are_relative_to_the_current_dir() -> MusshResult<()> {
+        let dir = config_dir("stdin", &[])?;
+        fs::write(
+            dir.join("db.toml"),
+            "[hosts.db]\nhostname = \"10.0.0.4\"\nusername = \"db\"\n",
+        )?;
+        let up: PathBuf = env::current_dir()?
+            .components()
+            .skip(1)
+            .map(|_| "..")
+            .collect();
+        let include = up.join(
+            dir.join("db.toml")
+                .components()
+                .skip(1)
+                .collect::<PathBuf>(),
+        );
+        let main = format!("include = {:?}\n{MAIN_TOML}", include.display().to_string());
+        let merged = with_fragments(Path::new(STDIN), main, None);
+        fs::remove_dir_all(&dir)?;
+
+        let config: Config = toml::from_str(&merged?)?;
+        assert!(config.hosts().contains_key("db"));
+        Ok(())
+    }
+
+    #[test]
+    fn include_cycles_fail() -> MusshResult<()> {
+        let dir = config_dir("include-cycle", &[])?;
+        fs::write(dir.join("a.toml"), "include = [\"b.toml\"]\n")?;
+        fs::write(dir.join("b.toml"), "include = \"mussh.toml\"\n")?;
+        let main = format!("include = \"a.toml\"\n{MAIN_TOML}");
+        fs::write(dir.join("mussh.toml"), &main)?;
+        let cycle = with_fragments(&dir.join("mussh.toml"), main, None);
+        let missing = with_fragments(
+            &dir.join("mussh.toml"),
+            "include = \"gone.toml\"\n".to_string(),
+            None,
+        );
+        let invalid = with_fragments(&dir.join("mussh.toml"), "include = 1\n".to_string(), None);
+        fs::remove_dir_all(&dir)?;
+
+        let error = cycle.err().ok_or("expected a cycle")?.to_string();
+        assert!(error.starts_with("The config includes itself: "));
+        assert!(error.ends_with("mussh.toml"));
+        assert_eq!(error.matches(" -> ").count(), 3);
+        assert!(missing
+            .err()
+            .is_some_and(|e| e.to_string().contains("gone.toml, included by ")));
+        assert!(invalid.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Output hashes
+use crate::error::{MusshErr, MusshResult};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// The hashes `--hash` knows by name.
+pub(crate) const HASHES: [&str; 1] = ["sha256"];
+
+/// How the output of a command is hashed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum OutputHash {
+    /// SHA-256, as lowercase hex.
+    Sha256,
+}
+
+impl FromStr for OutputHash {
+    type Err = MusshErr;
+
+    fn from_str(hash: &str) -> MusshResult<Self> {
+        match hash {
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(format!(
+                "Unknown hash '{hash}', expected one of {}",
+                HASHES.join(", ")
+            )
+            .into()),
+        }
+    }
+}
+
+impl OutputHash {
+    /// The hash of the output, as lowercase hex.
+    pub(crate) fn hash(self, output: &str) -> String {
+        match self {
+            Self::Sha256 => {
+                sha256(output.as_bytes())
+                    .iter()
+                    .fold(String::with_capacity(64), |mut hex, byte| {
+                        let _res = write!(hex, "{byte:02x}");
+                        hex
+                    })
+            }
+        }
+    }
+}
+
+/// The SHA-256 round constants.
+const K: [u32; 64] = [
+    0x428a_2f98,
+    0x7137_4491,
+    0xb5c0_fbcf,
+    0xe9b5_dba5,
+    0x3956_c25b,
+    0x59f1_11f1,
+    0x923f_82a4,
+    0xab1c_5ed5,
+    0xd807_aa98,
+    0x1283_5b01,
+    0x2431_85be,
+    0x550c_7dc3,
+    0x72be_5d74,
+    0x80de_b1fe,
+    0x9bdc_06a7,
+    0xc19b_f174,
+    0xe49b_69c1,
+    0xefbe_4786,
+    0x0fc1_9dc6,
+    0x240c_a1cc,
+    0x2de9_2c6f,
+    0x4a74_84aa,
+    0x5cb0_a9dc,
+    0x76f9_88da,
+    0x983e_5152,
+    0xa831_c66d,
+    0xb003_27c8,
+    0xbf59_7fc7,
+    0xc6e0_0bf3,
+    0xd5a7_9147,
+    0x06ca_6351,
+    0x1429_2967,
+    0x27b7_0a85,
+    0x2e1b_2138,
+    0x4d2c_6dfc,
+    0x5338_0d13,
+    0x650a_7354,
+    0x766a_0abb,
+    0x81c2_c92e,
+    0x9272_2c85,
+    0xa2bf_e8a1,
+    0xa81a_664b,
+    0xc24b_8b70,
+    0xc76c_51a3,
+    0xd192_e819,
+    0xd699_0624,
+    0xf40e_3585,
+    0x106a_a070,
+    0x19a4_c116,
+    0x1e37_6c08,
+    0x2748_774c,
+    0x34b0_bcb5,
+    0x391c_0cb3,
+    0x4ed8_aa4a,
+    0x5b9c_ca4f,
+    0x682e_6ff3,
+    0x748f_82ee,
+    0x78a5_636f,
+    0x84c8_7814,
+    0x8cc7_0208,
+    0x90be_fffa,
+    0xa450_6ceb,
+    0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+/// The SHA-256 initial hash values.
+const H: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+/// SHA-256, as in FIPS 180-4.  There is no hashing crate in the build, and
+/// the output of a command is small enough that a plain implementation will
+/// do.
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&bit_len.to_be_bytes());
+
+    let mut state = H;
+    for block in message.chunks_exact(64) {
+        let mut schedule = [0_u32; 64];
+        for (word, bytes) in schedule.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let (w15, w2) = (schedule[i - 15], schedule[i - 2]);
+            let s0 = w15.rotate_right(7) ^ w15.rotate_right(18) ^ (w15 >> 3);
+            let s1 = w2.rotate_right(17) ^ w2.rotate_right(19) ^ (w2 >> 10);
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+
+        // The working variables a to h.
+        let mut vars = state;
+        for (constant, word) in K.iter().zip(schedule.iter()) {
+            let s1 = vars[4].rotate_right(6) ^ vars[4].rotate_right(11) ^ vars[4].rotate_right(25);
+            let ch = (vars[4] & vars[5]) ^ (!vars[4] & vars[6]);
+            let t1 = vars[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(*constant)
+                .wrapping_add(*word);
+            let s0 = vars[0].rotate_right(2) ^ vars[0].rotate_right(13) ^ vars[0].rotate_right(22);
+            let maj = (vars[0] & vars[1]) ^ (vars[0] & vars[2]) ^ (vars[1] & vars[2]);
+            vars.rotate_right(1);
+            vars[0] = t1.wrapping_add(s0).wrapping_add(maj);
+            vars[4] = vars[4].wrapping_add(t1);
+        }
+        for (state, value) in state.iter_mut().zip(vars) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0_u8; 32];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state.iter()) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::OutputHash;
+    use crate::error::MusshResult;
+
+    #[test]
+    fn sha256_hashes() -> MusshResult<()> {
+        let sha256: OutputHash = "sha256".parse()?;
+        assert_eq!(
+            sha256.hash(""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256.hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256.hash("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert!("md5".parse::<OutputHash>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! References to environment variables in the config
+//!
+//! The `hostname`, `username`, `pem`, `jump` and `shell` of the hosts may
+//! refer to variables as `${VAR}` or `$VAR`, and the `command` of the commands
+//! as `${VAR}` only, as a `$VAR` in a command is usually meant for the shell on
+//! the host.  A variable that isn't set is an error, unless a default is given
+//! with `${VAR:-default}`, which is also used for a variable set to nothing.
+//! `$$` in front of a reference keeps it as it is, i.e. `$${VAR}` is `${VAR}`.
+use crate::error::MusshResult;
+use std::collections::HashMap;
+use toml::Value;
+
+/// The fields variables are expanded in, by section, and whether they expand
+/// `$VAR` as well as `${VAR}`.
+const FIELDS: &[(&str, &str, bool)] = &[
+    ("hosts", "hostname", true),
+    ("hosts", "username", true),
+    ("hosts", "pem", true),
+    ("hosts", "jump", true),
+    ("hosts", "shell", true),
+    ("cmd", "command", false),
+];
+
+/// Expand the references to the variables in the config.
+///
+/// Returns whether there were any references.
+pub(crate) fn apply(config: &mut Value, vars: &HashMap<String, String>) -> MusshResult<bool> {
+    let mut expanded = false;
+
+    for (section, field, bare) in FIELDS {
+        let Some(entries) = config.get_mut(*section).and_then(Value::as_table_mut) else {
+            continue;
+        };
+        for (name, entry) in entries.iter_mut() {
+            let Some(Value::String(text)) = entry.get_mut(*field) else {
+                continue;
+            };
+            if !text.contains('$') {
+                continue;
+            }
+            let location = format!("the {field} of {section}.{name}");
+            *text = interpolate(text, *bare, vars).map_err(|e| format!("In {location}, {e}"))?;
+            expanded = true;
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// Expand the references in `text`, with `$VAR` as well as `${VAR}` if
+/// `bare`.
+fn interpolate(text: &str, bare: bool, vars: &HashMap<String, String>) -> Result<String, String> {
+    let starts_reference = |c: Option<char>| match c {
+        Some('{') => true,
+        Some(c) => bare && (c == '_' || c.is_ascii_alphabetic()),
+        None => false,
+    };
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let mut chars = after.chars();
+        let next = chars.next();
+
+        if next == Some('$') && starts_reference(chars.next()) {
+            // An escaped reference, kept as it is after the second `$`.
+            expanded.push('$');
+            rest = &after[1..];
+            let reference_start = rest.chars().next().map_or(0, char::len_utf8);
+            expanded.push_str(&rest[..reference_start]);
+            rest = &rest[reference_start..];
+        } else if next == Some('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("'${after}' is missing its closing brace"))?;
+            let (name, default) = match after[1..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[1..end], None),
+            };
+            if !is_var_name(name) {
+                return Err(format!(
+                    "'${}' isn't a valid variable reference",
+                    &after[..=end]
+                ));
+            }
+            expanded.push_str(&value(name, default, vars)?);
+            rest = &after[end + 1..];
+        } else if starts_reference(next) {
+            let end = after
+                .find(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+                .unwrap_or(after.len());
+            expanded.push_str(&value(&after[..end], None, vars)?);
+            rest = &after[end..];
+        } else {
+            expanded.push('$');
+            rest = after;
+        }
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn value(
+    name: &str,
+    default: Option<&str>,
+    vars: &HashMap<String, String>,
+) -> Result<String, String> {
+    match (vars.get(name), default) {
+        (Some(value), Some(default)) if value.is_empty() => Ok(default.to_string()),
+        (Some(value), _) => Ok(value.clone()),
+        (None, Some(default)) => Ok(default.to_string()),
+        (None, None) => Err(format!(
+            "{name} isn't set (give a default with ${{{name}:-default}})"
+        )),
+    }
+}
+
+fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first == '_' || first.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, interpolate};
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use std::collections::HashMap;
+    use toml::Value;
+
+    const VARS_TOML: &str = r#"[hostlist.all]
+hostnames = ["web"]
+[hosts.web]
+hostname = "${WEB_HOST:-10.0.0.3}"
+username = "$USER"
+pem = "${HOME}/.ssh/$USER.pem"
+jump = "$USER@${BASTION:-bastion}"
+[cmd.ls]
+command = "ls ${DIR} $HOME $$ $${KEEP}"
+"#;
+
+    fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn expands_references() -> MusshResult<()> {
+        let mut value: Value = toml::from_str(VARS_TOML)?;
+        let env = vars(&[
+            ("USER", "jozias"),
+            ("HOME", "/home/jozias"),
+            ("DIR", "/tmp"),
+        ]);
+        assert!(apply(&mut value, &env)?);
+        assert_eq!(
+            value["hosts"]["web"]["jump"].as_str(),
+            Some("jozias@bastion")
+        );
+        let config: Config = value.try_into()?;
+
+        let web = config.hosts().get("web").ok_or("no web")?;
+        assert_eq!(web.hostname(), "10.0.0.3");
+        assert_eq!(web.username(), "jozias");
+        assert_eq!(web.pem().as_deref(), Some("/home/jozias/.ssh/jozias.pem"));
+        let ls = config.cmd().get("ls").ok_or("no ls")?;
+        assert_eq!(ls.command(), "ls /tmp $HOME $$ ${KEEP}");
+
+        let mut value: Value = toml::from_str("[hosts.web]\nhostname = \"h\"\n")?;
+        assert!(!apply(&mut value, &env)?);
+        Ok(())
+    }
+
+    #[test]
+    fn defaults_and_errors() -> MusshResult<()> {
+        let env = vars(&[("EMPTY", ""), ("SET", "x")]);
+        let expand = |text| interpolate(text, true, &env);
+        assert_eq!(expand("${EMPTY:-d}"), Ok("d".to_string()));
+        assert_eq!(expand("${SET:-d}-$SET"), Ok("x-x".to_string()));
+        assert_eq!(expand("${UNSET:-}"), Ok(String::new()));
+        assert_eq!(
+            expand("$$SET $1 $ $(cmd)"),
+            Ok("$SET $1 $ $(cmd)".to_string())
+        );
+        assert!(expand("$UNSET").is_err_and(|e| e.starts_with("UNSET isn't set")));
+        assert!(expand("${SET").is_err());
+        assert!(expand("${1X}").is_err());
+
+        let mut value: Value = toml::from_str("[cmd.ls]\ncommand = \"ls ${DIR}\"\n")?;
+        let error = apply(&mut value, &env).err().ok_or("expected an error")?;
+        assert!(error
+            .to_string()
+            .starts_with("In the command of cmd.ls, DIR isn't set"));
+        Ok(())
+    }
+}
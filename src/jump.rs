@@ -0,0 +1,302 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Connecting to hosts through a jump host
+//!
+//! As with a proxy, libmussh opens its own connection to each host, so a host
+//! with a `jump` is given a local port to connect to instead.  Each connection
+//! to it is forwarded over a `direct-tcpip` channel of an ssh session with the
+//! jump host, like `ssh -J`, and the session with the host itself runs over
+//! that channel.
+use crate::algorithms::Algorithms;
+use crate::auth;
+use crate::error::{MusshErrKind, MusshResult};
+use crate::extensions::Extensions;
+use crate::known_hosts::HostKeys;
+use ssh2::{Channel, Session};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+/// How long the forwarding of a connection sleeps when neither side has
+/// anything to send.
+const IDLE: Duration = Duration::from_millis(2);
+
+/// A jump host, from `[USER@]HOST[:PORT]`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Jump {
+    /// The user to log in to the jump host as, the user of the host if not
+    /// given.
+    username: Option<String>,
+    hostname: String,
+    port: u16,
+}
+
+impl FromStr for Jump {
+    type Err = crate::error::MusshErr;
+
+    fn from_str(jump: &str) -> MusshResult<Self> {
+        let invalid = || format!("Invalid jump host '{jump}', expected [USER@]HOST[:PORT]");
+        let (username, addr) = match jump.split_once('@') {
+            Some((username, addr)) if !username.is_empty() => (Some(username.to_string()), addr),
+            Some(_) => return Err(invalid().into()),
+            None => (None, jump),
+        };
+        let (hostname, port) = match addr.rsplit_once(':') {
+            Some((hostname, port)) => (hostname, port.parse::<u16>().map_err(|_| invalid())?),
+            None => (addr, 22),
+        };
+        if hostname.is_empty() {
+            return Err(invalid().into());
+        }
+        Ok(Self {
+            username,
+            hostname: hostname.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.port == 22 {
+            write!(f, "'{}'", self.hostname)
+        } else {
+            write!(f, "'{}:{}'", self.hostname, self.port)
+        }
+    }
+}
+
+/// The jump host of each host that has one, its `jump` in the config.
+pub(crate) fn host_jumps(
+    extensions: &Extensions,
+    hosts: &[&str],
+) -> MusshResult<HashMap<String, Jump>> {
+    let mut jumps = HashMap::new();
+    for host in hosts {
+        if let Some(jump) = extensions
+            .hosts
+            .get(*host)
+            .and_then(|host| host.jump.as_deref())
+        {
+            let _old = jumps.insert((*host).to_string(), jump.parse()?);
+        }
+    }
+    Ok(jumps)
+}
+
+/// How to get through a jump host to the hosts behind it.
+#[derive(Clone, Debug)]
+pub(crate) struct Route {
+    jump: Jump,
+    /// The user to log in as when the jump doesn't name one.
+    username: String,
+    /// The pem of the host, used for the jump host as well.
+    pem: Option<String>,
+    connect_timeout: Duration,
+    host_keys: HostKeys,
+}
+
+impl Route {
+    pub(crate) fn new(
+        jump: Jump,
+        username: String,
+        pem: Option<String>,
+        connect_timeout: Duration,
+        host_keys: HostKeys,
+    ) -> Self {
+        Self {
+            jump,
+            username,
+            pem,
+            connect_timeout,
+            host_keys,
+        }
+    }
+
+    /// Connect and authenticate to the jump host, and open a channel through
+    /// it to `host:port`.
+    ///
+    /// Failing to reach or authenticate with the jump host is a `Jump` error,
+    /// and the jump host failing to reach the host a plain one.
+    fn connect(&self, host: &str, port: u16) -> MusshResult<(Session, Channel)> {
+        let session = auth::session(
+            &self.jump.hostname,
+            self.jump.username.as_deref().unwrap_or(&self.username),
+            Some(self.jump.port),
+            self.pem.as_deref(),
+            self.connect_timeout,
+            &self.host_keys,
+            &Algorithms::default(),
+        )
+        .map_err(|e| MusshErrKind::Jump(self.jump.to_string(), e.to_string()))?;
+        let channel = session
+            .channel_direct_tcpip(host, port, None)
+            .map_err(|e| {
+                format!(
+                    "'{host}:{port}' is unreachable through the jump host {}: {e}",
+                    self.jump
+                )
+            })?;
+        Ok((session, channel))
+    }
+}
+
+/// Forward the connections to a new local port through the jump host to
+/// `host:port`, returning the local port.
+///
+/// The first channel through the jump host is opened straight away, so that
+/// not reaching the jump host or the host is an error here, rather than a
+/// failed ssh handshake later.
+pub(crate) fn forward(route: Route, host: String, port: u16) -> MusshResult<u16> {
+    let first = route.connect(&host, port)?;
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let local_port = listener.local_addr()?.port();
+    let _handle = thread::spawn(move || {
+        let mut first = Some(first);
+        for client in listener.incoming().flatten() {
+            let tunnel = match first.take() {
+                Some(tunnel) => Ok(tunnel),
+                None => route.connect(&host, port),
+            };
+            if let Ok((session, channel)) = tunnel {
+                let _handle = thread::spawn(move || pipe(client, &session, channel));
+            }
+        }
+    });
+    Ok(local_port)
+}
+
+/// Copy everything from the client to the channel and back, until either
+/// side closes.  A channel can't be shared between threads like a socket, so
+/// both directions are copied in turn, without blocking.
+fn pipe(mut client: TcpStream, session: &Session, mut channel: Channel) -> io::Result<()> {
+    client.set_nonblocking(true)?;
+    session.set_blocking(false);
+    let mut buf = [0; 16 * 1024];
+    let (mut to_host, mut to_client) = (Vec::new(), Vec::new());
+    let (mut client_open, mut host_open) = (true, true);
+
+    while (client_open || !to_host.is_empty()) && (host_open || !to_client.is_empty()) {
+        let mut idle = true;
+        if client_open && to_host.is_empty() {
+            match client.read(&mut buf) {
+                Ok(0) => client_open = false,
+                Ok(read) => to_host.extend_from_slice(&buf[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !to_host.is_empty() {
+            idle &= !send(&mut channel, &mut to_host)?;
+        }
+        if host_open && to_client.is_empty() {
+            match channel.read(&mut buf) {
+                Ok(0) => host_open = !channel.eof(),
+                Ok(read) => to_client.extend_from_slice(&buf[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !to_client.is_empty() {
+            idle &= !send(&mut client, &mut to_client)?;
+        }
+        if idle {
+            thread::sleep(IDLE);
+        }
+    }
+
+    let _res = client.shutdown(Shutdown::Both);
+    let _res = channel.close();
+    Ok(())
+}
+
+/// Write as much of `pending` as `to` takes without blocking, returning
+/// whether any of it was written.
+fn send<W: Write>(to: &mut W, pending: &mut Vec<u8>) -> io::Result<bool> {
+    match to.write(pending) {
+        Ok(written) => {
+            let _sent = pending.drain(..written);
+            Ok(written > 0)
+        }
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{forward, host_jumps, send, Jump, Route};
+    use crate::error::MusshResult;
+    use crate::known_hosts::{HostKeyCheck, HostKeys};
+    use std::env;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn parses() -> MusshResult<()> {
+        let jump: Jump = "ops@bastion.corp:2222".parse()?;
+        assert_eq!(jump.username.as_deref(), Some("ops"));
+        assert_eq!(jump.hostname, "bastion.corp");
+        assert_eq!(jump.port, 2222);
+        assert_eq!(jump.to_string(), "'bastion.corp:2222'");
+        let jump: Jump = "bastion".parse()?;
+        assert_eq!(jump.username, None);
+        assert_eq!(jump.port, 22);
+        assert_eq!(jump.to_string(), "'bastion'");
+        for bad in &["", "ops@", "@bastion", "bastion:ssh", ":22"] {
+            assert!(bad.parse::<Jump>().is_err());
+        }
+
+        let toml =
+            "[hosts.web]\njump = \"ops@bastion\"\n[hosts.db]\n[hosts.bad]\njump = \"ops@\"\n";
+        let extensions = toml::from_str(toml)?;
+        let jumps = host_jumps(&extensions, &["web", "db"])?;
+        assert_eq!(
+            jumps.get("web").map(|j| j.hostname.as_str()),
+            Some("bastion")
+        );
+        assert!(!jumps.contains_key("db"));
+        assert!(host_jumps(&extensions, &["bad"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unreachable_jump_host() -> MusshResult<()> {
+        // A port nothing listens on.
+        let port = TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port();
+        let route = Route::new(
+            format!("127.0.0.1:{port}").parse()?,
+            "jozias".to_string(),
+            None,
+            Duration::from_secs(2),
+            HostKeys::new(HostKeyCheck::Off, env::temp_dir().join("known_hosts")),
+        );
+        let e = forward(route, "10.0.0.1".to_string(), 22)
+            .err()
+            .ok_or("forwarded")?;
+        assert!(e
+            .to_string()
+            .starts_with(&format!("Unable to use the jump host '127.0.0.1:{port}': ")));
+        Ok(())
+    }
+
+    #[test]
+    fn sends_what_is_taken() -> MusshResult<()> {
+        let mut pending = b"hello".to_vec();
+        let mut to = Vec::new();
+        assert!(send(&mut to, &mut pending)?);
+        assert!(pending.is_empty());
+        assert_eq!(to, b"hello");
+        Ok(())
+    }
+}
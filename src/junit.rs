@@ -0,0 +1,165 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `JUnit` XML reports
+use crate::runner::HostRunResult;
+use indexmap::IndexMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Build a `JUnit` XML report of the run.
+///
+/// Each host is a test suite, and each command run on it a test case, named
+/// for the command.  A failed command is a test case failure, with the error
+/// as its message.
+pub(crate) fn report(results: &[HostRunResult]) -> String {
+    let results: Vec<&HostRunResult> = results.iter().collect();
+    let mut hosts: IndexMap<&str, Vec<&HostRunResult>> = IndexMap::new();
+    for result in &results {
+        hosts.entry(result.name()).or_default().push(result);
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _res = writeln!(
+        xml,
+        "<testsuites name=\"mussh\" tests=\"{}\" failures=\"{}\" time=\"{}\">",
+        results.len(),
+        failures(&results),
+        seconds(total(&results)),
+    );
+
+    for (name, results) in hosts {
+        let _res = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{}\">",
+            escape(name),
+            results.len(),
+            failures(&results),
+            seconds(total(&results)),
+        );
+
+        for result in results {
+            let _res = write!(
+                xml,
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{}\"",
+                escape(name),
+                escape(result.cmd_name()),
+                seconds(*result.duration()),
+            );
+            let _res = match result.error() {
+                Some(error) => writeln!(
+                    xml,
+                    ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                    escape(error),
+                    escape(error),
+                ),
+                None => writeln!(xml, "/>"),
+            };
+        }
+
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn failures(results: &[&HostRunResult]) -> usize {
+    results.iter().filter(|result| !result.success()).count()
+}
+
+fn total(results: &[&HostRunResult]) -> Duration {
+    results.iter().map(|result| *result.duration()).sum()
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+/// Escape text for use in an attribute or element, dropping the control
+/// characters XML 1.0 doesn't allow at all.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => {
+                let _res = write!(escaped, "&#{};", u32::from(c));
+            }
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape, report};
+    use crate::error::MusshResult;
+    use crate::runner::{self, Hooks};
+    use crate::targets;
+    use crate::warnings::Warnings;
+    use indexmap::IndexSet;
+    use libmussh::{Config, Multiplex, RuntimeConfig};
+
+    const JUNIT_TOML: &str = r#"[hostlist.all]
+hostnames = ["a", "b"]
+[hosts.a]
+hostname = "localhost"
+username = "jozias"
+[hosts.b]
+hostname = "localhost"
+username = "jozias"
+[cmd.pass]
+command = "true"
+[cmd.fail]
+command = "false"
+"#;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            escape("a<b> & \"c\" 'd'\n\u{1b}[0m"),
+            "a&lt;b&gt; &amp; &quot;c&quot; &apos;d&apos;&#10;[0m"
+        );
+    }
+
+    #[test]
+    fn report_cases() -> MusshResult<()> {
+        let config: Config = toml::from_str(JUNIT_TOML)?;
+        let mut runtime_config = RuntimeConfig::default();
+        let _ = runtime_config.set_hosts(vec!["all".to_string()].into_iter().collect());
+        let _ = runtime_config.set_cmds(
+            vec!["pass".to_string(), "fail".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        let (_, multiplex_map) =
+            targets::to_host_map(&config, &runtime_config, true, &mut Warnings::default())?;
+        let results = runner::run(
+            &Multiplex::default(),
+            &IndexSet::new(),
+            multiplex_map,
+            &Hooks::default(),
+        );
+
+        let xml = report(&results);
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites"));
+        assert!(xml.contains("tests=\"4\" failures=\"2\""));
+        assert!(xml.contains("<testsuite name=\"a\" tests=\"2\" failures=\"1\""));
+        assert!(xml.contains("<testcase classname=\"b\" name=\"pass\""));
+        assert_eq!(xml.matches("<failure message=").count(), 2);
+        assert!(xml.trim_end().ends_with("</testsuites>"));
+        Ok(())
+    }
+}
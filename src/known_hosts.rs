@@ -0,0 +1,280 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Host key verification against `~/.ssh/known_hosts`
+use crate::error::{MusshErrKind, MusshResult};
+use clap::{Arg, ArgMatches};
+use ssh2::{CheckResult, HostKeyType, KnownHostFileKind, KnownHosts, Session};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Held while adding a key, so hosts added at the same time don't add
+/// duplicate lines.
+static ADDING: Mutex<()> = Mutex::new(());
+
+/// What is done with the key of a host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum HostKeyCheck {
+    /// Refuse a host whose key is unknown or has changed.
+    Strict,
+    /// Add the key of an unknown host, like `StrictHostKeyChecking=accept-new`,
+    /// but refuse one whose key has changed.
+    AcceptNew,
+    /// Don't check the key at all, with `--insecure`.
+    Off,
+}
+
+/// The known hosts file, and what to do with the keys of the hosts.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct HostKeys {
+    check: HostKeyCheck,
+    path: PathBuf,
+    /// The hostname and port of the host each local port forwards to, for the
+    /// hosts reached through a proxy or a jump host.
+    forwarded: Arc<HashMap<u16, (String, u16)>>,
+}
+
+impl HostKeys {
+    pub(crate) fn new(check: HostKeyCheck, path: PathBuf) -> Self {
+        Self {
+            check,
+            path,
+            forwarded: Arc::default(),
+        }
+    }
+
+    /// Check the keys of the hosts reached through the local ports against
+    /// the hosts they forward to, rather than against `127.0.0.1`.
+    pub(crate) fn with_forwarded(mut self, forwarded: HashMap<u16, (String, u16)>) -> Self {
+        self.forwarded = Arc::new(forwarded);
+        self
+    }
+
+    /// The keys in `~/.ssh/known_hosts`.
+    pub(crate) fn user(check: HostKeyCheck) -> MusshResult<Self> {
+        let path = dirs::home_dir()
+            .ok_or("Unable to determine your home directory")?
+            .join(".ssh")
+            .join("known_hosts");
+        Ok(Self::new(check, path))
+    }
+
+    /// The keys in `~/.ssh/known_hosts`, checked unless `--insecure`, with the
+    /// keys of new hosts added with `--accept-new-host-keys`.
+    pub(crate) fn from_args(matches: &ArgMatches<'_>) -> MusshResult<Self> {
+        Self::user(if matches.is_present("insecure") {
+            HostKeyCheck::Off
+        } else if matches.is_present("accept_new_host_keys") {
+            HostKeyCheck::AcceptNew
+        } else {
+            HostKeyCheck::Strict
+        })
+    }
+
+    /// Are the keys checked at all?
+    pub(crate) fn checked(&self) -> bool {
+        self.check != HostKeyCheck::Off
+    }
+
+    /// The host the hostname and port lead to, the host forwarded to for a
+    /// local port.
+    fn origin<'a>(&'a self, hostname: &'a str, port: u16) -> (&'a str, u16) {
+        match self.forwarded.get(&port) {
+            Some((forwarded, forwarded_port)) if hostname == "127.0.0.1" => {
+                (forwarded.as_str(), *forwarded_port)
+            }
+            _ => (hostname, port),
+        }
+    }
+
+    /// Check the key the host sent in the handshake of the session.
+    pub(crate) fn verify(&self, session: &Session, hostname: &str, port: u16) -> MusshResult<()> {
+        if !self.checked() {
+            return Ok(());
+        }
+        let (key, key_type) = session
+            .host_key()
+            .ok_or_else(|| format!("'{hostname}' sent no host key"))?;
+        let (hostname, port) = self.origin(hostname, port);
+        self.verify_key(session, hostname, port, key, key_type)
+    }
+
+    fn verify_key(
+        &self,
+        session: &Session,
+        hostname: &str,
+        port: u16,
+        key: &[u8],
+        key_type: HostKeyType,
+    ) -> MusshResult<()> {
+        let refused = |changed| -> MusshResult<()> {
+            Err(
+                MusshErrKind::HostKeyMismatch(hostname.to_string(), self.path.clone(), changed)
+                    .into(),
+            )
+        };
+        match self.known_hosts(session)?.check_port(hostname, port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => refused(true),
+            CheckResult::NotFound if self.check == HostKeyCheck::AcceptNew => {
+                self.add(session, hostname, port, key, key_type)
+            }
+            CheckResult::NotFound => refused(false),
+            CheckResult::Failure => {
+                Err(format!("Unable to check the host key of '{hostname}'").into())
+            }
+        }
+    }
+
+    /// The known hosts in the file, none if there isn't one.
+    ///
+    /// The lines are read one at a time, so a line libssh2 can't parse, i.e. a
+    /// `@cert-authority` line or a key type it doesn't know, only skips that
+    /// line.  Hashed hostnames are matched by libssh2 itself.
+    fn known_hosts(&self, session: &Session) -> MusshResult<KnownHosts> {
+        let mut known_hosts = session.known_hosts()?;
+        if self.path.is_file() {
+            for line in fs::read_to_string(&self.path)?.lines() {
+                let line = line.trim();
+                if !line.is_empty() && !line.starts_with('#') {
+                    let _res = known_hosts.read_str(line, KnownHostFileKind::OpenSSH);
+                }
+            }
+        }
+        Ok(known_hosts)
+    }
+
+    /// Append the key of a host seen for the first time to the file.  The file
+    /// is only ever appended to, so nothing already in it is lost.
+    fn add(
+        &self,
+        session: &Session,
+        hostname: &str,
+        port: u16,
+        key: &[u8],
+        key_type: HostKeyType,
+    ) -> MusshResult<()> {
+        let _adding = ADDING.lock().map_err(|_| "Unable to add a host key")?;
+        // Another host may have added the same key in the meantime.
+        let mut known_hosts = self.known_hosts(session)?;
+        if let CheckResult::Match = known_hosts.check_port(hostname, port, key) {
+            return Ok(());
+        }
+
+        let name = if port == 22 {
+            hostname.to_string()
+        } else {
+            format!("[{hostname}]:{port}")
+        };
+        // The comment says where the line came from, and libssh2 crashes on an
+        // empty one.
+        known_hosts.add(&name, key, "added by mussh", key_type.into())?;
+        let host = known_hosts
+            .hosts()?
+            .into_iter()
+            .find(|host| host.name() == Some(name.as_str()))
+            .ok_or_else(|| format!("Unable to add the host key of '{hostname}'"))?;
+        let line = known_hosts.write_string(&host, KnownHostFileKind::OpenSSH)?;
+
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        write!(file, "{}", with_newline(&line))?;
+        Ok(())
+    }
+}
+
+/// The `--insecure` and `--accept-new-host-keys` args of the subcommands that
+/// connect to hosts.
+pub(crate) fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("insecure").long("insecure").help(
+            "Don't check the keys of the hosts against ~/.ssh/known_hosts, for lab use \
+             only, as anyone in the middle can then pose as a host",
+        ),
+        Arg::with_name("accept_new_host_keys")
+            .long("accept-new-host-keys")
+            .conflicts_with("insecure")
+            .help(
+                "Add the key of a host not in ~/.ssh/known_hosts to it on first contact, \
+                 like StrictHostKeyChecking=accept-new.  A changed key is still refused",
+            ),
+    ]
+}
+
+fn with_newline(line: &str) -> String {
+    if line.ends_with('\n') {
+        line.to_string()
+    } else {
+        format!("{line}\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HostKeyCheck, HostKeys};
+    use crate::error::MusshResult;
+    use ssh2::{HostKeyType, Session};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn verifies_keys() -> MusshResult<()> {
+        let dir = env::temp_dir().join(format!("mussh-known-hosts-{}", std::process::id()));
+        let path = dir.join("known_hosts");
+        let session = Session::new()?;
+        let (key, other_key) = (b"the key of web".to_vec(), b"another key".to_vec());
+        let verify = |check, port, key: &[u8]| {
+            HostKeys::new(check, path.clone()).verify_key(
+                &session,
+                "web",
+                port,
+                key,
+                HostKeyType::Ed255219,
+            )
+        };
+
+        // No file yet, so nothing is known.
+        assert!(verify(HostKeyCheck::Strict, 2222, &key).is_err());
+        verify(HostKeyCheck::AcceptNew, 2222, &key)?;
+        verify(HostKeyCheck::AcceptNew, 2222, &key)?;
+        verify(HostKeyCheck::Strict, 2222, &key)?;
+        let contents = fs::read_to_string(&path)?;
+
+        // The port is part of the entry.
+        assert!(verify(HostKeyCheck::Strict, 22, &key).is_err());
+        // A changed key is refused, even when accepting new keys.
+        let changed = verify(HostKeyCheck::AcceptNew, 2222, &other_key);
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.starts_with("[web]:2222 ssh-ed25519 "));
+        assert!(changed
+            .err()
+            .is_some_and(|e| e.to_string().contains("has changed")));
+        Ok(())
+    }
+    #[test]
+    fn forwarded_hosts() {
+        let forwarded = vec![(40022, ("web".to_string(), 2222))]
+            .into_iter()
+            .collect();
+        let host_keys = HostKeys::new(HostKeyCheck::Strict, env::temp_dir().join("known_hosts"))
+            .with_forwarded(forwarded);
+        assert_eq!(host_keys.origin("127.0.0.1", 40022), ("web", 2222));
+        assert_eq!(host_keys.origin("127.0.0.1", 40023), ("127.0.0.1", 40023));
+        assert_eq!(host_keys.origin("db", 40022), ("db", 40022));
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Older config layouts
+use toml::value::{Table, Value};
+
+/// The sections a config must have, even if empty.
+const SECTIONS: [&str; 3] = ["hostlist", "hosts", "cmd"];
+
+/// Bring a config in an older layout into the current one.
+///
+/// Older configs could leave out the `hostlist`, `hosts` or `cmd` sections
+/// they didn't use, which are now required, and had every host give an
+/// `alias` list, which is now left out when empty.  The missing sections are
+/// added empty and the empty alias lists removed.
+///
+/// Returns whether the config was in an older layout.
+pub(crate) fn normalize(config: &mut Value) -> bool {
+    let Some(root) = config.as_table_mut() else {
+        return false;
+    };
+    let mut legacy = false;
+
+    for section in &SECTIONS {
+        if !root.contains_key(*section) {
+            let _old = root.insert((*section).to_string(), Value::Table(Table::new()));
+            legacy = true;
+        }
+    }
+
+    if let Some(hosts) = root.get_mut("hosts").and_then(Value::as_table_mut) {
+        for host in hosts.iter_mut().filter_map(|(_, host)| host.as_table_mut()) {
+            if host
+                .get("alias")
+                .and_then(Value::as_array)
+                .is_some_and(Vec::is_empty)
+            {
+                let _alias = host.remove("alias");
+                legacy = true;
+            }
+        }
+    }
+
+    legacy
+}
+
+#[cfg(test)]
+mod test {
+    use super::normalize;
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use toml::Value;
+
+    const LEGACY_TOML: &str = r#"[hosts.web]
+hostname = "10.0.0.3"
+username = "jozias"
+alias = []
+[hosts.db]
+hostname = "10.0.0.4"
+username = "jozias"
+alias = [{ command = "ls_al", aliasfor = "ls" }]
+[cmd.ls]
+command = "ls"
+[cmd.ls_al]
+command = "ls -al"
+"#;
+
+    #[test]
+    fn loads_the_older_layout() -> MusshResult<()> {
+        assert!(toml::from_str::<Config>(LEGACY_TOML).is_err());
+
+        let mut value: Value = toml::from_str(LEGACY_TOML)?;
+        assert!(normalize(&mut value));
+        assert!(value["hosts"]["web"].get("alias").is_none());
+        let config: Config = value.try_into()?;
+        assert!(config.hostlist().is_empty());
+        let web = config.hosts().get("web").ok_or("no web")?;
+        assert!(web.alias().is_none());
+        let db = config.hosts().get("db").ok_or("no db")?;
+        assert_eq!(db.alias().as_ref().map(Vec::len), Some(1));
+        assert_eq!(config.cmd().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_the_current_layout() -> MusshResult<()> {
+        let mut value: Value =
+            toml::from_str("[hostlist]\n[hosts.web]\nhostname = \"h\"\nusername = \"u\"\n[cmd]\n")?;
+        let before = value.clone();
+        assert!(!normalize(&mut value));
+        assert_eq!(value, before);
+        Ok(())
+    }
+}
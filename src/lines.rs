@@ -0,0 +1,145 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The output of a command, a line at a time
+use crate::error::{MusshErr, MusshResult};
+use std::borrow::Cow;
+use std::io::{BufRead, BufReader, Read};
+use std::str::FromStr;
+
+/// The names `--encoding` takes.
+pub(crate) const ENCODINGS: [&str; 2] = ["utf-8", "latin1"];
+
+/// How the bytes of the output are decoded, with `--encoding`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) enum Encoding {
+    /// UTF-8, with any invalid bytes replaced by U+FFFD.
+    #[default]
+    Utf8,
+    /// ISO 8859-1, where every byte is the character with its value.
+    Latin1,
+}
+
+impl FromStr for Encoding {
+    type Err = MusshErr;
+
+    fn from_str(encoding: &str) -> MusshResult<Self> {
+        match encoding {
+            "utf-8" => Ok(Self::Utf8),
+            "latin1" => Ok(Self::Latin1),
+            _ => Err(format!(
+                "Unknown encoding '{encoding}', expected one of {}",
+                ENCODINGS.join(", ")
+            )
+            .into()),
+        }
+    }
+}
+
+impl Encoding {
+    /// The line without its `\n` or `\r\n`, decoded.
+    fn decode(self, bytes: &[u8]) -> Cow<'_, str> {
+        let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+        let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+        match self {
+            Self::Utf8 => String::from_utf8_lossy(bytes),
+            Self::Latin1 => Cow::Owned(bytes.iter().copied().map(char::from).collect()),
+        }
+    }
+}
+
+/// Read `reader` to its end, calling `line` with each line of it.
+///
+/// `BufRead::lines` gives up at the first line that isn't valid UTF-8, which
+/// loses the rest of the output and leaves the command blocked on a pipe that
+/// nobody reads any more.  The bytes of each line are read as they are and
+/// decoded with the `encoding` instead, lossily for UTF-8, so the reading only
+/// stops at the end, or at an error reading.
+pub(crate) fn read_lines(reader: impl Read, encoding: Encoding, mut line: impl FnMut(&str)) {
+    let mut reader = BufReader::new(reader);
+    let mut bytes = Vec::new();
+    loop {
+        bytes.clear();
+        match reader.read_until(b'\n', &mut bytes) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => line(&encoding.decode(&bytes)),
+        }
+    }
+}
+
+/// Bytes read a chunk at a time, split into lines as they come, for output
+/// that can't be read with `read_lines`.
+#[derive(Debug, Default)]
+pub(crate) struct Lines {
+    encoding: Encoding,
+    /// The start of a line whose end hasn't been read yet.
+    partial: Vec<u8>,
+}
+
+impl Lines {
+    pub(crate) fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Add the bytes read, calling `line` with each line they finish.
+    pub(crate) fn push(&mut self, mut bytes: &[u8], line: &mut impl FnMut(&str)) {
+        while let Some(end) = bytes.iter().position(|byte| *byte == b'\n') {
+            self.partial.extend_from_slice(&bytes[..=end]);
+            line(&self.encoding.decode(&self.partial));
+            self.partial.clear();
+            bytes = &bytes[end + 1..];
+        }
+        self.partial.extend_from_slice(bytes);
+    }
+
+    /// Call `line` with the last line, if the output didn't end with a line
+    /// ending.
+    pub(crate) fn finish(self, line: &mut impl FnMut(&str)) {
+        if !self.partial.is_empty() {
+            line(&self.encoding.decode(&self.partial));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_lines, Encoding, Lines};
+    use crate::error::MusshResult;
+
+    #[test]
+    fn invalid_utf8() {
+        let output: &[u8] = b"one\r\ntw\xffo\nthree";
+        let mut lines = Vec::new();
+        read_lines(output, Encoding::Utf8, |line| lines.push(line.to_string()));
+        assert_eq!(lines, vec!["one", "tw\u{fffd}o", "three"]);
+
+        let mut chunked = Vec::new();
+        let mut line = |line: &str| chunked.push(line.to_string());
+        let mut split = Lines::new(Encoding::Utf8);
+        for chunk in output.chunks(3) {
+            split.push(chunk, &mut line);
+        }
+        split.finish(&mut line);
+        assert_eq!(chunked, lines);
+    }
+
+    #[test]
+    fn encodings() -> MusshResult<()> {
+        let mut lines = Vec::new();
+        let encoding = "latin1".parse()?;
+        read_lines(&b"caf\xe9\n"[..], encoding, |line| {
+            lines.push(line.to_string());
+        });
+        assert_eq!(lines, vec!["caf\u{e9}"]);
+        assert!("ebcdic".parse::<Encoding>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Commands run on `localhost`, without ssh
+use crate::error::MusshResult;
+use crate::extensions::Extensions;
+use crate::lines::{self, Encoding};
+use crate::logging::STDERR_TAG;
+use crate::remote_env::{self, RemoteEnv};
+use crate::runner::Execution;
+use crate::session::POLL_INTERVAL;
+use crate::util::format_duration;
+use libmussh::{Multiplex, MultiplexMapType};
+use slog::{trace, Logger};
+use slog_try::{try_error, try_info, try_trace};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// The hostname that is run on locally.
+pub(crate) const LOCALHOST: &str = "localhost";
+
+/// The shell commands are run with when the host has no `shell` and `SHELL`
+/// isn't set.
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// The `shell` of each host that has one in the config, by host name.
+pub(crate) fn shells(extensions: &Extensions) -> MusshResult<HashMap<String, String>> {
+    let mut shells = HashMap::new();
+
+    for (name, host) in &extensions.hosts {
+        match &host.shell {
+            Some(shell) if !shell.is_empty() => {
+                let _old = shells.insert(name.clone(), shell.clone());
+            }
+            Some(_) => return Err(format!("The shell of host '{name}' must be a command").into()),
+            None => {}
+        }
+    }
+    Ok(shells)
+}
+
+/// The shell a command is run with on `localhost`: the host's `shell`,
+/// otherwise the `local_shell` given, otherwise the user's `SHELL`, otherwise
+/// `/bin/sh`.
+pub(crate) fn shell(host_shell: Option<&str>, local_shell: Option<&str>) -> String {
+    host_shell.or(local_shell).map_or_else(
+        || env::var("SHELL").unwrap_or_else(|_| DEFAULT_SHELL.to_string()),
+        str::to_string,
+    )
+}
+
+/// Run the single command of the single host in the map with `<shell> -c`, as
+/// libmussh runs a command over ssh: its stdout goes to the host's
+/// logger a line at a time, and the run is logged to the multiplex stdout or
+/// stderr.
+///
+/// Its stderr goes to the host's logger too, tagged as stderr, and is echoed
+/// to the multiplex stderr if the command fails.  Each is read on its own
+/// thread, so a command filling one pipe can't block on the other, and both are
+/// read to their end, whatever bytes the command writes, decoded with the
+/// `encoding`.
+///
+/// Once `cancelled` is set the command is killed and fails, without waiting for
+/// whatever it started to close its stdout and stderr.
+///
+/// The shell is the host's `shell`, otherwise the user's `SHELL`, otherwise
+/// `/bin/sh`.  The variables of the `env` for the command are set in its
+/// environment.  With `print_command`, the command is printed before it is
+/// run.
+///
+/// Unlike libmussh's own `localhost` path, the exit code is kept, so a local
+/// command gives the same result as a remote one.
+pub(crate) fn execute(
+    multiplex: &Multiplex,
+    cmd_map: MultiplexMapType,
+    (shell, env, encoding): (Option<&str>, &RemoteEnv, Encoding),
+    (cancelled, print_command): (&AtomicBool, Option<&Logger>),
+) -> Option<Execution> {
+    let (name, (host, cmds)) = cmd_map.into_iter().next()?;
+    let (cmd_name, cmd) = cmds.into_iter().flat_map(|(_, cmds)| cmds).next()?;
+    let cmd_logger = multiplex.host_loggers().get(&name).cloned().flatten();
+    let shell = self::shell(shell, None);
+
+    let vars = env.for_cmd(&cmd_name);
+    if !vars.is_empty() {
+        let names: Vec<&str> = vars.keys().map(String::as_str).collect();
+        try_trace!(multiplex.stdout(), "Setting the environment of the local command"; "host" => host.hostname(), "cmd" => &cmd_name, "vars" => names.join(","));
+    }
+
+    if let Some(logger) = print_command {
+        let command = (host.hostname().as_str(), cmd_name.as_str());
+        remote_env::print_command(logger, command, &cmd, (&BTreeMap::new(), &vars));
+    }
+    let timer = Instant::now();
+    let spawned = Command::new(&shell)
+        .arg("-c")
+        .arg(&cmd)
+        .envs(&vars)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            let message = format!("Unable to run '{cmd_name}' with '{shell}': {e}");
+            return Some(Execution::failed(
+                timer.elapsed(),
+                None,
+                message.as_str().into(),
+            ));
+        }
+    };
+    let stderr_logger = cmd_logger.clone();
+    let stderr_reader = child.stderr.take().map(|stderr| {
+        thread::spawn(move || {
+            let mut lines = Vec::new();
+            lines::read_lines(stderr, encoding, |line| {
+                if let Some(logger) = &stderr_logger {
+                    trace!(logger, #STDERR_TAG, "{}", line);
+                }
+                lines.push(line.to_string());
+            });
+            lines
+        })
+    });
+    let stdout_logger = cmd_logger.clone();
+    let stdout_reader = child.stdout.take().map(|stdout| {
+        thread::spawn(move || {
+            lines::read_lines(stdout, encoding, |line| {
+                try_trace!(stdout_logger, "{}", line);
+            });
+        })
+    });
+    let Some(status) = wait(&mut child, cancelled).transpose() else {
+        let message = format!("Cancelled '{cmd_name}' on '{}'", host.hostname());
+        return Some(Execution::failed(
+            timer.elapsed(),
+            None,
+            message.as_str().into(),
+        ));
+    };
+    let _res = stdout_reader.map(JoinHandle::join);
+    let stderr_lines = stderr_reader
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default();
+    let duration = timer.elapsed();
+    let elapsed = format_duration(&duration);
+
+    match status {
+        Ok(status) if status.success() => {
+            try_info!(multiplex.stdout(), "execute"; "host" => host.hostname(), "cmd" => &cmd_name, "duration" => elapsed);
+            Some(Execution::ok(duration))
+        }
+        Ok(status) => {
+            try_error!(multiplex.stderr(), "execute"; "host" => host.hostname(), "cmd" => &cmd_name, "duration" => elapsed);
+            for line in &stderr_lines {
+                try_error!(multiplex.stderr(), "{}", line; "host" => host.hostname(), "cmd" => &cmd_name, "stream" => STDERR_TAG);
+            }
+            let message = format!("Failed to run '{}' on '{cmd_name}'", host.hostname());
+            Some(Execution::failed(
+                duration,
+                status.code(),
+                message.as_str().into(),
+            ))
+        }
+        Err(e) => {
+            let message = format!("Failed to run '{}' on '{cmd_name}': {e}", host.hostname());
+            Some(Execution::failed(duration, None, message.as_str().into()))
+        }
+    }
+}
+
+/// Wait for the child to exit, killing it once `cancelled` is set.  `None` if
+/// it was killed.
+fn wait(child: &mut Child, cancelled: &AtomicBool) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if cancelled.load(Ordering::SeqCst) {
+            child.kill()?;
+            let _status = child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -7,17 +7,32 @@
 // modified, or distributed except according to those terms.
 
 //! Logging for the server.
+use crate::color::paint;
 use crate::error::{MusshErr, MusshResult};
-use chrono::{DateTime, Utc};
+use crate::util::pad_left;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Local, Utc};
 use clap::ArgMatches;
 use getset::Getters;
-use slog::{o, Drain, Level, Logger, Never, OwnedKVList, Record};
+use slog::{o, trace, Drain, Level, Logger, Never, OwnedKVList, Record};
 use slog_async::Async;
 use slog_term::{CompactFormat, TermDecorator};
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+/// The tag of the records of a command's stderr, logged to the host's logger
+/// alongside its stdout.
+pub(crate) const STDERR_TAG: &str = "stderr";
+
+/// The tag of the records of a file tailed with `--tail-file`, logged to the
+/// host's logger alongside the output of the command.
+pub(crate) const TAIL_TAG: &str = "tail";
 
 /// A struct that supports slog logging
 pub(crate) trait Slogger {
@@ -56,16 +71,12 @@ impl<'a> TryFrom<&'a ArgMatches<'a>> for Loggers {
             _ => Level::Trace,
         };
 
-        let stdout_decorator = TermDecorator::new().stdout().build();
-        let stdout_drain = CompactFormat::new(stdout_decorator).build().fuse();
-        let stdout_async_drain = Async::new(stdout_drain).build().filter_level(level).fuse();
-        let stdout = Logger::root(stdout_async_drain, o!());
-
+        let stdout = stdout_logger(level);
         let stderr_decorator = TermDecorator::new().stdout().build();
         let stderr_drain = CompactFormat::new(stderr_decorator).build().fuse();
         let stderr_async_drain = Async::new(stderr_drain)
             .build()
-            .filter_level(Level::Error)
+            .filter_level(Level::Warning)
             .fuse();
         let stderr = Logger::root(stderr_async_drain, o!());
 
@@ -76,35 +87,917 @@ impl<'a> TryFrom<&'a ArgMatches<'a>> for Loggers {
     }
 }
 
+/// A stdout logger that logs records at `level` and above.
+pub(crate) fn stdout_logger(level: Level) -> Logger {
+    let stdout_decorator = TermDecorator::new().stdout().build();
+    let stdout_drain = CompactFormat::new(stdout_decorator).build().fuse();
+    let stdout_async_drain = Async::new(stdout_drain).build().filter_level(level).fuse();
+    Logger::root(stdout_async_drain, o!())
+}
+
+/// How many rotated files of a log file are kept when `--log-keep` isn't
+/// given.
+pub(crate) const DEFAULT_LOG_KEEP: usize = 3;
+
+/// When a log file is rotated: once it holds `max_bytes`, it is moved to
+/// `<file>.1`, the `<file>.1` before it to `<file>.2`, and so on, keeping `keep`
+/// of them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Rotation {
+    /// The size a log file is rotated at.
+    max_bytes: u64,
+    /// How many rotated files are kept.
+    keep: usize,
+}
+
+impl Rotation {
+    pub(crate) fn new(max_bytes: u64, keep: usize) -> Self {
+        Self { max_bytes, keep }
+    }
+}
+
+/// How the lines of a log file are timestamped.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) enum Timestamps {
+    /// RFC 3339, in UTC.
+    #[default]
+    Rfc3339,
+    /// A strftime format, in UTC or local time.
+    Format { format: String, local: bool },
+    /// Not at all.
+    Off,
+}
+
+impl Timestamps {
+    /// Timestamps with the strftime `format`, RFC 3339 if none is given, in
+    /// local time if `local` and otherwise UTC.
+    pub(crate) fn new(format: Option<&str>, local: bool) -> MusshResult<Self> {
+        let format = match (format, local) {
+            (None, false) => return Ok(Self::Rfc3339),
+            (None, true) => "%Y-%m-%dT%H:%M:%S%.f%:z",
+            (Some(format), _) => format,
+        };
+        if StrftimeItems::new(format).any(|item| item == Item::Error) {
+            return Err(format!("Invalid timestamp format '{format}'").into());
+        }
+        Ok(Self::Format {
+            format: format.to_string(),
+            local,
+        })
+    }
+
+    /// The timestamp of a line logged now, if the lines are timestamped.
+    fn now(&self) -> Option<String> {
+        match self {
+            Self::Rfc3339 => Some(Utc::now().to_rfc3339()),
+            Self::Format {
+                format,
+                local: false,
+            } => Some(Utc::now().format(format).to_string()),
+            Self::Format {
+                format,
+                local: true,
+            } => Some(Local::now().format(format).to_string()),
+            Self::Off => None,
+        }
+    }
+}
+
+/// How a log file is written.
+#[derive(Clone, Debug, Default, Getters)]
+pub(crate) struct LogFile {
+    /// Where the log file is.
+    #[get = "pub(crate)"]
+    path: PathBuf,
+    /// When it is rotated, if ever.
+    rotation: Option<Rotation>,
+    /// How its lines are timestamped.
+    timestamps: Timestamps,
+}
+
+impl LogFile {
+    /// The same log file at another path.
+    pub(crate) fn at(&self, path: PathBuf) -> Self {
+        Self {
+            path,
+            ..self.clone()
+        }
+    }
+
+    /// Rotate the log file as it fills up.
+    pub(crate) fn with_rotation(mut self, rotation: Option<Rotation>) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Timestamp the lines of the log file so.
+    pub(crate) fn with_timestamps(mut self, timestamps: Timestamps) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+}
+
 /// A `slog` drain that writes to a file.
 #[derive(Debug)]
-#[allow(dead_code)]
 pub(crate) struct FileDrain {
-    /// The file to drain log records to.
-    file: File,
+    /// How the file is written.
+    log_file: LogFile,
+    /// The file to drain log records to, and how many bytes it holds.  It is
+    /// locked for the write and any rotation after it, so a record is never
+    /// written to a file as it is rotated away.
+    file: Mutex<(File, u64)>,
 }
 
-impl TryFrom<PathBuf> for FileDrain {
+impl TryFrom<LogFile> for FileDrain {
     type Error = MusshErr;
-    fn try_from(path: PathBuf) -> MusshResult<Self> {
+    fn try_from(log_file: LogFile) -> MusshResult<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_file.path)?;
+        let len = file.metadata()?.len();
         Ok(Self {
-            file: OpenOptions::new().create(true).append(true).open(path)?,
+            log_file,
+            file: Mutex::new((file, len)),
         })
     }
 }
 
+impl FileDrain {
+    /// Move the full log file to `<file>.1`, after moving the older rotated
+    /// files up one, dropping the oldest, and start a new one.
+    fn rotate(&self, rotation: Rotation) -> io::Result<File> {
+        let path = &self.log_file.path;
+        let rotated = |n: usize| with_extension_suffix(path, &n.to_string());
+        for n in (1..rotation.keep).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        fs::rename(path, rotated(1))?;
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+}
+
+/// `<path>.<suffix>`, i.e. `a.log.1`.
+fn with_extension_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
 impl Drain for FileDrain {
     type Ok = ();
     type Err = Never;
 
     fn log(&self, record: &Record<'_>, _: &OwnedKVList) -> ::std::result::Result<(), Never> {
-        if let Ok(mut log_file) = self.file.try_clone() {
-            let utc: DateTime<Utc> = Utc::now();
-            match writeln!(log_file, "{}: {}", utc.to_rfc3339(), record.msg()) {
-                Ok(()) => {}
-                Err(_e) => {}
+        let stream = if record.tag() == STDERR_TAG {
+            "stderr: "
+        } else {
+            ""
+        };
+        let line = match self.log_file.timestamps.now() {
+            Some(timestamp) => format!("{timestamp}: {stream}{}\n", record.msg()),
+            None => format!("{stream}{}\n", record.msg()),
+        };
+
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        if file.0.write_all(line.as_bytes()).is_err() {
+            return Ok(());
+        }
+        file.1 += line.len() as u64;
+        if let Some(rotation) = self.log_file.rotation {
+            if file.1 >= rotation.max_bytes {
+                if let Ok(rotated) = self.rotate(rotation) {
+                    *file = (rotated, 0);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the streamed output of the hosts is written: stdout, and the
+/// `--tee` file if there is one.
+///
+/// The lines are written to both under the one lock, so the file has them in
+/// the order they are on the terminal.
+#[derive(Debug, Default)]
+pub(crate) struct Stream {
+    /// The file the output is also written to.
+    tee: Mutex<Option<Tee>>,
+}
+
+#[derive(Debug)]
+struct Tee {
+    file: File,
+    /// Are the colors of the lines kept in the file?
+    color: bool,
+}
+
+impl Stream {
+    /// A stream also written to a new file at `path`, with the colors of the
+    /// lines only if `color`.
+    pub(crate) fn tee(path: &Path, color: bool) -> MusshResult<Self> {
+        Ok(Self {
+            tee: Mutex::new(Some(Tee {
+                file: File::create(path)?,
+                color,
+            })),
+        })
+    }
+
+    /// Write the lines together, so no other lines come between them.
+    pub(crate) fn write_lines<I, S>(&self, lines: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut tee = self.tee.lock().unwrap_or_else(PoisonError::into_inner);
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        for line in lines {
+            let line = line.as_ref();
+            let _res = writeln!(out, "{line}");
+            if let Some(tee) = tee.as_mut() {
+                let _res = if tee.color {
+                    writeln!(tee.file, "{line}")
+                } else {
+                    writeln!(tee.file, "{}", strip_colors(line))
+                };
+            }
+        }
+    }
+}
+
+/// The line without its ANSI color sequences.
+fn strip_colors(line: &str) -> String {
+    let mut plain = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("\x1b[") {
+        plain.push_str(&rest[..start]);
+        rest = &rest[start + 2..];
+        rest = rest.find('m').map_or("", |end| &rest[end + 1..]);
+    }
+    plain.push_str(rest);
+    plain
+}
+
+/// A `slog` drain that writes each record to the stream, prefixed with the
+/// host.
+#[derive(Clone, Debug)]
+pub(crate) struct TailDrain {
+    /// The prefix written before each line.
+    prefix: String,
+    /// Where the lines are written.
+    stream: Arc<Stream>,
+}
+
+impl TailDrain {
+    /// Create a drain prefixing lines with `[hostname]`, padded on the left to
+    /// `width` so the lines of hosts with shorter names line up, and optionally
+    /// colorized.
+    pub(crate) fn new(
+        hostname: &str,
+        width: usize,
+        color: Option<u8>,
+        stream: Arc<Stream>,
+    ) -> Self {
+        let prefix = pad_left(&format!("[{hostname}]"), width);
+        Self {
+            prefix: color.map_or_else(|| prefix.clone(), |color| paint(color, &prefix)),
+            stream,
+        }
+    }
+}
+
+impl Drain for TailDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record<'_>, _: &OwnedKVList) -> ::std::result::Result<(), Never> {
+        self.stream
+            .write_lines([format!("{} {}", self.prefix, record.msg())]);
+        Ok(())
+    }
+}
+
+/// A budget for the bytes of output logged for one host.
+#[derive(Debug, Default)]
+pub(crate) struct OutputLimit {
+    /// The most bytes to let through.
+    max: usize,
+    /// The bytes let through, and the bytes dropped once the limit was hit.
+    counts: Mutex<(usize, usize)>,
+}
+
+impl OutputLimit {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            max,
+            counts: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Take `len` bytes from the budget, returning whether they fit.  Once a
+    /// line hasn't fit, nothing more is let through.
+    fn take(&self, len: usize) -> bool {
+        if let Ok(mut counts) = self.counts.lock() {
+            let (written, truncated) = &mut *counts;
+            if *truncated == 0 && *written + len <= self.max {
+                *written += len;
+                return true;
+            }
+            *truncated += len;
+        }
+        false
+    }
+
+    /// The number of bytes dropped.
+    pub(crate) fn truncated(&self) -> usize {
+        self.counts.lock().map_or(0, |counts| counts.1)
+    }
+}
+
+/// A `slog` drain that passes records on to a logger until the output limit of
+/// the host is reached, then drops them.
+///
+/// Records are still accepted after the limit, so the command's output keeps
+/// being read to completion.
+#[derive(Clone, Debug)]
+pub(crate) struct LimitDrain {
+    /// The logger records within the limit are passed to.
+    logger: Logger,
+    /// The limit shared with the caller, who reports the truncation.
+    limit: Arc<OutputLimit>,
+}
+
+impl LimitDrain {
+    pub(crate) fn new(logger: Logger, limit: Arc<OutputLimit>) -> Self {
+        Self { logger, limit }
+    }
+}
+
+impl Drain for LimitDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record<'_>, _: &OwnedKVList) -> ::std::result::Result<(), Never> {
+        // Each record is one line of output, so count its newline too.
+        if self.limit.take(record.msg().to_string().len() + 1) {
+            self.logger.log(record);
+        }
+        Ok(())
+    }
+}
+
+/// Buffers the output of each host, so it can be printed as one block once the
+/// host is done, instead of interleaved with the output of other hosts.
+///
+/// Held output isn't printed as each host is done, but all at once, in a
+/// given order, at the end of the run.  Until then the output of every host is
+/// kept in memory.
+#[derive(Debug, Default)]
+pub(crate) struct BlockOutput {
+    /// The color of each host's header and footer.
+    colors: HashMap<String, u8>,
+    /// Is the output held until the end of the run?
+    held: bool,
+    /// The lines buffered for each host.
+    buffers: Mutex<HashMap<String, Vec<String>>>,
+    /// Where the blocks are written.
+    stream: Arc<Stream>,
+}
+
+impl BlockOutput {
+    pub(crate) fn new(colors: HashMap<String, u8>, held: bool, stream: Arc<Stream>) -> Self {
+        Self {
+            colors,
+            held,
+            buffers: Mutex::new(HashMap::new()),
+            stream,
+        }
+    }
+
+    fn push(&self, hostname: &str, line: String) {
+        if let Ok(mut buffers) = self.buffers.lock() {
+            buffers.entry(hostname.to_string()).or_default().push(line);
+        }
+    }
+
+    /// Print the output buffered for the host, unless the output is held.
+    pub(crate) fn flush(&self, hostname: &str) {
+        if !self.held {
+            self.print(hostname);
+        }
+    }
+
+    /// Print the output held for the hosts in `order`, then that of any other
+    /// host, by name.  Output that isn't held was printed as each host was done.
+    pub(crate) fn flush_all(&self, order: &[String]) {
+        if !self.held {
+            return;
+        }
+        let mut rest: Vec<String> = self
+            .buffers
+            .lock()
+            .map(|buffers| {
+                buffers
+                    .keys()
+                    .filter(|hostname| !order.contains(hostname))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        rest.sort();
+        for hostname in order.iter().chain(&rest) {
+            self.print(hostname);
+        }
+    }
+
+    /// Print the output buffered for the host between a header and footer, as
+    /// one write to the stream, so it can't be split by another host's block.
+    fn print(&self, hostname: &str) {
+        let lines = self
+            .buffers
+            .lock()
+            .ok()
+            .and_then(|mut buffers| buffers.remove(hostname))
+            .unwrap_or_default();
+        let header = format!("==> {hostname} <==");
+        let footer = format!("<== {hostname} ==>");
+        let (header, footer) = match self.colors.get(hostname) {
+            Some(color) => (paint(*color, &header), paint(*color, &footer)),
+            None => (header, footer),
+        };
+
+        self.stream.write_lines(
+            std::iter::once(header)
+                .chain(lines)
+                .chain(std::iter::once(footer)),
+        );
+    }
+}
+
+/// A `slog` drain that buffers each record in the host's block of output.
+#[derive(Clone, Debug)]
+pub(crate) struct BlockDrain {
+    /// The host the records are from.
+    hostname: String,
+    /// Where the output of every host is buffered.
+    output: Arc<BlockOutput>,
+}
+
+impl BlockDrain {
+    pub(crate) fn new(hostname: &str, output: Arc<BlockOutput>) -> Self {
+        Self {
+            hostname: hostname.to_string(),
+            output,
+        }
+    }
+}
+
+impl Drain for BlockDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record<'_>, _: &OwnedKVList) -> ::std::result::Result<(), Never> {
+        self.output.push(&self.hostname, record.msg().to_string());
+        Ok(())
+    }
+}
+
+/// A `slog` drain that keeps every record of a command's stdout, to check
+/// its output once it is done.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CaptureDrain {
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl CaptureDrain {
+    /// The captured lines, joined with newlines.
+    pub(crate) fn output(&self) -> String {
+        self.lines
+            .lock()
+            .map(|lines| lines.join("\n"))
+            .unwrap_or_default()
+    }
+}
+
+impl Drain for CaptureDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record<'_>, _: &OwnedKVList) -> ::std::result::Result<(), Never> {
+        if record.tag() == STDERR_TAG || record.tag() == TAIL_TAG {
+            return Ok(());
+        }
+        if let Ok(mut lines) = self.lines.lock() {
+            lines.push(record.msg().to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A `slog` drain that passes records on to a logger that can be switched, i.e.
+/// to the log file of the command being run.
+#[derive(Debug, Default)]
+pub(crate) struct SwitchDrain {
+    logger: Mutex<Option<Logger>>,
+}
+
+impl SwitchDrain {
+    /// Pass records on to `logger` from now on, or drop them.
+    pub(crate) fn switch(&self, logger: Option<Logger>) {
+        if let Ok(mut current) = self.logger.lock() {
+            *current = logger;
+        }
+    }
+}
+
+impl Drain for SwitchDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record<'_>, values: &OwnedKVList) -> ::std::result::Result<(), Never> {
+        if let Ok(current) = self.logger.lock() {
+            if let Some(logger) = current.as_ref() {
+                Drain::log(logger, record, values)?;
             }
         }
         Ok(())
     }
 }
+
+/// A local command the output of a host is piped through, i.e. `grep ERROR`.
+///
+/// The command is started when the first line is written, and runs until the
+/// host is done with `finish`.  If the host runs again later, a new one is
+/// started.
+#[derive(Debug)]
+pub(crate) struct OutputFilter {
+    /// The filter command, run with `sh -c`.
+    command: String,
+    /// The logger the output of the filter is passed to.
+    logger: Logger,
+    /// The running filter, if any.
+    running: Mutex<Option<RunningFilter>>,
+    /// The first non-zero exit code of the filter, or 0.
+    status: Mutex<Option<i32>>,
+}
+
+#[derive(Debug)]
+struct RunningFilter {
+    child: Child,
+    stdin: ChildStdin,
+    reader: JoinHandle<()>,
+}
+
+impl OutputFilter {
+    pub(crate) fn new(command: &str, logger: Logger) -> Self {
+        Self {
+            command: command.to_string(),
+            logger,
+            running: Mutex::new(None),
+            status: Mutex::new(None),
+        }
+    }
+
+    fn spawn(&self) -> MusshResult<RunningFilter> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or("Unable to get the filter's stdin")?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or("Unable to get the filter's stdout")?;
+        let logger = self.logger.clone();
+        let reader = thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                trace!(logger, "{}", line);
+            }
+        });
+        Ok(RunningFilter {
+            child,
+            stdin,
+            reader,
+        })
+    }
+
+    /// Write a line of output to the filter, starting it if need be.
+    pub(crate) fn write(&self, line: &str) {
+        if let Ok(mut running) = self.running.lock() {
+            if running.is_none() {
+                *running = self.spawn().ok();
+            }
+            if let Some(running) = running.as_mut() {
+                let _res = writeln!(running.stdin, "{line}");
+            }
+        }
+    }
+
+    /// Close the input of the filter, and wait for it to finish writing its
+    /// output.
+    pub(crate) fn finish(&self) {
+        let running = self
+            .running
+            .lock()
+            .ok()
+            .and_then(|mut running| running.take());
+
+        if let Some(RunningFilter {
+            mut child,
+            stdin,
+            reader,
+        }) = running
+        {
+            drop(stdin);
+            // A filter killed by a signal has no exit code.
+            let code = child
+                .wait()
+                .ok()
+                .and_then(|status| status.code())
+                .unwrap_or(-1);
+            let _res = reader.join();
+            if let Ok(mut status) = self.status.lock() {
+                if matches!(*status, None | Some(0)) {
+                    *status = Some(code);
+                }
+            }
+        }
+    }
+
+    /// The exit code of the filter, if it has run: the first that wasn't 0,
+    /// otherwise 0.
+    pub(crate) fn status(&self) -> Option<i32> {
+        self.status.lock().ok().and_then(|status| *status)
+    }
+}
+
+/// A `slog` drain that writes each record to the host's output filter.
+#[derive(Clone, Debug)]
+pub(crate) struct FilterDrain {
+    filter: Arc<OutputFilter>,
+}
+
+impl FilterDrain {
+    pub(crate) fn new(filter: Arc<OutputFilter>) -> Self {
+        Self { filter }
+    }
+}
+
+impl Drain for FilterDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record<'_>, _: &OwnedKVList) -> ::std::result::Result<(), Never> {
+        self.filter.write(&record.msg().to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        strip_colors, BlockDrain, BlockOutput, CaptureDrain, FileDrain, LimitDrain, LogFile,
+        OutputFilter, OutputLimit, Rotation, Stream, SwitchDrain, TailDrain, Timestamps,
+        STDERR_TAG, TAIL_TAG,
+    };
+    use crate::error::MusshResult;
+    use slog::{o, trace, Drain, Logger, Never, OwnedKVList, Record};
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct VecDrain {
+        lines: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Drain for VecDrain {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record<'_>, _: &OwnedKVList) -> Result<(), Never> {
+            if let Ok(mut lines) = self.lines.lock() {
+                lines.push(record.msg().to_string());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn switches_between_loggers() {
+        let (first, second) = (VecDrain::default(), VecDrain::default());
+        let switch = Arc::new(SwitchDrain::default());
+        let logger = Logger::root(Arc::clone(&switch), o!());
+
+        trace!(logger, "dropped");
+        switch.switch(Some(Logger::root(first.clone(), o!())));
+        trace!(logger, "one");
+        switch.switch(Some(Logger::root(second.clone(), o!())));
+        trace!(logger, "two");
+
+        let lines = |drain: &VecDrain| drain.lines.lock().map(|l| l.clone()).unwrap_or_default();
+        assert_eq!(lines(&first), vec!["one"]);
+        assert_eq!(lines(&second), vec!["two"]);
+    }
+
+    #[test]
+    fn output_is_truncated() {
+        let vec_drain = VecDrain::default();
+        let limit = Arc::new(OutputLimit::new(10));
+        let inner = Logger::root(vec_drain.clone(), o!());
+        let logger = Logger::root(LimitDrain::new(inner, Arc::clone(&limit)), o!());
+
+        for line in &["1234", "5678", "90", "ab"] {
+            trace!(logger, "{}", line);
+        }
+
+        let lines = vec_drain
+            .lines
+            .lock()
+            .map(|lines| lines.clone())
+            .unwrap_or_default();
+        assert_eq!(lines, vec!["1234", "5678"]);
+        assert_eq!(limit.truncated(), 6);
+    }
+
+    #[test]
+    fn blocks_are_buffered_per_host() {
+        let output = Arc::new(BlockOutput::new(HashMap::new(), false, Arc::default()));
+        let a = Logger::root(BlockDrain::new("a", Arc::clone(&output)), o!());
+        let b = Logger::root(BlockDrain::new("b", Arc::clone(&output)), o!());
+
+        trace!(a, "a1");
+        trace!(b, "b1");
+        trace!(a, "a2");
+
+        let buffers = output.buffers.lock().map(|b| b.clone()).unwrap_or_default();
+        assert_eq!(
+            buffers.get("a"),
+            Some(&vec!["a1".to_string(), "a2".to_string()])
+        );
+        assert_eq!(buffers.get("b"), Some(&vec!["b1".to_string()]));
+
+        output.flush("a");
+        let buffers = output.buffers.lock().map(|b| b.clone()).unwrap_or_default();
+        assert!(!buffers.contains_key("a"));
+        assert!(buffers.contains_key("b"));
+    }
+
+    #[test]
+    fn held_blocks_wait_for_the_end() {
+        let output = Arc::new(BlockOutput::new(HashMap::new(), true, Arc::default()));
+        let a = Logger::root(BlockDrain::new("a", Arc::clone(&output)), o!());
+        let b = Logger::root(BlockDrain::new("b", Arc::clone(&output)), o!());
+
+        trace!(a, "a1");
+        trace!(b, "b1");
+        output.flush("a");
+        let buffers = output.buffers.lock().map(|b| b.clone()).unwrap_or_default();
+        assert_eq!(buffers.len(), 2);
+
+        output.flush_all(&["b".to_string()]);
+        let buffers = output.buffers.lock().map(|b| b.clone()).unwrap_or_default();
+        assert!(buffers.is_empty());
+
+        let output = BlockOutput::new(HashMap::new(), false, Arc::default());
+        output.push("a", "a1".to_string());
+        output.flush_all(&["a".to_string()]);
+        let buffers = output.buffers.lock().map(|b| b.clone()).unwrap_or_default();
+        assert_eq!(buffers.len(), 1);
+    }
+
+    #[test]
+    fn log_files_rotate() -> MusshResult<()> {
+        let dir = env::temp_dir().join(format!("mussh-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("a.log");
+        let log_file = LogFile::default()
+            .at(path)
+            .with_rotation(Some(Rotation::new(10, 2)));
+        let drain = FileDrain::try_from(log_file)?;
+        let logger = Logger::root(drain.fuse(), o!());
+        for line in 0..10 {
+            trace!(logger, "line {}", line);
+        }
+        let read = |suffix: &str| fs::read_to_string(dir.join(format!("a.log{suffix}")));
+        let (current, first, second) = (read("")?, read(".1")?, read(".2")?);
+        let third = dir.join("a.log.3").exists();
+        fs::remove_dir_all(&dir)?;
+
+        // Every line is over 10 bytes, so the file is rotated after each.
+        assert!(current.is_empty());
+        assert!(first.ends_with(": line 9\n") && first.lines().count() == 1);
+        assert!(second.ends_with(": line 8\n"));
+        assert!(!third);
+        Ok(())
+    }
+
+    #[test]
+    fn log_timestamps() -> MusshResult<()> {
+        let dir = env::temp_dir().join(format!("mussh-timestamps-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let log = |name: &str, timestamps: Timestamps| -> MusshResult<String> {
+            let path = dir.join(name);
+            let log_file = LogFile::default()
+                .at(path.clone())
+                .with_timestamps(timestamps);
+            let logger = Logger::root(FileDrain::try_from(log_file)?.fuse(), o!());
+            trace!(logger, "up");
+            Ok(fs::read_to_string(path)?)
+        };
+        let off = log("off.log", Timestamps::Off)?;
+        let year = log("year.log", Timestamps::new(Some("%Y"), false)?)?;
+        let local = log("local.log", Timestamps::new(None, true)?)?;
+        let rfc3339 = log("rfc3339.log", Timestamps::default())?;
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(off, "up\n");
+        assert!(year.len() == "2026: up\n".len() && year.ends_with(": up\n"));
+        assert!(local.ends_with(": up\n") && local.contains('T'));
+        assert!(rfc3339.contains("+00:00: up"));
+        assert!(Timestamps::new(Some("%Q"), false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tee_matches_the_screen() -> MusshResult<()> {
+        let dir = env::temp_dir().join(format!("mussh-tee-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let (plain_path, color_path) = (dir.join("plain.log"), dir.join("color.log"));
+        let plain = Arc::new(Stream::tee(&plain_path, false)?);
+        let color = Arc::new(Stream::tee(&color_path, true)?);
+
+        for stream in [plain, color] {
+            let a = Logger::root(TailDrain::new("a", 0, Some(31), Arc::clone(&stream)), o!());
+            let output = Arc::new(BlockOutput::new(HashMap::new(), false, stream));
+            let b = Logger::root(BlockDrain::new("b", Arc::clone(&output)), o!());
+            trace!(a, "a1");
+            trace!(b, "b1");
+            trace!(a, "a2");
+            output.flush("b");
+        }
+        let plain = fs::read_to_string(&plain_path)?;
+        let color = fs::read_to_string(&color_path)?;
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(plain, "[a] a1\n[a] a2\n==> b <==\nb1\n<== b ==>\n");
+        assert!(color.starts_with("\x1b[31m[a]\x1b[0m a1\n"));
+        assert_eq!(strip_colors(&color), plain);
+        assert_eq!(
+            TailDrain::new("a", 6, None, Arc::default()).prefix,
+            "   [a]"
+        );
+        assert_eq!(
+            TailDrain::new("web1", 3, None, Arc::default()).prefix,
+            "[web1]"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn output_is_filtered() {
+        let vec_drain = VecDrain::default();
+        let filter = OutputFilter::new("grep ERROR", Logger::root(vec_drain.clone(), o!()));
+
+        for line in &["ok", "ERROR: one", "fine", "ERROR: two"] {
+            filter.write(line);
+        }
+        filter.finish();
+        assert_eq!(filter.status(), Some(0));
+
+        // A second run starts a new filter, and its status is kept.
+        filter.write("ok");
+        filter.finish();
+        assert_eq!(filter.status(), Some(1));
+
+        let lines = vec_drain
+            .lines
+            .lock()
+            .map(|lines| lines.clone())
+            .unwrap_or_default();
+        assert_eq!(lines, vec!["ERROR: one", "ERROR: two"]);
+    }
+
+    #[test]
+    fn capture_is_only_stdout() {
+        let capture = CaptureDrain::default();
+        let logger = Logger::root(capture.clone(), o!());
+        trace!(logger, "active");
+        trace!(logger, #STDERR_TAG, "warning: slow");
+        trace!(logger, #TAIL_TAG, "/var/log/app.log: started");
+        assert_eq!(capture.output(), "active");
+    }
+}
@@ -179,16 +179,54 @@
 #![cfg_attr(msrv, deny(clippy::all, clippy::pedantic))]
 // #![cfg_attr(msrv, allow())]
 
+mod algorithms;
+mod auth;
+mod chain;
+mod color;
+mod config_file;
+mod config_format;
+mod connect;
+mod defaults;
+mod env_config;
 mod error;
+mod expect;
+mod extensions;
+mod format;
+mod fragments;
+mod hash;
+mod interpolate;
+mod jump;
+mod junit;
+mod known_hosts;
+mod legacy;
+mod lines;
+mod local;
 mod logging;
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod proxy;
+mod remote_env;
+mod resolver;
 mod run;
+mod runner;
+mod session;
+mod ssh_config;
 mod subcmd;
+mod success;
+mod tags;
+mod targets;
+mod util;
+mod warnings;
 
 use crate::error::{MusshErr, MusshErrKind};
 use clap::ErrorKind;
 use std::error::Error;
 use std::process;
 
+/// The exit code when `--max-runtime` gave up on hosts, as with `timeout`.
+const MAX_RUNTIME_EXIT_CODE: i32 = 124;
+
 /// mussh entry point
 fn main() {
     process::exit(match run::run() {
@@ -198,7 +236,7 @@ fn main() {
                 eprintln!("{error}");
                 1
             },
-            |e| is_clap_help_or_version((&error, e)),
+            |e| error_exit_code((&error, e)),
         ),
     })
 }
@@ -207,7 +245,7 @@ fn is_lib_error<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a MusshErrKind
     error.downcast_ref::<MusshErrKind>()
 }
 
-fn is_clap_help_or_version(error_tuple: (&MusshErr, &MusshErrKind)) -> i32 {
+fn error_exit_code(error_tuple: (&MusshErr, &MusshErrKind)) -> i32 {
     let (error, k_error) = error_tuple;
     let disp_err = || {
         eprintln!("{error}");
@@ -223,6 +261,14 @@ fn is_clap_help_or_version(error_tuple: (&MusshErr, &MusshErrKind)) -> i32 {
             ErrorKind::VersionDisplayed => 0,
             _ => disp_err(),
         },
+        MusshErrKind::HostsFailed(_, code) => {
+            eprintln!("{error}");
+            *code
+        }
+        MusshErrKind::MaxRuntime(_) => {
+            eprintln!("{error}");
+            MAX_RUNTIME_EXIT_CODE
+        }
         _ => disp_err(),
     }
 }
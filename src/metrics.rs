@@ -0,0 +1,532 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Command metrics
+use crate::error::MusshResult;
+use crate::runner::HostRunResult;
+use chrono::Utc;
+use getset::Getters;
+use indexmap::IndexSet;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// What is recorded about a run as it happens.
+#[derive(Clone, Debug)]
+pub(crate) enum Metric {
+    /// A command ran, whether it succeeded or not.
+    Result(HostRunResult),
+    /// A host ran all of its commands.
+    HostDone(String),
+    /// Nothing more is to be written, even if hosts still running send more.
+    Finish,
+}
+
+/// A run recorded in the database, for `metrics runs`.
+#[derive(Clone, Debug, Default, Eq, Getters, PartialEq)]
+pub(crate) struct RunSummary {
+    #[get = "pub(crate)"]
+    run_id: String,
+    /// When the run started, in milliseconds since the epoch.
+    #[get = "pub(crate)"]
+    started_at: i64,
+    /// The `--label`s of the run, as `key=value`, by key.
+    #[get = "pub(crate)"]
+    labels: Vec<String>,
+    /// How many commands were recorded in the run.
+    #[get = "pub(crate)"]
+    commands: usize,
+    /// How many of those failed.
+    #[get = "pub(crate)"]
+    failed: usize,
+}
+
+/// The durations of a command on a host over every run, for `metrics stats`.
+/// The durations are of the successful runs of the command only.
+#[derive(Clone, Debug, Default, Eq, Getters, PartialEq)]
+pub(crate) struct DurationStats {
+    #[get = "pub(crate)"]
+    hostname: String,
+    #[get = "pub(crate)"]
+    cmdname: String,
+    /// How many times the command was recorded.
+    #[get = "pub(crate)"]
+    count: usize,
+    /// How many of those failed.
+    #[get = "pub(crate)"]
+    failed: usize,
+    #[get = "pub(crate)"]
+    avg: Duration,
+    #[get = "pub(crate)"]
+    min: Duration,
+    #[get = "pub(crate)"]
+    max: Duration,
+}
+
+/// The hostname and command name to narrow the metrics read back to.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct MetricsFilter<'a> {
+    pub(crate) hostname: Option<&'a str>,
+    pub(crate) cmdname: Option<&'a str>,
+}
+
+/// Writes the metrics of the commands run, and the hosts that completed, to
+/// the database.
+///
+/// sqlite allows a single writer, so every insert goes through one thread that
+/// owns the connection.  Host threads send their results over the channel
+/// returned by `sender`.
+#[derive(Debug)]
+pub(crate) struct MetricsWriter {
+    tx: Sender<Metric>,
+    handle: JoinHandle<MusshResult<usize>>,
+}
+
+impl MetricsWriter {
+    /// Open the database at the given path, creating its directory if need
+    /// be, record the run with its labels, and start the writer thread.
+    pub(crate) fn spawn(
+        db_path: &Path,
+        run_id: &str,
+        labels: &[(&str, &str)],
+    ) -> MusshResult<Self> {
+        if let Some(dir) = db_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(db_path)?;
+        create_metrics_table(&conn)?;
+        create_run_tables(&conn)?;
+        let _rows_changed = conn.execute(
+            "INSERT OR IGNORE INTO runs (run_id, started_at) VALUES (?1, ?2)",
+            params![run_id, Utc::now().timestamp_millis()],
+        )?;
+        for (key, value) in labels {
+            let _rows_changed = conn.execute(
+                "INSERT OR REPLACE INTO run_labels (run_id, key, value) VALUES (?1, ?2, ?3)",
+                params![run_id, key, value],
+            )?;
+        }
+        let run_id = run_id.to_string();
+        let (tx, rx) = mpsc::channel::<Metric>();
+
+        let handle = thread::spawn(move || {
+            let mut written = 0;
+            for metric in rx {
+                match metric {
+                    Metric::Result(result) => {
+                        insert_metrics(&conn, &run_id, &result)?;
+                        written += 1;
+                    }
+                    Metric::HostDone(host) => insert_completed(&conn, &run_id, &host)?,
+                    Metric::Finish => break,
+                }
+            }
+            Ok(written)
+        });
+
+        Ok(Self { tx, handle })
+    }
+
+    /// The channel to send results to.
+    pub(crate) fn sender(&self) -> &Sender<Metric> {
+        &self.tx
+    }
+
+    /// Wait for everything sent so far to be written, returning how many
+    /// metrics rows were inserted.
+    pub(crate) fn finish(self) -> MusshResult<usize> {
+        let _res = self.tx.send(Metric::Finish);
+        self.handle
+            .join()
+            .map_err(|_| "The metrics writer thread panicked")?
+    }
+}
+
+/// Create the metrics table, migrating one created by an older version.
+///
+/// Older versions only recorded the commands that succeeded, so their rows are
+/// marked as successful.
+fn create_metrics_table(conn: &Connection) -> MusshResult<()> {
+    let _rows_changed = conn.execute(
+        "CREATE TABLE IF NOT EXISTS metrics (
+          id          INTEGER PRIMARY KEY,
+          hostname    TEXT NOT NULL,
+          cmdname     TEXT NOT NULL,
+          secs        INTEGER NOT NULL,
+          micros      INTEGER NOT NULL,
+          timestamp   INTEGER NOT NULL,
+          started_at  INTEGER,
+          finished_at INTEGER,
+          run_id      TEXT,
+          output_hash TEXT,
+          exit_code   INTEGER,
+          success     INTEGER,
+          attempts    INTEGER
+        )",
+        [],
+    )?;
+    add_missing_columns(
+        conn,
+        &[
+            ("started_at", "INTEGER"),
+            ("finished_at", "INTEGER"),
+            ("run_id", "TEXT"),
+            ("output_hash", "TEXT"),
+            ("exit_code", "INTEGER"),
+            ("success", "INTEGER"),
+            ("attempts", "INTEGER"),
+        ],
+    )?;
+    let _rows_changed = conn.execute("UPDATE metrics SET success = 1 WHERE success IS NULL", [])?;
+    Ok(())
+}
+
+/// Add any of the given columns missing from a metrics table created by an
+/// older version.
+fn add_missing_columns(conn: &Connection, columns: &[(&str, &str)]) -> MusshResult<()> {
+    let mut stmt = conn.prepare("PRAGMA table_info(metrics)")?;
+    let existing = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (column, column_type) in columns {
+        if !existing.iter().any(|name| name == column) {
+            let _rows_changed = conn.execute(
+                &format!("ALTER TABLE metrics ADD COLUMN {column} {column_type}"),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Create the tables of runs, their labels, and the hosts that completed in
+/// each.
+fn create_run_tables(conn: &Connection) -> MusshResult<()> {
+    let _rows_changed = conn.execute(
+        "CREATE TABLE IF NOT EXISTS runs (
+          run_id     TEXT PRIMARY KEY,
+          started_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    let _rows_changed = conn.execute(
+        "CREATE TABLE IF NOT EXISTS run_hosts (
+          run_id       TEXT NOT NULL,
+          host         TEXT NOT NULL,
+          completed_at INTEGER NOT NULL,
+          PRIMARY KEY (run_id, host)
+        )",
+        [],
+    )?;
+    let _rows_changed = conn.execute(
+        "CREATE TABLE IF NOT EXISTS run_labels (
+          run_id TEXT NOT NULL,
+          key    TEXT NOT NULL,
+          value  TEXT NOT NULL,
+          PRIMARY KEY (run_id, key)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Record that the host ran all of its commands in the run.
+fn insert_completed(conn: &Connection, run_id: &str, host: &str) -> MusshResult<()> {
+    let _rows_changed = conn.execute(
+        "INSERT OR REPLACE INTO run_hosts (run_id, host, completed_at) VALUES (?1, ?2, ?3)",
+        params![run_id, host, Utc::now().timestamp_millis()],
+    )?;
+    Ok(())
+}
+
+/// The hosts that ran all of their commands in the given run, for `--resume`.
+pub(crate) fn completed_hosts(db_path: &Path, run_id: &str) -> MusshResult<IndexSet<String>> {
+    let conn = Connection::open(db_path)?;
+    create_run_tables(&conn)?;
+    let known: Option<String> = conn
+        .query_row(
+            "SELECT run_id FROM runs WHERE run_id = ?1",
+            params![run_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if known.is_none() {
+        return Err(format!("Unknown run id '{run_id}'").into());
+    }
+
+    let mut stmt = conn.prepare("SELECT host FROM run_hosts WHERE run_id = ?1")?;
+    let hosts = stmt
+        .query_map(params![run_id], |row| row.get::<_, String>(0))?
+        .collect::<Result<IndexSet<_>, _>>()?;
+    Ok(hosts)
+}
+
+/// Open the database at the given path to read it back, migrating it if it
+/// was written by an older version.  Unlike a run, this doesn't create it.
+fn open_existing(db_path: &Path) -> MusshResult<Connection> {
+    if !db_path.is_file() {
+        return Err(format!(
+            "No metrics recorded yet, {} doesn't exist",
+            db_path.display()
+        )
+        .into());
+    }
+    let conn = Connection::open(db_path)?;
+    create_metrics_table(&conn)?;
+    create_run_tables(&conn)?;
+    Ok(conn)
+}
+
+/// The most recent runs, newest first, that ran on the hostname and command of
+/// the filter.
+pub(crate) fn runs(
+    db_path: &Path,
+    filter: MetricsFilter<'_>,
+    limit: usize,
+) -> MusshResult<Vec<RunSummary>> {
+    let conn = open_existing(db_path)?;
+    let mut stmt = conn.prepare(
+        "SELECT runs.run_id, runs.started_at,
+           (SELECT group_concat(key || '=' || value, ',')
+              FROM (SELECT key, value FROM run_labels
+                    WHERE run_labels.run_id = runs.run_id ORDER BY key)),
+           (SELECT COUNT(*) FROM metrics WHERE metrics.run_id = runs.run_id),
+           (SELECT COUNT(*) FROM metrics WHERE metrics.run_id = runs.run_id AND success = 0)
+         FROM runs
+         WHERE (?1 IS NULL AND ?2 IS NULL)
+            OR runs.run_id IN (SELECT run_id FROM metrics
+                               WHERE (?1 IS NULL OR hostname = ?1)
+                                 AND (?2 IS NULL OR cmdname = ?2))
+         ORDER BY runs.started_at DESC
+         LIMIT ?3",
+    )?;
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+    let runs = stmt
+        .query_map(params![filter.hostname, filter.cmdname, limit], |row| {
+            let labels: Option<String> = row.get(2)?;
+            Ok(RunSummary {
+                run_id: row.get(0)?,
+                started_at: row.get(1)?,
+                labels: labels
+                    .map(|labels| labels.split(',').map(str::to_string).collect())
+                    .unwrap_or_default(),
+                commands: row.get(3)?,
+                failed: row.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(runs)
+}
+
+/// The durations of each command on each host matching the filter, by
+/// hostname and then command name.
+pub(crate) fn duration_stats(
+    db_path: &Path,
+    filter: MetricsFilter<'_>,
+) -> MusshResult<Vec<DurationStats>> {
+    let conn = open_existing(db_path)?;
+    let mut stmt = conn.prepare(
+        "SELECT hostname, cmdname, COUNT(*), SUM(success = 0),
+           CAST(AVG(CASE WHEN success THEN secs * 1000000 + micros END) AS INTEGER),
+           MIN(CASE WHEN success THEN secs * 1000000 + micros END),
+           MAX(CASE WHEN success THEN secs * 1000000 + micros END)
+         FROM metrics
+         WHERE (?1 IS NULL OR hostname = ?1) AND (?2 IS NULL OR cmdname = ?2)
+         GROUP BY hostname, cmdname
+         ORDER BY hostname, cmdname",
+    )?;
+    let micros = |micros: Option<i64>| {
+        Duration::from_micros(
+            micros
+                .and_then(|micros| u64::try_from(micros).ok())
+                .unwrap_or(0),
+        )
+    };
+    let stats = stmt
+        .query_map(params![filter.hostname, filter.cmdname], |row| {
+            Ok(DurationStats {
+                hostname: row.get(0)?,
+                cmdname: row.get(1)?,
+                count: row.get(2)?,
+                failed: row.get(3)?,
+                avg: micros(row.get(4)?),
+                min: micros(row.get(5)?),
+                max: micros(row.get(6)?),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(stats)
+}
+
+/// Record the metrics of a command in the run.  The exit code is only known
+/// if the command got as far as exiting, see `HostRunResult::exit_code`.
+fn insert_metrics(conn: &Connection, run_id: &str, result: &HostRunResult) -> MusshResult<()> {
+    let _rows_changed = conn.execute(
+        "INSERT INTO metrics
+           (hostname, cmdname, secs, micros, timestamp, started_at, finished_at, run_id,
+            output_hash, exit_code, success, attempts)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![
+            result.hostname(),
+            result.cmd_name(),
+            result.duration().as_secs(),
+            result.duration().subsec_micros(),
+            result.finished_at(),
+            result.started_at(),
+            result.finished_at(),
+            run_id,
+            result.output_hash(),
+            result.exit_code(),
+            result.success(),
+            result.attempts(),
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        completed_hosts, create_metrics_table, duration_stats, runs, MetricsFilter, MetricsWriter,
+    };
+    use crate::error::MusshResult;
+    use crate::runner::{self, Hooks};
+    use crate::targets;
+    use crate::warnings::Warnings;
+    use indexmap::IndexSet;
+    use libmussh::{Config, Multiplex, RuntimeConfig};
+    use rusqlite::Connection;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn metrics_table_migrates() -> MusshResult<()> {
+        let conn = Connection::open_in_memory()?;
+        let _rows_changed = conn.execute(
+            "CREATE TABLE metrics (
+              id         INTEGER PRIMARY KEY,
+              hostname   TEXT NOT NULL,
+              cmdname    TEXT NOT NULL,
+              secs       INTEGER NOT NULL,
+              micros     INTEGER NOT NULL,
+              timestamp  INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        let _rows_changed = conn.execute(
+            "INSERT INTO metrics (hostname, cmdname, secs, micros, timestamp)
+             VALUES ('web', 'deploy', 1, 0, 0)",
+            [],
+        )?;
+        create_metrics_table(&conn)?;
+        create_metrics_table(&conn)?;
+
+        let mut stmt = conn.prepare("PRAGMA table_info(metrics)")?;
+        let columns = stmt
+            .query_map([], |row| row.get::<_, String>(1))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert!(columns.contains(&"started_at".to_string()));
+        assert!(columns.contains(&"finished_at".to_string()));
+        assert!(columns.contains(&"run_id".to_string()));
+        assert!(columns.contains(&"output_hash".to_string()));
+        assert!(columns.contains(&"exit_code".to_string()));
+        assert!(columns.contains(&"attempts".to_string()));
+        let success: bool = conn.query_row("SELECT success FROM metrics", [], |row| row.get(0))?;
+        assert!(success);
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_writes_all_land() -> MusshResult<()> {
+        let names: Vec<String> = (0..32).map(|i| format!("\"h{i}\"")).collect();
+        let hosts: Vec<String> = (0..32)
+            .map(|i| format!("[hosts.h{i}]\nhostname = \"localhost\"\nusername = \"jozias\""))
+            .collect();
+        let toml = format!(
+            "[hostlist.all]\nhostnames = [{}]\n[cmd.pass]\ncommand = \"true\"\n[cmd.fail]\n\
+             command = \"exit 3\"\n{}\n",
+            names.join(", "),
+            hosts.join("\n")
+        );
+        let config: Config = toml::from_str(&toml)?;
+        let mut runtime_config = RuntimeConfig::default();
+        let _ = runtime_config.set_hosts(vec!["all".to_string()].into_iter().collect());
+        let _ = runtime_config.set_cmds(
+            vec!["pass".to_string(), "fail".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        let (_, multiplex_map) =
+            targets::to_host_map(&config, &runtime_config, true, &mut Warnings::default())?;
+
+        let dir = env::temp_dir().join(format!("mussh-metrics-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let db_path = dir.join("mussh.db");
+        let writer = MetricsWriter::spawn(&db_path, "run-1", &[("stage", "canary")])?;
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_metrics(Some(writer.sender().clone()));
+        let results = runner::run(
+            &Multiplex::default(),
+            &IndexSet::new(),
+            multiplex_map,
+            &hooks,
+        );
+        drop(hooks);
+        assert_eq!(writer.finish()?, 64);
+
+        let conn = Connection::open(&db_path)?;
+        let rows: i64 = conn.query_row("SELECT COUNT(*) FROM metrics", [], |row| row.get(0))?;
+        let failed: Vec<(i64, bool, usize)> = conn
+            .prepare(
+                "SELECT DISTINCT exit_code, success, attempts FROM metrics WHERE cmdname = 'fail'",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        let canary_rows: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM metrics JOIN run_labels USING (run_id)
+             WHERE key = 'stage' AND value = 'canary'",
+            [],
+            |row| row.get(0),
+        )?;
+        let completed = completed_hosts(&db_path, "run-1")?;
+        let unknown = completed_hosts(&db_path, "run-2");
+        let all_runs = runs(&db_path, MetricsFilter::default(), 10)?;
+        let elsewhere = MetricsFilter {
+            hostname: Some("10.0.0.3"),
+            cmdname: None,
+        };
+        let other_runs = runs(&db_path, elsewhere, 10)?;
+        let stats = duration_stats(&db_path, MetricsFilter::default())?;
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(results.len(), 64);
+        assert_eq!(rows, 64);
+        assert_eq!(failed, vec![(3, false, 1)]);
+        assert_eq!(canary_rows, 64);
+        assert_eq!(completed.len(), 32);
+        assert!(unknown.is_err());
+
+        assert_eq!(all_runs.len(), 1);
+        assert_eq!(all_runs[0].run_id(), "run-1");
+        assert_eq!(all_runs[0].labels(), &["stage=canary".to_string()]);
+        assert_eq!((*all_runs[0].commands(), *all_runs[0].failed()), (64, 32));
+        assert!(other_runs.is_empty());
+        let counts: Vec<(&str, usize, usize)> = stats
+            .iter()
+            .map(|stats| (stats.cmdname().as_str(), *stats.count(), *stats.failed()))
+            .collect();
+        assert_eq!(counts, vec![("fail", 32, 32), ("pass", 32, 0)]);
+        assert!(stats[0].max().is_zero());
+        assert!(stats[1].min() <= stats[1].avg() && stats[1].avg() <= stats[1].max());
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! An in-memory executor, to drive runs in tests without ssh
+use crate::runner::{Execute, Execution};
+use libmussh::{Multiplex, MultiplexMapType};
+use slog::trace;
+use ssh2::ErrorCode;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// What a command does when run by the `MockExecutor`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum MockCmd {
+    /// Write the lines to the host's output, take the time, and exit with the
+    /// code.
+    Exit(i32, Vec<String>, Duration),
+    /// Never finish, unless the run is cancelled.
+    Hang,
+    /// Fail to authenticate with the host.
+    AuthFailure,
+    /// Fail to connect to the host the first so many times, then exit 0.
+    Unreachable(usize),
+}
+
+impl MockCmd {
+    /// Exit 0 straight away, without output.
+    pub(crate) fn ok() -> Self {
+        Self::Exit(0, Vec::new(), Duration::from_millis(0))
+    }
+
+    /// Exit with the code straight away, without output.
+    pub(crate) fn exit(code: i32) -> Self {
+        Self::Exit(code, Vec::new(), Duration::from_millis(0))
+    }
+}
+
+/// Runs each command as its `MockCmd` says, by command name.  Commands it
+/// doesn't know exit 0.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MockExecutor {
+    cmds: HashMap<String, MockCmd>,
+    /// How many times each host has been connected to, for `Unreachable`.
+    connects: Arc<Mutex<HashMap<String, usize>>>,
+    /// Set once the run is cancelled, ending the `Hang` commands.
+    cancelled: Arc<AtomicBool>,
+}
+
+impl MockExecutor {
+    pub(crate) fn with(mut self, cmd_name: &str, cmd: MockCmd) -> Self {
+        let _old = self.cmds.insert(cmd_name.to_string(), cmd);
+        self
+    }
+}
+
+impl Execute for MockExecutor {
+    fn execute(&self, multiplex: Multiplex, cmd_map: MultiplexMapType) -> Option<Execution> {
+        let (name, (host, cmds)) = cmd_map.into_iter().next()?;
+        let cmd_name = cmds.values().flat_map(|cmds| cmds.keys()).next()?.clone();
+        let logger = multiplex.host_loggers().get(&name).cloned().flatten();
+
+        match self
+            .cmds
+            .get(&cmd_name)
+            .cloned()
+            .unwrap_or_else(MockCmd::ok)
+        {
+            MockCmd::Exit(code, lines, took) => {
+                if let Some(logger) = logger {
+                    for line in lines {
+                        trace!(logger, "{}", line);
+                    }
+                }
+                thread::sleep(took);
+                Some(if code == 0 {
+                    Execution::ok(took)
+                } else {
+                    let message = format!("Failed to run '{}' on '{cmd_name}'", host.hostname());
+                    Execution::failed(took, Some(code), message.as_str().into())
+                })
+            }
+            MockCmd::Hang => {
+                while !self.cancelled.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Some(Execution::failed(
+                    Duration::from_millis(0),
+                    None,
+                    "Cancelled".into(),
+                ))
+            }
+            MockCmd::AuthFailure => Some(Execution::failed(
+                Duration::from_millis(0),
+                None,
+                ssh2::Error::new(ErrorCode::Session(-18), "Authentication failed (publickey)")
+                    .into(),
+            )),
+            MockCmd::Unreachable(failures) => {
+                let connects = self.connects.lock().map(|mut connects| {
+                    let connects = connects.entry(name).or_default();
+                    *connects += 1;
+                    *connects
+                });
+                Some(if connects.is_ok_and(|connects| connects <= failures) {
+                    let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+                    Execution::failed(Duration::from_millis(0), None, refused.into())
+                } else {
+                    Execution::ok(Duration::from_millis(0))
+                })
+            }
+        }
+    }
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
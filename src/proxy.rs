@@ -0,0 +1,334 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Connecting to hosts through a SOCKS5 proxy
+//!
+//! libmussh opens its own connection to each host, so a proxied host is given
+//! a local port to connect to instead, which forwards each connection through
+//! the proxy to the host.
+use crate::error::{MusshErrKind, MusshResult};
+use crate::extensions::Extensions;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+
+/// The `proxy` of a host that connects directly, whatever `--proxy` says.
+const NO_PROXY: &str = "none";
+
+/// A SOCKS5 proxy, from `socks5://[USER:PASS@]HOST:PORT`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Proxy {
+    /// The `HOST:PORT` of the proxy.
+    addr: String,
+    /// The username and password to give the proxy.
+    auth: Option<(String, String)>,
+}
+
+impl FromStr for Proxy {
+    type Err = crate::error::MusshErr;
+
+    fn from_str(url: &str) -> MusshResult<Self> {
+        let invalid = || format!("Invalid proxy '{url}', expected socks5://[USER:PASS@]HOST:PORT");
+        let rest = url.strip_prefix("socks5://").ok_or_else(invalid)?;
+        let (auth, addr) = match rest.rsplit_once('@') {
+            Some((userinfo, addr)) => {
+                let (user, pass) = userinfo.split_once(':').ok_or_else(invalid)?;
+                (Some((user.to_string(), pass.to_string())), addr)
+            }
+            None => (None, rest),
+        };
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(Self {
+                addr: addr.to_string(),
+                auth,
+            }),
+            _ => Err(invalid().into()),
+        }
+    }
+}
+
+impl Proxy {
+    /// Connect to `host:port` through the proxy.
+    ///
+    /// Failing to reach or authenticate with the proxy is a `Proxy` error, and
+    /// the proxy failing to reach the host a plain one.
+    pub(crate) fn connect(&self, host: &str, port: u16) -> MusshResult<TcpStream> {
+        let proxy_err =
+            |e: &dyn std::fmt::Display| MusshErrKind::Proxy(self.addr.clone(), e.to_string());
+        let mut stream = TcpStream::connect(&self.addr).map_err(|e| proxy_err(&e))?;
+
+        let method = if self.auth.is_some() { 2 } else { 0 };
+        stream
+            .write_all(&[5, 1, method])
+            .map_err(|e| proxy_err(&e))?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).map_err(|e| proxy_err(&e))?;
+        if reply != [5, method] {
+            return Err(proxy_err(&"it accepts none of the offered authentication methods").into());
+        }
+        if let Some((user, pass)) = &self.auth {
+            let mut request = vec![1];
+            for field in &[user, pass] {
+                request.push(u8::try_from(field.len()).map_err(|e| proxy_err(&e))?);
+                request.extend(field.as_bytes());
+            }
+            stream.write_all(&request).map_err(|e| proxy_err(&e))?;
+            stream.read_exact(&mut reply).map_err(|e| proxy_err(&e))?;
+            if reply[1] != 0 {
+                return Err(proxy_err(&"it rejected the username and password").into());
+            }
+        }
+
+        stream
+            .write_all(&connect_request(host, port).map_err(|e| proxy_err(&e))?)
+            .map_err(|e| proxy_err(&e))?;
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).map_err(|e| proxy_err(&e))?;
+        if reply[1] != 0 {
+            return Err(format!(
+                "'{host}:{port}' is unreachable through the proxy {}: {}",
+                self.addr,
+                reply_error(reply[1])
+            )
+            .into());
+        }
+        let bound_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            _ => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len).map_err(|e| proxy_err(&e))?;
+                usize::from(len[0])
+            }
+        };
+        let mut bound = vec![0; bound_len + 2];
+        stream.read_exact(&mut bound).map_err(|e| proxy_err(&e))?;
+        Ok(stream)
+    }
+}
+
+/// The SOCKS5 request to connect to `host:port`.
+fn connect_request(host: &str, port: u16) -> MusshResult<Vec<u8>> {
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend(&ip.octets());
+        }
+        Err(_) => {
+            request.push(3);
+            request.push(u8::try_from(host.len()).map_err(|_| "The hostname is too long")?);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(&port.to_be_bytes());
+    Ok(request)
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by the proxy's rules",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// The proxy each host connects through: its `proxy` in the config, otherwise
+/// the `--proxy` given.  A host with `proxy = "none"` connects directly.
+pub(crate) fn host_proxies(
+    extensions: &Extensions,
+    default: Option<&str>,
+    hosts: &[&str],
+) -> MusshResult<HashMap<String, Arc<Proxy>>> {
+    let default = default.map(Proxy::from_str).transpose()?.map(Arc::new);
+    let mut proxies = HashMap::new();
+
+    for host in hosts {
+        let proxy = match extensions
+            .hosts
+            .get(*host)
+            .and_then(|host| host.proxy.as_deref())
+        {
+            Some(NO_PROXY) => None,
+            Some(proxy) => Some(Arc::new(proxy.parse()?)),
+            None => default.clone(),
+        };
+        if let Some(proxy) = proxy {
+            let _old = proxies.insert((*host).to_string(), proxy);
+        }
+    }
+
+    Ok(proxies)
+}
+
+/// Forward the connections to a new local port through the proxy to
+/// `host:port`, returning the local port.
+///
+/// The first connection through the proxy is made straight away, so that not
+/// reaching the proxy or the host is an error here, rather than a failed ssh
+/// handshake later.
+pub(crate) fn forward(proxy: Arc<Proxy>, host: String, port: u16) -> MusshResult<u16> {
+    let first = proxy.connect(&host, port)?;
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let local_port = listener.local_addr()?.port();
+
+    let _handle = thread::spawn(move || {
+        let mut first = Some(first);
+        for client in listener.incoming().flatten() {
+            let upstream = match first.take() {
+                Some(upstream) => Ok(upstream),
+                None => proxy.connect(&host, port),
+            };
+            if let Ok(upstream) = upstream {
+                pipe(client, upstream);
+            }
+        }
+    });
+    Ok(local_port)
+}
+
+/// Copy everything from each stream to the other, until either side closes.
+fn pipe(client: TcpStream, upstream: TcpStream) {
+    let (Ok(client_in), Ok(upstream_out)) = (client.try_clone(), upstream.try_clone()) else {
+        return;
+    };
+    for (mut from, mut to) in [(client_in, upstream_out), (upstream, client)] {
+        let _handle = thread::spawn(move || {
+            let _bytes = io::copy(&mut from, &mut to);
+            let _res = to.shutdown(Shutdown::Write);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{forward, host_proxies, Proxy};
+    use crate::error::MusshResult;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// A SOCKS5 proxy for one connection that wants `user:pass`, and connects
+    /// anything but `10.0.0.4` to an echo.
+    fn fake_proxy() -> MusshResult<String> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let addr = listener.local_addr()?.to_string();
+        let _handle = thread::spawn(move || -> MusshResult<()> {
+            for stream in listener.incoming() {
+                let mut stream = stream?;
+                let mut greeting = [0; 3];
+                stream.read_exact(&mut greeting)?;
+                stream.write_all(&[5, 2])?;
+                let mut auth = [0; 11];
+                stream.read_exact(&mut auth)?;
+                if &auth[..] != b"\x01\x04user\x04pass" {
+                    stream.write_all(&[1, 1])?;
+                    continue;
+                }
+                stream.write_all(&[1, 0])?;
+                let mut request = [0; 10];
+                stream.read_exact(&mut request)?;
+                if request[4..8] == [10, 0, 0, 4] {
+                    stream.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0])?;
+                    continue;
+                }
+                stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 22])?;
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf)?;
+                stream.write_all(&buf)?;
+            }
+            Ok(())
+        });
+        Ok(addr)
+    }
+
+    #[test]
+    fn parses() -> MusshResult<()> {
+        let proxy: Proxy = "socks5://jozias:s3:cret@proxy.corp:1080".parse()?;
+        assert_eq!(proxy.addr, "proxy.corp:1080");
+        assert_eq!(
+            proxy.auth,
+            Some(("jozias".to_string(), "s3:cret".to_string()))
+        );
+        assert!("socks5://proxy.corp:1080".parse::<Proxy>()?.auth.is_none());
+        for bad in &[
+            "http://proxy:80",
+            "socks5://proxy",
+            "socks5://user@proxy:1080",
+        ] {
+            assert!(bad.parse::<Proxy>().is_err());
+        }
+
+        let toml =
+            "[hosts.web]\nproxy = \"socks5://web-proxy:1080\"\n[hosts.db]\nproxy = \"none\"\n";
+        let proxies = host_proxies(
+            &toml::from_str(toml)?,
+            Some("socks5://proxy:1080"),
+            &["web", "db", "cache"],
+        )?;
+        assert_eq!(
+            proxies.get("web").map(|p| p.addr.as_str()),
+            Some("web-proxy:1080")
+        );
+        assert!(!proxies.contains_key("db"));
+        assert_eq!(
+            proxies.get("cache").map(|p| p.addr.as_str()),
+            Some("proxy:1080")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn forwards_through_the_proxy() -> MusshResult<()> {
+        let addr = fake_proxy()?;
+        let proxy = Arc::new(format!("socks5://user:pass@{addr}").parse::<Proxy>()?);
+        let port = forward(Arc::clone(&proxy), "10.0.0.3".to_string(), 22)?;
+        let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+        stream.write_all(b"hello")?;
+        let mut echoed = [0; 5];
+        stream.read_exact(&mut echoed)?;
+        assert_eq!(&echoed, b"hello");
+
+        let unreachable = proxy
+            .connect("10.0.0.4", 22)
+            .err()
+            .ok_or("expected an error")?;
+        assert!(unreachable.to_string().contains("host unreachable"));
+
+        let wrong = format!("socks5://user:nope@{addr}").parse::<Proxy>()?;
+        let rejected = wrong
+            .connect("10.0.0.3", 22)
+            .err()
+            .ok_or("expected an error")?;
+        assert!(rejected.to_string().starts_with("Unable to use the proxy"));
+
+        let down = TcpListener::bind(("127.0.0.1", 0))?.local_addr()?;
+        let down = format!("socks5://{down}").parse::<Proxy>()?;
+        let refused = down
+            .connect("10.0.0.3", 22)
+            .err()
+            .ok_or("expected an error")?;
+        assert!(refused.to_string().starts_with("Unable to use the proxy"));
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Environment variables for the commands on the hosts
+//!
+//! The variables come from `--env-passthrough`, the `env` table of a
+//! `[cmd.<name>]` in the config and `--env KEY=VAL`, each overriding the one
+//! before.  Over ssh they are set with `setenv` requests on the channel, and
+//! the ones sshd refuses, as it does for names its `AcceptEnv` doesn't allow,
+//! are exported in front of the command instead, which works on any host with
+//! a POSIX shell.  A local command gets them in its environment.
+use crate::error::MusshResult;
+use crate::extensions::Extensions;
+use crate::ssh_config;
+use crate::util::shell_quote;
+use slog::{info, Logger};
+use slog_try::try_debug;
+use std::collections::{BTreeMap, HashMap};
+
+/// What the value of a variable is shown as when a command is printed.
+const HIDDEN: &str = "***";
+
+/// The variables to set for each command.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RemoteEnv {
+    /// The local variables passed through with `--env-passthrough`.
+    passthrough: BTreeMap<String, String>,
+    /// The `env` tables of the `[cmd.<name>]` tables in the config.
+    cmds: HashMap<String, BTreeMap<String, String>>,
+    /// The variables given with `--env`.
+    flags: BTreeMap<String, String>,
+}
+
+impl RemoteEnv {
+    /// Check the `env` tables of the commands in the config, and parse the
+    /// `--env` assignments.
+    pub(crate) fn parse(
+        extensions: &Extensions,
+        passthrough: BTreeMap<String, String>,
+        assignments: &[&str],
+    ) -> MusshResult<Self> {
+        let mut cmds = HashMap::new();
+
+        for (name, cmd) in &extensions.cmd {
+            if let Some(env) = &cmd.env {
+                let _old = cmds.insert(name.clone(), cmd_env(name, env)?);
+            }
+        }
+
+        Ok(Self {
+            passthrough,
+            cmds,
+            flags: assignments
+                .iter()
+                .map(|assignment| parse_assignment(assignment))
+                .collect::<MusshResult<_>>()?,
+        })
+    }
+
+    /// The variables to set for the given command.
+    pub(crate) fn for_cmd(&self, cmd_name: &str) -> BTreeMap<String, String> {
+        let mut vars = self.passthrough.clone();
+        vars.extend(self.cmds.get(cmd_name).cloned().unwrap_or_default());
+        vars.extend(self.flags.clone());
+        vars
+    }
+}
+
+fn cmd_env(name: &str, env: &BTreeMap<String, String>) -> MusshResult<BTreeMap<String, String>> {
+    match env.keys().find(|var| !is_env_name(var)) {
+        Some(var) => Err(format!("Invalid variable name '{var}' in the env of '{name}'").into()),
+        None => Ok(env.clone()),
+    }
+}
+
+/// Parse a `KEY=VAL` given with `--env`.
+fn parse_assignment(assignment: &str) -> MusshResult<(String, String)> {
+    match assignment.split_once('=') {
+        Some((name, value)) if is_env_name(name) => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("Invalid value '{assignment}' for --env, expected KEY=VAL").into()),
+    }
+}
+
+/// The local environment variables matching the `--env-passthrough` patterns,
+/// by name.  A pattern is a variable name, or a glob like `AWS_*`.  A pattern
+/// matching no set variable is skipped, with a debug log.
+pub(crate) fn passthrough_env(
+    patterns: &[&str],
+    vars: impl IntoIterator<Item = (String, String)>,
+    stderr: Option<&Logger>,
+) -> BTreeMap<String, String> {
+    let vars: BTreeMap<String, String> = vars
+        .into_iter()
+        .filter(|(name, _)| is_env_name(name))
+        .collect();
+    let mut passthrough = BTreeMap::new();
+    for pattern in patterns {
+        let matched: Vec<_> = vars
+            .iter()
+            .filter(|(name, _)| ssh_config::glob_match(pattern, name))
+            .collect();
+        if matched.is_empty() {
+            try_debug!(stderr, "Not passing through '{}', it isn't set", pattern);
+        }
+        for (name, value) in matched {
+            let _old = passthrough.insert(name.clone(), value.clone());
+        }
+    }
+    passthrough
+}
+
+/// Can the shell export a variable with this name?
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first == '_' || first.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Export the variables before running the command, so they are set for all
+/// of it, whatever it is wrapped in.
+pub(crate) fn with_env(command: &str, vars: &BTreeMap<String, String>) -> String {
+    let exports: Vec<String> = vars
+        .iter()
+        .map(|(name, value)| format!("{name}={}", shell_quote(value)))
+        .collect();
+    format!("export {}; {command}", exports.join(" "))
+}
+
+/// Log the command at info, exactly as it is sent to its host, just before it
+/// is run: with the `exported` variables exported in front of it, and the
+/// variables `set` in its environment another way listed after it.  Their
+/// values are shown as `***`, as they may well be secrets.
+pub(crate) fn print_command(
+    logger: &Logger,
+    (hostname, cmd_name): (&str, &str),
+    command: &str,
+    (exported, set): (&BTreeMap<String, String>, &BTreeMap<String, String>),
+) {
+    let command = if exported.is_empty() {
+        command.to_string()
+    } else {
+        let hidden = exported
+            .keys()
+            .map(|name| (name.clone(), HIDDEN.to_string()))
+            .collect();
+        with_env(command, &hidden)
+    };
+    let env: Vec<String> = set.keys().map(|name| format!("{name}={HIDDEN}")).collect();
+    info!(logger, "exec"; "host" => hostname, "cmd" => cmd_name, "command" => command, "env" => env.join(" "));
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_assignment, passthrough_env, with_env, RemoteEnv};
+    use crate::error::MusshResult;
+    use crate::extensions::Extensions;
+    use std::collections::BTreeMap;
+
+    const ENV_TOML: &str = r#"[cmd.deploy]
+command = "make deploy"
+env = { RELEASE = "v2", REGION = "eu-west-1" }
+[cmd.ls]
+command = "ls"
+"#;
+
+    #[test]
+    fn env_passthrough() -> MusshResult<()> {
+        let vars = [
+            ("HTTP_PROXY", "http://proxy:3128"),
+            ("AWS_REGION", "eu-west-1"),
+            ("AWS_SECRET", "it's"),
+            ("AWSX", "no"),
+            ("HOME", "/root"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let passthrough = passthrough_env(&["HTTP_PROXY", "AWS_*", "NO_PROXY"], vars, None);
+        assert_eq!(
+            passthrough.keys().collect::<Vec<_>>(),
+            ["AWS_REGION", "AWS_SECRET", "HTTP_PROXY"]
+        );
+
+        let command = with_env("sh -c 'echo \"$AWS_SECRET\"'", &passthrough);
+        assert!(command.starts_with("export AWS_REGION='eu-west-1' AWS_SECRET='it'\\''s' "));
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .output()?;
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "it's\n");
+        Ok(())
+    }
+
+    #[test]
+    fn precedence() -> MusshResult<()> {
+        let passthrough: BTreeMap<String, String> = [("REGION", "us-east-1"), ("USER", "me")]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let env = RemoteEnv::parse(
+            &toml::from_str(ENV_TOML)?,
+            passthrough,
+            &["RELEASE=v3", "DEBUG="],
+        )?;
+
+        let deploy = env.for_cmd("deploy");
+        assert_eq!(deploy["REGION"], "eu-west-1");
+        assert_eq!(deploy["RELEASE"], "v3");
+        assert_eq!(deploy["DEBUG"], "");
+        assert_eq!(deploy["USER"], "me");
+        let ls = env.for_cmd("ls");
+        assert_eq!(ls["REGION"], "us-east-1");
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_assignment("A=b=c").is_ok_and(|(_, value)| value == "b=c"));
+        assert!(parse_assignment("NOVALUE").is_err());
+        assert!(parse_assignment("1A=b").is_err());
+        for bad in &[
+            "[cmd.x]\nenv = \"A=b\"\n",
+            "[cmd.x]\nenv = { \"A-B\" = \"c\" }\n",
+            "[cmd.x]\nenv = { A = 1 }\n",
+        ] {
+            let extensions: MusshResult<Extensions> = toml::from_str(bad).map_err(Into::into);
+            assert!(extensions
+                .and_then(|extensions| RemoteEnv::parse(&extensions, BTreeMap::new(), &[]))
+                .is_err());
+        }
+    }
+}
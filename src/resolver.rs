@@ -0,0 +1,126 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Host resolution against a specific nameserver
+use crate::connect;
+use crate::error::MusshResult;
+use crate::warnings::Warnings;
+use libmussh::Config;
+use slog::Logger;
+use slog_try::try_trace;
+use std::net::{IpAddr, SocketAddr};
+use toml::Value;
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::Resolver;
+
+/// Resolve the hostname of every configured host against the nameserver at
+/// `nameserver`, and connect to the addresses returned, in order, rather than
+/// to those of the system resolver.
+///
+/// The hosts keep their hostnames, so their keys are checked against the names
+/// in the known hosts, as without a resolver.  Hostnames that are already
+/// addresses, and `localhost`, are left alone.  If a hostname can't be
+/// resolved it is left to the system resolver at connect time, with a warning.
+pub(crate) fn apply(
+    config: &Config,
+    nameserver: SocketAddr,
+    stdout: Option<&Logger>,
+    warnings: &mut Warnings,
+) -> MusshResult<()> {
+    let resolver = Resolver::new(
+        ResolverConfig::from_parts(
+            None,
+            vec![],
+            NameServerConfigGroup::from_ips_clear(&[nameserver.ip()], nameserver.port(), true),
+        ),
+        ResolverOpts::default(),
+    )?;
+    let value = Value::try_from(config)?;
+
+    if let Some(hosts) = value.get("hosts").and_then(Value::as_table) {
+        for (name, host) in hosts {
+            let hostname = match host.get("hostname").and_then(Value::as_str) {
+                Some(hostname) if needs_lookup(hostname) => hostname.to_string(),
+                _ => continue,
+            };
+
+            match resolver.lookup_ip(hostname.as_str()) {
+                Ok(lookup) if lookup.iter().next().is_some() => {
+                    let addrs: Vec<IpAddr> = lookup.iter().collect();
+                    let listed: Vec<String> = addrs.iter().map(IpAddr::to_string).collect();
+                    try_trace!(stdout, "resolved"; "host" => name, "hostname" => &hostname, "addrs" => listed.join(","));
+                    connect::resolve_to(&hostname, addrs);
+                }
+                Ok(_) => warnings.warn(format!(
+                    "No addresses for '{hostname}' (host '{name}') from {nameserver}, using the \
+                     system resolver"
+                )),
+                Err(e) => warnings.warn(format!(
+                    "Lookup of '{hostname}' (host '{name}') against {nameserver} failed, using \
+                     the system resolver: {e}"
+                )),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn needs_lookup(hostname: &str) -> bool {
+    hostname != "localhost" && hostname.parse::<IpAddr>().is_err()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, needs_lookup};
+    use crate::error::MusshResult;
+    use crate::warnings::Warnings;
+    use libmussh::Config;
+
+    const RESOLVE_TOML: &str = r#"[hostlist]
+[hosts.local]
+hostname = "localhost"
+username = "jozias"
+[hosts.addr]
+hostname = "10.0.0.3"
+username = "jozias"
+[hosts.name]
+hostname = "nope.invalid"
+username = "jozias"
+[cmd]
+"#;
+
+    #[test]
+    fn lookups() {
+        assert!(!needs_lookup("localhost"));
+        assert!(!needs_lookup("10.0.0.3"));
+        assert!(!needs_lookup("::1"));
+        assert!(needs_lookup("db01.example.com"));
+    }
+
+    #[test]
+    fn falls_back_when_the_lookup_fails() -> MusshResult<()> {
+        let config: Config = toml::from_str(RESOLVE_TOML)?;
+        let mut warnings = Warnings::default();
+        apply(
+            &config,
+            "127.0.0.1:9".parse().map_err(|_| "bad addr")?,
+            None,
+            &mut warnings,
+        )?;
+        let mut hostnames: Vec<_> = config
+            .hosts()
+            .values()
+            .map(|host| host.hostname().clone())
+            .collect();
+        hostnames.sort();
+        assert_eq!(hostnames, vec!["10.0.0.3", "localhost", "nope.invalid"]);
+        assert!(warnings.emit(None, true).is_err());
+        Ok(())
+    }
+}
@@ -7,18 +7,32 @@
 // modified, or distributed except according to those terms.
 
 //! Runtime
-use crate::error::MusshResult;
+use crate::chain;
+use crate::config_file;
+use crate::config_format::{self, ConfigFormat};
+use crate::defaults;
+use crate::env_config;
+use crate::error::{MusshErr, MusshErrKind, MusshResult};
+use crate::extensions::Extensions;
+use crate::format;
+use crate::fragments;
+use crate::interpolate;
+use crate::legacy;
 use crate::logging::Loggers;
-use crate::subcmd::{Run, Subcommand};
-use clap::{App, Arg};
+use crate::subcmd::{
+    Alias, Check, Cmd, ConfigCmd, Hostlist, Hosts, Inventory, Metrics, Pull, Push, Run, Subcommand,
+};
+use clap::{App, Arg, ArgMatches};
 use libmussh::Config;
-use slog_try::try_trace;
+use slog::Logger;
+use slog_try::{try_trace, try_warn};
 use std::convert::TryFrom;
 use std::env;
-use std::path::PathBuf;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
-pub(crate) const MUSSH_CONFIG_FILE_NAME: &str = "mussh.toml";
 pub(crate) const MUSSH_DB_FILE_NAME: &str = "mussh.db";
+const EMPTY_CONFIG: &str = "[hostlist]\n[hosts]\n[cmd]\n";
 
 fn base_config_dir() -> MusshResult<PathBuf> {
     Ok(if let Some(config_dir) = dirs::config_dir() {
@@ -31,6 +45,99 @@ fn base_config_dir() -> MusshResult<PathBuf> {
     .join(env!("CARGO_PKG_NAME")))
 }
 
+/// Read the mussh config from the `--config` directory, or from stdin if it is
+/// `-`.  Returns where the config came from, and its contents.
+///
+/// A `--target` only run doesn't need a config file, and nor does one with
+/// config overrides in the environment, so a missing one reads as empty.
+///
+/// The config file is `mussh.toml`, or `mussh.yaml` or `mussh.yml` if there
+/// is no `mussh.toml`.  A YAML config is read as TOML.  The fragment configs in
+/// a `mussh.d` directory next to the config file are merged into it, and then
+/// the `[defaults]` filled in for the hosts.  A config read from stdin has no
+/// fragments, and includes configs relative to the current directory.
+fn read_config(
+    matches: &ArgMatches<'_>,
+    stderr: Option<&Logger>,
+) -> MusshResult<(PathBuf, String)> {
+    let config_dir = matches.value_of("config").unwrap_or("./");
+
+    if config_dir == config_file::STDIN {
+        let mut contents = String::new();
+        let _bytes = io::stdin().read_to_string(&mut contents)?;
+        let config_path = PathBuf::from(config_file::STDIN);
+        let contents = fragments::with_fragments(&config_path, contents, stderr)?;
+        let contents = defaults::with_defaults(&config_path, contents)?;
+        Ok((config_path, contents))
+    } else {
+        let config_path = config_format::config_path(Path::new(config_dir));
+        let contents = if !config_path.exists()
+            && (targets_only(matches) || env_config::has_overrides(env::vars()))
+        {
+            String::new()
+        } else {
+            config_format::read(&config_path)?
+        };
+        let contents = fragments::with_fragments(&config_path, contents, stderr)?;
+        let contents = defaults::with_defaults(&config_path, contents)?;
+        Ok((config_path, contents))
+    }
+}
+
+/// Load the mussh config, reporting parse errors with the file, line and
+/// column they occurred at.  The fields only mussh knows about are returned
+/// with it, and so is the config document it was loaded from.
+///
+/// Chained commands and references to the variables are expanded, the
+/// overrides in the environment applied, and the `[defaults]` filled in for
+/// the hosts the overrides add, first.  Only a config that has any of
+/// them goes through a `toml::Value`, which
+/// loses the position of errors in the values.  So does a YAML config, as the
+/// positions would be in the TOML it was read as.  An empty config is an empty
+/// `Config` for the overrides to fill in.
+///
+/// A config in an older layout is brought into the current one, with a
+/// warning.
+pub(crate) fn load_config<I>(
+    path: &Path,
+    contents: &str,
+    vars: I,
+    stderr: Option<&Logger>,
+) -> MusshResult<(Config, Extensions, toml::Value)>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let parse_err = |e| -> MusshErr { MusshErrKind::ConfigParse(path.to_path_buf(), e).into() };
+    let contents = if contents.trim().is_empty() {
+        EMPTY_CONFIG
+    } else {
+        contents
+    };
+    let mut value: toml::Value = toml::from_str(contents).map_err(parse_err)?;
+    let legacy = legacy::normalize(&mut value);
+    if legacy {
+        try_warn!(
+            stderr,
+            "{} is in an older config layout, rewrite it in the current one with the output \
+             of --dump-resolved-config",
+            path.display()
+        );
+    }
+    let expanded = chain::expand(&mut value)?;
+    let vars: Vec<(String, String)> = vars.into_iter().collect();
+    let interpolated = interpolate::apply(&mut value, &vars.iter().cloned().collect())?;
+    let yaml = ConfigFormat::of(path) == ConfigFormat::Yaml;
+    let overridden = env_config::apply(&mut value, vars)?;
+    let defaulted = defaults::apply(&mut value)?;
+    let config = if overridden || defaulted || expanded || interpolated || legacy || yaml {
+        value.clone().try_into().map_err(parse_err)?
+    } else {
+        toml::from_str(contents).map_err(parse_err)?
+    };
+    let extensions = value.clone().try_into().map_err(parse_err)?;
+    Ok((config, extensions, value))
+}
+
 pub(crate) fn run() -> MusshResult<()> {
     // Setup the default config path for use in clap App
     let base_path = base_config_dir()?;
@@ -41,32 +148,78 @@ pub(crate) fn run() -> MusshResult<()> {
     let (stdout, stderr) = Loggers::try_from(&matches)?.split();
 
     // Grab the mussh config
-    let config_path =
-        PathBuf::from(matches.value_of("config").unwrap_or("./")).join(MUSSH_CONFIG_FILE_NAME);
+    let (config_path, config_toml) = read_config(&matches, stderr.as_ref())?;
     try_trace!(stdout, "Config Path: {}", config_path.display());
-    let config = Config::try_from(config_path)?;
+    let (config, extensions, config_value) = if config_toml.is_empty() && targets_only(&matches) {
+        (
+            Config::default(),
+            Extensions::default(),
+            toml::Value::try_from(Config::default())?,
+        )
+    } else {
+        load_config(&config_path, &config_toml, env::vars(), stderr.as_ref())?
+    };
 
-    let db_path =
-        PathBuf::from(matches.value_of("config").unwrap_or("./")).join(MUSSH_DB_FILE_NAME);
+    // With the config on stdin the metrics db lives in the default config dir.
+    let db_path = match matches.value_of("config") {
+        Some(config_file::STDIN) | None => base_path.join(MUSSH_DB_FILE_NAME),
+        Some(config_dir) => PathBuf::from(config_dir).join(MUSSH_DB_FILE_NAME),
+    };
 
     if matches.is_present("output") {
         try_trace!(stdout, "{:?}", config);
     }
 
+    if matches.is_present("dump_resolved_config") {
+        print!("{}", ConfigFormat::of(&config_path).write(&config_value)?);
+        return Ok(());
+    }
+
     // Run, run, run...
     match matches.subcommand() {
+        // 'alias' subcommand
+        ("alias", Some(sub_m)) => Alias::new(stdout, stderr, config_path).execute(&config, sub_m),
+        // 'check' subcommand
+        ("check", Some(sub_m)) => Check::new(config_path, extensions).execute(&config, sub_m),
         // 'cmd' subcommand
-        // ("cmd", Some(sub_m)) => command::cmd(&mut config, sub_m, &stderr),
+        ("cmd", Some(sub_m)) => Cmd::new(stdout, config_path, extensions).execute(&config, sub_m),
+        // 'config' subcommand
+        ("config", Some(sub_m)) => ConfigCmd::new(stderr).execute(&config, sub_m),
         // 'hostlist' subcommand
-        // ("hostlist", Some(sub_m)) => hostlist::cmd(&mut config, sub_m, &stderr),
+        ("hostlist", Some(sub_m)) => {
+            Hostlist::new(stdout, stderr, config_path).execute(&config, sub_m)
+        }
         // 'hosts' subcommand
-        // ("hosts", Some(sub_m)) => hosts::cmd(&mut config, sub_m),
+        ("hosts", Some(sub_m)) => Hosts::new(stdout, config_path).execute(&config, sub_m),
+        // 'inventory' subcommand
+        ("inventory", Some(sub_m)) => Inventory::new(stderr).execute(&config, sub_m),
+        // 'metrics' subcommand
+        ("metrics", Some(sub_m)) => Metrics::new(db_path).execute(&config, sub_m),
+        // 'pull' subcommand
+        ("pull", Some(sub_m)) => Pull::new(stderr, extensions).execute(&config, sub_m),
+        // 'push' subcommand
+        ("push", Some(sub_m)) => Push::new(stderr, extensions).execute(&config, sub_m),
         // 'run' subcommand
-        ("run", Some(sub_m)) => Run::new(stdout, stderr, db_path).execute(&config, sub_m),
+        ("run", Some(sub_m)) => {
+            let formatter = format::named(sub_m.value_of("format").unwrap_or("human"))?;
+            // Quiet runs log nothing to stdout, leaving only the results there.
+            let stdout = stdout.filter(|_| !sub_m.is_present("quiet"));
+            Run::new(stdout, stderr, db_path, extensions)
+                .with_formatter(formatter)
+                .execute(&config, sub_m)
+        }
         (cmd, _) => Err(format!("Unknown subcommand {cmd}").into()),
     }
 }
 
+/// A run on `--target` hosts only, which doesn't need a config file.
+fn targets_only(matches: &ArgMatches<'_>) -> bool {
+    match matches.subcommand() {
+        ("run", Some(sub_m)) => sub_m.is_present("target") && !sub_m.is_present("hosts"),
+        _ => false,
+    }
+}
+
 fn app<'b>(default_config_path: &'_ str) -> App<'_, 'b> {
     App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -77,7 +230,14 @@ fn app<'b>(default_config_path: &'_ str) -> App<'_, 'b> {
                 .short("c")
                 .long("config")
                 .value_name("CONFIG")
-                .help("Specify a path for the TOML config file.")
+                .help(
+                    "Specify a path for the TOML config file, or - to read it from stdin. \
+                     The .toml, .yaml and .yml files in a mussh.d directory next to it are \
+                     merged in by file name, and replace its hosts, hostlists and cmds of the \
+                     same name. MUSSH_HOSTS_<host>_<FIELD>, MUSSH_CMD_<cmd>_COMMAND and \
+                     MUSSH_HOSTLIST_<list>_HOSTNAMES environment variables override it, \
+                     and the run's flags override those.",
+                )
                 .default_value(default_config_path)
                 .takes_value(true),
         )
@@ -99,14 +259,36 @@ fn app<'b>(default_config_path: &'_ str) -> App<'_, 'b> {
                 .long("output")
                 .help("Show the TOML configuration"),
         )
+        .arg(
+            Arg::with_name("dump_resolved_config")
+                .long("dump-resolved-config")
+                .help(
+                    "Print the configuration as loaded, after the fragments, defaults, variables \
+                     and environment overrides, in the format of the config file (TOML, or YAML \
+                     for a mussh.yaml), without running anything",
+                ),
+        )
+        .subcommand(Alias::subcommand())
+        .subcommand(Check::subcommand())
+        .subcommand(Cmd::subcommand())
+        .subcommand(ConfigCmd::subcommand())
+        .subcommand(Hostlist::subcommand())
+        .subcommand(Hosts::subcommand())
+        .subcommand(Inventory::subcommand())
+        .subcommand(Metrics::subcommand())
+        .subcommand(Pull::subcommand())
+        .subcommand(Push::subcommand())
         .subcommand(Run::subcommand())
 }
 
 #[cfg(test)]
 mod test {
-    use super::app;
+    use super::{app, load_config};
+    use crate::config_format::ConfigFormat;
     use crate::error::MusshResult;
     use clap::ArgMatches;
+    use std::fs;
+    use std::path::PathBuf;
 
     fn check_multiple_arg(m: &ArgMatches<'_>, name: &str, expected: &[&str]) {
         assert!(m.is_present(name));
@@ -299,4 +481,69 @@ mod test {
             ])
             .is_err());
     }
+
+    #[test]
+    fn config_parse_error_has_position() -> MusshResult<()> {
+        let path = PathBuf::from("test_cfg").join("mussh.toml");
+        let contents = "[hostlist]\n[hosts]\n[cmd.ls]\ncommand = ls\n";
+
+        let error = load_config(&path, contents, Vec::new(), None)
+            .err()
+            .ok_or("expected a parse error")?;
+        let message = error.to_string();
+        assert!(message.starts_with(&format!("{}:4:11: ", path.display())));
+        assert!(!message.contains(" at line "));
+        Ok(())
+    }
+
+    #[test]
+    fn dumped_config_reloads() -> MusshResult<()> {
+        let path = PathBuf::from("test_cfg").join("mussh.toml");
+        let (config, _, value) = load_config(&path, &fs::read_to_string(&path)?, Vec::new(), None)?;
+        let dumped = ConfigFormat::Toml.write(&value)?;
+        let reloaded: libmussh::Config = toml::from_str(&dumped)?;
+        assert_eq!(reloaded, config);
+        assert!(dumped.contains("[hosts.m1]"));
+        Ok(())
+    }
+
+    #[test]
+    fn dumped_config_is_resolved() -> MusshResult<()> {
+        let path = PathBuf::from("test_cfg").join("mussh.toml");
+        let contents = "[hostlist]\n[hosts.m1]\nhostname = \"10.0.0.1\"\n\
+                        username = \"${MUSSH_TEST_USER}\"\njump = \"bastion\"\n[cmd]\n";
+        let vars = vec![
+            ("MUSSH_TEST_USER".to_string(), "jozias".to_string()),
+            ("MUSSH_HOSTS_m1_PORT".to_string(), "2222".to_string()),
+        ];
+        let (_, extensions, value) = load_config(&path, contents, vars, None)?;
+        let dumped = ConfigFormat::Toml.write(&value)?;
+        assert!(dumped.contains("username = \"jozias\""));
+        assert!(dumped.contains("port = 2222"));
+        assert!(dumped.contains("jump = \"bastion\""));
+        assert_eq!(extensions.hosts["m1"].jump.as_deref(), Some("bastion"));
+        Ok(())
+    }
+
+    #[test]
+    fn env_overrides_config_hosts() -> MusshResult<()> {
+        let path = PathBuf::from("test_cfg").join("mussh.toml");
+        let vars = vec![(
+            "MUSSH_HOSTS_m1_HOSTNAME".to_string(),
+            "10.0.0.99".to_string(),
+        )];
+        let (config, ..) = load_config(&path, &fs::read_to_string(&path)?, vars.clone(), None)?;
+        let m1 = config.hosts().get("m1").ok_or("no m1 host")?;
+        assert_eq!(m1.hostname(), "10.0.0.99");
+
+        let mut vars = vars;
+        vars.push(("MUSSH_HOSTS_m1_USERNAME".to_string(), "jozias".to_string()));
+        let (config, ..) = load_config(&path, "", vars, None)?;
+        assert_eq!(config.hosts().len(), 1);
+        assert!(load_config(&path, "", Vec::new(), None)?
+            .0
+            .hosts()
+            .is_empty());
+        Ok(())
+    }
 }
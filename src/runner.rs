@@ -0,0 +1,1697 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Per-host command execution
+use crate::connect::ConnectTimeouts;
+use crate::error::{MusshErr, MusshErrKind};
+use crate::expect::{self, Expectations};
+use crate::hash::OutputHash;
+use crate::lines::Encoding;
+use crate::local;
+use crate::logging::CaptureDrain;
+use crate::metrics::Metric;
+use crate::remote_env::{self, RemoteEnv};
+use crate::session::Sessions;
+use crate::success::SuccessCodes;
+use chrono::Utc;
+use getset::{Getters, Setters};
+use indexmap::{IndexMap, IndexSet};
+use libmussh::{Multiplex, MultiplexMapType};
+use regex::Regex;
+use slog::{o, Drain, Duplicate, Logger};
+use slog_try::try_warn;
+use ssh2::ErrorCode;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The result of running one command on one host.
+#[derive(Clone, Debug, Default, Eq, Getters, PartialEq, Setters)]
+pub(crate) struct HostRunResult {
+    /// The host key in the `hosts` table.
+    #[get = "pub(crate)"]
+    name: String,
+    /// The hostname (or address) the command was run on.
+    #[get = "pub(crate)"]
+    hostname: String,
+    /// The name of the command that was run.
+    #[get = "pub(crate)"]
+    cmd_name: String,
+    /// How long the command took, including connecting to the host.
+    #[get = "pub(crate)"]
+    duration: Duration,
+    /// When the command was started, in milliseconds since the unix epoch.
+    #[get = "pub(crate)"]
+    started_at: i64,
+    /// When the command finished, in milliseconds since the unix epoch.
+    #[get = "pub(crate)"]
+    finished_at: i64,
+    /// The error message, if the command failed.
+    #[get = "pub(crate)"]
+    #[set = "pub(crate)"]
+    error: Option<String>,
+    /// Was the command given up on at the `--max-runtime` deadline?
+    #[get = "pub(crate)"]
+    timed_out: bool,
+    /// Did the command fail because the host refused to authenticate?
+    #[get = "pub(crate)"]
+    auth_failed: bool,
+    /// The exit code of the command, if it ran and the code is known.  It
+    /// isn't when the host couldn't be connected to, or the command was run by
+    /// libmussh without `Sessions`, which only tells that it exited non-zero.
+    #[get = "pub(crate)"]
+    exit_code: Option<i32>,
+    /// The `--hash` of the command's output, if it ran and was hashed.
+    #[get = "pub(crate)"]
+    output_hash: Option<String>,
+    /// How many times the command was tried, more than once with `--retries`
+    /// if the host couldn't be connected to.  0 if it was never started.
+    #[get = "pub(crate)"]
+    attempts: usize,
+}
+
+/// The libssh2 session error codes of a failed authentication,
+/// `LIBSSH2_ERROR_AUTHENTICATION_FAILED` and `LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED`.
+const AUTH_FAILURE_CODES: [ErrorCode; 2] = [ErrorCode::Session(-18), ErrorCode::Session(-19)];
+
+/// The most hosts run on at the same time when `--parallel` isn't given.
+pub(crate) const DEFAULT_MAX_PARALLEL: usize = 32;
+
+/// How long to wait before the first `--retries` retry when `--retry-delay`
+/// isn't given.
+pub(crate) const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Called with the name of a host once it has run all of its commands.
+pub(crate) type HostDone = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Called with the names of a host and command just before the command is run.
+pub(crate) type CmdStart = Arc<dyn Fn(&str, &str) + Send + Sync>;
+
+/// Runs a command on a host: connecting, authenticating, running it and
+/// waiting for it to exit.
+///
+/// This is where a run can be driven without ssh, as the tests do with a
+/// `MockExecutor`.
+pub(crate) trait Execute: Send + Sync {
+    /// Run the single command of the single host in the map.  `None` if
+    /// nothing was run.
+    fn execute(&self, multiplex: Multiplex, cmd_map: MultiplexMapType) -> Option<Execution>;
+
+    /// Called on the host's thread once the host `name` has run all of its
+    /// commands, to let go of anything kept for it between them.
+    fn host_done(&self, _name: &str) {}
+
+    /// Called once the run is given up on at its deadline, to end the commands
+    /// still running, which then fail.
+    fn cancel(&self) {}
+}
+
+/// What came of running a command on a host.
+#[derive(Debug)]
+pub(crate) struct Execution {
+    /// How long the command took, including connecting to the host.
+    duration: Duration,
+    /// The exit code of the command, if it is known.
+    exit_code: Option<i32>,
+    /// Why the command failed, if it did.
+    error: Option<MusshErr>,
+}
+
+impl Execution {
+    /// The command exited 0.
+    pub(crate) fn ok(duration: Duration) -> Self {
+        Self {
+            duration,
+            exit_code: Some(0),
+            error: None,
+        }
+    }
+
+    /// The command failed, with the exit code if it got as far as exiting.
+    pub(crate) fn failed(duration: Duration, exit_code: Option<i32>, error: MusshErr) -> Self {
+        Self {
+            duration,
+            exit_code,
+            error: Some(error),
+        }
+    }
+}
+
+/// Runs commands over ssh, or locally for `localhost`.  With `Sessions`, the
+/// commands of a host run over its one ssh session, keeping their exit codes.
+/// Without, libmussh opens a session for each command, and a command it runs
+/// can't be cancelled.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SshExecutor {
+    /// The `shell` of each `localhost` host that has one, by host name.
+    shells: HashMap<String, String>,
+    /// The ssh session of each host, kept between its commands.
+    sessions: Option<Sessions>,
+    /// How the output of the commands is decoded.
+    encoding: Encoding,
+    /// The environment variables to set for each command.
+    env: RemoteEnv,
+    /// Set once the run is cancelled, to end the commands still running.
+    cancelled: Arc<AtomicBool>,
+    /// Logs each command at info, exactly as it is sent to its host, just
+    /// before it is run.
+    print_command: Option<Logger>,
+}
+
+impl SshExecutor {
+    pub(crate) fn new(shells: HashMap<String, String>) -> Self {
+        Self {
+            shells,
+            sessions: None,
+            encoding: Encoding::default(),
+            env: RemoteEnv::default(),
+            cancelled: Arc::default(),
+            print_command: None,
+        }
+    }
+
+    pub(crate) fn with_print_command(mut self, print_command: Option<Logger>) -> Self {
+        self.print_command = print_command;
+        self
+    }
+
+    pub(crate) fn with_env(mut self, env: RemoteEnv) -> Self {
+        self.env = env;
+        self
+    }
+
+    pub(crate) fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub(crate) fn with_sessions(mut self, sessions: Sessions) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+}
+
+impl Execute for SshExecutor {
+    fn execute(&self, multiplex: Multiplex, cmd_map: MultiplexMapType) -> Option<Execution> {
+        if let Some((name, _)) = cmd_map
+            .iter()
+            .find(|(_, (host, _))| host.hostname() == local::LOCALHOST)
+        {
+            let shell = self.shells.get(name).cloned();
+            let run = (shell.as_deref(), &self.env, self.encoding);
+            let print_command = self.print_command.as_ref();
+            return local::execute(&multiplex, cmd_map, run, (&self.cancelled, print_command));
+        }
+        if let Some(sessions) = &self.sessions {
+            let cancelled = self.cancelled.as_ref();
+            let run = (
+                &self.env,
+                self.encoding,
+                cancelled,
+                self.print_command.as_ref(),
+            );
+            return sessions.execute(&multiplex, cmd_map, run);
+        }
+
+        // libmussh runs the command itself, so the variables can only be
+        // exported in front of it.
+        let mut cmd_map = cmd_map;
+        for (_, (host, cmds)) in &mut cmd_map {
+            for (cmd_name, command) in cmds.values_mut().flat_map(IndexMap::iter_mut) {
+                let vars = self.env.for_cmd(cmd_name);
+                if let Some(logger) = &self.print_command {
+                    let printed = (host.hostname().as_str(), cmd_name.as_str());
+                    remote_env::print_command(logger, printed, command, (&vars, &BTreeMap::new()));
+                }
+                if !vars.is_empty() {
+                    *command = remote_env::with_env(command, &vars);
+                }
+            }
+        }
+        let timer = Instant::now();
+        multiplex
+            .multiplex(&IndexSet::new(), cmd_map)
+            .pop()
+            .map(|result| match result {
+                Ok(metrics) => Execution::ok(*metrics.duration()),
+                Err(e) => Execution::failed(timer.elapsed(), None, e.into()),
+            })
+    }
+
+    fn host_done(&self, name: &str) {
+        if let Some(sessions) = &self.sessions {
+            sessions.close(name);
+        }
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// How a command is tried again when its host couldn't be connected or
+/// authenticated to, with `--retries`.
+#[derive(Clone, Debug)]
+pub(crate) struct Retry {
+    /// How many more times the command is tried.
+    retries: usize,
+    /// How long to wait before the first retry, doubled before each one after.
+    delay: Duration,
+    /// The connect timeout of each host, which every attempt at a command
+    /// together with the waits between them has to fit in.
+    timeouts: ConnectTimeouts,
+}
+
+impl Retry {
+    pub(crate) fn new(retries: usize, delay: Duration, timeouts: ConnectTimeouts) -> Self {
+        Self {
+            retries,
+            delay,
+            timeouts,
+        }
+    }
+
+    /// Make `attempt` at the host `name`, and make it again after each backoff
+    /// while `failed` says it failed, warning of each retry.  Returns what came
+    /// of the last attempt, and how many attempts were made.
+    pub(crate) fn attempt<T>(
+        &self,
+        name: &str,
+        stdout: Option<&Logger>,
+        mut attempt: impl FnMut() -> T,
+        failed: impl Fn(&T) -> bool,
+    ) -> (T, usize) {
+        let started = Instant::now();
+        let mut attempts = 1;
+
+        loop {
+            let result = attempt();
+            let retry_after = Some(&result)
+                .filter(|result| failed(result))
+                .and_then(|_| self.backoff(name, attempts, started.elapsed()));
+            let Some(delay) = retry_after else {
+                return (result, attempts);
+            };
+            attempts += 1;
+            try_warn!(
+                stdout,
+                "retrying";
+                "host" => name,
+                "attempt" => attempts,
+                "delay_ms" => delay.as_millis()
+            );
+            thread::sleep(delay);
+        }
+    }
+
+    /// How long to wait before trying the host `name` again, after `attempts`
+    /// attempts that took `elapsed` in all.  `None` once the retries are used
+    /// up, or if waiting would take it past the host's connect timeout.
+    fn backoff(&self, name: &str, attempts: usize, elapsed: Duration) -> Option<Duration> {
+        if attempts > self.retries {
+            return None;
+        }
+        let doublings = u32::try_from(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+        let delay = self.delay.saturating_mul(2_u32.saturating_pow(doublings));
+        (elapsed.saturating_add(delay) < self.timeouts.for_host(name)).then_some(delay)
+    }
+}
+
+/// What is told about a run as it happens.
+#[derive(Clone, Default, Setters)]
+pub(crate) struct Hooks {
+    /// Sent the result of each command run as soon as it finishes, and each
+    /// host once it is done.
+    #[set = "pub(crate)"]
+    metrics: Option<Sender<Metric>>,
+    /// Called on the host's thread before each command.
+    #[set = "pub(crate)"]
+    cmd_start: Option<CmdStart>,
+    /// Called on the host's thread once it is done.
+    #[set = "pub(crate)"]
+    host_done: Option<HostDone>,
+    /// The output each command must match to succeed.
+    #[set = "pub(crate)"]
+    expect: Option<Arc<Expectations>>,
+    /// The exit codes each command succeeds with, only `0` if not given.
+    #[set = "pub(crate)"]
+    success_codes: Option<Arc<SuccessCodes>>,
+    /// How the output of each command is hashed.
+    #[set = "pub(crate)"]
+    hash: Option<OutputHash>,
+    /// The stdout logger of each `--host-verbose` host, used in place of the
+    /// multiplex one.
+    #[set = "pub(crate)"]
+    host_stdout: HashMap<String, Logger>,
+    /// When the whole run has to be done by.
+    #[set = "pub(crate)"]
+    deadline: Option<Instant>,
+    /// Runs each command, `SshExecutor` if not given.
+    #[set = "pub(crate)"]
+    executor: Option<Arc<dyn Execute>>,
+    /// Stop the run the first time a host fails to authenticate.
+    #[set = "pub(crate)"]
+    fail_fast_on_auth: bool,
+    /// Stop the run the first time a command fails on a host, for whatever
+    /// reason.
+    #[set = "pub(crate)"]
+    fail_fast: bool,
+    /// The most hosts run on at the same time, all of them if not given.
+    #[set = "pub(crate)"]
+    max_parallel: Option<usize>,
+    /// Try a command again when its host can't be connected to.
+    #[set = "pub(crate)"]
+    retry: Option<Retry>,
+    /// Run the rest of a host's commands after one of them fails, rather than
+    /// skipping them.
+    #[set = "pub(crate)"]
+    keep_going: bool,
+    /// Why the run was stopped, if it was, shared by every run with these
+    /// hooks.
+    stop: Arc<Mutex<Option<Stop>>>,
+}
+
+/// Why a run was stopped before it was done.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Stop {
+    /// The host, by hostname, failed to authenticate, with
+    /// `fail_fast_on_auth`.
+    AuthFailure(String),
+    /// A command failed on the host, by hostname, with `fail_fast`.
+    Failure(String),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AuthFailure(hostname) => write!(f, "authentication failed on '{hostname}'"),
+            Self::Failure(hostname) => write!(f, "a command failed on '{hostname}'"),
+        }
+    }
+}
+
+impl HostRunResult {
+    /// The result of a command that was never run, with no error yet.
+    pub(crate) fn not_run(name: &str, hostname: &str, cmd_name: &str) -> Self {
+        let now = Utc::now().timestamp_millis();
+        Self {
+            name: name.to_string(),
+            hostname: hostname.to_string(),
+            cmd_name: cmd_name.to_string(),
+            started_at: now,
+            finished_at: now,
+            ..Self::default()
+        }
+    }
+
+    /// The result of a command that wasn't run because the command `failed`
+    /// before it on the host.
+    fn skipped(name: &str, hostname: &str, cmd_name: &str, failed: &str) -> Self {
+        Self {
+            error: Some(format!("Not run, '{failed}' failed")),
+            ..Self::not_run(name, hostname, cmd_name)
+        }
+    }
+
+    /// Did the command succeed?
+    pub(crate) fn success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl Hooks {
+    /// What runs each command, `SshExecutor` if no executor was given.
+    fn executor(&self) -> Arc<dyn Execute> {
+        self.executor
+            .clone()
+            .unwrap_or_else(|| Arc::new(SshExecutor::default()))
+    }
+
+    /// The output the command must match, and the exit codes it succeeds with.
+    fn checks(&self, cmd_name: &str) -> (Option<&Regex>, &[u8]) {
+        let expect = self
+            .expect
+            .as_ref()
+            .and_then(|expect| expect.for_cmd(cmd_name));
+        let success_codes = self
+            .success_codes
+            .as_ref()
+            .map_or(&[0][..], |codes| codes.for_cmd(cmd_name));
+        (expect, success_codes)
+    }
+
+    /// Tell `host_done`, and `metrics`, that the host `name` is done.
+    fn host_finished(&self, name: String) {
+        if let Some(host_done) = &self.host_done {
+            host_done(&name);
+        }
+        if let Some(metrics) = &self.metrics {
+            let _res = metrics.send(Metric::HostDone(name));
+        }
+    }
+
+    /// Has the deadline passed?  A host that waited for its turn with
+    /// `max_parallel` may only get it after.
+    fn past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    /// Why the run was stopped, if it was.
+    pub(crate) fn stopped(&self) -> Option<Stop> {
+        self.stop.lock().ok().and_then(|stop| stop.clone())
+    }
+
+    /// Stop the run if this result is the first authentication failure with
+    /// `fail_fast_on_auth`, or the first failure with `fail_fast`.  Returns
+    /// whether it was.
+    fn stop_on_failure(&self, result: &HostRunResult) -> bool {
+        let stop = if self.fail_fast_on_auth && result.auth_failed {
+            Stop::AuthFailure(result.hostname.clone())
+        } else if self.fail_fast && !result.success() {
+            Stop::Failure(result.hostname.clone())
+        } else {
+            return false;
+        };
+        match self.stop.lock() {
+            Ok(mut stopped) if stopped.is_none() => {
+                *stopped = Some(stop);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Run every command in the multiplex map, attributing each result to the
+/// host and command it came from.
+///
+/// The `hooks` are told about each result and host as they finish.
+///
+/// This keeps the libmussh ordering: every host runs its commands, then the
+/// hosts that aren't sync hosts wait for the sync hosts to finish before
+/// running their sync commands.
+///
+/// With `max_parallel`, at most that many hosts run at the same time, and the
+/// rest wait for one of them to finish.  A host waiting for the sync hosts
+/// makes way for another while it waits, so the sync hosts always get to run.
+///
+/// With a deadline, the commands that haven't finished by then are given up on
+/// and returned as timed out, and nothing is started once it has passed.  The
+/// commands still running are cancelled, and the threads of their hosts joined
+/// before this returns, so their sessions are closed.
+///
+/// A host runs its commands in order, and once one of them fails the rest are
+/// skipped and returned as not run, unless `keep_going`.
+///
+/// With `fail_fast_on_auth`, the first authentication failure, in this or an
+/// earlier run with the same hooks, stops the run: the unfinished commands are
+/// returned as cancelled and no more commands are started.  With `fail_fast`,
+/// the first failure of any kind does.  The commands already running are left
+/// to finish on their threads, which then exit without starting another.
+pub(crate) fn run(
+    multiplex: &Multiplex,
+    sync_hosts: &IndexSet<String>,
+    multiplex_map: MultiplexMapType,
+    hooks: &Hooks,
+) -> Vec<HostRunResult> {
+    let sync_count = multiplex_map
+        .keys()
+        .filter(|name| sync_hosts.contains(*name))
+        .count();
+    let latch = Arc::new(Latch::new(sync_count));
+    let slots = Arc::new(Semaphore::new(hooks.max_parallel.unwrap_or(usize::MAX)));
+    let (tx, rx) = mpsc::channel();
+    let started = Instant::now();
+    let executor = hooks.executor();
+    let (mut pending, mut not_started, mut handles) = (Vec::new(), Vec::new(), Vec::new());
+
+    for (name, (host, cmd_map)) in multiplex_map {
+        let mut single_map = MultiplexMapType::new();
+        let hostname = host.hostname().clone();
+        let _old = single_map.insert(name.clone(), (host, cmd_map));
+        let past_deadline =
+            hooks.deadline.is_some_and(|deadline| deadline <= started) || hooks.stopped().is_some();
+        let unfinished = if past_deadline {
+            &mut not_started
+        } else {
+            &mut pending
+        };
+        for (_, cmd_name) in commands(&single_map) {
+            unfinished.push((name.clone(), hostname.clone(), cmd_name));
+        }
+        let sync_host = sync_hosts.contains(&name);
+        if past_deadline {
+            // Don't keep the hosts already started waiting for this one.
+            if sync_host {
+                latch.done();
+            }
+            continue;
+        }
+
+        let mut multiplex = multiplex.clone();
+        let latch = Arc::clone(&latch);
+        let slots = Arc::clone(&slots);
+        let tx = tx.clone();
+        let hooks = hooks.clone();
+        let executor = Arc::clone(&executor);
+        if let Some(stdout) = hooks.host_stdout.get(&name) {
+            let _ = multiplex.set_stdout(Some(stdout.clone()));
+        }
+
+        handles.push(thread::spawn(move || {
+            // Keep all of the hooks alive until the host is done.
+            let _ = &hooks;
+            let mut slot = slots.acquire();
+            let mut failed: Option<String> = None;
+
+            for (kind_idx, cmd_name) in commands(&single_map) {
+                if let Some(failed) = &failed {
+                    let skipped = HostRunResult::skipped(&name, &hostname, &cmd_name, failed);
+                    if tx.send(skipped).is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                if is_sync_cmd(&single_map, kind_idx) && !sync_host {
+                    drop(slot);
+                    latch.wait();
+                    slot = slots.acquire();
+                }
+                if hooks.stopped().is_some() || hooks.past_deadline() {
+                    break;
+                }
+                if let Some(cmd_start) = &hooks.cmd_start {
+                    cmd_start(&name, &cmd_name);
+                }
+                let cmd = (kind_idx, cmd_name.as_str());
+                let result = run_one(
+                    (executor.as_ref(), hooks.retry.as_ref()),
+                    &multiplex,
+                    &single_map,
+                    cmd,
+                    hooks.checks(&cmd_name),
+                    hooks.hash,
+                );
+                if let Some(metrics) = &hooks.metrics {
+                    let _res = metrics.send(Metric::Result(result.clone()));
+                }
+                if !result.success() && !hooks.keep_going {
+                    failed = Some(cmd_name);
+                }
+                if tx.send(result).is_err() {
+                    break;
+                }
+            }
+
+            executor.host_done(&name);
+            drop(slot);
+            if sync_host {
+                latch.done();
+            }
+            hooks.host_finished(name);
+        }));
+    }
+
+    drop(tx);
+    let mut results = collect(&rx, hooks, started, pending);
+    results.extend(not_started_results(hooks, not_started, started));
+    if hooks.past_deadline() {
+        cancel(executor.as_ref(), handles);
+    }
+    results
+}
+
+/// Cancel the commands still running, and wait for the threads of their hosts,
+/// which close their sessions as they finish.
+fn cancel(executor: &dyn Execute, handles: Vec<JoinHandle<()>>) {
+    executor.cancel();
+    for handle in handles {
+        let _res = handle.join();
+    }
+}
+
+/// The results of the (name, hostname, command name) triples that were never
+/// started, because the run was stopped or of the deadline.
+fn not_started_results(
+    hooks: &Hooks,
+    not_started: Vec<(String, String, String)>,
+    started: Instant,
+) -> Vec<HostRunResult> {
+    match hooks.stopped() {
+        Some(stop) => cancelled(not_started, started, &format!("Not started, {stop}")),
+        None => timed_out(
+            not_started,
+            started,
+            "Not started by the --max-runtime deadline",
+        ),
+    }
+}
+
+/// Collect the results sent before the deadline, if there is one.  The
+/// `pending` (name, hostname, command name) triples without a result by then
+/// are timed out.
+///
+/// A failure that stops the run stops the collecting too, and the triples
+/// still pending are cancelled.
+fn collect(
+    rx: &Receiver<HostRunResult>,
+    hooks: &Hooks,
+    started: Instant,
+    mut pending: Vec<(String, String, String)>,
+) -> Vec<HostRunResult> {
+    let mut results = Vec::new();
+
+    loop {
+        let received = match hooks.deadline {
+            Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(result) => {
+                if let Some(idx) = pending.iter().position(|(name, _, cmd_name)| {
+                    name == &result.name && cmd_name == &result.cmd_name
+                }) {
+                    let _done = pending.remove(idx);
+                }
+                let stop = hooks.stop_on_failure(&result);
+                results.push(result);
+                if let Some(stop) = hooks.stopped().filter(|_| stop) {
+                    results.extend(cancelled(pending, started, &format!("Cancelled, {stop}")));
+                    return results;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return results,
+            Err(RecvTimeoutError::Timeout) => break,
+        }
+    }
+
+    results.extend(timed_out(
+        pending,
+        started,
+        "Not done by the --max-runtime deadline",
+    ));
+    results
+}
+
+/// The timed out results of the unfinished (name, hostname, command name)
+/// triples of a run started at `started`.
+fn timed_out(
+    unfinished: Vec<(String, String, String)>,
+    started: Instant,
+    error: &str,
+) -> Vec<HostRunResult> {
+    unfinished_results(unfinished, started, error, true)
+}
+
+/// The cancelled results of the unfinished (name, hostname, command name)
+/// triples of a run started at `started`.
+fn cancelled(
+    unfinished: Vec<(String, String, String)>,
+    started: Instant,
+    error: &str,
+) -> Vec<HostRunResult> {
+    unfinished_results(unfinished, started, error, false)
+}
+
+fn unfinished_results(
+    unfinished: Vec<(String, String, String)>,
+    started: Instant,
+    error: &str,
+    timed_out: bool,
+) -> Vec<HostRunResult> {
+    let finished_at = Utc::now().timestamp_millis();
+    let started_at = finished_at - elapsed_millis(started);
+    unfinished
+        .into_iter()
+        .map(|(name, hostname, cmd_name)| HostRunResult {
+            name,
+            hostname,
+            cmd_name,
+            duration: started.elapsed(),
+            started_at,
+            finished_at,
+            error: Some(error.to_string()),
+            timed_out,
+            auth_failed: false,
+            exit_code: None,
+            output_hash: None,
+            attempts: 0,
+        })
+        .collect()
+}
+
+fn elapsed_millis(started: Instant) -> i64 {
+    i64::try_from(started.elapsed().as_millis()).unwrap_or(i64::MAX)
+}
+
+/// The (kind index, command name) pairs for the single host in the map, with
+/// the commands before the sync commands.
+fn commands(single_map: &MultiplexMapType) -> Vec<(usize, String)> {
+    let mut commands = Vec::new();
+    let mut sync_commands = Vec::new();
+
+    for (_, cmd_map) in single_map.values() {
+        for (kind_idx, cmds) in cmd_map.values().enumerate() {
+            let names = cmds.keys().map(|cmd_name| (kind_idx, cmd_name.clone()));
+            if is_sync_cmd(single_map, kind_idx) {
+                sync_commands.extend(names);
+            } else {
+                commands.extend(names);
+            }
+        }
+    }
+
+    commands.extend(sync_commands);
+    commands
+}
+
+fn is_sync_cmd(single_map: &MultiplexMapType, kind_idx: usize) -> bool {
+    single_map.values().any(
+        |(_, cmd_map)| matches!(cmd_map.keys().nth(kind_idx), Some(kind) if is_sync_kind(kind)),
+    )
+}
+
+/// libmussh doesn't export its command type, but it does display it.
+pub(crate) fn is_sync_kind(kind: &impl fmt::Display) -> bool {
+    kind.to_string() == "sync_cmd"
+}
+
+/// Run a single command from the map on its host, with the `retry` policy if
+/// there is one.
+///
+/// The command succeeds if it exits with one of its `success_codes`, and the
+/// result keeps the code it exited with.  With an `expect` regex, the output of
+/// the command is captured, and the command fails if it doesn't match, whatever
+/// its exit code.  With a `hash`, the output is captured and hashed once the
+/// command has run.
+fn run_one(
+    (executor, retry): (&dyn Execute, Option<&Retry>),
+    multiplex: &Multiplex,
+    single_map: &MultiplexMapType,
+    (kind_idx, cmd_name): (usize, &str),
+    (expect, success_codes): (Option<&Regex>, &[u8]),
+    hash: Option<OutputHash>,
+) -> HostRunResult {
+    let mut cmd_map = single_map.clone();
+    let (name, hostname) = cmd_map
+        .iter_mut()
+        .map(|(name, (host, cmds_map))| {
+            for (idx, cmds) in cmds_map.values_mut().enumerate() {
+                if idx == kind_idx {
+                    cmds.retain(|cmd, _| cmd == cmd_name);
+                } else {
+                    *cmds = IndexMap::new();
+                }
+            }
+            (name.clone(), host.hostname().clone())
+        })
+        .next()
+        .unwrap_or_default();
+
+    let mut multiplex = multiplex.clone();
+    let capture =
+        (expect.is_some() || hash.is_some()).then(|| capture_output(&mut multiplex, &name));
+
+    let timer = Instant::now();
+    let started_at = Utc::now().timestamp_millis();
+    let (execution, attempts) = execute(executor, &multiplex, &cmd_map, retry);
+    let finished_at = Utc::now().timestamp_millis();
+    let mut auth_failed = false;
+    let output_hash = execution
+        .as_ref()
+        .and(hash.zip(capture.as_ref()))
+        .map(|(hash, capture)| hash.hash(&capture.output()));
+    let (duration, exit_code, error) = match execution {
+        Some(Execution {
+            duration,
+            exit_code,
+            error,
+        }) => {
+            let succeeded = exit_code
+                .map(|code| u8::try_from(code).is_ok_and(|code| success_codes.contains(&code)));
+            let error = match (succeeded, error) {
+                (Some(true), _) | (None, None) => expect
+                    .zip(capture)
+                    .and_then(|(regex, capture)| expect::check(regex, &capture.output())),
+                (_, Some(e)) => {
+                    auth_failed = is_auth_error(&e);
+                    Some(error_message(&e))
+                }
+                (Some(false), None) => {
+                    Some("Exited 0, which isn't one of its success codes".to_string())
+                }
+            };
+            (duration, exit_code, error)
+        }
+        None => (
+            timer.elapsed(),
+            None,
+            Some("No result returned".to_string()),
+        ),
+    };
+
+    HostRunResult {
+        name,
+        hostname,
+        cmd_name: cmd_name.to_string(),
+        duration,
+        started_at,
+        finished_at,
+        error,
+        timed_out: false,
+        auth_failed,
+        exit_code,
+        output_hash,
+        attempts,
+    }
+}
+
+/// Run the single command of the single host in the map, trying it again as
+/// `retry` allows while the host can't be connected or authenticated to.
+/// Returns what came of the last attempt, and how many attempts were made.
+fn execute(
+    executor: &dyn Execute,
+    multiplex: &Multiplex,
+    cmd_map: &MultiplexMapType,
+    retry: Option<&Retry>,
+) -> (Option<Execution>, usize) {
+    let execute = || executor.execute(multiplex.clone(), cmd_map.clone());
+    match (retry, cmd_map.iter().next()) {
+        (Some(retry), Some((name, (host, _)))) if host.hostname() != local::LOCALHOST => retry
+            .attempt(name, multiplex.stdout().as_ref(), execute, |execution| {
+                execution.as_ref().is_some_and(can_retry)
+            }),
+        _ => (execute(), 1),
+    }
+}
+
+/// Did the command fail before it got to run, connecting to the host, opening
+/// the ssh session or authenticating?  Unlike a command that ran and failed,
+/// that is worth trying again: an io error from connecting, or an ssh2 error
+/// from the session.  libmussh's own errors don't say which they are, so a
+/// command it ran isn't tried again.
+fn can_retry(execution: &Execution) -> bool {
+    execution.exit_code.is_none()
+        && execution.error.as_ref().is_some_and(|error| {
+            matches!(error.kind(), MusshErrKind::Io(_) | MusshErrKind::Ssh2(_))
+        })
+}
+
+/// Capture the output of the host, as well as writing it to the host's logger.
+fn capture_output(multiplex: &mut Multiplex, name: &str) -> CaptureDrain {
+    let capture = CaptureDrain::default();
+    let mut host_loggers = multiplex.host_loggers().clone();
+    let logger = match host_loggers.get(name).cloned().flatten() {
+        Some(logger) => Logger::root(Duplicate::new(logger, capture.clone()).ignore_res(), o!()),
+        None => Logger::root(capture.clone(), o!()),
+    };
+    let _old = host_loggers.insert(name.to_string(), Some(logger));
+    let _ = multiplex.set_host_loggers(host_loggers);
+    capture
+}
+
+/// The message of the error.  The `Display` impl on `libmussh::Error` formats
+/// itself, so use the message of the error kind it wraps instead.
+fn error_message(error: &MusshErr) -> String {
+    match error.kind() {
+        MusshErrKind::Libmussh(inner) => {
+            inner.source().map(ToString::to_string).unwrap_or_default()
+        }
+        _ => error.to_string(),
+    }
+}
+
+/// Did the host refuse to authenticate?  Either none of the credentials were
+/// accepted, or a `userauth_*` call failed with one of libssh2's
+/// authentication failure codes.
+fn is_auth_error(error: &MusshErr) -> bool {
+    match error.kind() {
+        MusshErrKind::Authentication(_username) => true,
+        MusshErrKind::Ssh2(inner) => AUTH_FAILURE_CODES.contains(&inner.code()),
+        _ => false,
+    }
+}
+
+/// Blocks waiters until `done` has been called `count` times.
+#[derive(Debug, Default)]
+struct Latch {
+    count: Mutex<usize>,
+    cvar: Condvar,
+}
+
+impl Latch {
+    fn new(count: usize) -> Self {
+        Self {
+            count: Mutex::new(count),
+            cvar: Condvar::new(),
+        }
+    }
+
+    fn done(&self) {
+        if let Ok(mut count) = self.count.lock() {
+            *count = count.saturating_sub(1);
+            self.cvar.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        if let Ok(mut count) = self.count.lock() {
+            while *count > 0 {
+                count = match self.cvar.wait(count) {
+                    Ok(count) => count,
+                    Err(_) => return,
+                };
+            }
+        }
+    }
+}
+
+/// Limits how many hosts are run on, or connected to, at the same time.
+pub(crate) struct Semaphore {
+    permits: Mutex<usize>,
+    cvar: Condvar,
+}
+
+/// A host's turn to run, given back when dropped.
+pub(crate) struct Permit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Self {
+        Self {
+            permits: Mutex::new(permits),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Wait until there is a permit free, and take it.
+    pub(crate) fn acquire(&self) -> Permit<'_> {
+        if let Ok(mut permits) = self.permits.lock() {
+            while *permits == 0 {
+                permits = match self.cvar.wait(permits) {
+                    Ok(permits) => permits,
+                    Err(_) => return Permit(self),
+                };
+            }
+            *permits -= 1;
+        }
+        Permit(self)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut permits) = self.0.permits.lock() {
+            *permits = permits.saturating_add(1);
+            self.0.cvar.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        collect, error_message, is_auth_error, run, Execute, Execution, Hooks, HostRunResult,
+        Retry, SshExecutor, Stop,
+    };
+    use crate::connect::ConnectTimeouts;
+    use crate::error::{MusshErr, MusshErrKind, MusshResult};
+    use crate::extensions::Extensions;
+    use crate::lines::Encoding;
+    use crate::local;
+    use crate::logging::CaptureDrain;
+    use crate::mock::{MockCmd, MockExecutor};
+    use crate::remote_env::{self, RemoteEnv};
+    use crate::success::SuccessCodes;
+    use crate::targets;
+    use crate::warnings::Warnings;
+    use indexmap::IndexSet;
+    use libmussh::{Config, Multiplex, MultiplexMapType, RuntimeConfig};
+    use slog::{o, Drain, Logger};
+    use slog_term::{FullFormat, PlainSyncDecorator};
+    use ssh2::ErrorCode;
+    use std::collections::{BTreeMap, HashMap};
+    use std::io::{self, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const MOCK_TOML: &str = r#"[hostlist.all]
+hostnames = ["web", "db"]
+[hosts.web]
+hostname = "10.0.0.3"
+username = "jozias"
+[hosts.db]
+hostname = "10.0.0.4"
+username = "jozias"
+[cmd.deploy]
+command = "make deploy"
+"#;
+
+    const LOCAL_TOML: &str = r#"[hostlist.all]
+hostnames = ["local"]
+[hosts.local]
+hostname = "localhost"
+username = "jozias"
+[cmd.fail]
+command = "echo failing; echo broken >&2; exit 3"
+[cmd.noisy]
+command = "yes noise | head -n 20000 >&2; echo done"
+[cmd.latin1]
+command = "printf 'caf\\351\\n'; echo after"
+"#;
+
+    fn mock_map() -> MusshResult<MultiplexMapType> {
+        host_map(MOCK_TOML, "deploy")
+    }
+
+    fn host_map(config_toml: &str, cmd: &str) -> MusshResult<MultiplexMapType> {
+        let config: Config = toml::from_str(config_toml)?;
+        let mut runtime_config = RuntimeConfig::default();
+        let _ = runtime_config
+            .set_hosts(vec!["all".to_string()].into_iter().collect())
+            .set_cmds(vec![cmd.to_string()].into_iter().collect());
+        let (_, map) =
+            targets::to_host_map(&config, &runtime_config, true, &mut Warnings::default())?;
+        Ok(map)
+    }
+
+    fn mock_run(executor: MockExecutor, hooks: &mut Hooks) -> MusshResult<Vec<HostRunResult>> {
+        let _ = hooks.set_executor(Some(Arc::new(executor)));
+        Ok(run(
+            &Multiplex::default(),
+            &IndexSet::new(),
+            mock_map()?,
+            hooks,
+        ))
+    }
+
+    fn result(name: &str, error: Option<&str>, auth_failed: bool) -> HostRunResult {
+        HostRunResult {
+            name: name.to_string(),
+            hostname: format!("{name}.example.com"),
+            cmd_name: "ls".to_string(),
+            error: error.map(ToString::to_string),
+            auth_failed,
+            ..HostRunResult::default()
+        }
+    }
+
+    fn pending(names: &[&str]) -> Vec<(String, String, String)> {
+        names
+            .iter()
+            .map(|name| {
+                (
+                    name.to_string(),
+                    format!("{name}.example.com"),
+                    "ls".to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn auth_errors() {
+        let auth = MusshErr::from(ssh2::Error::new(
+            ErrorCode::Session(-18),
+            "Authentication failed (publickey)",
+        ));
+        assert!(is_auth_error(&auth));
+        assert_eq!(
+            error_message(&auth),
+            "[Session(-18)] Authentication failed (publickey)"
+        );
+        let timeout = MusshErr::from(ssh2::Error::new(ErrorCode::Session(-9), "Timed out"));
+        assert!(!is_auth_error(&timeout));
+        assert!(!is_auth_error(&MusshErr::from("Non-zero exit code")));
+        let refused = MusshErr::from(MusshErrKind::Authentication("jozias".to_string()));
+        assert!(is_auth_error(&refused));
+        assert_eq!(error_message(&refused), "Unable to authenticate as jozias");
+    }
+
+    #[test]
+    fn auth_failure_stops_the_run() {
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_fail_fast_on_auth(true);
+        let (tx, rx) = mpsc::channel();
+        for sent in [
+            result("web", Some("Non-zero exit code"), false),
+            result("db", Some("Authentication failed"), true),
+            result("cache", Some("Authentication failed"), true),
+        ] {
+            let _res = tx.send(sent);
+        }
+
+        let results = collect(
+            &rx,
+            &hooks,
+            Instant::now(),
+            pending(&["web", "db", "cache", "queue"]),
+        );
+        let errors: Vec<(&str, Option<&str>)> = results
+            .iter()
+            .map(|result| (result.name().as_str(), result.error().as_deref()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("web", Some("Non-zero exit code")),
+                ("db", Some("Authentication failed")),
+                (
+                    "cache",
+                    Some("Cancelled, authentication failed on 'db.example.com'")
+                ),
+                (
+                    "queue",
+                    Some("Cancelled, authentication failed on 'db.example.com'")
+                ),
+            ]
+        );
+        assert_eq!(
+            hooks.stopped(),
+            Some(Stop::AuthFailure("db.example.com".to_string()))
+        );
+        assert!(!hooks.clone().stop_on_failure(&results[2]));
+    }
+
+    #[test]
+    fn failure_stops_the_run() {
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_fail_fast(true);
+        let (tx, rx) = mpsc::channel();
+        let _res = tx.send(result("web", None, false));
+        let _res = tx.send(result("db", Some("Non-zero exit code"), false));
+
+        let results = collect(
+            &rx,
+            &hooks,
+            Instant::now(),
+            pending(&["web", "db", "cache"]),
+        );
+        let errors: Vec<Option<&str>> = results
+            .iter()
+            .map(|result| result.error().as_deref())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                None,
+                Some("Non-zero exit code"),
+                Some("Cancelled, a command failed on 'db.example.com'"),
+            ]
+        );
+        assert_eq!(
+            hooks.stopped(),
+            Some(Stop::Failure("db.example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn auth_failure_without_fail_fast() {
+        let hooks = Hooks::default();
+        let (tx, rx) = mpsc::channel();
+        let _res = tx.send(result("db", Some("Authentication failed"), true));
+        drop(tx);
+
+        let results = collect(&rx, &hooks, Instant::now(), pending(&["db"]));
+        assert_eq!(results.len(), 1);
+        assert!(hooks.stopped().is_none());
+    }
+
+    #[test]
+    fn mock_non_zero_exit() -> MusshResult<()> {
+        let executor = MockExecutor::default().with("deploy", MockCmd::exit(2));
+        let results = mock_run(executor, &mut Hooks::default())?;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| {
+            result.error().as_deref()
+                == Some(format!("Failed to run '{}' on 'deploy'", result.hostname()).as_str())
+        }));
+        assert!(results.iter().all(|result| !*result.auth_failed()));
+        assert!(results.iter().all(|result| *result.exit_code() == Some(2)));
+
+        let results = mock_run(
+            MockExecutor::default().with("deploy", MockCmd::ok()),
+            &mut Hooks::default(),
+        )?;
+        assert!(results.iter().all(HostRunResult::success));
+        Ok(())
+    }
+
+    #[test]
+    fn local_exit_code() -> MusshResult<()> {
+        let capture = CaptureDrain::default();
+        let mut host_loggers = HashMap::new();
+        let _old = host_loggers.insert(
+            "local".to_string(),
+            Some(Logger::root(capture.clone(), o!())),
+        );
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.set_host_loggers(host_loggers);
+
+        let results = run(
+            &multiplex,
+            &IndexSet::new(),
+            host_map(LOCAL_TOML, "fail")?,
+            &Hooks::default(),
+        );
+        assert_eq!(results.len(), 1);
+        assert_eq!(*results[0].exit_code(), Some(3));
+        assert_eq!(
+            results[0].error().as_deref(),
+            Some("Failed to run 'localhost' on 'fail'")
+        );
+        assert_eq!(capture.output(), "failing");
+        Ok(())
+    }
+
+    #[test]
+    fn local_stderr() -> MusshResult<()> {
+        let (capture, stderr) = (CaptureDrain::default(), CaptureDrain::default());
+        let mut host_loggers = HashMap::new();
+        let _old = host_loggers.insert(
+            "local".to_string(),
+            Some(Logger::root(capture.clone(), o!())),
+        );
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.set_host_loggers(host_loggers);
+        let _ = multiplex.set_stderr(Some(Logger::root(stderr.clone(), o!())));
+
+        let results = run(
+            &multiplex,
+            &IndexSet::new(),
+            host_map(LOCAL_TOML, "fail")?,
+            &Hooks::default(),
+        );
+        assert!(!results[0].success());
+        assert_eq!(capture.output(), "failing");
+        assert_eq!(stderr.output(), "execute\nbroken");
+
+        // More stderr than a pipe holds doesn't block the stdout after it.
+        let results = run(
+            &multiplex,
+            &IndexSet::new(),
+            host_map(LOCAL_TOML, "noisy")?,
+            &Hooks::default(),
+        );
+        assert!(results[0].success());
+        assert_eq!(capture.output(), "failing\ndone");
+        assert_eq!(stderr.output(), "execute\nbroken");
+        Ok(())
+    }
+
+    #[test]
+    fn local_invalid_utf8() -> MusshResult<()> {
+        let capture = CaptureDrain::default();
+        let mut host_loggers = HashMap::new();
+        let _old = host_loggers.insert(
+            "local".to_string(),
+            Some(Logger::root(capture.clone(), o!())),
+        );
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.set_host_loggers(host_loggers);
+
+        let results = run(
+            &multiplex,
+            &IndexSet::new(),
+            host_map(LOCAL_TOML, "latin1")?,
+            &Hooks::default(),
+        );
+        assert!(results[0].success());
+        assert_eq!(capture.output(), "caf\u{fffd}\nafter");
+
+        let mut hooks = Hooks::default();
+        let executor = SshExecutor::default().with_encoding(Encoding::Latin1);
+        let _ = hooks.set_executor(Some(Arc::new(executor)));
+        let _results = run(
+            &multiplex,
+            &IndexSet::new(),
+            host_map(LOCAL_TOML, "latin1")?,
+            &hooks,
+        );
+        assert_eq!(capture.output(), "caf\u{fffd}\nafter\ncaf\u{e9}\nafter");
+        Ok(())
+    }
+
+    #[test]
+    fn local_shell() -> MusshResult<()> {
+        let config_toml = LOCAL_TOML.replace(
+            "username = \"jozias\"\n",
+            "username = \"jozias\"\nshell = \"bash\"\n[cmd.shell]\ncommand = \"echo $0\"\n",
+        );
+        let capture = CaptureDrain::default();
+        let mut host_loggers = HashMap::new();
+        let _old = host_loggers.insert(
+            "local".to_string(),
+            Some(Logger::root(capture.clone(), o!())),
+        );
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.set_host_loggers(host_loggers);
+        let mut hooks = Hooks::default();
+        let shells = local::shells(&toml::from_str(&config_toml)?)?;
+        let _ = hooks.set_executor(Some(Arc::new(SshExecutor::new(shells))));
+
+        let results = run(
+            &multiplex,
+            &IndexSet::new(),
+            host_map(&config_toml, "shell")?,
+            &hooks,
+        );
+        assert!(results[0].success());
+        assert_eq!(capture.output(), "bash");
+
+        let invalid = config_toml.replace("shell = \"bash\"", "shell = \"\"");
+        assert!(local::shells(&toml::from_str(&invalid)?).is_err());
+        Ok(())
+    }
+
+    /// Counts how many commands run at the same time.
+    #[derive(Default)]
+    struct CountingExecutor {
+        running: AtomicUsize,
+        most: AtomicUsize,
+    }
+
+    impl Execute for CountingExecutor {
+        fn execute(&self, _: Multiplex, _: MultiplexMapType) -> Option<Execution> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            let _most = self.most.fetch_max(running, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            let _running = self.running.fetch_sub(1, Ordering::SeqCst);
+            Some(Execution::ok(Duration::from_millis(20)))
+        }
+    }
+
+    #[test]
+    fn max_parallel() -> MusshResult<()> {
+        let hosts = ["h1", "h2", "h3", "h4"]
+            .map(|name| format!("[hosts.{name}]\nhostname = \"{name}\"\nusername = \"jozias\"\n"));
+        let config_toml = MOCK_TOML.replace(
+            "hostnames = [\"web\", \"db\"]",
+            "hostnames = [\"web\", \"db\", \"h1\", \"h2\", \"h3\", \"h4\"]",
+        ) + &hosts.concat();
+        let executor = Arc::new(CountingExecutor::default());
+        let mut hooks = Hooks::default();
+        let _ = hooks
+            .set_executor(Some(executor.clone()))
+            .set_max_parallel(Some(2));
+
+        let results = run(
+            &Multiplex::default(),
+            &IndexSet::new(),
+            host_map(&config_toml, "deploy")?,
+            &hooks,
+        );
+        assert_eq!(results.len(), 6);
+        assert!(results.iter().all(HostRunResult::success));
+        assert_eq!(executor.most.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn mock_timeout() -> MusshResult<()> {
+        let mut hooks = Hooks::default();
+        let done = Arc::new(AtomicUsize::new(0));
+        let host_done = Arc::clone(&done);
+        let _ = hooks
+            .set_deadline(Instant::now().checked_add(Duration::from_millis(100)))
+            .set_host_done(Some(Arc::new(move |_: &str| {
+                let _prev = host_done.fetch_add(1, Ordering::SeqCst);
+            })));
+        let results = mock_run(
+            MockExecutor::default().with("deploy", MockCmd::Hang),
+            &mut hooks,
+        )?;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| *result.timed_out()));
+        // The hung commands were cancelled, and their hosts done, by the time
+        // the run returned.
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn mock_auth_failure_fails_fast() -> MusshResult<()> {
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_fail_fast_on_auth(true);
+        let results = mock_run(
+            MockExecutor::default().with("deploy", MockCmd::AuthFailure),
+            &mut hooks,
+        )?;
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results
+                .iter()
+                .filter(|result| *result.auth_failed())
+                .count(),
+            1
+        );
+        assert!(results.iter().any(|result| result
+            .error()
+            .as_deref()
+            .is_some_and(|error| error.starts_with("Cancelled, authentication failed"))));
+        Ok(())
+    }
+
+    #[test]
+    fn success_codes_keep_the_exit_code() -> MusshResult<()> {
+        let codes = SuccessCodes::parse(&toml::from_str(MOCK_TOML)?, Some("0,1"))?;
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_success_codes(Some(Arc::new(codes)));
+        let results = mock_run(
+            MockExecutor::default().with("deploy", MockCmd::exit(1)),
+            &mut hooks,
+        )?;
+        assert!(results.iter().all(HostRunResult::success));
+        assert!(results.iter().all(|result| *result.exit_code() == Some(1)));
+
+        let codes = SuccessCodes::parse(&toml::from_str(MOCK_TOML)?, Some("1"))?;
+        let _ = hooks.set_success_codes(Some(Arc::new(codes)));
+        let results = mock_run(MockExecutor::default(), &mut hooks)?;
+        assert!(results.iter().all(|result| *result.exit_code() == Some(0)
+            && result.error().as_deref()
+                == Some("Exited 0, which isn't one of its success codes")));
+        Ok(())
+    }
+
+    #[test]
+    fn mock_failure_fails_fast() -> MusshResult<()> {
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_fail_fast(true).set_max_parallel(Some(1));
+        let results = mock_run(
+            MockExecutor::default().with("deploy", MockCmd::exit(2)),
+            &mut hooks,
+        )?;
+        let errors: Vec<Option<&str>> = results
+            .iter()
+            .map(|result| result.error().as_deref())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                Some("Failed to run '10.0.0.3' on 'deploy'"),
+                Some("Cancelled, a command failed on '10.0.0.3'"),
+            ]
+        );
+
+        // Later runs with the same hooks aren't started at all.
+        let results = mock_run(MockExecutor::default(), &mut hooks)?;
+        assert!(results.iter().all(|result| result
+            .error()
+            .as_deref()
+            .is_some_and(|error| error == "Not started, a command failed on '10.0.0.3'")));
+        Ok(())
+    }
+
+    #[test]
+    fn retry_backoff() -> MusshResult<()> {
+        let timeouts =
+            ConnectTimeouts::parse(&toml::from_str("[hosts.web]\nconnect_timeout = 1\n")?, None)?;
+        let retry = Retry::new(3, Duration::from_millis(200), timeouts);
+        let delays: Vec<Option<u128>> = (1..=4)
+            .map(|attempts| {
+                retry
+                    .backoff("db", attempts, Duration::ZERO)
+                    .map(|delay| delay.as_millis())
+            })
+            .collect();
+        assert_eq!(delays, vec![Some(200), Some(400), Some(800), None]);
+        let elapsed = Duration::from_millis(700);
+        assert!(retry.backoff("web", 1, elapsed).is_some());
+        assert!(retry.backoff("web", 2, elapsed).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn mock_retries() -> MusshResult<()> {
+        let mut hooks = Hooks::default();
+        let retry = Retry::new(2, Duration::from_millis(1), ConnectTimeouts::default());
+        let _ = hooks.set_retry(Some(retry));
+        let attempts = |results: &[HostRunResult]| -> Vec<(bool, usize)> {
+            results
+                .iter()
+                .map(|result| (result.success(), *result.attempts()))
+                .collect()
+        };
+
+        let executor = MockExecutor::default().with("deploy", MockCmd::Unreachable(2));
+        let results = mock_run(executor, &mut hooks)?;
+        assert_eq!(attempts(&results), vec![(true, 3), (true, 3)]);
+        let executor = MockExecutor::default().with("deploy", MockCmd::Unreachable(3));
+        let results = mock_run(executor, &mut hooks)?;
+        assert_eq!(attempts(&results), vec![(false, 3), (false, 3)]);
+        let results = mock_run(
+            MockExecutor::default().with("deploy", MockCmd::AuthFailure),
+            &mut hooks,
+        )?;
+        assert_eq!(attempts(&results), vec![(false, 3), (false, 3)]);
+        let results = mock_run(
+            MockExecutor::default().with("deploy", MockCmd::exit(2)),
+            &mut hooks,
+        )?;
+        assert_eq!(attempts(&results), vec![(false, 1), (false, 1)]);
+
+        let executor = MockExecutor::default().with("deploy", MockCmd::Unreachable(1));
+        let results = mock_run(executor, &mut Hooks::default())?;
+        assert_eq!(attempts(&results), vec![(false, 1), (false, 1)]);
+        Ok(())
+    }
+
+    const SEQUENCE_TOML: &str = r#"[hostlist.all]
+hostnames = ["web"]
+[hosts.web]
+hostname = "10.0.0.3"
+username = "jozias"
+[cmd.build]
+command = "make"
+[cmd.deploy]
+command = "make deploy"
+[cmd.restart]
+command = "systemctl restart web"
+"#;
+
+    #[test]
+    fn stops_at_the_first_failure() -> MusshResult<()> {
+        let config: Config = toml::from_str(SEQUENCE_TOML)?;
+        let mut runtime_config = RuntimeConfig::default();
+        let _ = runtime_config
+            .set_hosts(vec!["all".to_string()].into_iter().collect())
+            .set_cmds(
+                ["restart", "build", "deploy"]
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+            );
+        let (_, map) =
+            targets::to_host_map(&config, &runtime_config, true, &mut Warnings::default())?;
+        let sequence = |hooks: &mut Hooks| -> Vec<(String, Option<String>, usize)> {
+            let executor = MockExecutor::default().with("build", MockCmd::exit(2));
+            let _ = hooks.set_executor(Some(Arc::new(executor)));
+            run(&Multiplex::default(), &IndexSet::new(), map.clone(), hooks)
+                .into_iter()
+                .map(|result| (result.cmd_name, result.error, result.attempts))
+                .collect()
+        };
+        let failed = Some("Failed to run '10.0.0.3' on 'build'".to_string());
+
+        assert_eq!(
+            sequence(&mut Hooks::default()),
+            vec![
+                ("restart".to_string(), None, 1),
+                ("build".to_string(), failed.clone(), 1),
+                (
+                    "deploy".to_string(),
+                    Some("Not run, 'build' failed".to_string()),
+                    0
+                ),
+            ]
+        );
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_keep_going(true);
+        assert_eq!(
+            sequence(&mut hooks),
+            vec![
+                ("restart".to_string(), None, 1),
+                ("build".to_string(), failed, 1),
+                ("deploy".to_string(), None, 1),
+            ]
+        );
+        Ok(())
+    }
+
+    /// Where a test logger writes its formatted records.
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl Written {
+        fn logger(&self) -> Logger {
+            let decorator = PlainSyncDecorator::new(self.clone());
+            Logger::root(FullFormat::new(decorator).build().fuse(), o!())
+        }
+
+        fn output(&self) -> String {
+            self.0
+                .lock()
+                .map(|written| String::from_utf8_lossy(&written).into_owned())
+                .unwrap_or_default()
+        }
+    }
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Ok(mut written) = self.0.lock() {
+                written.extend_from_slice(buf);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn prints_commands_without_the_env_values() -> MusshResult<()> {
+        let written = Written::default();
+        let env = RemoteEnv::parse(&Extensions::default(), BTreeMap::new(), &["TOKEN=hunter2"])?;
+        let executor = SshExecutor::default()
+            .with_env(env)
+            .with_print_command(Some(written.logger()));
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_executor(Some(Arc::new(executor)));
+        let results = run(
+            &Multiplex::default(),
+            &IndexSet::new(),
+            host_map(LOCAL_TOML, "fail")?,
+            &hooks,
+        );
+        assert_eq!(results.len(), 1);
+
+        // As it is printed when sshd refuses the setenv.
+        let vars: BTreeMap<String, String> = [("TOKEN".to_string(), "hunter2".to_string())].into();
+        remote_env::print_command(
+            &written.logger(),
+            ("web", "deploy"),
+            "make deploy",
+            (&vars, &BTreeMap::new()),
+        );
+        let output = written.output();
+        assert!(output.contains("command: echo failing; echo broken >&2; exit 3"));
+        assert!(output.contains("env: TOKEN=***"));
+        assert!(output.contains("command: export TOKEN='***'; make deploy"));
+        assert!(!output.contains("hunter2"));
+        Ok(())
+    }
+}
@@ -0,0 +1,648 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Commands run over one ssh session per host
+use crate::algorithms::SshAlgorithms;
+use crate::auth;
+use crate::connect::ConnectTimeouts;
+use crate::error::{MusshErr, MusshResult};
+use crate::known_hosts::HostKeys;
+use crate::lines::{Encoding, Lines};
+use crate::logging::{STDERR_TAG, TAIL_TAG};
+use crate::remote_env::{self, RemoteEnv};
+use crate::runner::Execution;
+use crate::util::{format_duration, shell_quote};
+use clap::Arg;
+use libmussh::{Multiplex, MultiplexMapType};
+use slog::trace;
+use slog::Logger;
+use slog_try::{try_error, try_info, try_trace, try_warn};
+use ssh2::{Channel, PtyModeOpcode, PtyModes, Session};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The terminal type of the pseudo-terminal of a command run with `--tty`.
+const PTY_TERM: &str = "xterm";
+
+/// What a command run with `--tty` is sent when it is given up on: Ctrl-C,
+/// which its terminal turns into a SIGINT.
+const INTERRUPT: &[u8] = b"\x03";
+
+/// How long an interrupted command has to exit when `--interrupt-grace` isn't
+/// given.
+pub(crate) const DEFAULT_INTERRUPT_GRACE: Duration = Duration::from_secs(5);
+
+/// The index of the stream of the tail channel among those read.
+const TAIL_STREAM: usize = 2;
+
+/// How long to wait for more output when a command has none.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often a session sends a keepalive when `--keepalive` isn't given.
+const DEFAULT_KEEPALIVE_SECS: usize = 30;
+
+/// The `--keepalive` and `--session-timeout` args of the subcommands that
+/// keep a session open while they use it.
+pub(crate) fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("keepalive")
+            .long("keepalive")
+            .value_name("SECS")
+            .help(
+                "Send the host a keepalive every SECS seconds while its session is in use, 30 \
+                 by default, so firewalls don't drop a long copy or command as idle",
+            ),
+        Arg::with_name("session_timeout")
+            .long("session-timeout")
+            .value_name("SECS")
+            .help(
+                "Fail a copy or command that has stalled, with nothing read or written for SECS \
+                 seconds.  Without it a stalled one waits for as long as the connection stays up",
+            ),
+    ]
+}
+
+/// How an open session is kept alive, and how long it may stall, with
+/// `--keepalive` and `--session-timeout`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Keepalive {
+    interval: Duration,
+    session_timeout: Option<Duration>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl Keepalive {
+    pub(crate) fn new(keepalive_secs: Option<usize>, session_timeout_secs: Option<usize>) -> Self {
+        Self {
+            interval: secs(keepalive_secs.unwrap_or(DEFAULT_KEEPALIVE_SECS)),
+            session_timeout: session_timeout_secs.map(secs),
+        }
+    }
+
+    /// Have the session send keepalives as it is used and, with a session
+    /// timeout, fail a blocking read or write that has stalled.
+    pub(crate) fn apply(&self, session: &Session) {
+        session.set_keepalive(
+            true,
+            u32::try_from(self.interval.as_secs()).unwrap_or(u32::MAX),
+        );
+        if let Some(timeout) = self.session_timeout {
+            session.set_timeout(u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX));
+        }
+    }
+}
+
+fn secs(secs: usize) -> Duration {
+    Duration::from_secs(u64::try_from(secs).unwrap_or(u64::MAX))
+}
+
+/// The ssh session of each host, opened for the first command run on the host
+/// and kept for the commands after it, so the commands of a host run one after
+/// the other over a single authenticated session.
+///
+/// libmussh connects and authenticates again for every command.
+#[derive(Clone)]
+pub(crate) struct Sessions {
+    timeouts: ConnectTimeouts,
+    host_keys: HostKeys,
+    algorithms: SshAlgorithms,
+    /// Run each command on a pseudo-terminal, with `--tty`.
+    pty: bool,
+    /// How long a command run with `pty` has to exit once it is interrupted.
+    interrupt_grace: Duration,
+    /// The remote file tailed while each command runs, with `--tail-file`.
+    tail_file: Option<String>,
+    keepalive: Keepalive,
+    /// The open session of each host, by host name.
+    open: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Sessions {
+    pub(crate) fn new(timeouts: ConnectTimeouts, host_keys: HostKeys) -> Self {
+        Self {
+            timeouts,
+            host_keys,
+            algorithms: SshAlgorithms::default(),
+            pty: false,
+            interrupt_grace: DEFAULT_INTERRUPT_GRACE,
+            tail_file: None,
+            keepalive: Keepalive::default(),
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn with_algorithms(mut self, algorithms: SshAlgorithms) -> Self {
+        self.algorithms = algorithms;
+        self
+    }
+
+    pub(crate) fn with_pty(mut self, pty: bool) -> Self {
+        self.pty = pty;
+        self
+    }
+
+    pub(crate) fn with_interrupt_grace(mut self, interrupt_grace: Duration) -> Self {
+        self.interrupt_grace = interrupt_grace;
+        self
+    }
+
+    pub(crate) fn with_tail_file(mut self, tail_file: Option<String>) -> Self {
+        self.tail_file = tail_file;
+        self
+    }
+
+    pub(crate) fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Run the single command of the single host in the map over the host's
+    /// session, connecting within its connect timeout, preferring its ssh
+    /// algorithms and checking its host key if it has no session yet.  As when
+    /// libmussh runs a command, its stdout goes to the host's logger a line at
+    /// a time, and the run is logged to the multiplex stdout or stderr.  Its
+    /// stderr goes to the host's logger too, tagged as stderr, and is echoed to
+    /// the multiplex stderr if the command fails, as a local command's is.
+    ///
+    /// Unlike libmussh, the exit code is kept.  A session that fails is
+    /// dropped, and the next command run on the host opens a new one.
+    pub(crate) fn execute(
+        &self,
+        multiplex: &Multiplex,
+        cmd_map: MultiplexMapType,
+        (env, encoding, cancelled, print_command): (
+            &RemoteEnv,
+            Encoding,
+            &AtomicBool,
+            Option<&Logger>,
+        ),
+    ) -> Option<Execution> {
+        let (name, (host, cmds)) = cmd_map.into_iter().next()?;
+        let (cmd_name, cmd) = cmds.into_iter().flat_map(|(_, cmds)| cmds).next()?;
+        let cmd_logger = multiplex.host_loggers().get(&name).cloned().flatten();
+
+        let timer = Instant::now();
+        let opened: MusshResult<Session> = self.take(&name).map_or_else(
+            || {
+                let session = auth::session(
+                    host.hostname(),
+                    host.username(),
+                    *host.port(),
+                    host.pem().as_deref(),
+                    self.timeouts.for_host(&name),
+                    &self.host_keys,
+                    self.algorithms.for_host(&name),
+                )?;
+                self.keepalive.apply(&session);
+                try_trace!(multiplex.stdout(), "execute"; "host" => host.hostname(), "message" => "Opened the session");
+                Ok(session)
+            },
+            Ok,
+        );
+        let session = match opened {
+            Ok(session) => session,
+            Err(e) => return Some(Execution::failed(timer.elapsed(), None, e)),
+        };
+
+        let command = (host.hostname().as_str(), cmd_name.as_str(), cmd.as_str());
+        let loggers = (
+            multiplex.stdout().as_ref(),
+            cmd_logger.as_ref(),
+            print_command,
+        );
+        let vars = env.for_cmd(&cmd_name);
+        let (exit_code, stderr_lines) =
+            match self.run(&session, command, &vars, loggers, (encoding, cancelled)) {
+                Ok(ran) => ran,
+                Err(e) => return Some(Execution::failed(timer.elapsed(), None, e)),
+            };
+        if let Ok(mut open) = self.open.lock() {
+            let _old = open.insert(name, session);
+        }
+        let duration = timer.elapsed();
+        let elapsed = format_duration(&duration);
+
+        if exit_code == 0 {
+            try_info!(multiplex.stdout(), "execute"; "host" => host.hostname(), "cmd" => &cmd_name, "duration" => elapsed);
+            Some(Execution::ok(duration))
+        } else {
+            try_error!(multiplex.stderr(), "execute"; "host" => host.hostname(), "cmd" => &cmd_name, "duration" => elapsed);
+            for line in &stderr_lines {
+                try_error!(multiplex.stderr(), "{}", line; "host" => host.hostname(), "cmd" => &cmd_name, "stream" => STDERR_TAG);
+            }
+            let message = format!("Failed to run '{}' on '{cmd_name}'", host.hostname());
+            Some(Execution::failed(
+                duration,
+                Some(exit_code),
+                message.as_str().into(),
+            ))
+        }
+    }
+
+    /// Close the session of the host `name`, once it has run all of its
+    /// commands.
+    pub(crate) fn close(&self, name: &str) {
+        if let Some(session) = self.take(name) {
+            let _res = session.disconnect(None, "mussh run done", None);
+        }
+    }
+
+    fn take(&self, name: &str) -> Option<Session> {
+        self.open.lock().ok()?.remove(name)
+    }
+
+    /// Run the command on a channel of its own, writing its stdout to the
+    /// logger, and its stderr tagged as stderr, and return its exit code and
+    /// the lines of its stderr.
+    ///
+    /// The variables are set with `setenv` requests on the channel, and any
+    /// that sshd refuses are exported in front of the command instead.  With
+    /// `print_command`, the command is printed as it is then sent.
+    ///
+    /// With `pty`, the channel gets a pseudo-terminal before the command is
+    /// run, which merges its stderr into its stdout as a terminal does.  The
+    /// terminal doesn't turn `\n` into `\r\n`, so the lines are as they
+    /// would be without it.
+    ///
+    /// With a `tail_file`, the file is tailed on a second channel of the
+    /// session while the command runs, and its lines are logged tagged as
+    /// tail, after the path.  The tail is stopped once the command is done.
+    /// It failing doesn't fail the command.
+    ///
+    /// The session sends a keepalive whenever one is due while the output is
+    /// waited on, and with a session timeout the command fails once nothing
+    /// has been read from it for that long.  It fails too once `cancelled` is
+    /// set.  A command on a pseudo-terminal is interrupted first, and has the
+    /// interrupt grace to exit before its channel is closed.  Without one
+    /// there's no way to signal it, as libssh2 can't send a signal request, so
+    /// its channel is closed straight away.
+    ///
+    /// A failure to open the channel or start the command is returned as the
+    /// ssh2 error it is, as the command never started, but once it has started
+    /// a failure is returned as the command failing.
+    fn run(
+        &self,
+        session: &Session,
+        (hostname, cmd_name, cmd): (&str, &str, &str),
+        vars: &BTreeMap<String, String>,
+        (stdout, cmd_logger, print_command): (Option<&Logger>, Option<&Logger>, Option<&Logger>),
+        (encoding, cancelled): (Encoding, &AtomicBool),
+    ) -> MusshResult<(i32, Vec<String>)> {
+        let mut channel = session.channel_session()?;
+        if self.pty {
+            request_pty(&mut channel)?;
+        }
+        let (set, refused): (BTreeMap<String, String>, BTreeMap<String, String>) = vars
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .partition(|(name, value)| channel.setenv(name, value).is_ok());
+        if let Some(logger) = print_command {
+            remote_env::print_command(logger, (hostname, cmd_name), cmd, (&refused, &set));
+        }
+        let names = |vars: &BTreeMap<String, String>| {
+            vars.keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        if refused.is_empty() {
+            if !vars.is_empty() {
+                try_trace!(stdout, "Set the environment with setenv"; "host" => hostname, "cmd" => cmd_name, "vars" => names(vars));
+            }
+            channel.exec(cmd)?;
+        } else {
+            try_trace!(stdout, "sshd refused setenv, exporting the variables in front of the command instead"; "host" => hostname, "cmd" => cmd_name, "vars" => names(&refused));
+            channel.exec(&remote_env::with_env(cmd, &refused))?;
+        }
+
+        let tail = self
+            .tail_file
+            .as_deref()
+            .and_then(|path| match tail(session, path) {
+                Ok(tail) => Some(tail),
+                Err(e) => {
+                    try_warn!(stdout, "Not tailing the file"; "host" => hostname, "cmd" => cmd_name, "path" => path, "error" => e.to_string());
+                    None
+                }
+            });
+
+        let mut stderr_lines = Vec::new();
+        let interrupt_grace = self.pty.then_some(self.interrupt_grace);
+        let read = read_output(
+            (session, &channel, tail.as_ref()),
+            (encoding, self.keepalive.session_timeout, interrupt_grace),
+            cancelled,
+            |line| try_trace!(cmd_logger, "{}", line),
+            |line| {
+                if let Some(logger) = cmd_logger {
+                    trace!(logger, #STDERR_TAG, "{}", line);
+                }
+                stderr_lines.push(line.to_string());
+            },
+            |line| {
+                if let (Some(logger), Some(path)) = (cmd_logger, &self.tail_file) {
+                    trace!(logger, #TAIL_TAG, "{}: {}", path, line);
+                }
+            },
+        );
+        if let Some(mut tail) = tail {
+            // Ctrl-C, and then the terminal hanging up, both stop the tail.
+            let _res = tail.write_all(INTERRUPT);
+            let _res = tail.close();
+        }
+        let lost = |e: &dyn fmt::Display| -> MusshErr {
+            format!("Lost the channel running the command: {e}")
+                .as_str()
+                .into()
+        };
+        if let Err(e) = read {
+            let _res = channel.close();
+            return Err(lost(&e));
+        }
+        channel
+            .wait_close()
+            .and_then(|()| channel.exit_status())
+            .map(|exit_code| (exit_code, stderr_lines))
+            .map_err(|e| lost(&e))
+    }
+}
+
+impl fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let open: Vec<String> = self
+            .open
+            .lock()
+            .map(|open| open.keys().cloned().collect())
+            .unwrap_or_default();
+        f.debug_struct("Sessions")
+            .field("timeouts", &self.timeouts)
+            .field("host_keys", &self.host_keys)
+            .field("algorithms", &self.algorithms)
+            .field("pty", &self.pty)
+            .field("interrupt_grace", &self.interrupt_grace)
+            .field("tail_file", &self.tail_file)
+            .field("keepalive", &self.keepalive)
+            .field("open", &open)
+            .finish()
+    }
+}
+
+/// The error of a command given up on and interrupted, that either exited,
+/// or was still running once the `grace` it was given was over.
+fn interrupted_error(e: &io::Error, grace: Option<Duration>) -> io::Error {
+    let message = match grace {
+        Some(grace) => format!(
+            "{e}, it was still running {} after it was interrupted",
+            format_duration(&grace)
+        ),
+        None => format!("{e}, it was interrupted"),
+    };
+    io::Error::new(e.kind(), message)
+}
+
+/// Open a channel on the session that follows the remote file at `path`, on a
+/// pseudo-terminal so that it is hung up on when the channel is closed.
+fn tail(session: &Session, path: &str) -> MusshResult<Channel> {
+    let mut channel = session.channel_session()?;
+    request_pty(&mut channel)?;
+    channel.exec(&format!("tail -n 0 -F {}", shell_quote(path)))?;
+    Ok(channel)
+}
+
+/// Give the channel a pseudo-terminal that leaves `\n` as it is.
+fn request_pty(channel: &mut Channel) -> MusshResult<()> {
+    let mut modes = PtyModes::new();
+    modes.set_boolean(PtyModeOpcode::ONLCR, false);
+    Ok(channel.request_pty(PTY_TERM, Some(modes), None)?)
+}
+
+/// Read the stdout and stderr of the command on the channel to their end,
+/// calling `stdout` and `stderr` with each of their lines, decoded with the
+/// `encoding`.  The output of the `tail` channel, if there is one, is read
+/// alongside until then, calling `tail` with each of its lines.  It failing
+/// or ending is ignored, and it doesn't count as output of the command.
+///
+/// Both are read on the one thread, with the session non-blocking, from
+/// whichever has output, so a command writing a lot to one can't fill up the
+/// channel's window while the other is waited on.  The session's own timeout
+/// only applies to blocking calls, so a read that has stalled for longer than
+/// the `session_timeout` is failed here, and as libssh2 only sends keepalives
+/// when asked to, one is sent whenever it is due.  The read fails too once
+/// `cancelled` is set.
+///
+/// With an `interrupt_grace`, a command that is given up on is sent Ctrl-C
+/// and its output read on for up to the grace, before the read fails.  The
+/// error tells whether it exited in that time.
+fn read_output(
+    (session, channel, tail): (&Session, &Channel, Option<&Channel>),
+    (encoding, session_timeout, interrupt_grace): (Encoding, Option<Duration>, Option<Duration>),
+    cancelled: &AtomicBool,
+    mut stdout: impl FnMut(&str),
+    mut stderr: impl FnMut(&str),
+    mut tail_line: impl FnMut(&str),
+) -> io::Result<()> {
+    let mut streams = vec![
+        (channel.stream(0), Lines::new(encoding), false),
+        (channel.stream(1), Lines::new(encoding), false),
+    ];
+    if let Some(tail) = tail {
+        streams.push((tail.stream(0), Lines::new(encoding), false));
+    }
+    let mut buf = [0; 8192];
+    let mut last_read = Instant::now();
+    // Why the command was given up on, and when it was interrupted.
+    let mut given_up: Option<(io::Error, Instant)> = None;
+    session.set_blocking(false);
+
+    let read = loop {
+        let mut idle = true;
+        for (idx, (stream, lines, done)) in streams.iter_mut().enumerate() {
+            let read = if *done {
+                continue;
+            } else {
+                stream.read(&mut buf)
+            };
+            match read {
+                Ok(0) => *done = true,
+                Ok(len) if idx == 0 => lines.push(&buf[..len], &mut stdout),
+                Ok(len) if idx == 1 => lines.push(&buf[..len], &mut stderr),
+                Ok(len) => {
+                    lines.push(&buf[..len], &mut tail_line);
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(_) if idx == TAIL_STREAM => {
+                    *done = true;
+                    continue;
+                }
+                Err(e) => {
+                    session.set_blocking(true);
+                    return Err(e);
+                }
+            }
+            idle = false;
+        }
+        let exited = streams[..TAIL_STREAM].iter().all(|(_, _, done)| *done);
+        if let Some((e, interrupted)) = &given_up {
+            let grace = interrupt_grace.unwrap_or_default();
+            if exited || interrupted.elapsed() >= grace {
+                break Err(interrupted_error(e, (!exited).then_some(grace)));
+            }
+        } else if exited {
+            break Ok(());
+        }
+
+        let reason = if given_up.is_some() {
+            None
+        } else if cancelled.load(Ordering::SeqCst) {
+            Some(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "the run was cancelled",
+            ))
+        } else if !idle {
+            last_read = Instant::now();
+            continue;
+        } else {
+            session_timeout
+                .filter(|t| last_read.elapsed() >= *t)
+                .map(|timeout| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "nothing read from the command for {}",
+                            format_duration(&timeout)
+                        ),
+                    )
+                })
+        };
+        match reason {
+            Some(e) if interrupt_grace.is_some() && streams[0].0.write_all(INTERRUPT).is_ok() => {
+                given_up = Some((e, Instant::now()));
+            }
+            Some(e) => break Err(e),
+            None if idle => {
+                // Not sent while the session would block, and a dead
+                // connection fails the next read anyway.
+                let _secs = session.keepalive_send();
+                thread::sleep(POLL_INTERVAL);
+            }
+            None => {}
+        }
+    };
+    session.set_blocking(true);
+
+    for (idx, (_, lines, _)) in streams.into_iter().enumerate() {
+        match idx {
+            0 => lines.finish(&mut stdout),
+            1 => lines.finish(&mut stderr),
+            _ => lines.finish(&mut tail_line),
+        }
+    }
+    read
+}
+
+#[cfg(test)]
+mod test {
+    use super::{interrupted_error, Keepalive, Sessions};
+    use crate::connect::ConnectTimeouts;
+    use crate::error::MusshResult;
+    use crate::known_hosts::{HostKeyCheck, HostKeys};
+    use crate::lines::Encoding;
+    use crate::remote_env::RemoteEnv;
+    use crate::targets;
+    use crate::warnings::Warnings;
+    use libmussh::{Config, Multiplex, RuntimeConfig};
+    use std::env;
+    use std::io;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    const REFUSED_TOML: &str = r#"[hostlist.all]
+hostnames = ["closed"]
+[hosts.closed]
+hostname = "127.0.0.1"
+port = 1
+username = "jozias"
+connect_timeout = 1
+[cmd.ls]
+command = "ls"
+"#;
+
+    #[test]
+    fn keepalives() {
+        let defaults = Keepalive::default();
+        assert_eq!(defaults.interval, Duration::from_secs(30));
+        assert_eq!(defaults.session_timeout, None);
+        let keepalive = Keepalive::new(Some(5), Some(90));
+        assert_eq!(keepalive.interval, Duration::from_secs(5));
+        assert_eq!(keepalive.session_timeout, Some(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn refused_host() -> MusshResult<()> {
+        let config: Config = toml::from_str(REFUSED_TOML)?;
+        let mut runtime_config = RuntimeConfig::default();
+        let _ = runtime_config
+            .set_hosts(vec!["all".to_string()].into_iter().collect())
+            .set_cmds(vec!["ls".to_string()].into_iter().collect());
+        let (_, map) =
+            targets::to_host_map(&config, &runtime_config, true, &mut Warnings::default())?;
+        let host_keys = HostKeys::new(HostKeyCheck::Off, env::temp_dir().join("known_hosts"));
+        let sessions = Sessions::new(
+            ConnectTimeouts::parse(&toml::from_str(REFUSED_TOML)?, None)?,
+            host_keys,
+        );
+
+        let execution = sessions
+            .execute(
+                &Multiplex::default(),
+                map,
+                (
+                    &RemoteEnv::default(),
+                    Encoding::Utf8,
+                    &AtomicBool::new(false),
+                    None,
+                ),
+            )
+            .ok_or("nothing was run")?;
+        let execution = format!("{execution:?}");
+        assert!(execution.contains("exit_code: None"));
+        assert!(execution.contains("Io("));
+        assert!(sessions.open.lock().map_err(|_| "poisoned")?.is_empty());
+        sessions.close("closed");
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_errors() {
+        let e = io::Error::new(
+            io::ErrorKind::TimedOut,
+            "nothing read from the command for 30s",
+        );
+        let exited = interrupted_error(&e, None);
+        assert_eq!(exited.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            exited.to_string(),
+            "nothing read from the command for 30s, it was interrupted"
+        );
+        assert!(interrupted_error(&e, Some(Duration::from_secs(5)))
+            .to_string()
+            .ends_with("it was still running 5.000s after it was interrupted"));
+    }
+}
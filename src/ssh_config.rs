@@ -0,0 +1,283 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `~/.ssh/config` support
+use crate::error::MusshResult;
+use libmussh::Config;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use toml::Value;
+
+/// The `Host` blocks of an ssh config file.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct SshConfig {
+    blocks: Vec<Block>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Block {
+    /// The `Host` patterns, `None` for a `Match` block.
+    patterns: Option<Vec<String>>,
+    /// The options in the block, with lowercased keywords.
+    options: Vec<(String, String)>,
+}
+
+impl SshConfig {
+    /// Read the ssh config at the given path.  A missing file is empty.
+    pub(crate) fn read(path: &Path) -> MusshResult<Self> {
+        if path.exists() {
+            Ok(Self::parse(&fs::read_to_string(path)?))
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Parse the contents of an ssh config file.  Options before the first
+    /// `Host` apply to every host, and `Match` blocks are ignored.
+    pub(crate) fn parse(contents: &str) -> Self {
+        let mut blocks = vec![Block {
+            patterns: Some(vec!["*".to_string()]),
+            options: Vec::new(),
+        }];
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (keyword, value) = line
+                .split_once(|c: char| c.is_whitespace() || c == '=')
+                .map_or((line, ""), |(keyword, value)| {
+                    (
+                        keyword,
+                        value.trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+                    )
+                });
+            let keyword = keyword.to_lowercase();
+            let value = value.trim().trim_matches('"').to_string();
+
+            match keyword.as_str() {
+                "host" => blocks.push(Block {
+                    patterns: Some(value.split_whitespace().map(ToString::to_string).collect()),
+                    options: Vec::new(),
+                }),
+                "match" => blocks.push(Block::default()),
+                _ => {
+                    if let Some(block) = blocks.last_mut() {
+                        block.options.push((keyword, value));
+                    }
+                }
+            }
+        }
+
+        Self { blocks }
+    }
+
+    /// The options that apply to the given host.  As with ssh, the first value
+    /// found for an option wins.
+    pub(crate) fn lookup(&self, host: &str) -> HashMap<String, String> {
+        let mut options = HashMap::new();
+
+        for block in &self.blocks {
+            if block.matches(host) {
+                for (keyword, value) in &block.options {
+                    let _value = options
+                        .entry(keyword.clone())
+                        .or_insert_with(|| value.clone());
+                }
+            }
+        }
+
+        options
+    }
+}
+
+impl Block {
+    fn matches(&self, host: &str) -> bool {
+        let Some(patterns) = &self.patterns else {
+            return false;
+        };
+        let negated = patterns
+            .iter()
+            .filter_map(|pattern| pattern.strip_prefix('!'))
+            .any(|pattern| glob_match(pattern, host));
+        !negated
+            && patterns
+                .iter()
+                .filter(|pattern| !pattern.starts_with('!'))
+                .any(|pattern| glob_match(pattern, host))
+    }
+}
+
+/// Match `text` against an ssh pattern, where `*` matches any run of
+/// characters and `?` matches exactly one.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Fill in the connection details missing from each configured host with the
+/// `HostName`, `User`, `Port` and `IdentityFile` from the ssh config.
+///
+/// The `hostname` of each host is looked up against the `Host` patterns.  A
+/// `pem`, `port` or `username` set in the mussh config is always kept.
+pub(crate) fn apply(config: &Config, ssh_config: &SshConfig) -> MusshResult<Config> {
+    let mut value = Value::try_from(config)?;
+
+    if let Some(hosts) = value.get_mut("hosts").and_then(Value::as_table_mut) {
+        for host in hosts.iter_mut().filter_map(|(_, host)| host.as_table_mut()) {
+            let alias = host
+                .get("hostname")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            let options = ssh_config.lookup(&alias);
+
+            if let Some(hostname) = options.get("hostname") {
+                let _old = host.insert(
+                    "hostname".to_string(),
+                    Value::String(hostname.replace("%h", &alias)),
+                );
+            }
+            if let Some(user) = options.get("user") {
+                if host.get("username").and_then(Value::as_str) == Some("") {
+                    let _old = host.insert("username".to_string(), Value::String(user.clone()));
+                }
+            }
+            if let Some(port) = options
+                .get("port")
+                .and_then(|port| port.parse::<u16>().ok())
+            {
+                let _value = host
+                    .entry("port".to_string())
+                    .or_insert_with(|| Value::Integer(i64::from(port)));
+            }
+            if let Some(identity_file) = options.get("identityfile") {
+                let _value = host
+                    .entry("pem".to_string())
+                    .or_insert_with(|| Value::String(expand_tilde(identity_file)));
+            }
+        }
+    }
+
+    Ok(value.try_into()?)
+}
+
+fn expand_tilde(path: &str) -> String {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).display().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, glob_match, SshConfig};
+    use crate::error::MusshResult;
+    use libmussh::Config;
+
+    const SSH_CONFIG: &str = r#"
+# Defaults for the web boxes
+Host web?? !web99
+    HostName %h.example.com
+    User deploy
+    Port 2222
+    IdentityFile /keys/web.pem
+
+Host db01
+    HostName=10.0.0.5
+    IdentityFile "/keys/db.pem"
+
+Match host web01
+    User nobody
+
+Host *
+    User fallback
+    Port 22
+"#;
+
+    const MUSSH_TOML: &str = r#"[hostlist]
+[hosts.web]
+hostname = "web01"
+username = ""
+[hosts.web99]
+hostname = "web99"
+username = "jozias"
+[hosts.db]
+hostname = "db01"
+username = "jozias"
+port = 2200
+pem = "/keys/mine.pem"
+[cmd]
+"#;
+
+    #[test]
+    fn patterns() {
+        assert!(glob_match("web??", "web01"));
+        assert!(!glob_match("web??", "web1"));
+        assert!(glob_match("*.example.com", "a.b.example.com"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("db*x", "db01"));
+    }
+
+    #[test]
+    fn lookup_first_value_wins() {
+        let ssh_config = SshConfig::parse(SSH_CONFIG);
+        let options = ssh_config.lookup("web01");
+        assert_eq!(options.get("user").map(String::as_str), Some("deploy"));
+        assert_eq!(options.get("port").map(String::as_str), Some("2222"));
+        let options = ssh_config.lookup("web99");
+        assert_eq!(options.get("user").map(String::as_str), Some("fallback"));
+        assert_eq!(options.get("hostname"), None);
+    }
+
+    #[test]
+    fn fills_missing_fields_only() -> MusshResult<()> {
+        let config: Config = toml::from_str(MUSSH_TOML)?;
+        let config = apply(&config, &SshConfig::parse(SSH_CONFIG))?;
+
+        let web = config.hosts().get("web").ok_or("no web host")?;
+        assert_eq!(web.hostname(), "web01.example.com");
+        assert_eq!(web.username(), "deploy");
+        assert_eq!(*web.port(), Some(2222));
+        assert_eq!(web.pem().as_deref(), Some("/keys/web.pem"));
+
+        let web99 = config.hosts().get("web99").ok_or("no web99 host")?;
+        assert_eq!(web99.hostname(), "web99");
+        assert_eq!(web99.username(), "jozias");
+        assert_eq!(*web99.port(), Some(22));
+        assert_eq!(*web99.pem(), None);
+
+        let db = config.hosts().get("db").ok_or("no db host")?;
+        assert_eq!(db.hostname(), "10.0.0.5");
+        assert_eq!(*db.port(), Some(2200));
+        assert_eq!(db.pem().as_deref(), Some("/keys/mine.pem"));
+        Ok(())
+    }
+}
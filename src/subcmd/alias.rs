@@ -0,0 +1,249 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! alias subcommand
+use crate::config_file;
+use crate::error::MusshResult;
+use crate::subcmd::Subcommand;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use libmussh::Config;
+use slog::Logger;
+use slog_try::{try_trace, try_warn};
+use std::path::PathBuf;
+use toml_edit::{value, ArrayOfTables, Document, Item, Table};
+
+#[derive(Clone, Default)]
+pub(crate) struct Alias {
+    stdout: Option<Logger>,
+    stderr: Option<Logger>,
+    config_path: PathBuf,
+}
+
+impl Alias {
+    pub(crate) fn new(
+        stdout: Option<Logger>,
+        stderr: Option<Logger>,
+        config_path: PathBuf,
+    ) -> Self {
+        Self {
+            stdout,
+            stderr,
+            config_path,
+        }
+    }
+
+    /// Warn about any of the given names that aren't configured commands.
+    fn check_cmds(&self, config: &Config, names: &[&str]) {
+        for name in names {
+            if !config.cmd().contains_key(*name) {
+                try_warn!(self.stderr, "'{}' is not a configured command", name);
+            }
+        }
+    }
+
+    fn edit<F>(&self, edit: F) -> MusshResult<()>
+    where
+        F: FnOnce(&mut Document) -> MusshResult<()>,
+    {
+        let mut document = config_file::load(&self.config_path)?;
+        edit(&mut document)?;
+        try_trace!(self.stdout, "Writing config"; "path" => self.config_path.display().to_string());
+        config_file::save(&self.config_path, &document)
+    }
+}
+
+impl Subcommand for Alias {
+    fn subcommand<'a, 'b>() -> App<'a, 'b> {
+        let host_arg = Arg::with_name("host")
+            .value_name("HOST")
+            .help("The host the alias is on")
+            .required(true);
+        let aliasfor_arg = Arg::with_name("aliasfor")
+            .value_name("ALIASFOR")
+            .help("The command that is replaced on the host")
+            .required(true);
+
+        SubCommand::with_name("alias")
+            .about("Work with the command aliases of a host")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("add")
+                    .about("Run COMMAND on the host in place of ALIASFOR")
+                    .arg(host_arg.clone())
+                    .arg(aliasfor_arg.clone())
+                    .arg(
+                        Arg::with_name("command")
+                            .value_name("COMMAND")
+                            .help("The command to run instead")
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("remove")
+                    .about("Remove the alias for ALIASFOR from the host")
+                    .arg(host_arg.clone())
+                    .arg(aliasfor_arg),
+            )
+            .subcommand(
+                SubCommand::with_name("list")
+                    .about("List the aliases of the host")
+                    .arg(host_arg),
+            )
+    }
+
+    fn execute(&self, config: &Config, matches: &ArgMatches<'_>) -> MusshResult<()> {
+        match matches.subcommand() {
+            ("add", Some(sub_m)) => {
+                let host = sub_m.value_of("host").unwrap_or_default();
+                let aliasfor = sub_m.value_of("aliasfor").unwrap_or_default();
+                let command = sub_m.value_of("command").unwrap_or_default();
+                self.check_cmds(config, &[aliasfor, command]);
+                self.edit(|document| add(document, host, aliasfor, command))?;
+                println!("'{aliasfor}' runs '{command}' on '{host}'");
+                Ok(())
+            }
+            ("remove", Some(sub_m)) => {
+                let host = sub_m.value_of("host").unwrap_or_default();
+                let aliasfor = sub_m.value_of("aliasfor").unwrap_or_default();
+                self.edit(|document| remove(document, host, aliasfor))?;
+                println!("Removed the alias for '{aliasfor}' from '{host}'");
+                Ok(())
+            }
+            ("list", Some(sub_m)) => {
+                let name = sub_m.value_of("host").unwrap_or_default();
+                let host = config
+                    .hosts()
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown host '{name}'"))?;
+                for alias in host.alias().iter().flatten() {
+                    println!("{} -> {}", alias.aliasfor(), alias.command());
+                }
+                Ok(())
+            }
+            (cmd, _) => Err(format!("Unknown subcommand {cmd}").into()),
+        }
+    }
+}
+
+fn host_table<'a>(document: &'a mut Document, host: &str) -> MusshResult<&'a mut Table> {
+    document
+        .get_mut("hosts")
+        .and_then(|hosts| hosts.get_mut(host))
+        .and_then(Item::as_table_mut)
+        .ok_or_else(|| format!("Unknown host '{host}'").into())
+}
+
+/// Add an alias to the host, replacing any existing alias for the same
+/// command.
+fn add(document: &mut Document, host: &str, aliasfor: &str, command: &str) -> MusshResult<()> {
+    let host_table = host_table(document, host)?;
+    let aliases = host_table
+        .entry("alias")
+        .or_insert_with(|| Item::ArrayOfTables(ArrayOfTables::new()))
+        .as_array_of_tables_mut()
+        .ok_or_else(|| format!("The aliases of '{host}' are not an array of tables"))?;
+
+    let existing = aliases
+        .iter_mut()
+        .find(|alias| alias.get("aliasfor").and_then(Item::as_str) == Some(aliasfor));
+    if let Some(alias) = existing {
+        alias["command"] = value(command);
+    } else {
+        let mut alias = Table::new();
+        alias["command"] = value(command);
+        alias["aliasfor"] = value(aliasfor);
+        aliases.push(alias);
+    }
+    Ok(())
+}
+
+/// Remove the alias for `aliasfor` from the host.
+fn remove(document: &mut Document, host: &str, aliasfor: &str) -> MusshResult<()> {
+    let host_table = host_table(document, host)?;
+    let aliases = host_table
+        .get_mut("alias")
+        .and_then(Item::as_array_of_tables_mut);
+    let idx = aliases.as_ref().and_then(|aliases| {
+        aliases
+            .iter()
+            .position(|alias| alias.get("aliasfor").and_then(Item::as_str) == Some(aliasfor))
+    });
+
+    match (aliases, idx) {
+        (Some(aliases), Some(idx)) => {
+            aliases.remove(idx);
+            if aliases.is_empty() {
+                let _old = host_table.remove("alias");
+            }
+            Ok(())
+        }
+        _ => Err(format!("'{host}' has no alias for '{aliasfor}'").into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{add, remove};
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use toml_edit::Document;
+
+    const ALIAS_TOML: &str = r#"[hostlist.all]
+hostnames = ["m1"]
+
+[hosts.m1]
+hostname = "10.0.0.3"
+username = "jozias"
+
+[cmd.ls]
+command = "ls"
+
+[cmd.ls_al]
+command = "ls -al"
+"#;
+
+    fn aliases(document: &Document) -> MusshResult<Vec<(String, String)>> {
+        let config: Config = toml::from_str(&document.to_string())?;
+        let host = config.hosts().get("m1").ok_or("no m1 host")?;
+        Ok(host
+            .alias()
+            .iter()
+            .flatten()
+            .map(|alias| (alias.aliasfor().clone(), alias.command().clone()))
+            .collect())
+    }
+
+    #[test]
+    fn add_and_remove() -> MusshResult<()> {
+        let mut document: Document = ALIAS_TOML.parse().map_err(|_| "bad toml")?;
+        add(&mut document, "m1", "ls", "ls_al")?;
+        assert_eq!(
+            aliases(&document)?,
+            vec![("ls".to_string(), "ls_al".to_string())]
+        );
+
+        add(&mut document, "m1", "ls", "ls")?;
+        assert_eq!(
+            aliases(&document)?,
+            vec![("ls".to_string(), "ls".to_string())]
+        );
+
+        remove(&mut document, "m1", "ls")?;
+        assert!(aliases(&document)?.is_empty());
+        assert!(!document.to_string().contains("alias"));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_hosts_and_aliases() -> MusshResult<()> {
+        let mut document: Document = ALIAS_TOML.parse().map_err(|_| "bad toml")?;
+        assert!(add(&mut document, "m2", "ls", "ls_al").is_err());
+        assert!(remove(&mut document, "m1", "ls").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! check subcommand
+use crate::algorithms::SshAlgorithms;
+use crate::connect::ConnectTimeouts;
+use crate::error::MusshResult;
+use crate::extensions::Extensions;
+use crate::jump;
+use crate::remote_env::RemoteEnv;
+use crate::subcmd::Subcommand;
+use crate::tags;
+use crate::targets;
+use crate::warnings::Warnings;
+use clap::{App, ArgMatches, SubCommand};
+use indexmap::IndexSet;
+use libmussh::Config;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Default)]
+pub(crate) struct Check {
+    config_path: PathBuf,
+    extensions: Extensions,
+}
+
+impl Check {
+    pub(crate) fn new(config_path: PathBuf, extensions: Extensions) -> Self {
+        Self {
+            config_path,
+            extensions,
+        }
+    }
+}
+
+impl Subcommand for Check {
+    fn subcommand<'a, 'b>() -> App<'a, 'b> {
+        SubCommand::with_name("check").about(
+            "Check the config without running anything: that hostlists only name hosts and \
+             hostlists, aliases only name commands, ports are valid and pem files exist",
+        )
+    }
+
+    fn execute(&self, config: &Config, _matches: &ArgMatches<'_>) -> MusshResult<()> {
+        let problems = problems(config, &self.extensions);
+        let path = self.config_path.display();
+        if problems.is_empty() {
+            println!(
+                "{path}: ok, {} host(s), {} hostlist(s), {} command(s)",
+                config.hosts().len(),
+                config.hostlist().len(),
+                config.cmd().len()
+            );
+            return Ok(());
+        }
+
+        for problem in &problems {
+            println!("{path}: {problem}");
+        }
+        Err(format!("{} problem(s) in {path}", problems.len()).into())
+    }
+}
+
+/// Everything wrong with the config, each once, in the order hostlists, hosts
+/// and their aliases, commands, then the fields only mussh reads.
+fn problems(config: &Config, extensions: &Extensions) -> IndexSet<String> {
+    let mut problems = IndexSet::new();
+
+    // A hostlist included by another is checked with each, so its problems are
+    // only kept once.
+    for name in config.hostlist().keys() {
+        let mut warnings = Warnings::default();
+        if let Err(e) = targets::resolve_targets(config, &[name.as_str()], &mut warnings) {
+            let _new = problems.insert(e.to_string());
+        }
+        problems.extend(warnings.into_messages());
+    }
+
+    for (name, host) in config.hosts() {
+        if host.hostname().trim().is_empty() {
+            let _new = problems.insert(format!("Host '{name}' has no hostname"));
+        }
+        if host.username().trim().is_empty() {
+            let _new = problems.insert(format!("Host '{name}' has no username"));
+        }
+        if *host.port() == Some(0) {
+            let _new = problems.insert(format!("Host '{name}' has port 0, expected 1-65535"));
+        }
+        if let Some(pem) = host.pem() {
+            if !Path::new(pem).is_file() {
+                let _new =
+                    problems.insert(format!("The pem '{pem}' of host '{name}' doesn't exist"));
+            }
+        }
+        for alias in host.alias().iter().flatten() {
+            for (role, cmd) in &[("aliasfor", alias.aliasfor()), ("command", alias.command())] {
+                if !config.cmd().contains_key(*cmd) {
+                    let _new = problems.insert(format!(
+                        "The alias {role} '{cmd}' of host '{name}' is not a configured command"
+                    ));
+                }
+            }
+        }
+    }
+
+    for (name, cmd) in config.cmd() {
+        if cmd.command().trim().is_empty() {
+            let _new = problems.insert(format!("Command '{name}' is empty"));
+        }
+    }
+
+    let hosts: Vec<&str> = config.hosts().keys().map(String::as_str).collect();
+    let extras = vec![
+        ConnectTimeouts::parse(extensions, None).map(drop),
+        SshAlgorithms::parse(extensions, None).map(drop),
+        jump::host_jumps(extensions, &hosts).map(drop),
+        RemoteEnv::parse(extensions, BTreeMap::new(), &[]).map(drop),
+        tags::host_tags(extensions).map(drop),
+    ];
+    problems.extend(
+        extras
+            .into_iter()
+            .filter_map(|res| res.err().map(|e| e.to_string())),
+    );
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::problems;
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use std::env;
+
+    const CHECK_TOML: &str = r#"[hostlist.all]
+hostnames = ["web", "db"]
+[hostlist.most]
+hostnames = ["all", "gone"]
+[hosts.web]
+hostname = "10.0.0.3"
+username = "jozias"
+connect_timeout = 0
+[[hosts.web.alias]]
+command = "ls_al"
+aliasfor = "lss"
+[hosts.db]
+hostname = "10.0.0.4"
+username = "jozias"
+port = 0
+pem = "/nonexistent/db.pem"
+[cmd.ls]
+command = "ls"
+[cmd.ls_al]
+command = ""
+"#;
+
+    #[test]
+    fn finds_every_problem() -> MusshResult<()> {
+        let config: Config = toml::from_str(CHECK_TOML)?;
+        let problems: Vec<String> = problems(&config, &toml::from_str(CHECK_TOML)?)
+            .into_iter()
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "Hostlist 'most' includes the unknown host 'gone'",
+                "Host 'db' has port 0, expected 1-65535",
+                "The pem '/nonexistent/db.pem' of host 'db' doesn't exist",
+                "The alias aliasfor 'lss' of host 'web' is not a configured command",
+                "Command 'ls_al' is empty",
+                "The connect_timeout of host 'web' must be a positive number of seconds",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn passes_a_good_config() -> MusshResult<()> {
+        let pem = env::temp_dir().join(format!("mussh-check-{}.pem", std::process::id()));
+        std::fs::write(&pem, "")?;
+        let toml = CHECK_TOML
+            .replace(", \"gone\"", "")
+            .replace("connect_timeout = 0", "connect_timeout = 5")
+            .replace("\"lss\"", "\"ls\"")
+            .replace("port = 0", "port = 2222")
+            .replace("/nonexistent/db.pem", &pem.display().to_string())
+            .replace("command = \"\"", "command = \"ls -al\"");
+        let config: Config = toml::from_str(&toml)?;
+        let problems = problems(&config, &toml::from_str(&toml)?);
+        std::fs::remove_file(&pem)?;
+        assert!(problems.is_empty());
+        Ok(())
+    }
+}
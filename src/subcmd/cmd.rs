@@ -0,0 +1,300 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! cmd subcommand
+use crate::config_file;
+use crate::error::MusshResult;
+use crate::extensions::Extensions;
+use crate::local;
+use crate::subcmd::Subcommand;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use libmussh::Config;
+use slog::Logger;
+use slog_try::try_trace;
+use std::path::PathBuf;
+use std::process::Command;
+use toml_edit::{table, value, Document, Item};
+
+#[derive(Clone, Default)]
+pub(crate) struct Cmd {
+    stdout: Option<Logger>,
+    config_path: PathBuf,
+    extensions: Extensions,
+}
+
+impl Cmd {
+    pub(crate) fn new(
+        stdout: Option<Logger>,
+        config_path: PathBuf,
+        extensions: Extensions,
+    ) -> Self {
+        Self {
+            stdout,
+            config_path,
+            extensions,
+        }
+    }
+
+    fn edit<F>(&self, edit: F) -> MusshResult<()>
+    where
+        F: FnOnce(&mut Document) -> MusshResult<()>,
+    {
+        let mut document = config_file::load(&self.config_path)?;
+        edit(&mut document)?;
+        try_trace!(self.stdout, "Writing config"; "path" => self.config_path.display().to_string());
+        config_file::save(&self.config_path, &document)
+    }
+
+    /// Run the named command through the local shell, without touching any
+    /// configured host.  The shell is the one a run on `localhost` uses: the
+    /// `shell` of the `localhost` host in the config, otherwise
+    /// `--local-shell`, otherwise the user's `SHELL`, otherwise `/bin/sh`.
+    fn test(&self, config: &Config, name: &str, local_shell: Option<&str>) -> MusshResult<i32> {
+        let command = config
+            .cmd()
+            .get(name)
+            .ok_or_else(|| format!("Unknown command '{name}'"))?;
+        let shells = local::shells(&self.extensions)?;
+        let host_shell = config
+            .hosts()
+            .iter()
+            .filter(|(_, host)| host.hostname() == local::LOCALHOST)
+            .find_map(|(name, _)| shells.get(name));
+        let shell = local::shell(host_shell.map(String::as_str), local_shell);
+        try_trace!(
+            self.stdout,
+            "cmd test";
+            "shell" => &shell,
+            "command" => command.command()
+        );
+
+        let status = Command::new(shell)
+            .arg("-c")
+            .arg(command.command())
+            .status()?;
+        Ok(status.code().unwrap_or(-1))
+    }
+}
+
+impl Subcommand for Cmd {
+    fn subcommand<'a, 'b>() -> App<'a, 'b> {
+        let name_arg = Arg::with_name("name")
+            .value_name("NAME")
+            .help("The name of the command")
+            .required(true);
+        let command_arg = Arg::with_name("command")
+            .value_name("COMMAND")
+            .help("The command line run on the hosts")
+            .required(true);
+
+        SubCommand::with_name("cmd")
+            .about("Work with the configured commands")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("add")
+                    .about("Add a command")
+                    .arg(name_arg.clone())
+                    .arg(command_arg.clone()),
+            )
+            .subcommand(
+                SubCommand::with_name("update")
+                    .about("Change the command line of a command")
+                    .arg(name_arg.clone())
+                    .arg(command_arg),
+            )
+            .subcommand(
+                SubCommand::with_name("remove")
+                    .about("Remove a command that no host alias uses")
+                    .arg(name_arg),
+            )
+            .subcommand(SubCommand::with_name("list").about("List the commands"))
+            .subcommand(
+                SubCommand::with_name("test")
+                    .about(
+                        "Run a command on localhost only, to check it before running it on hosts",
+                    )
+                    .arg(
+                        Arg::with_name("name")
+                            .value_name("NAME")
+                            .help("The command to run")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("local_shell")
+                            .long("local-shell")
+                            .value_name("SHELL")
+                            .help(
+                                "The shell to run the command with, unless the localhost host \
+                                 in the config has its own shell (SHELL, or /bin/sh, by default)",
+                            ),
+                    ),
+            )
+    }
+
+    fn execute(&self, config: &Config, matches: &ArgMatches<'_>) -> MusshResult<()> {
+        match matches.subcommand() {
+            ("add", Some(sub_m)) => {
+                let name = sub_m.value_of("name").unwrap_or_default();
+                let command = sub_m.value_of("command").unwrap_or_default();
+                self.edit(|document| set(document, name, command, false))?;
+                println!("Added command '{name}'");
+                Ok(())
+            }
+            ("update", Some(sub_m)) => {
+                let name = sub_m.value_of("name").unwrap_or_default();
+                let command = sub_m.value_of("command").unwrap_or_default();
+                self.edit(|document| set(document, name, command, true))?;
+                println!("Updated command '{name}'");
+                Ok(())
+            }
+            ("remove", Some(sub_m)) => {
+                let name = sub_m.value_of("name").unwrap_or_default();
+                self.edit(|document| remove(document, name))?;
+                println!("Removed command '{name}'");
+                Ok(())
+            }
+            ("list", Some(_)) => {
+                for (name, cmd) in config.cmd() {
+                    println!("{name}: {}", cmd.command());
+                }
+                Ok(())
+            }
+            ("test", Some(sub_m)) => {
+                let name = sub_m.value_of("name").unwrap_or_default();
+                match self.test(config, name, sub_m.value_of("local_shell"))? {
+                    0 => {
+                        println!("'{name}' exited with code 0");
+                        Ok(())
+                    }
+                    code => Err(format!("'{name}' exited with code {code}").into()),
+                }
+            }
+            (cmd, _) => Err(format!("Unknown subcommand {cmd}").into()),
+        }
+    }
+}
+
+/// Set the command line of the command `name`, which must already exist to
+/// update it, and mustn't to add it.
+fn set(document: &mut Document, name: &str, command: &str, update: bool) -> MusshResult<()> {
+    let cmds = config_file::section(document, "cmd")?;
+    match (cmds.get_mut(name), update) {
+        (Some(cmd), true) => {
+            cmd["command"] = value(command);
+            Ok(())
+        }
+        (None, false) => {
+            let mut cmd = table();
+            cmd["command"] = value(command);
+            let _old = cmds.insert(name, cmd);
+            Ok(())
+        }
+        (Some(_), false) => {
+            Err(format!("Command '{name}' already exists, use cmd update to change it").into())
+        }
+        (None, true) => Err(format!("Unknown command '{name}'").into()),
+    }
+}
+
+/// Remove the command `name`, unless a host alias still uses it.
+fn remove(document: &mut Document, name: &str) -> MusshResult<()> {
+    let hosts = document.get("hosts").and_then(Item::as_table_like);
+    for (host, host_table) in hosts.into_iter().flat_map(|hosts| hosts.iter()) {
+        let aliases = host_table
+            .get("alias")
+            .and_then(Item::as_array_of_tables)
+            .into_iter()
+            .flat_map(|aliases| aliases.iter());
+        for alias in aliases {
+            let names = ["aliasfor", "command"].map(|key| alias.get(key).and_then(Item::as_str));
+            if names.contains(&Some(name)) {
+                return Err(format!(
+                    "Command '{name}' is used by an alias of host '{host}', remove the alias first"
+                )
+                .into());
+            }
+        }
+    }
+
+    let _cmd = config_file::remove(document, "cmd", name)?
+        .ok_or_else(|| format!("Unknown command '{name}'"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{remove, set, Cmd};
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use std::path::PathBuf;
+    use toml_edit::Document;
+
+    const CMD_TOML: &str = r#"[hostlist]
+[hosts]
+[cmd.pass]
+command = "true"
+[cmd.fail]
+command = "exit 3"
+"#;
+
+    const ALIAS_TOML: &str = r#"[hosts.m1]
+hostname = "10.0.0.3"
+username = "jozias"
+[[hosts.m1.alias]]
+command = "pass"
+aliasfor = "fail"
+"#;
+
+    #[test]
+    fn test_exit_codes() -> MusshResult<()> {
+        let config: Config = toml::from_str(CMD_TOML)?;
+        let cmd = Cmd::default();
+        assert_eq!(cmd.test(&config, "pass", None)?, 0);
+        assert_eq!(cmd.test(&config, "fail", None)?, 3);
+        assert!(cmd.test(&config, "nope", None).is_err());
+        // `false` ignores the command, so fails whatever it is.
+        assert_eq!(cmd.test(&config, "pass", Some("false"))?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_the_localhost_shell() -> MusshResult<()> {
+        let toml = CMD_TOML.replace(
+            "[hosts]\n",
+            "[hosts.local]\nhostname = \"localhost\"\nusername = \"jozias\"\nshell = \"false\"\n",
+        );
+        let config: Config = toml::from_str(&toml)?;
+        let cmd = Cmd::new(None, PathBuf::new(), toml::from_str(&toml)?);
+        assert_eq!(cmd.test(&config, "pass", Some("sh"))?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn add_update_and_remove() -> MusshResult<()> {
+        let toml = format!("# Commands\n{CMD_TOML}{ALIAS_TOML}");
+        let mut document: Document = toml.parse().map_err(|_| "bad toml")?;
+        set(&mut document, "ls", "ls -al", false)?;
+        assert!(set(&mut document, "ls", "ls", false).is_err());
+        set(&mut document, "pass", "exit 0", true)?;
+        assert!(set(&mut document, "nope", "ls", true).is_err());
+        assert!(remove(&mut document, "fail").is_err());
+        remove(&mut document, "ls")?;
+        assert!(remove(&mut document, "ls").is_err());
+
+        let edited = document.to_string();
+        assert!(edited.starts_with("# Commands\n"));
+        let config: Config = toml::from_str(&edited)?;
+        let commands: Vec<(&str, &str)> = config
+            .cmd()
+            .iter()
+            .map(|(name, cmd)| (name.as_str(), cmd.command().as_str()))
+            .collect();
+        assert_eq!(commands, vec![("fail", "exit 3"), ("pass", "exit 0")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! config subcommand
+use crate::config_format;
+use crate::error::MusshResult;
+use crate::fragments;
+use crate::run;
+use crate::subcmd::Subcommand;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use libmussh::Config;
+use serde_json::{json, Value};
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+#[derive(Clone, Default)]
+pub(crate) struct ConfigCmd {
+    stderr: Option<Logger>,
+}
+
+impl ConfigCmd {
+    pub(crate) fn new(stderr: Option<Logger>) -> Self {
+        Self { stderr }
+    }
+}
+
+impl Subcommand for ConfigCmd {
+    fn subcommand<'a, 'b>() -> App<'a, 'b> {
+        SubCommand::with_name("config")
+            .about("Work with the config as a whole")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("diff")
+                    .about(
+                        "Show the hosts, hostlists and commands added, removed or changed in \
+                         another config",
+                    )
+                    .arg(
+                        Arg::with_name("other")
+                            .value_name("OTHER")
+                            .help(
+                                "The config to compare with, or a directory with a mussh.toml \
+                                 or mussh.yaml",
+                            )
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("format")
+                            .long("format")
+                            .value_name("FORMAT")
+                            .help("Print the differences for people, or as JSON for tools")
+                            .possible_values(&["human", "json"])
+                            .default_value("human"),
+                    ),
+            )
+    }
+
+    fn execute(&self, config: &Config, matches: &ArgMatches<'_>) -> MusshResult<()> {
+        match matches.subcommand() {
+            ("diff", Some(sub_m)) => {
+                let mut other_path = PathBuf::from(sub_m.value_of("other").unwrap_or_default());
+                if other_path.is_dir() {
+                    other_path = config_format::config_path(&other_path);
+                }
+                let contents = fragments::with_fragments(
+                    &other_path,
+                    config_format::read(&other_path)?,
+                    self.stderr.as_ref(),
+                )?;
+                let (other, ..) =
+                    run::load_config(&other_path, &contents, env::vars(), self.stderr.as_ref())?;
+
+                let diff = ConfigDiff::new(config, &other);
+                if sub_m.value_of("format") == Some("json") {
+                    println!("{}", serde_json::to_string_pretty(&diff.json())?);
+                } else {
+                    print!("{}", diff.human());
+                }
+                Ok(())
+            }
+            (cmd, _) => Err(format!("Unknown subcommand {cmd}").into()),
+        }
+    }
+}
+
+/// The keys of a config section that differ in another config.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct SectionDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<String>,
+}
+
+impl SectionDiff {
+    fn new<T: PartialEq>(ours: &BTreeMap<String, T>, theirs: &BTreeMap<String, T>) -> Self {
+        let mut diff = Self::default();
+        for (key, value) in ours {
+            match theirs.get(key) {
+                None => diff.removed.push(key.clone()),
+                Some(other) if other != value => diff.changed.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.added = theirs
+            .keys()
+            .filter(|key| !ours.contains_key(*key))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    fn json(&self) -> Value {
+        json!({ "added": self.added, "removed": self.removed, "changed": self.changed })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// How another config differs from this one, by key.
+///
+/// Both configs are compared as loaded, so formatting, comments and the order
+/// of the entries make no difference.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct ConfigDiff {
+    hosts: SectionDiff,
+    hostlists: SectionDiff,
+    cmds: SectionDiff,
+}
+
+impl ConfigDiff {
+    fn new(ours: &Config, theirs: &Config) -> Self {
+        Self {
+            hosts: SectionDiff::new(ours.hosts(), theirs.hosts()),
+            hostlists: SectionDiff::new(ours.hostlist(), theirs.hostlist()),
+            cmds: SectionDiff::new(ours.cmd(), theirs.cmd()),
+        }
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "hosts": self.hosts.json(),
+            "hostlists": self.hostlists.json(),
+            "cmds": self.cmds.json(),
+        })
+    }
+
+    /// A `+`, `-` or `~` line per added, removed or changed key, under the
+    /// section it is in.
+    fn human(&self) -> String {
+        let sections = [
+            ("hosts", &self.hosts),
+            ("hostlists", &self.hostlists),
+            ("cmds", &self.cmds),
+        ];
+        let mut output = String::new();
+        for (name, section) in &sections {
+            if section.is_empty() {
+                continue;
+            }
+            let _res = writeln!(output, "{name}:");
+            for (marker, keys) in &[
+                ('+', &section.added),
+                ('-', &section.removed),
+                ('~', &section.changed),
+            ] {
+                for key in *keys {
+                    let _res = writeln!(output, "  {marker} {key}");
+                }
+            }
+        }
+        if output.is_empty() {
+            output.push_str("The configs are the same\n");
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConfigDiff;
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use serde_json::json;
+
+    const OURS_TOML: &str = r#"[hostlist.all]
+hostnames = ["web", "db"]
+[hosts.web]
+hostname = "10.0.0.3"
+username = "jozias"
+[hosts.db]
+hostname = "10.0.0.4"
+username = "jozias"
+[cmd.ls]
+command = "ls"
+"#;
+
+    const THEIRS_TOML: &str = r#"[cmd.ls]
+command = "ls -al"
+[hosts.db]
+username = "jozias"
+hostname = "10.0.0.4"
+[hosts.app]
+hostname = "10.0.0.5"
+username = "jozias"
+[hostlist.all]
+hostnames = ["web", "db"]
+"#;
+
+    #[test]
+    fn diffs_by_key() -> MusshResult<()> {
+        let ours: Config = toml::from_str(OURS_TOML)?;
+        let theirs: Config = toml::from_str(THEIRS_TOML)?;
+        let diff = ConfigDiff::new(&ours, &theirs);
+
+        assert_eq!(
+            diff.json(),
+            json!({
+                "hosts": { "added": ["app"], "removed": ["web"], "changed": [] },
+                "hostlists": { "added": [], "removed": [], "changed": [] },
+                "cmds": { "added": [], "removed": [], "changed": ["ls"] },
+            })
+        );
+        assert_eq!(diff.human(), "hosts:\n  + app\n  - web\ncmds:\n  ~ ls\n");
+        assert_eq!(
+            ConfigDiff::new(&ours, &ours).human(),
+            "The configs are the same\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! hostlist subcommand
+use crate::config_file;
+use crate::error::MusshResult;
+use crate::subcmd::Subcommand;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use libmussh::Config;
+use slog::Logger;
+use slog_try::{try_trace, try_warn};
+use std::path::PathBuf;
+use toml_edit::{table, value, Array, Document, Item};
+
+#[derive(Clone, Default)]
+pub(crate) struct Hostlist {
+    stdout: Option<Logger>,
+    stderr: Option<Logger>,
+    config_path: PathBuf,
+}
+
+impl Hostlist {
+    pub(crate) fn new(
+        stdout: Option<Logger>,
+        stderr: Option<Logger>,
+        config_path: PathBuf,
+    ) -> Self {
+        Self {
+            stdout,
+            stderr,
+            config_path,
+        }
+    }
+
+    /// Warn about any of the given names that aren't configured hosts or
+    /// hostlists.
+    fn check_hosts(&self, config: &Config, names: &[&str]) {
+        for name in names {
+            let name = name.trim_start_matches('!');
+            if !config.hosts().contains_key(name) && !config.hostlist().contains_key(name) {
+                try_warn!(
+                    self.stderr,
+                    "'{}' is not a configured host or hostlist",
+                    name
+                );
+            }
+        }
+    }
+
+    fn edit<F>(&self, edit: F) -> MusshResult<()>
+    where
+        F: FnOnce(&mut Document) -> MusshResult<()>,
+    {
+        let mut document = config_file::load(&self.config_path)?;
+        edit(&mut document)?;
+        try_trace!(self.stdout, "Writing config"; "path" => self.config_path.display().to_string());
+        config_file::save(&self.config_path, &document)
+    }
+}
+
+impl Subcommand for Hostlist {
+    fn subcommand<'a, 'b>() -> App<'a, 'b> {
+        let name_arg = Arg::with_name("name")
+            .value_name("NAME")
+            .help("The name of the hostlist")
+            .required(true);
+        let hostnames_arg = Arg::with_name("hostnames")
+            .value_name("HOSTNAMES")
+            .help("The hosts and hostlists in the hostlist, !<name> to leave one out")
+            .multiple(true)
+            .required(true);
+
+        SubCommand::with_name("hostlist")
+            .about("Work with the configured hostlists")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("add")
+                    .about("Add a hostlist")
+                    .arg(name_arg.clone())
+                    .arg(hostnames_arg.clone()),
+            )
+            .subcommand(
+                SubCommand::with_name("update")
+                    .about("Replace the hosts of a hostlist")
+                    .arg(name_arg.clone())
+                    .arg(hostnames_arg),
+            )
+            .subcommand(
+                SubCommand::with_name("remove")
+                    .about("Remove a hostlist that no other hostlist includes")
+                    .arg(name_arg),
+            )
+            .subcommand(SubCommand::with_name("list").about("List the hostlists"))
+    }
+
+    fn execute(&self, config: &Config, matches: &ArgMatches<'_>) -> MusshResult<()> {
+        match matches.subcommand() {
+            ("add", Some(sub_m)) => {
+                let name = sub_m.value_of("name").unwrap_or_default();
+                let hostnames: Vec<&str> =
+                    sub_m.values_of("hostnames").into_iter().flatten().collect();
+                self.check_hosts(config, &hostnames);
+                self.edit(|document| set(document, name, &hostnames, false))?;
+                println!("Added hostlist '{name}'");
+                Ok(())
+            }
+            ("update", Some(sub_m)) => {
+                let name = sub_m.value_of("name").unwrap_or_default();
+                let hostnames: Vec<&str> =
+                    sub_m.values_of("hostnames").into_iter().flatten().collect();
+                self.check_hosts(config, &hostnames);
+                self.edit(|document| set(document, name, &hostnames, true))?;
+                println!("Updated hostlist '{name}'");
+                Ok(())
+            }
+            ("remove", Some(sub_m)) => {
+                let name = sub_m.value_of("name").unwrap_or_default();
+                self.edit(|document| remove(document, name))?;
+                println!("Removed hostlist '{name}'");
+                Ok(())
+            }
+            ("list", Some(_)) => {
+                for (name, hostlist) in config.hostlist() {
+                    println!("{name}: {}", hostlist.hostnames().join(", "));
+                }
+                Ok(())
+            }
+            (cmd, _) => Err(format!("Unknown subcommand {cmd}").into()),
+        }
+    }
+}
+
+/// Set the hosts of the hostlist `name`, which must already exist to update
+/// it, and mustn't to add it.
+fn set(document: &mut Document, name: &str, hostnames: &[&str], update: bool) -> MusshResult<()> {
+    let hostlists = config_file::section(document, "hostlist")?;
+    let hostlist = match (hostlists.contains_key(name), update) {
+        (true, true) => hostlists.get_mut(name).ok_or("no hostlist")?,
+        (false, false) => hostlists.entry(name).or_insert(table()),
+        (true, false) => {
+            return Err(format!(
+                "Hostlist '{name}' already exists, use hostlist update to change it"
+            )
+            .into())
+        }
+        (false, true) => return Err(format!("Unknown hostlist '{name}'").into()),
+    };
+    hostlist["hostnames"] = value(hostnames.iter().copied().collect::<Array>());
+    Ok(())
+}
+
+/// Remove the hostlist `name`, unless another hostlist still includes it.
+fn remove(document: &mut Document, name: &str) -> MusshResult<()> {
+    let hostlists = document.get("hostlist").and_then(Item::as_table_like);
+    for (hostlist, item) in hostlists.into_iter().flat_map(|hostlists| hostlists.iter()) {
+        let includes = item
+            .get("hostnames")
+            .and_then(Item::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|hostname| hostname.as_str())
+            .any(|hostname| hostname.trim_start_matches('!') == name);
+        if includes && hostlist != name {
+            return Err(format!(
+                "Hostlist '{name}' is included by hostlist '{hostlist}', update it first"
+            )
+            .into());
+        }
+    }
+
+    let _hostlist = config_file::remove(document, "hostlist", name)?
+        .ok_or_else(|| format!("Unknown hostlist '{name}'"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{remove, set};
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use toml_edit::Document;
+
+    const HOSTLIST_TOML: &str = r#"[hostlist.all]
+hostnames = ["m1", "m2"]
+[hostlist.most]
+hostnames = ["all", "!m1"]
+
+[hosts.m1]
+hostname = "10.0.0.3"
+username = "jozias"
+
+[hosts.m2]
+hostname = "10.0.0.4"
+username = "jozias"
+
+[cmd.ls]
+command = "ls"
+"#;
+
+    #[test]
+    fn add_update_and_remove() -> MusshResult<()> {
+        let mut document: Document = HOSTLIST_TOML.parse().map_err(|_| "bad toml")?;
+        set(&mut document, "one", &["m1"], false)?;
+        assert!(set(&mut document, "one", &["m2"], false).is_err());
+        set(&mut document, "most", &["all", "!m2"], true)?;
+        assert!(set(&mut document, "none", &["m2"], true).is_err());
+        assert!(remove(&mut document, "all").is_err());
+        remove(&mut document, "most")?;
+        assert!(remove(&mut document, "most").is_err());
+        remove(&mut document, "all")?;
+
+        let config: Config = toml::from_str(&document.to_string())?;
+        let hostlists: Vec<(&str, &[String])> = config
+            .hostlist()
+            .iter()
+            .map(|(name, hostlist)| (name.as_str(), hostlist.hostnames().as_slice()))
+            .collect();
+        assert_eq!(hostlists, vec![("one", &["m1".to_string()][..])]);
+        assert_eq!(config.hosts().len(), 2);
+        Ok(())
+    }
+}
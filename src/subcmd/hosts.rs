@@ -0,0 +1,397 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! hosts subcommand
+use crate::config_file;
+use crate::error::MusshResult;
+use crate::subcmd::Subcommand;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use libmussh::Config;
+use slog::Logger;
+use slog_try::try_trace;
+use std::path::PathBuf;
+use toml_edit::{table, value, Document, Item, Value};
+
+#[derive(Clone, Default)]
+pub(crate) struct Hosts {
+    stdout: Option<Logger>,
+    config_path: PathBuf,
+}
+
+impl Hosts {
+    pub(crate) fn new(stdout: Option<Logger>, config_path: PathBuf) -> Self {
+        Self {
+            stdout,
+            config_path,
+        }
+    }
+
+    fn edit<F>(&self, edit: F) -> MusshResult<()>
+    where
+        F: FnOnce(&mut Document) -> MusshResult<()>,
+    {
+        let mut document = config_file::load(&self.config_path)?;
+        edit(&mut document)?;
+        try_trace!(self.stdout, "Writing config"; "path" => self.config_path.display().to_string());
+        config_file::save(&self.config_path, &document)
+    }
+}
+
+/// The fields of a host that can be set from the command line.
+const FIELDS: [&str; 4] = ["hostname", "username", "port", "pem"];
+
+/// The options setting the fields of a host, the hostname required if `add`.
+fn field_args<'a, 'b>(add: bool) -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("hostname")
+            .long("hostname")
+            .value_name("HOSTNAME")
+            .help("The hostname or address to connect to")
+            .required(add),
+        Arg::with_name("username")
+            .long("username")
+            .value_name("USERNAME")
+            .help("The user to connect as"),
+        Arg::with_name("port")
+            .long("port")
+            .value_name("PORT")
+            .help("The port to connect to"),
+        Arg::with_name("pem")
+            .long("pem")
+            .value_name("PEM")
+            .help("The private key to authenticate with"),
+    ]
+}
+
+/// The fields given on the command line, by name.
+fn fields(matches: &ArgMatches<'_>) -> MusshResult<Vec<(&'static str, Value)>> {
+    let mut fields = Vec::new();
+    for field in &FIELDS {
+        let Some(given) = matches.value_of(field) else {
+            continue;
+        };
+        let given = if *field == "port" {
+            let port = given
+                .parse::<u16>()
+                .ok()
+                .filter(|port| *port > 0)
+                .ok_or_else(|| format!("Invalid port '{given}', expected 1-65535"))?;
+            Value::from(i64::from(port))
+        } else {
+            Value::from(given)
+        };
+        fields.push((*field, given));
+    }
+    Ok(fields)
+}
+
+/// Set the fields of the host `name`, which must already exist to update it,
+/// and mustn't to add it.
+fn set(
+    document: &mut Document,
+    name: &str,
+    fields: &[(&str, Value)],
+    update: bool,
+) -> MusshResult<()> {
+    let hosts = config_file::section(document, "hosts")?;
+    let host = match (hosts.contains_key(name), update) {
+        (true, true) => hosts.get_mut(name).ok_or("no host")?,
+        (false, false) => hosts.entry(name).or_insert(table()),
+        (true, false) => {
+            return Err(
+                format!("Host '{name}' already exists, use hosts update to change it").into(),
+            )
+        }
+        (false, true) => return Err(format!("Unknown host '{name}'").into()),
+    };
+    for (field, given) in fields {
+        host[*field] = value(given.clone());
+    }
+    Ok(())
+}
+
+/// Remove the host `name`, and remove it from every hostlist that names it (or
+/// excludes it with `!`).  Returns the names of the hostlists updated.
+fn remove(document: &mut Document, name: &str) -> MusshResult<Vec<String>> {
+    let _host = config_file::remove(document, "hosts", name)?
+        .ok_or_else(|| format!("Unknown host '{name}'"))?;
+
+    let mut updated = Vec::new();
+    let hostlists = document
+        .get_mut("hostlist")
+        .and_then(Item::as_table_like_mut);
+    for (hostlist, hostnames) in hostlists
+        .into_iter()
+        .flat_map(|hostlists| hostlists.iter_mut())
+        .filter_map(|(hostlist, item)| Some((hostlist, item.get_mut("hostnames")?.as_array_mut()?)))
+    {
+        let before = hostnames.len();
+        hostnames.retain(|hostname| {
+            hostname
+                .as_str()
+                .is_none_or(|hostname| hostname.trim_start_matches('!') != name)
+        });
+        if hostnames.len() != before {
+            updated.push(hostlist.to_string());
+        }
+    }
+    Ok(updated)
+}
+
+impl Subcommand for Hosts {
+    fn subcommand<'a, 'b>() -> App<'a, 'b> {
+        let name_arg = Arg::with_name("name")
+            .value_name("NAME")
+            .help("The name of the host")
+            .required(true);
+
+        SubCommand::with_name("hosts")
+            .about("Work with the configured hosts")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("add")
+                    .about("Add a host")
+                    .arg(name_arg.clone())
+                    .args(&field_args(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("update")
+                    .about("Change the given fields of a host")
+                    .arg(name_arg.clone())
+                    .args(&field_args(false))
+                    .group(
+                        ArgGroup::with_name("fields")
+                            .args(&FIELDS)
+                            .multiple(true)
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("remove")
+                    .about("Remove a host, and remove it from every hostlist that includes it")
+                    .arg(name_arg),
+            )
+            .subcommand(SubCommand::with_name("list").about("List the hosts"))
+            .subcommand(
+                SubCommand::with_name("rename")
+                    .about("Rename a host, updating every hostlist that includes it")
+                    .arg(
+                        Arg::with_name("old")
+                            .value_name("OLD")
+                            .help("The host to rename")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("new")
+                            .value_name("NEW")
+                            .help("The new name of the host")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("force")
+                            .long("force")
+                            .help("Replace the host named NEW if it already exists"),
+                    ),
+            )
+    }
+
+    fn execute(&self, config: &Config, matches: &ArgMatches<'_>) -> MusshResult<()> {
+        match matches.subcommand() {
+            ("add", Some(sub_m)) => {
+                let name = sub_m.value_of("name").unwrap_or_default();
+                let fields = fields(sub_m)?;
+                self.edit(|document| set(document, name, &fields, false))?;
+                println!("Added host '{name}'");
+                Ok(())
+            }
+            ("update", Some(sub_m)) => {
+                let name = sub_m.value_of("name").unwrap_or_default();
+                let fields = fields(sub_m)?;
+                self.edit(|document| set(document, name, &fields, true))?;
+                println!("Updated host '{name}'");
+                Ok(())
+            }
+            ("remove", Some(sub_m)) => {
+                let name = sub_m.value_of("name").unwrap_or_default();
+                let mut hostlists = Vec::new();
+                self.edit(|document| {
+                    hostlists = remove(document, name)?;
+                    Ok(())
+                })?;
+                println!("Removed host '{name}'");
+                for hostlist in hostlists {
+                    println!("Updated hostlist '{hostlist}'");
+                }
+                Ok(())
+            }
+            ("list", Some(_)) => {
+                for (name, host) in config.hosts() {
+                    println!(
+                        "{name}: {}@{}:{}",
+                        host.username(),
+                        host.hostname(),
+                        host.port().unwrap_or(22)
+                    );
+                }
+                Ok(())
+            }
+            ("rename", Some(sub_m)) => {
+                let old = sub_m.value_of("old").unwrap_or_default();
+                let new = sub_m.value_of("new").unwrap_or_default();
+                let mut hostlists = Vec::new();
+                self.edit(|document| {
+                    hostlists = rename(document, old, new, sub_m.is_present("force"))?;
+                    Ok(())
+                })?;
+
+                println!("Renamed host '{old}' to '{new}'");
+                for hostlist in hostlists {
+                    println!("Updated hostlist '{hostlist}'");
+                }
+                Ok(())
+            }
+            (cmd, _) => Err(format!("Unknown subcommand {cmd}").into()),
+        }
+    }
+}
+
+/// Rename the host `old` to `new`, including in every hostlist that names it
+/// (or excludes it with `!`).  Returns the names of the hostlists updated.
+fn rename(document: &mut Document, old: &str, new: &str, force: bool) -> MusshResult<Vec<String>> {
+    let hosts = document
+        .get_mut("hosts")
+        .and_then(|hosts| hosts.as_table_like_mut())
+        .ok_or("The config has no hosts")?;
+    if hosts.contains_key(new) && !force {
+        return Err(format!("Host '{new}' already exists, use --force to replace it").into());
+    }
+    let host = hosts
+        .remove(old)
+        .ok_or_else(|| format!("Unknown host '{old}'"))?;
+    let _old = hosts.insert(new, host);
+
+    let mut updated = Vec::new();
+    let hostlists = document
+        .get_mut("hostlist")
+        .and_then(|hostlists| hostlists.as_table_like_mut());
+
+    for (name, hostlist) in hostlists
+        .into_iter()
+        .flat_map(|hostlists| hostlists.iter_mut())
+    {
+        let hostnames = hostlist
+            .get_mut("hostnames")
+            .and_then(|hostnames| hostnames.as_array_mut());
+        let mut renamed = false;
+
+        for hostname in hostnames
+            .into_iter()
+            .flat_map(|hostnames| hostnames.iter_mut())
+        {
+            let replacement = match hostname.as_str() {
+                Some(value) if value == old => new.to_string(),
+                Some(value) if value.strip_prefix('!') == Some(old) => format!("!{new}"),
+                _ => continue,
+            };
+            let decor = hostname.decor().clone();
+            *hostname = Value::from(replacement);
+            *hostname.decor_mut() = decor;
+            renamed = true;
+        }
+
+        if renamed {
+            updated.push(name.to_string());
+        }
+    }
+
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{remove, rename, set};
+    use crate::error::MusshResult;
+    use libmussh::Config;
+    use toml_edit::{Document, Value};
+
+    const HOSTS_TOML: &str = r#"[hostlist.all]
+hostnames = ["m1", "m2"]
+[hostlist.most]
+hostnames = ["all", "!m1"]
+[hostlist.other]
+hostnames = ["m2"]
+
+# The first box
+[hosts.m1]
+hostname = "10.0.0.3"
+username = "jozias"
+
+[hosts.m2]
+hostname = "10.0.0.4"
+username = "jozias"
+
+[cmd.ls]
+command = "ls"
+"#;
+
+    #[test]
+    fn rename_updates_hostlists() -> MusshResult<()> {
+        let mut document: Document = HOSTS_TOML.parse().map_err(|_| "bad toml")?;
+        let updated = rename(&mut document, "m1", "web1", false)?;
+        assert_eq!(updated, vec!["all", "most"]);
+
+        let renamed = document.to_string();
+        assert!(renamed.contains(r#"hostnames = ["web1", "m2"]"#));
+        assert!(renamed.contains(r#"hostnames = ["all", "!web1"]"#));
+        assert!(renamed.contains("# The first box"));
+
+        let config: Config = toml::from_str(&renamed)?;
+        assert!(config.hosts().contains_key("web1"));
+        assert!(!config.hosts().contains_key("m1"));
+        Ok(())
+    }
+
+    #[test]
+    fn rename_existing_needs_force() -> MusshResult<()> {
+        let mut document: Document = HOSTS_TOML.parse().map_err(|_| "bad toml")?;
+        assert!(rename(&mut document, "m1", "m2", false).is_err());
+        assert!(rename(&mut document, "nope", "m3", false).is_err());
+        let updated = rename(&mut document, "m1", "m2", true)?;
+        assert_eq!(updated, vec!["all", "most"]);
+        let config: Config = toml::from_str(&document.to_string())?;
+        assert_eq!(config.hosts().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn add_update_and_remove() -> MusshResult<()> {
+        let mut document: Document = HOSTS_TOML.parse().map_err(|_| "bad toml")?;
+        let fields = [
+            ("hostname", Value::from("10.0.0.5")),
+            ("username", Value::from("jozias")),
+        ];
+        set(&mut document, "m3", &fields, false)?;
+        assert!(set(&mut document, "m3", &fields, false).is_err());
+        set(&mut document, "m1", &[("port", Value::from(2222))], true)?;
+        assert!(set(&mut document, "m4", &fields, true).is_err());
+        assert_eq!(remove(&mut document, "m2")?, vec!["all", "other"]);
+        assert!(remove(&mut document, "m2").is_err());
+
+        let edited = document.to_string();
+        assert!(edited.contains("# The first box"));
+        assert!(edited.contains(r#"hostnames = ["m1"]"#));
+        let config: Config = toml::from_str(&edited)?;
+        let m1 = config.hosts().get("m1").ok_or("no m1 host")?;
+        assert_eq!(*m1.port(), Some(2222));
+        assert_eq!(m1.hostname(), "10.0.0.3");
+        let m3 = config.hosts().get("m3").ok_or("no m3 host")?;
+        assert_eq!(m3.hostname(), "10.0.0.5");
+        assert!(!config.hosts().contains_key("m2"));
+        Ok(())
+    }
+}
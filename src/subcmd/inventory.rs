@@ -0,0 +1,159 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! inventory subcommand
+use crate::error::MusshResult;
+use crate::subcmd::Subcommand;
+use crate::targets;
+use crate::warnings::Warnings;
+use clap::{App, AppSettings, ArgMatches, SubCommand};
+use libmussh::Config;
+use serde_json::{json, Map, Value};
+use slog::Logger;
+
+/// The port ssh connects to when a host doesn't give one.
+const DEFAULT_PORT: u16 = 22;
+
+#[derive(Clone, Default)]
+pub(crate) struct Inventory {
+    stderr: Option<Logger>,
+}
+
+impl Inventory {
+    pub(crate) fn new(stderr: Option<Logger>) -> Self {
+        Self { stderr }
+    }
+}
+
+impl Subcommand for Inventory {
+    fn subcommand<'a, 'b>() -> App<'a, 'b> {
+        SubCommand::with_name("inventory")
+            .about("Work with the configured hosts, hostlists and commands as a whole")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("dump")
+                    .about("Print the hosts, hostlists and commands as one JSON document"),
+            )
+    }
+
+    fn execute(&self, config: &Config, matches: &ArgMatches<'_>) -> MusshResult<()> {
+        match matches.subcommand() {
+            ("dump", Some(_)) => {
+                let mut warnings = Warnings::default();
+                let inventory = dump(config, &mut warnings)?;
+                warnings.emit(self.stderr.as_ref(), false)?;
+                println!("{}", serde_json::to_string_pretty(&inventory)?);
+                Ok(())
+            }
+            (cmd, _) => Err(format!("Unknown subcommand {cmd}").into()),
+        }
+    }
+}
+
+/// The inventory as JSON.
+///
+/// Hosts have their defaults filled in, and each hostlist is expanded to the
+/// hosts it selects, in order, as `run --hosts` would select them.  Unknown
+/// names in hostlists are skipped and noted in `warnings`.
+fn dump(config: &Config, warnings: &mut Warnings) -> MusshResult<Value> {
+    let hosts: Map<String, Value> = config
+        .hosts()
+        .iter()
+        .map(|(name, host)| {
+            let aliases: Vec<Value> = host
+                .alias()
+                .iter()
+                .flatten()
+                .map(|alias| json!({ "aliasfor": alias.aliasfor(), "command": alias.command() }))
+                .collect();
+            let host = json!({
+                "hostname": host.hostname(),
+                "username": host.username(),
+                "port": host.port().unwrap_or(DEFAULT_PORT),
+                "pem": host.pem(),
+                "alias": aliases,
+            });
+            (name.clone(), host)
+        })
+        .collect();
+
+    let mut hostlists = Map::new();
+    for name in config.hostlist().keys() {
+        let names: Vec<String> = targets::resolve_targets(config, &[name.as_str()], warnings)?
+            .into_iter()
+            .map(|host| host.name().clone())
+            .collect();
+        let _old = hostlists.insert(name.clone(), json!(names));
+    }
+
+    let cmds: Map<String, Value> = config
+        .cmd()
+        .iter()
+        .map(|(name, cmd)| (name.clone(), json!({ "command": cmd.command() })))
+        .collect();
+
+    Ok(json!({ "hosts": hosts, "hostlists": hostlists, "cmds": cmds }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::dump;
+    use crate::error::MusshResult;
+    use crate::warnings::Warnings;
+    use libmussh::Config;
+    use serde_json::json;
+
+    const INVENTORY_TOML: &str = r#"[hostlist.all]
+hostnames = ["web", "db"]
+[hostlist.most]
+hostnames = ["db", "all", "gone"]
+[hosts.web]
+hostname = "10.0.0.3"
+username = "jozias"
+[[hosts.web.alias]]
+command = "ls_al"
+aliasfor = "ls"
+[hosts.db]
+hostname = "10.0.0.4"
+username = "jozias"
+port = 2222
+pem = "/home/jozias/.ssh/db"
+[cmd.ls]
+command = "ls"
+[cmd.ls_al]
+command = "ls -al"
+"#;
+
+    #[test]
+    fn dumps_the_inventory() -> MusshResult<()> {
+        let config: Config = toml::from_str(INVENTORY_TOML)?;
+        let mut warnings = Warnings::default();
+        let inventory = dump(&config, &mut warnings)?;
+
+        assert_eq!(
+            inventory["hosts"]["web"],
+            json!({
+                "hostname": "10.0.0.3",
+                "username": "jozias",
+                "port": 22,
+                "pem": null,
+                "alias": [{ "aliasfor": "ls", "command": "ls_al" }],
+            })
+        );
+        assert_eq!(inventory["hosts"]["db"]["port"], json!(2222));
+        assert_eq!(
+            inventory["hosts"]["db"]["pem"],
+            json!("/home/jozias/.ssh/db")
+        );
+        assert_eq!(inventory["hostlists"]["all"], json!(["web", "db"]));
+        assert_eq!(inventory["hostlists"]["most"], json!(["db", "web"]));
+        assert_eq!(inventory["cmds"]["ls_al"], json!({ "command": "ls -al" }));
+        assert!(warnings.emit(None, true).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! metrics subcommand
+use crate::error::MusshResult;
+use crate::metrics::{self, DurationStats, MetricsFilter, RunSummary};
+use crate::subcmd::Subcommand;
+use crate::util::{format_duration, table};
+use chrono::{TimeZone, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use libmussh::Config;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+/// How many runs `metrics runs` lists when `--limit` isn't given.
+const DEFAULT_RUN_LIMIT: usize = 10;
+
+#[derive(Clone, Default)]
+pub(crate) struct Metrics {
+    db_path: PathBuf,
+}
+
+impl Metrics {
+    pub(crate) fn new(db_path: PathBuf) -> Self {
+        Self { db_path }
+    }
+}
+
+impl Subcommand for Metrics {
+    fn subcommand<'a, 'b>() -> App<'a, 'b> {
+        SubCommand::with_name("metrics")
+            .about("Read back the metrics recorded by runs")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("runs")
+                    .about("List the most recent runs, newest first")
+                    .args(&filter_args())
+                    .arg(
+                        Arg::with_name("limit")
+                            .long("limit")
+                            .value_name("N")
+                            .help("List at most N runs, 10 by default"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("stats")
+                    .about(
+                        "Print the average, min and max durations of the successful runs of \
+                         each command on each host",
+                    )
+                    .args(&filter_args()),
+            )
+    }
+
+    fn execute(&self, _config: &Config, matches: &ArgMatches<'_>) -> MusshResult<()> {
+        match matches.subcommand() {
+            ("runs", Some(sub_m)) => {
+                let limit = match sub_m.value_of("limit") {
+                    Some(limit) => limit.parse::<usize>().map_err(|_| {
+                        format!("Invalid value '{limit}' for --limit, expected a number")
+                    })?,
+                    None => DEFAULT_RUN_LIMIT,
+                };
+                let runs = metrics::runs(&self.db_path, filter(sub_m), limit)?;
+                if sub_m.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&runs_json(&runs))?);
+                } else {
+                    print!("{}", runs_table(&runs));
+                }
+                Ok(())
+            }
+            ("stats", Some(sub_m)) => {
+                let stats = metrics::duration_stats(&self.db_path, filter(sub_m))?;
+                if sub_m.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&stats_json(&stats))?);
+                } else {
+                    print!("{}", stats_table(&stats));
+                }
+                Ok(())
+            }
+            (cmd, _) => Err(format!("Unknown subcommand {cmd}").into()),
+        }
+    }
+}
+
+fn filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("hostname")
+            .long("hostname")
+            .value_name("HOSTNAME")
+            .help("Only the metrics of the commands run on HOSTNAME"),
+        Arg::with_name("cmdname")
+            .long("cmdname")
+            .value_name("CMD")
+            .help("Only the metrics of the command CMD"),
+        Arg::with_name("json")
+            .long("json")
+            .help("Print the metrics as JSON, for scripting"),
+    ]
+}
+
+fn filter<'a>(matches: &'a ArgMatches<'_>) -> MetricsFilter<'a> {
+    MetricsFilter {
+        hostname: matches.value_of("hostname"),
+        cmdname: matches.value_of("cmdname"),
+    }
+}
+
+/// When a run started, in UTC.
+fn started_at(millis: i64) -> String {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .map_or_else(String::new, |started| {
+            started.format("%Y-%m-%d %H:%M:%S").to_string()
+        })
+}
+
+fn runs_table(runs: &[RunSummary]) -> String {
+    let rows = runs
+        .iter()
+        .map(|run| {
+            vec![
+                run.run_id().clone(),
+                started_at(*run.started_at()),
+                run.commands().to_string(),
+                run.failed().to_string(),
+                run.labels().join(","),
+            ]
+        })
+        .collect();
+    table(
+        &["RUN", "STARTED (UTC)", "COMMANDS", "FAILED", "LABELS"],
+        &[false, false, true, true, false],
+        rows,
+    )
+}
+
+fn runs_json(runs: &[RunSummary]) -> Value {
+    runs.iter()
+        .map(|run| {
+            json!({
+                "run_id": run.run_id(),
+                "started_at": run.started_at(),
+                "labels": run.labels(),
+                "commands": run.commands(),
+                "failed": run.failed(),
+            })
+        })
+        .collect()
+}
+
+fn stats_table(stats: &[DurationStats]) -> String {
+    let rows = stats
+        .iter()
+        .map(|stats| {
+            vec![
+                stats.hostname().clone(),
+                stats.cmdname().clone(),
+                stats.count().to_string(),
+                stats.failed().to_string(),
+                format_duration(stats.avg()),
+                format_duration(stats.min()),
+                format_duration(stats.max()),
+            ]
+        })
+        .collect();
+    table(
+        &["HOSTNAME", "CMD", "RUNS", "FAILED", "AVG", "MIN", "MAX"],
+        &[false, false, true, true, true, true, true],
+        rows,
+    )
+}
+
+fn stats_json(stats: &[DurationStats]) -> Value {
+    stats
+        .iter()
+        .map(|stats| {
+            json!({
+                "hostname": stats.hostname(),
+                "cmdname": stats.cmdname(),
+                "count": stats.count(),
+                "failed": stats.failed(),
+                "avg_ms": stats.avg().as_millis(),
+                "min_ms": stats.min().as_millis(),
+                "max_ms": stats.max().as_millis(),
+            })
+        })
+        .collect()
+}
@@ -11,8 +11,29 @@ use crate::error::MusshResult;
 use clap::{App, ArgMatches};
 use libmussh::Config;
 
+mod alias;
+mod check;
+mod cmd;
+mod config;
+mod hostlist;
+mod hosts;
+mod inventory;
+mod metrics;
+mod pull;
+mod push;
 mod run;
+mod transfer;
 
+pub(crate) use self::alias::Alias;
+pub(crate) use self::check::Check;
+pub(crate) use self::cmd::Cmd;
+pub(crate) use self::config::ConfigCmd;
+pub(crate) use self::hostlist::Hostlist;
+pub(crate) use self::hosts::Hosts;
+pub(crate) use self::inventory::Inventory;
+pub(crate) use self::metrics::Metrics;
+pub(crate) use self::pull::Pull;
+pub(crate) use self::push::Push;
 pub(crate) use self::run::Run;
 
 pub(crate) trait Subcommand {
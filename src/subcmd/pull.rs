@@ -0,0 +1,229 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! pull subcommand
+use crate::error::{MusshErr, MusshResult};
+use crate::extensions::Extensions;
+use crate::local;
+use crate::subcmd::transfer::{self, Connections, HostConnection};
+use crate::subcmd::Subcommand;
+use crate::targets::ResolvedHost;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use libmussh::Config;
+use slog::Logger;
+use ssh2::ErrorCode;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// `LIBSSH2_FX_NO_SUCH_FILE`, the sftp status for a path that doesn't exist.
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+#[derive(Clone, Default)]
+pub(crate) struct Pull {
+    stderr: Option<Logger>,
+    extensions: Extensions,
+}
+
+impl Pull {
+    pub(crate) fn new(stderr: Option<Logger>, extensions: Extensions) -> Self {
+        Self { stderr, extensions }
+    }
+}
+
+/// What is pulled, and where to.
+#[derive(Clone, Debug)]
+struct PullFile {
+    /// The path on the hosts.
+    remote: String,
+    /// The local directory that gets a directory per host.
+    out_dir: PathBuf,
+}
+
+impl PullFile {
+    /// Where the copy from the host goes, `<out_dir>/<host>/<file name>`.
+    fn local(&self, host: &str) -> MusshResult<PathBuf> {
+        let name = Path::new(&self.remote)
+            .file_name()
+            .ok_or_else(|| format!("'{}' doesn't name a file", self.remote))?;
+        Ok(self.out_dir.join(host).join(name))
+    }
+}
+
+impl Subcommand for Pull {
+    fn subcommand<'a, 'b>() -> App<'a, 'b> {
+        SubCommand::with_name("pull")
+            .about("Copy a file from each host over sftp, into a directory per host")
+            .arg(transfer::hosts_arg(
+                "The hosts to copy the file from, with ranges like web[01-10] and globs like \
+                 web-*, as with run (comma separated, or give -h again)",
+            ))
+            .arg(
+                Arg::with_name("remote")
+                    .value_name("REMOTE")
+                    .help(
+                        "The file to copy from each host, relative to the home directory of the \
+                         user unless absolute",
+                    )
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("out_dir")
+                    .value_name("OUTDIR")
+                    .help(
+                        "Where to copy it to, as OUTDIR/<host>/<file name>, replacing a copy \
+                         pulled before",
+                    )
+                    .required(true),
+            )
+            .args(&transfer::connection_args())
+    }
+
+    fn execute(&self, config: &Config, matches: &ArgMatches<'_>) -> MusshResult<()> {
+        let hosts = transfer::hosts(config, matches, self.stderr.as_ref())?;
+        let file = PullFile {
+            remote: matches.value_of("remote").unwrap_or_default().to_string(),
+            out_dir: PathBuf::from(matches.value_of("out_dir").unwrap_or_default()),
+        };
+        // Fail before connecting to any host if REMOTE doesn't name a file.
+        let _local = file.local("")?;
+        let connections = Connections::from_args(&self.extensions, matches)?;
+        transfer::report(&transfer::copy_all(
+            &hosts,
+            &connections,
+            move |host, connection| pull(host, &file, connection),
+        ))
+    }
+}
+
+/// Pull the file from the host over sftp, or copy it for `localhost`,
+/// returning how many bytes were copied.  A file missing on the host fails
+/// only that host.
+fn pull(host: &ResolvedHost, file: &PullFile, connection: &HostConnection) -> MusshResult<u64> {
+    let local = file.local(host.name())?;
+    if host.hostname() == local::LOCALHOST {
+        return copy_local(file, &local);
+    }
+
+    let session = connection.session(host)?;
+    let sftp = session.sftp()?;
+    let remote = Path::new(&file.remote);
+    match sftp.stat(remote) {
+        Ok(stat) if stat.is_dir() => return Err(is_a_directory(&file.remote)),
+        Ok(_) => {}
+        Err(e) if e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => {
+            return Err(no_such_file(&file.remote))
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut remote_file = sftp.open(remote)?;
+    let bytes = transfer::copy(&session, &mut remote_file, &mut create(&local)?)?;
+    drop(remote_file);
+    session.disconnect(None, "mussh pull", None)?;
+    Ok(bytes)
+}
+
+fn copy_local(file: &PullFile, local: &Path) -> MusshResult<u64> {
+    match fs::metadata(&file.remote) {
+        Ok(metadata) if metadata.is_dir() => Err(is_a_directory(&file.remote)),
+        Ok(_) => Ok(io::copy(
+            &mut File::open(&file.remote)?,
+            &mut create(local)?,
+        )?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(no_such_file(&file.remote)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Create the local copy, and the directory of the host for it.
+fn create(local: &Path) -> MusshResult<File> {
+    if let Some(dir) = local.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(File::create(local)?)
+}
+
+fn no_such_file(remote: &str) -> MusshErr {
+    format!("'{remote}' doesn't exist").into()
+}
+
+fn is_a_directory(remote: &str) -> MusshErr {
+    format!("'{remote}' is a directory").into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pull, PullFile};
+    use crate::connect::ConnectTimeouts;
+    use crate::error::MusshResult;
+    use crate::known_hosts::{HostKeyCheck, HostKeys};
+    use crate::subcmd::transfer::{self, Connections};
+    use crate::targets;
+    use crate::warnings::Warnings;
+    use libmussh::Config;
+    use std::env;
+    use std::fs;
+
+    const PULL_TOML: &str = r#"[hostlist]
+[hosts.local]
+hostname = "localhost"
+username = "jozias"
+[cmd]
+"#;
+
+    #[test]
+    fn local_paths() -> MusshResult<()> {
+        let file = PullFile {
+            remote: "/var/log/app.log".to_string(),
+            out_dir: "logs".into(),
+        };
+        assert_eq!(file.local("web")?, std::path::Path::new("logs/web/app.log"));
+        let file = PullFile {
+            remote: "/".to_string(),
+            out_dir: "logs".into(),
+        };
+        assert!(file.local("web").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pulls_locally() -> MusshResult<()> {
+        let dir = env::temp_dir().join(format!("mussh-pull-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let remote = dir.join("app.log");
+        fs::write(&remote, "started\n")?;
+        let config: Config = toml::from_str(PULL_TOML)?;
+        let hosts = targets::resolve_targets(&config, &["local"], &mut Warnings::default())?;
+        let host_keys = HostKeys::new(HostKeyCheck::Off, dir.join("known_hosts"));
+        let connections = Connections::new(ConnectTimeouts::default(), host_keys, 1);
+        let pull_all = |remote: &std::path::Path| {
+            let file = PullFile {
+                remote: remote.display().to_string(),
+                out_dir: dir.join("out"),
+            };
+            transfer::copy_all(&hosts, &connections, move |host, connection| {
+                pull(host, &file, connection)
+            })
+            .remove(0)
+            .bytes
+        };
+
+        let pulled = pull_all(&remote);
+        let copy = fs::read_to_string(dir.join("out").join("local").join("app.log"))?;
+        let missing = pull_all(&dir.join("missing.log"));
+        let directory = pull_all(&dir);
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(pulled, Ok(8));
+        assert_eq!(copy, "started\n");
+        assert!(missing.is_err_and(|e| e.contains("missing.log' doesn't exist")));
+        assert!(directory.is_err_and(|e| e.contains("is a directory")));
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! push subcommand
+use crate::error::{MusshErr, MusshResult};
+use crate::extensions::Extensions;
+use crate::local;
+use crate::subcmd::transfer::{self, Connections, HostConnection};
+use crate::subcmd::Subcommand;
+use crate::targets::ResolvedHost;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use libmussh::Config;
+use slog::Logger;
+use ssh2::{FileStat, OpenFlags, OpenType, Sftp};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Default)]
+pub(crate) struct Push {
+    stderr: Option<Logger>,
+    extensions: Extensions,
+}
+
+impl Push {
+    pub(crate) fn new(stderr: Option<Logger>, extensions: Extensions) -> Self {
+        Self { stderr, extensions }
+    }
+}
+
+/// What is pushed, where to, and how.
+#[derive(Clone, Debug)]
+struct PushFile {
+    /// The local file.
+    local: PathBuf,
+    /// The path on the hosts.  An existing directory gets the file under its
+    /// own name.
+    remote: String,
+    /// The permission bits the file gets on the hosts.
+    mode: u32,
+    /// Replace a file already at the remote path.
+    overwrite: bool,
+}
+
+impl Subcommand for Push {
+    fn subcommand<'a, 'b>() -> App<'a, 'b> {
+        SubCommand::with_name("push")
+            .about("Copy a file to hosts over sftp")
+            .arg(transfer::hosts_arg(
+                "The hosts to copy the file to, with ranges like web[01-10] and globs like \
+                 web-*, as with run (comma separated, or give -h again)",
+            ))
+            .arg(
+                Arg::with_name("local")
+                    .value_name("LOCAL")
+                    .help("The file to copy")
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("remote")
+                    .value_name("REMOTE")
+                    .help(
+                        "Where to copy it to on each host, relative to the home directory of \
+                         the user unless absolute.  A directory gets the file under its own name",
+                    )
+                    .required(true),
+            )
+            .arg(Arg::with_name("mode").long("mode").value_name("MODE").help(
+                "The permission bits of the copies, in octal (i.e. 0644), the bits of \
+                 the local file by default",
+            ))
+            .arg(
+                Arg::with_name("overwrite")
+                    .long("overwrite")
+                    .help("Replace a file already on a host, which is refused otherwise"),
+            )
+            .args(&transfer::connection_args())
+    }
+
+    fn execute(&self, config: &Config, matches: &ArgMatches<'_>) -> MusshResult<()> {
+        let hosts = transfer::hosts(config, matches, self.stderr.as_ref())?;
+
+        let local = PathBuf::from(matches.value_of("local").unwrap_or_default());
+        let metadata = fs::metadata(&local)?;
+        if !metadata.is_file() {
+            return Err(format!("'{}' isn't a file", local.display()).into());
+        }
+        let file = PushFile {
+            local,
+            remote: matches.value_of("remote").unwrap_or_default().to_string(),
+            mode: match matches.value_of("mode") {
+                Some(mode) => parse_mode(mode)?,
+                None => metadata.permissions().mode() & 0o7777,
+            },
+            overwrite: matches.is_present("overwrite"),
+        };
+        let connections = Connections::from_args(&self.extensions, matches)?;
+        transfer::report(&transfer::copy_all(
+            &hosts,
+            &connections,
+            move |host, connection| push(host, &file, connection),
+        ))
+    }
+}
+
+/// Parse permission bits given in octal, with or without a leading `0` or
+/// `0o`.
+fn parse_mode(mode: &str) -> MusshResult<u32> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    match u32::from_str_radix(digits, 8) {
+        Ok(bits) if bits <= 0o7777 => Ok(bits),
+        _ => {
+            Err(format!("Invalid mode '{mode}', expected octal permission bits, i.e. 0644").into())
+        }
+    }
+}
+
+/// Push the file to the host over sftp, or copy it for `localhost`, returning
+/// how many bytes were copied.  The copy always ends up with the mode, whether
+/// it is new or replaces a file.
+fn push(host: &ResolvedHost, file: &PushFile, connection: &HostConnection) -> MusshResult<u64> {
+    if host.hostname() == local::LOCALHOST {
+        return copy_local(file);
+    }
+
+    let session = connection.session(host)?;
+    let sftp = session.sftp()?;
+    let remote = remote_path(&sftp, file);
+    if !file.overwrite && sftp.stat(&remote).is_ok() {
+        return Err(already_exists(&remote));
+    }
+
+    let mode = i32::try_from(file.mode).unwrap_or(0o644);
+    let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+    let mut remote_file = sftp.open_mode(&remote, flags, mode, OpenType::File)?;
+    let bytes = transfer::copy(&session, &mut File::open(&file.local)?, &mut remote_file)?;
+    drop(remote_file);
+    // The mode given when creating is masked by the server's umask, and isn't
+    // applied at all to a file that is replaced.
+    sftp.setstat(
+        &remote,
+        FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(file.mode),
+            atime: None,
+            mtime: None,
+        },
+    )?;
+    session.disconnect(None, "mussh push", None)?;
+    Ok(bytes)
+}
+
+/// The remote path, with the name of the local file added if it is a
+/// directory.
+fn remote_path(sftp: &Sftp, file: &PushFile) -> PathBuf {
+    let remote = PathBuf::from(&file.remote);
+    match sftp.stat(&remote) {
+        Ok(stat) if stat.is_dir() => in_dir(&remote, &file.local),
+        _ => remote,
+    }
+}
+
+fn copy_local(file: &PushFile) -> MusshResult<u64> {
+    let remote = PathBuf::from(&file.remote);
+    let remote = if remote.is_dir() {
+        in_dir(&remote, &file.local)
+    } else {
+        remote
+    };
+    if !file.overwrite && remote.exists() {
+        return Err(already_exists(&remote));
+    }
+    let bytes = fs::copy(&file.local, &remote)?;
+    fs::set_permissions(&remote, fs::Permissions::from_mode(file.mode))?;
+    Ok(bytes)
+}
+
+fn in_dir(dir: &Path, local: &Path) -> PathBuf {
+    match local.file_name() {
+        Some(name) => dir.join(name),
+        None => dir.to_path_buf(),
+    }
+}
+
+fn already_exists(remote: &Path) -> MusshErr {
+    format!(
+        "'{}' already exists, give --overwrite to replace it",
+        remote.display()
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_mode, push, PushFile};
+    use crate::connect::ConnectTimeouts;
+    use crate::error::MusshResult;
+    use crate::known_hosts::{HostKeyCheck, HostKeys};
+    use crate::subcmd::transfer::{self, Connections};
+    use crate::targets;
+    use crate::warnings::Warnings;
+    use libmussh::Config;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    const PUSH_TOML: &str = r#"[hostlist]
+[hosts.local]
+hostname = "localhost"
+username = "jozias"
+[cmd]
+"#;
+
+    #[test]
+    fn modes() -> MusshResult<()> {
+        assert_eq!(parse_mode("0644")?, 0o644);
+        assert_eq!(parse_mode("755")?, 0o755);
+        assert_eq!(parse_mode("0o4755")?, 0o4755);
+        assert!(parse_mode("0999").is_err());
+        assert!(parse_mode("17777").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pushes_locally() -> MusshResult<()> {
+        let dir = env::temp_dir().join(format!("mussh-push-{}", std::process::id()));
+        fs::create_dir_all(dir.join("remote"))?;
+        let local = dir.join("app.conf");
+        fs::write(&local, "listen = 80\n")?;
+        let config: Config = toml::from_str(PUSH_TOML)?;
+        let hosts = targets::resolve_targets(&config, &["local"], &mut Warnings::default())?;
+        let host_keys = HostKeys::new(HostKeyCheck::Off, dir.join("known_hosts"));
+        let connections = Connections::new(ConnectTimeouts::default(), host_keys, 1);
+        let mut file = PushFile {
+            local,
+            remote: dir.join("remote").display().to_string(),
+            mode: 0o600,
+            overwrite: false,
+        };
+        let push_all = |file: &PushFile| {
+            let file = file.clone();
+            transfer::copy_all(&hosts, &connections, move |host, connection| {
+                push(host, &file, connection)
+            })
+            .remove(0)
+            .bytes
+        };
+
+        let pushed = push_all(&file);
+        let copy = dir.join("remote").join("app.conf");
+        let mode = fs::metadata(&copy)?.permissions().mode() & 0o7777;
+        let refused = push_all(&file);
+        file.overwrite = true;
+        file.mode = 0o640;
+        let replaced = push_all(&file);
+        let replaced_mode = fs::metadata(&copy)?.permissions().mode() & 0o7777;
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(pushed, Ok(12));
+        assert_eq!(mode, 0o600);
+        assert!(refused.is_err_and(|e| e.contains("already exists")));
+        assert_eq!(replaced, Ok(12));
+        assert_eq!(replaced_mode, 0o640);
+        Ok(())
+    }
+}
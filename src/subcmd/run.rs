@@ -7,33 +7,543 @@
 // modified, or distributed except according to those terms.
 
 //! run subcommand
-use crate::error::MusshResult;
-use crate::logging::FileDrain;
+use crate::algorithms::{self, SshAlgorithms};
+use crate::auth;
+use crate::color::{host_colors, use_color};
+use crate::connect::{self, ConnectTimeouts, Family};
+use crate::error::{MusshErr, MusshErrKind, MusshResult};
+use crate::expect::Expectations;
+use crate::extensions::{Extensions, Options};
+use crate::format::{self, Formatter, Human};
+use crate::hash;
+use crate::jump;
+use crate::junit;
+use crate::known_hosts::{self, HostKeys};
+use crate::lines;
+use crate::local;
+use crate::logging::{
+    self, BlockDrain, BlockOutput, FileDrain, FilterDrain, LimitDrain, LogFile, OutputFilter,
+    OutputLimit, Rotation, Stream, SwitchDrain, TailDrain, Timestamps, DEFAULT_LOG_KEEP,
+};
+use crate::metrics::{self, MetricsWriter};
+use crate::proxy;
+use crate::remote_env::{passthrough_env, RemoteEnv};
+use crate::resolver;
+use crate::runner::{
+    self, CmdStart, Hooks, HostDone, HostRunResult, Retry, Semaphore, SshExecutor, Stop,
+    DEFAULT_MAX_PARALLEL, DEFAULT_RETRY_DELAY,
+};
+use crate::session::{self, Keepalive, Sessions, DEFAULT_INTERRUPT_GRACE};
+use crate::ssh_config::{self, SshConfig};
 use crate::subcmd::Subcommand;
-use clap::{App, Arg, ArgMatches, SubCommand};
-use libmussh::{Config, Multiplex, RuntimeConfig};
-use rusqlite::Connection;
-use slog::{o, Drain, Logger};
-use slog_try::try_trace;
+use crate::success::SuccessCodes;
+use crate::tags::{self, TagExpr};
+use crate::targets;
+use crate::util::{run_id, shell_quote};
+use crate::warnings::Warnings;
+use chrono::Utc;
+use clap::{App, Arg, ArgGroup, ArgMatches, SubCommand};
+use indexmap::{IndexMap, IndexSet};
+use libmussh::{Config, Multiplex, MultiplexMapType, RuntimeConfig};
+use slog::{o, trace, Drain, Duplicate, Level, Logger, Never};
+use slog_try::{try_debug, try_trace, try_warn};
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::env;
+use std::fs;
+use std::iter::FromIterator;
+use std::mem;
+use std::net::SocketAddr;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The command output logger for each host.
+type HostLoggers = HashMap<String, Option<Logger>>;
+/// The `--filter` each host's output is piped through.
+type HostFilters = HashMap<String, Arc<OutputFilter>>;
 
 #[derive(Clone, Default)]
 pub(crate) struct Run {
     stdout: Option<Logger>,
     stderr: Option<Logger>,
     db_path: PathBuf,
+    /// The fields of the config only mussh knows about.
+    extensions: Extensions,
+    /// The run id, which names the host log files of this run.
+    id: String,
+    /// Formats the results, `Human` if not given.
+    formatter: Option<Formatter>,
 }
 
 impl Run {
-    pub(crate) fn new(stdout: Option<Logger>, stderr: Option<Logger>, db_path: PathBuf) -> Self {
+    pub(crate) fn new(
+        stdout: Option<Logger>,
+        stderr: Option<Logger>,
+        db_path: PathBuf,
+        extensions: Extensions,
+    ) -> Self {
         Self {
             stdout,
             stderr,
             db_path,
+            extensions,
+            id: run_id(Utc::now()),
+            formatter: None,
+        }
+    }
+
+    /// Format the results of the run with the given formatter.
+    pub(crate) fn with_formatter(mut self, formatter: Formatter) -> Self {
+        self.formatter = Some(formatter);
+        self
+    }
+
+    /// The id of the run.  With `--resume` it is the id of the resumed run, and
+    /// the hosts that run completed are skipped.
+    fn resume<'a>(
+        &'a self,
+        matches: &'a ArgMatches<'_>,
+        multiplex_maps: &mut Vec<MultiplexMapType>,
+    ) -> MusshResult<&'a str> {
+        let run_id = matches.value_of("resume").unwrap_or(&self.id);
+        if matches.is_present("resume") {
+            let completed = metrics::completed_hosts(&self.db_path, run_id)?;
+            println!(
+                "Resuming run {run_id}, skipping {} completed host(s)",
+                completed.len()
+            );
+            skip_completed(multiplex_maps, &completed);
+        }
+        Ok(run_id)
+    }
+
+    /// The hooks of the run, for its metrics, `--expect`, `--hash`,
+    /// `--max-runtime`, `--fail-fast-on-auth`, `--fail-fast`, `--keep-going`,
+    /// `--retries`, `--print-command` and the shells of the `localhost` hosts.
+    ///
+    /// The commands of each host run over the one ssh session, connected as
+    /// `connect` has it.
+    fn hooks(
+        &self,
+        matches: &ArgMatches<'_>,
+        metrics: Option<&MetricsWriter>,
+        (timeouts, host_keys, algorithms, max_parallel, retry): Connect<'_>,
+    ) -> MusshResult<Hooks> {
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_metrics(metrics.map(|metrics| metrics.sender().clone()));
+        let expectations = Expectations::parse(&self.extensions, matches.value_of("expect"))?;
+        if !expectations.is_empty() {
+            let _ = hooks.set_expect(Some(Arc::new(expectations)));
+        }
+        let codes = SuccessCodes::parse(&self.extensions, matches.value_of("success_codes"))?;
+        let _ = hooks
+            .set_success_codes(Some(Arc::new(codes)))
+            .set_hash(matches.value_of("hash").map(str::parse).transpose()?);
+        if let Some(secs) = positive_number(matches, "max_runtime")? {
+            let secs = u64::try_from(secs).unwrap_or(u64::MAX);
+            let _ = hooks.set_deadline(Instant::now().checked_add(Duration::from_secs(secs)));
+        }
+        let _ = hooks
+            .set_fail_fast_on_auth(matches.is_present("fail_fast_on_auth"))
+            .set_fail_fast(matches.is_present("fail_fast"))
+            .set_keep_going(matches.is_present("keep_going"));
+        let _ = hooks
+            .set_max_parallel(Some(max_parallel))
+            .set_retry(retry.cloned());
+        let shells = local::shells(&self.extensions)?;
+        let sessions = Sessions::new(timeouts.clone(), host_keys.clone())
+            .with_algorithms(algorithms.clone())
+            .with_pty(matches.is_present("tty"))
+            .with_interrupt_grace(
+                positive_number(matches, "interrupt_grace")?
+                    .map_or(DEFAULT_INTERRUPT_GRACE, |secs| {
+                        Duration::from_secs(u64::try_from(secs).unwrap_or(u64::MAX))
+                    }),
+            )
+            .with_tail_file(matches.value_of("tail_file").map(str::to_string))
+            .with_keepalive(Keepalive::new(
+                option_number(matches, &self.extensions.options, "keepalive")?,
+                option_number(matches, &self.extensions.options, "session_timeout")?,
+            ));
+        let encoding = matches.value_of("encoding").map(str::parse).transpose()?;
+        let print_command = matches
+            .is_present("print_command")
+            .then(|| logging::stdout_logger(Level::Info));
+        let executor = SshExecutor::new(shells)
+            .with_env(self.remote_env(matches)?)
+            .with_sessions(sessions)
+            .with_encoding(encoding.unwrap_or_default())
+            .with_print_command(print_command);
+        let _ = hooks.set_executor(Some(Arc::new(executor)));
+        Ok(hooks)
+    }
+
+    /// Point each host that connects through a jump host or a proxy, its own
+    /// `jump` or `proxy`, or `--proxy`, at a local port forwarding to it.  The
+    /// hosts are connected to through their jump hosts and proxies in
+    /// parallel.  Returns the hostname and port each local port forwards to.
+    fn forward_hosts(
+        &self,
+        matches: &ArgMatches<'_>,
+        multiplex_maps: &mut [MultiplexMapType],
+        (timeouts, host_keys): (&ConnectTimeouts, &HostKeys),
+    ) -> MusshResult<HashMap<u16, (String, u16)>> {
+        let hosts: IndexMap<String, (String, u16)> = multiplex_maps
+            .iter()
+            .flat_map(IndexMap::iter)
+            .filter(|(_, (host, _))| host.hostname() != "localhost")
+            .map(|(name, (host, _))| {
+                (
+                    name.clone(),
+                    (host.hostname().clone(), host.port().unwrap_or(22)),
+                )
+            })
+            .collect();
+        let names: Vec<&str> = hosts.keys().map(String::as_str).collect();
+        let proxies = proxy::host_proxies(&self.extensions, matches.value_of("proxy"), &names)?;
+        let jumps = jump::host_jumps(&self.extensions, &names)?;
+        // The jump host is logged in to as the user of the host, with its pem.
+        let routes: HashMap<&String, jump::Route> = multiplex_maps
+            .iter()
+            .flat_map(IndexMap::iter)
+            .filter_map(|(name, (host, _))| {
+                let route = jump::Route::new(
+                    jumps.get(name)?.clone(),
+                    host.username().clone(),
+                    host.pem().clone(),
+                    timeouts.for_host(name),
+                    host_keys.clone(),
+                );
+                Some((name, route))
+            })
+            .collect();
+
+        let forwarded = thread::scope(|scope| {
+            let handles: Vec<_> = hosts
+                .iter()
+                .filter_map(|(name, (hostname, port))| {
+                    let (hostname, port) = (hostname.clone(), *port);
+                    let handle = if let Some(route) = routes.get(name).cloned() {
+                        scope.spawn(move || jump::forward(route, hostname, port))
+                    } else {
+                        let proxy = Arc::clone(proxies.get(name)?);
+                        scope.spawn(move || proxy::forward(proxy, hostname, port))
+                    };
+                    Some((name, handle))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|(name, handle)| {
+                    let port = handle
+                        .join()
+                        .map_err(|_| format!("Forwarding to host '{name}' panicked"))??;
+                    Ok((name.clone(), port))
+                })
+                .collect::<MusshResult<HashMap<String, u16>>>()
+        })?;
+
+        let origins = forwarded
+            .iter()
+            .filter_map(|(name, port)| Some((*port, hosts.get(name)?.clone())))
+            .collect();
+        for (name, (host, _)) in multiplex_maps.iter_mut().flat_map(IndexMap::iter_mut) {
+            if let Some(port) = forwarded.get(name) {
+                let mut value = toml::Value::try_from(&*host)?;
+                if let Some(table) = value.as_table_mut() {
+                    let _old = table.insert(
+                        "hostname".to_string(),
+                        toml::Value::String("127.0.0.1".to_string()),
+                    );
+                    let _old =
+                        table.insert("port".to_string(), toml::Value::Integer(i64::from(*port)));
+                }
+                *host = value.try_into()?;
+            }
+        }
+        Ok(origins)
+    }
+
+    /// The sync hosts and the multiplex maps of the run, once the warnings
+    /// about them are emitted (failing the run with `--strict`).
+    fn run_maps(
+        &self,
+        config: &Config,
+        matches: &ArgMatches<'_>,
+    ) -> MusshResult<(IndexSet<String>, Vec<MultiplexMapType>)> {
+        let mut warnings = Warnings::default();
+        let tagged = tagged_hosts(&self.extensions, matches, &mut warnings)?;
+        let default_cmd = self.extensions.default_cmd.as_deref();
+        let (config, runtime_config) = one_off_config(config, matches, default_cmd, &tagged)?;
+        let config = self.connect_config(&config, matches, &mut warnings)?;
+        let (sync_hosts, mut multiplex_maps) =
+            multiplex_maps(&config, &runtime_config, matches, &mut warnings)?;
+        let skipped: Vec<&str> = matches
+            .values_of("skip_command")
+            .into_iter()
+            .flatten()
+            .collect();
+        for name in skip_commands(&mut multiplex_maps, &skipped) {
+            warnings.warn(format!("Skipped command '{name}' isn't run on any host"));
+        }
+        warnings.emit(self.stderr.as_ref(), matches.is_present("strict"))?;
+        Ok((sync_hosts, multiplex_maps))
+    }
+
+    /// Wrap each command for `--remote-timeout`.
+    fn wrap_commands(
+        matches: &ArgMatches<'_>,
+        multiplex_maps: &mut [MultiplexMapType],
+    ) -> MusshResult<()> {
+        if let Some(secs) = positive_number(matches, "remote_timeout")? {
+            for multiplex_map in multiplex_maps {
+                remote_timeout(multiplex_map, secs);
+            }
+        }
+        Ok(())
+    }
+
+    /// The environment variables to set for each command, from
+    /// `--env-passthrough`, the config and `--env`.
+    fn remote_env(&self, matches: &ArgMatches<'_>) -> MusshResult<RemoteEnv> {
+        let patterns: Vec<&str> = matches
+            .values_of("env_passthrough")
+            .into_iter()
+            .flatten()
+            .collect();
+        let assignments: Vec<&str> = matches.values_of("env").into_iter().flatten().collect();
+        RemoteEnv::parse(
+            &self.extensions,
+            passthrough_env(&patterns, env::vars(), self.stderr.as_ref()),
+            &assignments,
+        )
+    }
+
+    /// The writer of the run's metrics.  If the metrics db can't be opened, the
+    /// run goes ahead without metrics, with a warning, unless they were asked
+    /// for with `--label` or `--resume`.
+    fn metrics_writer(
+        &self,
+        matches: &ArgMatches<'_>,
+        run_id: &str,
+    ) -> MusshResult<Option<MetricsWriter>> {
+        let labels = labels(matches)?;
+        match MetricsWriter::spawn(&self.db_path, run_id, &labels) {
+            Ok(metrics) => Ok(Some(metrics)),
+            Err(e) if labels.is_empty() && !matches.is_present("resume") => {
+                try_warn!(
+                    self.stderr,
+                    "Not recording metrics, unable to open {}: {}",
+                    self.db_path.display(),
+                    e
+                );
+                Ok(None)
+            }
+            Err(e) => Err(e),
         }
     }
+
+    /// Fill in the connection details of the hosts from `~/.ssh/config` and
+    /// the `--resolver` nameserver, and pin them to the `--ipv4` or `--ipv6`
+    /// address family, if asked to.
+    fn connect_config(
+        &self,
+        config: &Config,
+        matches: &ArgMatches<'_>,
+        warnings: &mut Warnings,
+    ) -> MusshResult<Config> {
+        let mut config = config.clone();
+
+        if matches.is_present("use_ssh_config") {
+            let ssh_config_path = dirs::home_dir()
+                .ok_or("Unable to determine your home directory")?
+                .join(".ssh")
+                .join("config");
+            try_trace!(self.stdout, "Using ssh config"; "path" => ssh_config_path.display().to_string());
+            config = ssh_config::apply(&config, &SshConfig::read(&ssh_config_path)?)?;
+        }
+
+        if let Some(nameserver) = matches.value_of("resolver") {
+            let nameserver = nameserver
+                .parse::<SocketAddr>()
+                .map_err(|_| format!("Invalid resolver '{nameserver}', expected IP:PORT"))?;
+            resolver::apply(&config, nameserver, self.stdout.as_ref(), warnings)?;
+        }
+
+        let family = if matches.is_present("ipv4") {
+            Some(Family::V4)
+        } else if matches.is_present("ipv6") {
+            Some(Family::V6)
+        } else {
+            None
+        };
+        if let Some(family) = family {
+            let timeouts = self.connect_timeouts(matches)?;
+            config =
+                connect::pin_family(&config, family, &timeouts, self.stdout.as_ref(), warnings)?;
+        }
+
+        Ok(config)
+    }
+
+    /// The connect timeout of each host, its own or `--connect-timeout`.
+    fn connect_timeouts(&self, matches: &ArgMatches<'_>) -> MusshResult<ConnectTimeouts> {
+        ConnectTimeouts::parse(
+            &self.extensions,
+            option_number(matches, &self.extensions.options, "connect_timeout")?,
+        )
+    }
+
+    /// With `--log-per-command`, the logger of each host that writes to the log
+    /// file of the command being run, `<host>/<cmd>.<run id>.log`.  It is
+    /// switched over to each command's file by the `cmd_start` hook.
+    fn cmd_log_files(
+        &self,
+        matches: &ArgMatches<'_>,
+        multiplex_maps: &[MultiplexMapType],
+        run_id: &str,
+        hooks: &mut Hooks,
+    ) -> MusshResult<HashMap<String, Logger>> {
+        if !matches.is_present("log_per_command") {
+            return Ok(HashMap::new());
+        }
+        let settings = log_file(matches)?;
+
+        let switches: HashMap<String, Arc<SwitchDrain>> = run_hosts(multiplex_maps)
+            .into_iter()
+            .map(|host| (host.to_string(), Arc::new(SwitchDrain::default())))
+            .collect();
+        let loggers = switches
+            .iter()
+            .map(|(host, switch)| (host.clone(), Logger::root(Arc::clone(switch), o!())))
+            .collect();
+        let (stderr, run_id) = (self.stderr.clone(), run_id.to_string());
+        let cmd_start: CmdStart = Arc::new(move |host: &str, cmd_name: &str| {
+            if let Some(switch) = switches.get(host) {
+                let path = log_dir().join(host).join(log_file_name(cmd_name, &run_id));
+                switch.switch(file_logger(None, stderr.as_ref(), settings.at(path)));
+            }
+        });
+        let _ = hooks.set_cmd_start(Some(cmd_start));
+        Ok(loggers)
+    }
+
+    /// Build the logger each host's command output is written to, and the hook
+    /// that finishes a host's output once the host is done.
+    ///
+    /// With `--max-output-bytes`, each logger is limited, and the hook notes
+    /// how much output was dropped.  With `--interleave-lines false`, the
+    /// streamed output of each host is buffered and the hook prints it, or with
+    /// `--sort-output-by` it is held and returned to be printed at the end.  With
+    /// `--filter`, the output is piped through the filter first, and the hook
+    /// waits for the filter to finish.
+    fn host_loggers(
+        &self,
+        matches: &ArgMatches<'_>,
+        multiplex_maps: &[MultiplexMapType],
+        run_id: &str,
+        hooks: &mut Hooks,
+    ) -> MusshResult<(HostLoggers, HostFilters, Option<Arc<BlockOutput>>)> {
+        let max_output_bytes = positive_number(matches, "max_output_bytes")?;
+        let tail = matches.is_present("tail");
+        let (colors, prefix_width) = tail_prefixes(matches, multiplex_maps);
+        let stream = Arc::new(stream(matches)?);
+        let block_output = block_output(matches, &colors, &stream)?;
+        let mut cmd_log_files = self.cmd_log_files(matches, multiplex_maps, run_id, hooks)?;
+        let settings = log_file(matches)?;
+        let mut cmd_loggers_map = HashMap::new();
+        let mut output_limits = HashMap::new();
+        let mut filters = HashMap::new();
+        let mut host_stdout = HashMap::new();
+        let agent_sock = env::var("SSH_AUTH_SOCK").ok();
+        let (verbose_hosts, verbose_stdout) = verbose_hosts(matches);
+
+        for (host, (host_config, _)) in multiplex_maps.iter().flat_map(IndexMap::iter) {
+            if cmd_loggers_map.contains_key(host) {
+                continue;
+            }
+            let verbose = verbose_stdout
+                .as_ref()
+                .filter(|_| verbose_hosts.contains(&host.as_str()));
+            let stdout = verbose.or(self.stdout.as_ref());
+            let file_logger = cmd_log_files.remove(host).or_else(|| {
+                host_file_logger(stdout, self.stderr.as_ref(), host, run_id, &settings)
+            });
+            if stdout.is_some_and(Logger::is_debug_enabled) {
+                let method = auth::describe(
+                    host_config.hostname(),
+                    host_config.username(),
+                    host_config.pem().as_deref(),
+                    agent_sock.as_deref(),
+                );
+                try_debug!(stdout, "auth"; "host" => host, "method" => &method);
+                try_debug!(file_logger, "auth: {}", method);
+            }
+            if let Some(verbose) = verbose {
+                let _old =
+                    host_stdout.insert(host.clone(), verbose_logger(verbose, file_logger.as_ref()));
+            }
+            let logger = match (&block_output, tail) {
+                (Some(block_output), _) => Some(tail_logger(
+                    file_logger,
+                    BlockDrain::new(host, Arc::clone(block_output)),
+                )),
+                (None, true) => Some(tail_logger(
+                    file_logger,
+                    TailDrain::new(
+                        host,
+                        prefix_width,
+                        colors.get(host).copied(),
+                        Arc::clone(&stream),
+                    ),
+                )),
+                (None, false) => file_logger,
+            };
+            let logger = match (logger, max_output_bytes) {
+                (Some(logger), Some(max)) => {
+                    let limit = Arc::new(OutputLimit::new(max));
+                    let _old =
+                        output_limits.insert(host.clone(), (logger.clone(), Arc::clone(&limit)));
+                    Some(Logger::root(LimitDrain::new(logger, limit).fuse(), o!()))
+                }
+                (logger, _) => logger,
+            };
+            let logger = match (logger, matches.value_of("filter")) {
+                (Some(logger), Some(command)) => {
+                    let filter = Arc::new(OutputFilter::new(command, logger));
+                    let _old = filters.insert(host.clone(), Arc::clone(&filter));
+                    Some(Logger::root(FilterDrain::new(filter).fuse(), o!()))
+                }
+                (logger, _) => logger,
+            };
+            let _old = cmd_loggers_map.insert(host.clone(), logger);
+        }
+
+        let host_filters = filters.clone();
+        let held_output = block_output.clone();
+        let host_done: HostDone = Arc::new(move |host: &str| {
+            if let Some(filter) = host_filters.get(host) {
+                filter.finish();
+            }
+            if let Some((logger, limit)) = output_limits.get(host) {
+                if limit.truncated() > 0 {
+                    trace!(logger, "[truncated {} bytes]", limit.truncated());
+                }
+            }
+            if let Some(block_output) = &block_output {
+                block_output.flush(host);
+            }
+        });
+
+        let _ = hooks
+            .set_host_done(Some(host_done))
+            .set_host_stdout(host_stdout);
+        Ok((cmd_loggers_map, filters, held_output))
+    }
 }
 
 impl Subcommand for Run {
@@ -44,23 +554,47 @@ impl Subcommand for Run {
                 "Parse config and setup the client, \
                  but don't run it.",
             ))
+            .args(&selection_args())
+            .group(
+                ArgGroup::with_name("selected_hosts")
+                    .args(&["hosts", "tag"])
+                    .multiple(true),
+            )
             .arg(
-                Arg::with_name("hosts")
-                    .short("h")
-                    .long("hosts")
+                Arg::with_name("only")
+                    .long("only")
                     .value_name("HOSTS")
-                    .help("The hosts to multiplex the command over")
+                    .help("Run on only these of the selected hosts (each must be one of them)")
                     .multiple(true)
                     .use_delimiter(true),
             )
+            .arg(
+                Arg::with_name("unique")
+                    .long("unique")
+                    .overrides_with("no_unique")
+                    .help("Run a host selected more than once only once (the default)"),
+            )
+            .arg(
+                Arg::with_name("no_unique")
+                    .long("no-unique")
+                    .overrides_with("unique")
+                    .help(
+                        "Run a host selected more than once as many times, one after the \
+                         other, after the run of the other hosts",
+                    ),
+            )
             .arg(
                 Arg::with_name("commands")
                     .short("c")
                     .long("commands")
                     .value_name("CMD")
-                    .help("The commands to multiplex")
+                    .help(
+                        "The commands to multiplex, the default_cmd of the config if none.  \
+                         Each host runs them in order over one ssh session, and stops at the \
+                         first that fails unless --keep-going",
+                    )
                     .multiple(true)
-                    .requires("hosts")
+                    .requires("selected_hosts")
                     .use_delimiter(true),
             )
             .arg(
@@ -68,9 +602,13 @@ impl Subcommand for Run {
                     .short("s")
                     .long("sync_hosts")
                     .value_name("HOSTS")
-                    .help("The hosts to run the sync commands on before running on any other hosts")
+                    .help(
+                        "The hosts to run the sync commands on before running on any other \
+                         hosts, which are only run on if the sync commands succeeded on every \
+                         sync host",
+                    )
                     .use_delimiter(true)
-                    .required_unless("hosts")
+                    .required_unless_one(&["hosts", "tag", "plan", "target"])
                     .requires("sync_commands"),
             )
             .arg(
@@ -85,78 +623,2448 @@ impl Subcommand for Run {
                 "Run the given commadn synchronously across the \
                  hosts.",
             ))
+            .arg(
+                Arg::with_name("plan")
+                    .long("plan")
+                    .value_name("GROUP=CMD")
+                    .help("Run the command on the given hostlist (i.e. db=migrate)")
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .args(&command_args())
+            .args(&connection_args())
+            .args(&channel_args())
+            .args(&retry_args())
+            .args(&address_args())
+            .args(&session::args())
+            .args(&algorithms::args())
+            .args(&known_hosts::args())
+            .args(&output_args())
+            .args(&report_args())
+            .args(&log_args())
+            .args(&rollout_args())
+            .args(&status_args())
     }
 
     fn execute(&self, config: &Config, matches: &ArgMatches<'_>) -> MusshResult<()> {
-        let runtime_config = RuntimeConfig::from(matches);
-        let sync_hosts = runtime_config.sync_hosts();
-        let multiplex_map = config.to_host_map(&runtime_config);
-        let conn = Connection::open(&self.db_path)?;
-        create_metrics_table(&conn)?;
-
-        let mut cmd_loggers_map = HashMap::new();
-        for host in multiplex_map.keys() {
-            let _ = cmd_loggers_map
-                .entry(host.clone())
-                .or_insert_with(|| host_file_logger(&self.stdout, host));
+        let (sync_hosts, mut multiplex_maps) = self.run_maps(config, matches)?;
+        if matches.is_present("print_hosts") {
+            print_hosts(&multiplex_maps);
+            return Ok(());
+        }
+        let timeouts = self.connect_timeouts(matches)?;
+        let host_keys = HostKeys::from_args(matches)?;
+        let forwarded =
+            self.forward_hosts(matches, &mut multiplex_maps, (&timeouts, &host_keys))?;
+        let host_keys = host_keys.with_forwarded(forwarded);
+        let retry = retry(matches, &self.extensions.options, &timeouts)?;
+        let algorithms = SshAlgorithms::parse(&self.extensions, Some(matches))?;
+        let connect = (
+            &timeouts,
+            &host_keys,
+            &algorithms,
+            max_parallel(matches, &self.extensions.options)?,
+            retry.as_ref(),
+        );
+        if matches.is_present("check_auth") {
+            let auth_retries = positive_number(matches, "connect_retries_on_auth")?;
+            return check_auth(&multiplex_maps, connect, auth_retries.unwrap_or(0));
         }
+        let formatter = self.formatter.clone().unwrap_or_else(|| Arc::new(Human));
+        let not_run = preconnect(matches, &mut multiplex_maps, connect, &formatter);
+        Self::wrap_commands(matches, &mut multiplex_maps)?;
+        let run_id = self.resume(matches, &mut multiplex_maps)?;
+        let metrics = self.metrics_writer(matches, run_id)?;
+
+        let mut hooks = self.hooks(matches, metrics.as_ref(), connect)?;
+        let (cmd_loggers_map, filters, block_output) =
+            self.host_loggers(matches, &multiplex_maps, run_id, &mut hooks)?;
         let mut multiplex = Multiplex::default();
         let _ = multiplex.set_stdout(self.stdout.clone());
         let _ = multiplex.set_stderr(self.stderr.clone());
         let _ = multiplex.set_host_loggers(cmd_loggers_map);
-        for metrics in multiplex
-            .multiplex(sync_hosts, multiplex_map)
+        let report = |result: &HostRunResult| {
+            if formatter.streams() {
+                print!("{}", formatter.format(slice::from_ref(result)));
+            }
+        };
+        not_run.iter().for_each(report);
+
+        let (mut results, multiplex_maps) = if sync_hosts.is_empty() {
+            (Vec::new(), multiplex_maps)
+        } else {
+            preflight(&multiplex, &sync_hosts, multiplex_maps, &hooks, report)
+        };
+
+        let waves = match positive_number(matches, "wave_size")? {
+            Some(size) => multiplex_maps
+                .into_iter()
+                .flat_map(|multiplex_map| waves(multiplex_map, size))
+                .collect(),
+            None => multiplex_maps,
+        };
+        let wave_count = waves.len();
+        let require_success = matches.is_present("wave_require_success");
+        let (wave_results, failed_wave) = run_waves(
+            &multiplex,
+            &IndexSet::new(),
+            waves,
+            require_success,
+            &hooks,
+            report,
+        );
+        results.extend(wave_results);
+        results.extend(not_run);
+        if matches.is_present("filter_affects_status") {
+            filter_failures(&mut results, &filters);
+        }
+        // The hooks hold a sender, which has to be gone before the writer can finish.
+        let stopped = hooks.stopped();
+        drop(hooks);
+        if let Some(metrics) = metrics {
+            let _written = metrics.finish()?;
+        }
+
+        if let Some(block_output) = block_output {
+            block_output.flush_all(&SortOutputBy::from(matches).hosts(&results));
+        }
+        if !formatter.streams() {
+            print!("{}", formatter.format(&results));
+        }
+        print_hash_report(&formatter, &results);
+        if let Some(wave) = failed_wave {
+            status(
+                &formatter,
+                &format!(
+                    "Wave {wave} of {wave_count} failed, skipping the remaining {} wave(s)",
+                    wave_count - wave
+                ),
+            );
+        }
+
+        if let Some(path) = matches.value_of("junit") {
+            fs::write(path, junit::report(&results))?;
+        }
+        let logs = log_files(matches, run_id);
+        status(
+            &formatter,
+            &format!("Run {run_id}, host logs in {}", logs.display()),
+        );
+
+        run_status(matches, &results, stopped)
+    }
+}
+
+/// How the run went: stopped by an authentication failure with
+/// `--fail-fast-on-auth` or a failure with `--fail-fast`, cut short by
+/// `--max-runtime`, or as the `--exit-code-mode` has it.
+fn run_status(
+    matches: &ArgMatches<'_>,
+    results: &[HostRunResult],
+    stopped: Option<Stop>,
+) -> MusshResult<()> {
+    if let Some(stop) = stopped {
+        return Err(match stop {
+            Stop::AuthFailure(hostname) => MusshErrKind::AuthFailed(hostname),
+            Stop::Failure(hostname) => MusshErrKind::FailFast(hostname),
+        }
+        .into());
+    }
+    let timed_out = timed_out_hosts(results);
+    match ExitCodeMode::from(matches).exit_code(results) {
+        _ if timed_out > 0 => Err(MusshErrKind::MaxRuntime(timed_out).into()),
+        0 => Ok(()),
+        code => Err(MusshErrKind::HostsFailed(failed_hosts(results), code).into()),
+    }
+}
+
+/// Print the `--hash` report once the run is done.
+fn print_hash_report(formatter: &Formatter, results: &[HostRunResult]) {
+    for line in hash_report(results) {
+        status(formatter, &line);
+    }
+}
+
+/// The `--hash` of each command's output, a `host: hash` line per host by
+/// name, then the hosts grouped by hash, the largest group first, so the hosts
+/// whose output drifted stand out.  Empty if nothing was hashed.
+fn hash_report(results: &[HostRunResult]) -> Vec<String> {
+    let mut by_cmd: IndexMap<&str, Vec<(&str, &str)>> = IndexMap::new();
+    for result in results {
+        if let Some(hash) = result.output_hash() {
+            by_cmd
+                .entry(result.cmd_name().as_str())
+                .or_default()
+                .push((result.name().as_str(), hash.as_str()));
+        }
+    }
+
+    let mut lines = Vec::new();
+    for (cmd_name, mut hashes) in by_cmd {
+        hashes.sort_unstable();
+        lines.push(format!("Output hashes of '{cmd_name}':"));
+        let mut groups: IndexMap<&str, Vec<&str>> = IndexMap::new();
+        for (name, hash) in hashes {
+            lines.push(format!("  {name}: {hash}"));
+            groups.entry(hash).or_default().push(name);
+        }
+        lines.push(format!(
+            "'{cmd_name}' had {} distinct output(s):",
+            groups.len()
+        ));
+        groups.sort_by(|_, a, _, b| b.len().cmp(&a.len()));
+        for (hash, names) in groups {
+            lines.push(format!("  {hash}: {}", names.join(", ")));
+        }
+    }
+    lines
+}
+
+/// Where the output of each host is buffered with `--interleave-lines false`,
+/// held until the end of the run with `--sort-output-by`.
+fn block_output(
+    matches: &ArgMatches<'_>,
+    colors: &HashMap<String, u8>,
+    stream: &Arc<Stream>,
+) -> MusshResult<Option<Arc<BlockOutput>>> {
+    let sorted = matches.is_present("sort_output_by");
+    if matches.is_present("tail") && matches.value_of("interleave_lines") == Some("false") {
+        let stream = Arc::clone(stream);
+        Ok(Some(Arc::new(BlockOutput::new(
+            colors.clone(),
+            sorted,
+            stream,
+        ))))
+    } else if sorted {
+        Err("--sort-output-by needs --interleave-lines false".into())
+    } else {
+        Ok(None)
+    }
+}
+
+/// The arguments adding to and removing from the commands run.
+fn command_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("exec")
+            .long("exec")
+            .value_name("COMMAND")
+            .help("A command to run on the hosts, in addition to any configured commands"),
+        Arg::with_name("skip_command")
+            .long("skip-command")
+            .value_name("CMD")
+            .help("A command not to run, on any host")
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("keep_going")
+            .long("keep-going")
+            .help("Run the rest of a host's commands after one of them fails"),
+    ]
+}
+
+/// The arguments controlling how a command is tried again when its host
+/// can't be connected to.
+fn retry_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("retries")
+            .long("retries")
+            .value_name("N")
+            .help(
+                "Try a command up to N more times when its host can't be connected or \
+                 authenticated to (a command that ran and exited non-zero isn't retried), \
+                 as long as the attempts fit in the host's connect timeout",
+            ),
+        Arg::with_name("retry_delay")
+            .long("retry-delay")
+            .value_name("MILLIS")
+            .help(
+                "With retries, wait MILLIS before the first retry, 500 by default, doubling it \
+                 each retry",
+            ),
+    ]
+}
+
+/// The arguments controlling the channel each command runs on.
+fn channel_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("tty").long("tty").alias("pty").help(
+            "Run each command on a pseudo-terminal on its host, for commands that behave \
+             differently without one (localhost runs it without one).  The host merges its \
+             stderr into its stdout, so none of it is logged as stderr or echoed when it fails",
+        ),
+        Arg::with_name("tail_file")
+            .long("tail-file")
+            .value_name("REMOTE_PATH")
+            .help(
+                "Follow the file at REMOTE_PATH on each host while each command runs, logging \
+                 its new lines to the host's output after the path (with --tail they're \
+                 streamed).  Not being able to tail it doesn't fail the host, and localhost \
+                 doesn't tail it",
+            ),
+        Arg::with_name("interrupt_grace")
+            .long("interrupt-grace")
+            .value_name("SECS")
+            .requires("tty")
+            .help(
+                "Give a command run with --tty that is given up on, at the --session-timeout \
+                 or the --max-runtime deadline, SECS seconds to exit once it is sent Ctrl-C \
+                 before closing its channel, 5 by default",
+            ),
+    ]
+}
+
+/// The arguments controlling how hosts are connected to.
+fn connection_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("print_hosts").long("print-hosts").help(
+            "Print the hosts the run would run on, one per line, and exit without running \
+             anything",
+        ),
+        Arg::with_name("check_auth").long("check-auth").help(
+            "Connect and authenticate to each host, and open a channel, but run nothing, \
+             reporting whether each host could have run",
+        ),
+        Arg::with_name("warmup").long("warmup").help(
+            "Connect and authenticate to every host before running anything, and leave out \
+             the hosts that fail, so the commands start on hosts known to be reachable",
+        ),
+        Arg::with_name("proxy")
+            .long("proxy")
+            .value_name("URL")
+            .help(
+                "Connect to the hosts through a SOCKS5 proxy, socks5://[USER:PASS@]HOST:PORT.  \
+                 A host's own proxy in the config (or \"none\") takes precedence",
+            ),
+        Arg::with_name("connect_timeout")
+            .long("connect-timeout")
+            .value_name("SECS")
+            .help(
+                "Give up connecting to a host after SECS seconds, 10 by default, and leave it \
+                 out of the run (a host's own connect_timeout in the config takes precedence)",
+            ),
+        Arg::with_name("connect_retries_on_auth")
+            .long("connect-retries-on-auth")
+            .value_name("N")
+            .requires("check_auth")
+            .help(
+                "Try authenticating to a host up to N more times, half a second apart, \
+                 before --check-auth fails it (connection errors aren't retried)",
+            ),
+        Arg::with_name("target")
+            .long("target")
+            .value_name("USER@HOST:PORT")
+            .help(
+                "A host to run on that isn't in the config (the config file isn't needed \
+                 when only targets are used), with ranges like web[01-10] standing for each \
+                 of the hosts in them",
+            )
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("pem")
+            .long("pem")
+            .value_name("PATH")
+            .requires("target")
+            .help("The pem file to authenticate to the targets with, instead of the agent"),
+        Arg::with_name("use_ssh_config")
+            .long("use-ssh-config")
+            .help(
+                "Fill in the pem, username, port and hostname missing from a host \
+                 with the matching block in ~/.ssh/config",
+            ),
+        Arg::with_name("remote_timeout")
+            .long("remote-timeout")
+            .value_name("SECS")
+            .help(
+                "Run each command under `timeout SECS` on the host, so the host kills it \
+                 after SECS seconds (hosts without timeout run it without a deadline)",
+            ),
+        Arg::with_name("env_passthrough")
+            .long("env-passthrough")
+            .value_name("VARS")
+            .help(
+                "Set these local environment variables, i.e. HTTP_PROXY,NO_PROXY or 'AWS_*', \
+                 for each command on its host.  Variables that aren't set are skipped",
+            )
+            .use_delimiter(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("env")
+            .long("env")
+            .value_name("KEY=VAL")
+            .help(
+                "Set the variable KEY to VAL for each command on its host, over the env table \
+                 of the command in the config and --env-passthrough.  Give --env again for more \
+                 variables",
+            )
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
+/// `--hosts` and `--tag`, selecting the hosts to run on.
+fn selection_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("hosts")
+            .short("h")
+            .long("hosts")
+            .value_name("HOSTS")
+            .help(
+                "The hosts to multiplex the command over, with ranges like web[01-10] or \
+                 db[1,3,5] standing for each of the hosts in them, and globs like web-* or \
+                 db?? for each configured host they match (!web-* excludes them)",
+            )
+            .multiple(true)
+            .use_delimiter(true),
+        Arg::with_name("tag")
+            .long("tag")
+            .value_name("EXPR")
+            .help(
+                "Also run on the hosts whose tags match EXPR, i.e. web or 'web && !canary', \
+                 with && (and), || (or), ! (not) and parentheses.  Give --tag again for the \
+                 hosts matching either",
+            )
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
+/// The arguments controlling which addresses the hostnames of the hosts are
+/// resolved to.
+fn address_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("resolver")
+            .long("resolver")
+            .value_name("IP:PORT")
+            .help(
+                "Resolve the hostnames of hosts with this nameserver instead of the system \
+                 resolver, falling back to the system resolver if it fails",
+            ),
+        Arg::with_name("ipv4").long("ipv4").short("4").help(
+            "Connect to the hosts over IPv4 only, trying each of their IPv4 addresses in \
+                 turn.  A host without one is connected to as usual, with a warning",
+        ),
+        Arg::with_name("ipv6")
+            .long("ipv6")
+            .short("6")
+            .conflicts_with("ipv4")
+            .help(
+                "Connect to the hosts over IPv6 only, trying each of their IPv6 addresses in \
+                 turn.  A host without one is connected to as usual, with a warning",
+            ),
+    ]
+}
+
+/// The arguments controlling the log files of the hosts.
+fn log_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("log_per_command")
+            .long("log-per-command")
+            .help("Log the output of each command to its own file, <host>/<cmd>.<run>.log"),
+        Arg::with_name("log_max_bytes")
+            .long("log-max-bytes")
+            .value_name("BYTES")
+            .help(
+                "Rotate a log file once it holds BYTES: it is moved to <file>.1, and the \
+                 older rotated files up one",
+            ),
+        Arg::with_name("log_keep")
+            .long("log-keep")
+            .value_name("N")
+            .requires("log_max_bytes")
+            .help("Keep N rotated files of each log file, 3 if not given, dropping older ones"),
+        Arg::with_name("log_time_format")
+            .long("log-time-format")
+            .value_name("FORMAT")
+            .help(
+                "Timestamp the lines of the log files with this strftime format, i.e. \
+                 \"%H:%M:%S%.3f\", rather than RFC 3339",
+            ),
+        Arg::with_name("log_local_time")
+            .long("log-local-time")
+            .help("Timestamp the lines of the log files in local time rather than UTC"),
+        Arg::with_name("no_log_timestamps")
+            .long("no-log-timestamps")
+            .conflicts_with_all(&["log_time_format", "log_local_time"])
+            .help("Don't timestamp the lines of the log files"),
+    ]
+}
+
+/// The arguments controlling how the results of the run are reported.
+fn report_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("format")
+            .long("format")
+            .alias("output")
+            .value_name("FORMAT")
+            .possible_values(&format::FORMATS)
+            .default_value("human")
+            .help(
+                "How the results are printed: a line per command as it finishes (human), or \
+                 once the run is done a JSON array (json), a JSON object per line and then a \
+                 summary object (jsonl) or a JUnit XML report (junit)",
+            ),
+        Arg::with_name("junit")
+            .long("junit")
+            .value_name("PATH")
+            .help("Write a JUnit XML report of the run to PATH"),
+        Arg::with_name("label")
+            .long("label")
+            .value_name("KEY=VALUE")
+            .help(
+                "Label the run in the metrics db (i.e. stage=canary), to tell its timings \
+                 apart from other runs of the same commands",
+            )
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
+/// The arguments controlling how the output of each host is shown.
+fn output_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("tail").long("tail").alias("prefix").help(
+            "Stream the output of each host, each line prefixed with [host], padded so \
+                 the lines of every host line up (--prefix is the same)",
+        ),
+        Arg::with_name("quiet")
+            .long("quiet")
+            .short("q")
+            .conflicts_with_all(&["tail", "host_verbose"])
+            .help(
+                "Only print the result of each host and the warnings: the output of the hosts \
+                 only goes to their log files, and nothing is logged to stdout whatever the \
+                 verbosity",
+            ),
+        Arg::with_name("tee")
+            .long("tee")
+            .value_name("PATH")
+            .requires("tail")
+            .help(
+                "Also write the streamed output of the hosts to PATH, exactly as it is \
+                 streamed, without its colors unless --tee-color is given",
+            ),
+        Arg::with_name("tee_color")
+            .long("tee-color")
+            .requires("tee")
+            .help("Keep the colors of the streamed output in the --tee file"),
+        Arg::with_name("interleave_lines")
+            .long("interleave-lines")
+            .value_name("BOOL")
+            .possible_values(&["true", "false"])
+            .default_value("true")
+            .requires_if("false", "tail")
+            .help(
+                "With false, print the output of each host as one block when the host is \
+                 done, instead of line by line as it arrives",
+            ),
+        Arg::with_name("sort_output_by")
+            .long("sort-output-by")
+            .value_name("ORDER")
+            .possible_values(&["host", "duration", "status"])
+            .requires("tail")
+            .help(
+                "With --interleave-lines false, print the blocks of output once the run is \
+                 done, by host name, slowest host first (duration) or failed hosts first \
+                 (status).  All of the output is held in memory until then",
+            ),
+        Arg::with_name("max_output_bytes")
+            .long("max-output-bytes")
+            .value_name("BYTES")
+            .help(
+                "Stop logging and streaming the output of a host after this many bytes, \
+                 noting how many bytes were dropped",
+            ),
+        Arg::with_name("encoding")
+            .long("encoding")
+            .value_name("ENCODING")
+            .possible_values(&lines::ENCODINGS)
+            .help(
+                "Decode the output of the commands as utf-8, the default, with any bytes that \
+                 aren't valid utf-8 shown as U+FFFD, or as latin1",
+            ),
+        Arg::with_name("filter")
+            .long("filter")
+            .value_name("COMMAND")
+            .help(
+                "Pipe the output of each host through this local command, i.e. \"grep ERROR\", \
+                 before it is shown or logged",
+            ),
+        Arg::with_name("filter_affects_status")
+            .long("filter-affects-status")
+            .requires("filter")
+            .help("Fail a host if its filter exits non-zero (i.e. grep found no match)"),
+        Arg::with_name("color")
+            .long("color")
+            .value_name("WHEN")
+            .possible_values(&["auto", "always", "never"])
+            .default_value("auto")
+            .help("Colorize the host prefix of streamed output"),
+        Arg::with_name("print_command").long("print-command").help(
+            "Log each command exactly as it is sent to its host, after aliases and \
+             wrapping, just before it is run",
+        ),
+        Arg::with_name("host_verbose")
+            .long("host-verbose")
+            .value_name("HOST")
+            .help(
+                "Log everything about HOST at trace level, to stdout and its log file, \
+                 whatever the -v level (other hosts keep the -v level)",
+            )
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
+/// The arguments deciding what counts as a failure.
+fn status_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("exit_code_mode")
+            .long("exit-code-mode")
+            .value_name("MODE")
+            .possible_values(&["any-fail", "all-fail", "count"])
+            .default_value("any-fail")
+            .help(
+                "How the exit code is set: any-fail exits 1 if any host failed, \
+                 all-fail exits 1 only if every host failed, count exits with the \
+                 number of failed hosts (at most 125)",
+            ),
+        Arg::with_name("expect")
+            .long("expect")
+            .value_name("REGEX")
+            .help(
+                "Fail a command whose output doesn't match REGEX, even if it exited 0 \
+                 (a command with an expect regex in the config uses that instead)",
+            ),
+        Arg::with_name("hash")
+            .long("hash")
+            .value_name("ALGO")
+            .possible_values(&hash::HASHES)
+            .help(
+                "Hash the output of each command on each host, reporting the hash of each \
+                 host and the hosts grouped by hash once the run is done",
+            ),
+        Arg::with_name("success_codes")
+            .long("success-codes")
+            .value_name("CODES")
+            .help(
+                "The exit codes, i.e. 0,1, a command succeeds with, instead of only 0 \
+                 (a command with success_codes in the config uses those instead)",
+            ),
+        Arg::with_name("strict").long("strict").help(
+            "Fail before running anything if there are any warnings, i.e. a host \
+                 selected twice or an unknown host in a hostlist",
+        ),
+    ]
+}
+
+/// The arguments controlling the order hosts and plans are run in.
+fn rollout_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("parallel")
+            .long("parallel")
+            .value_name("N")
+            .help(
+                "Run on, and connect to, at most N hosts at the same time, 32 by default \
+                 (the sync hosts still finish their sync commands before the other hosts \
+                 run theirs)",
+            ),
+        Arg::with_name("wave_size")
+            .long("wave-size")
+            .value_name("N")
+            .help(
+                "Run the hosts in waves of N, waiting for each wave to finish before \
+                 starting the next",
+            ),
+        Arg::with_name("max_runtime")
+            .long("max-runtime")
+            .value_name("SECS")
+            .help(
+                "Give up on the hosts still running after SECS seconds for the whole run, \
+                 reporting them as timed out and exiting with 124",
+            ),
+        Arg::with_name("fail_fast_on_auth")
+            .long("fail-fast-on-auth")
+            .help(
+                "Stop the run the first time a host fails to authenticate, cancelling the \
+                 commands not done yet.  Failed commands don't stop it",
+            ),
+        Arg::with_name("fail_fast").long("fail-fast").help(
+            "Stop the run the first time a command fails on a host, with a non-zero exit \
+                 or an error connecting, cancelling the commands not started yet.  The \
+                 commands already running are left to finish",
+        ),
+        Arg::with_name("wave_require_success")
+            .long("wave-require-success")
+            .requires("wave_size")
+            .help("Stop before the next wave if any host in a wave failed"),
+        Arg::with_name("resume")
+            .long("resume")
+            .value_name("RUN_ID")
+            .help(
+                "Resume an interrupted run, only running on the hosts that didn't complete \
+                 in it (give the same hosts and commands as the run)",
+            ),
+        Arg::with_name("ordered")
+            .long("ordered")
+            .requires("plan")
+            .help("Run each plan to completion, in the order given, before starting the next"),
+    ]
+}
+
+/// Print a status line of the run, to stdout alongside the results if they
+/// are streamed, otherwise to stderr.
+fn status(formatter: &Formatter, line: &str) {
+    if formatter.streams() {
+        println!("{line}");
+    } else {
+        eprintln!("{line}");
+    }
+}
+
+/// How the process exit code is derived from the per-host results.
+///
+/// * `any-fail` (the default) exits with 1 if any host failed.
+/// * `all-fail` exits with 1 only if every host failed.
+/// * `count` exits with the number of failed hosts, capped at 125.
+///
+/// A host has failed if any command run on it failed.  When no host failed the
+/// exit code is always 0.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ExitCodeMode {
+    AnyFail,
+    AllFail,
+    Count,
+}
+
+impl From<&ArgMatches<'_>> for ExitCodeMode {
+    fn from(matches: &ArgMatches<'_>) -> Self {
+        match matches.value_of("exit_code_mode") {
+            Some("all-fail") => Self::AllFail,
+            Some("count") => Self::Count,
+            _ => Self::AnyFail,
+        }
+    }
+}
+
+impl ExitCodeMode {
+    fn exit_code(self, results: &[HostRunResult]) -> i32 {
+        let failed = failed_hosts(results);
+        let hosts = results
+            .iter()
+            .map(HostRunResult::name)
+            .collect::<IndexSet<_>>()
+            .len();
+
+        match self {
+            _ if failed == 0 => 0,
+            Self::AnyFail => 1,
+            Self::AllFail => i32::from(failed == hosts),
+            Self::Count => i32::try_from(failed.min(125)).unwrap_or(125),
+        }
+    }
+}
+
+/// The order the held blocks of output are printed in with `--sort-output-by`.
+///
+/// * `host` prints them by host name.
+/// * `duration` prints the slowest host first, by the time its commands took.
+/// * `status` prints the failed hosts first.
+///
+/// Hosts that tie are printed by name.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SortOutputBy {
+    Host,
+    Duration,
+    Status,
+}
+
+impl From<&ArgMatches<'_>> for SortOutputBy {
+    fn from(matches: &ArgMatches<'_>) -> Self {
+        match matches.value_of("sort_output_by") {
+            Some("duration") => Self::Duration,
+            Some("status") => Self::Status,
+            _ => Self::Host,
+        }
+    }
+}
+
+impl SortOutputBy {
+    /// The hosts of the results, in order.
+    fn hosts(self, results: &[HostRunResult]) -> Vec<String> {
+        let mut hosts: IndexMap<&str, (Duration, bool)> = IndexMap::new();
+        for result in results {
+            let (duration, failed) = hosts.entry(result.name()).or_default();
+            *duration += *result.duration();
+            *failed |= !result.success();
+        }
+
+        let mut hosts: Vec<(&str, (Duration, bool))> = hosts.into_iter().collect();
+        match self {
+            Self::Host => hosts.sort_by_key(|(name, _)| *name),
+            Self::Duration => hosts.sort_by(|(a, (a_dur, _)), (b, (b_dur, _))| {
+                b_dur.cmp(a_dur).then_with(|| a.cmp(b))
+            }),
+            Self::Status => {
+                hosts.sort_by(|(a, (_, a_failed)), (b, (_, b_failed))| {
+                    b_failed.cmp(a_failed).then_with(|| a.cmp(b))
+                });
+            }
+        }
+        hosts
             .into_iter()
-            .flatten()
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+}
+
+/// Drop the hosts that completed in the run being resumed.
+fn skip_completed(multiplex_maps: &mut Vec<MultiplexMapType>, completed: &IndexSet<String>) {
+    for multiplex_map in multiplex_maps.iter_mut() {
+        multiplex_map.retain(|name, _| !completed.contains(name));
+    }
+    multiplex_maps.retain(|multiplex_map| !multiplex_map.is_empty());
+}
+
+/// Fail the last command of each host whose `--filter` exited non-zero, unless
+/// the host already failed.
+fn filter_failures(results: &mut [HostRunResult], filters: &HostFilters) {
+    for (host, filter) in filters {
+        let code = match filter.status() {
+            Some(code) if code != 0 => code,
+            _ => continue,
+        };
+        let host_results = || results.iter().filter(|result| result.name() == host);
+        if host_results().any(|result| !result.success()) {
+            continue;
+        }
+        if let Some(result) = results
+            .iter_mut()
+            .rev()
+            .find(|result| result.name() == host)
         {
-            let secs = metrics.duration().as_secs();
-            let ms = metrics.duration().subsec_millis();
-            println!(
-                "'{}' run on '{}' in {}.{}",
-                metrics.cmd_name(),
-                metrics.hostname(),
-                secs,
-                ms
-            );
+            println!("The filter exited with {code} on '{}'", result.hostname());
+            let _ = result.set_error(Some(format!("The filter exited with {code}")));
         }
+    }
+}
+
+/// The hosts of the run, in run order, each once.
+fn run_hosts(multiplex_maps: &[MultiplexMapType]) -> IndexSet<&str> {
+    multiplex_maps
+        .iter()
+        .flat_map(IndexMap::keys)
+        .map(String::as_str)
+        .collect()
+}
+
+/// Print the hosts of the run, for `--print-hosts`.
+fn print_hosts(multiplex_maps: &[MultiplexMapType]) {
+    for host in run_hosts(multiplex_maps) {
+        println!("{host}");
+    }
+}
 
+/// Check that every host of the run can be connected and authenticated to, for
+/// `--check-auth`.  The hosts are checked at the same time, and reported in
+/// run order.
+fn check_auth(
+    multiplex_maps: &[MultiplexMapType],
+    connect: Connect<'_>,
+    auth_retries: usize,
+) -> MusshResult<()> {
+    let mut failed = 0;
+    for (name, (method, checked)) in check_hosts(multiplex_maps, connect, auth_retries) {
+        match checked {
+            Ok(()) => println!("'{name}' could run, auth: {method}"),
+            Err(e) => {
+                failed += 1;
+                println!("'{name}' could not run, auth: {method}: {e}");
+            }
+        }
+    }
+
+    if failed == 0 {
         Ok(())
+    } else {
+        Err(MusshErrKind::HostsFailed(failed, 1).into())
+    }
+}
+
+/// Connect and authenticate to every host of the run, as many at the same time
+/// as `--parallel` allows, and open a channel, without running anything, trying
+/// each again as the `retry` policy allows.  Returns how each host was
+/// authenticated to, and whether it could be, in run order.
+fn check_hosts(
+    multiplex_maps: &[MultiplexMapType],
+    (timeouts, host_keys, algorithms, max_parallel, retry): Connect<'_>,
+    auth_retries: usize,
+) -> IndexMap<String, (String, Result<(), String>)> {
+    let (tx, rx) = mpsc::channel();
+    let slots = Arc::new(Semaphore::new(max_parallel));
+    let agent_sock = env::var("SSH_AUTH_SOCK").ok();
+    let mut methods = IndexMap::new();
+
+    for (name, (host, _)) in multiplex_maps.iter().flat_map(IndexMap::iter) {
+        if methods.contains_key(name) {
+            continue;
+        }
+        let method = auth::describe(
+            host.hostname(),
+            host.username(),
+            host.pem().as_deref(),
+            agent_sock.as_deref(),
+        );
+        let _old = methods.insert(name.clone(), method);
+        let timeout = timeouts.for_host(name);
+        let algorithms = algorithms.for_host(name).clone();
+        let (name, host, tx) = (name.clone(), host.clone(), tx.clone());
+        let (host_keys, slots, retry) = (host_keys.clone(), Arc::clone(&slots), retry.cloned());
+        let _handle = thread::spawn(move || {
+            let _slot = slots.acquire();
+            let check = || {
+                auth::check(
+                    host.hostname(),
+                    host.username(),
+                    *host.port(),
+                    host.pem().as_deref(),
+                    (timeout, auth_retries),
+                    &host_keys,
+                    &algorithms,
+                )
+            };
+            let (checked, attempts) = match &retry {
+                Some(retry) => retry.attempt(&name, None, check, Result::is_err),
+                None => (check(), 1),
+            };
+            let _res = tx.send((name, checked.map_err(|e| with_attempts(&e, attempts))));
+        });
+    }
+
+    drop(tx);
+    let mut checked: HashMap<String, Result<(), String>> = rx.into_iter().collect();
+    methods
+        .into_iter()
+        .map(|(name, method)| {
+            let checked = checked
+                .remove(&name)
+                .unwrap_or_else(|| Err("the check panicked".to_string()));
+            (name, (method, checked))
+        })
+        .collect()
+}
+
+/// With `--retries`, or `retries` in the `[options]`, how a host that can't be
+/// connected to is tried again.
+fn retry(
+    matches: &ArgMatches<'_>,
+    options: &Options,
+    timeouts: &ConnectTimeouts,
+) -> MusshResult<Option<Retry>> {
+    let Some(retries) = option_number(matches, options, "retries")? else {
+        return Ok(None);
+    };
+    let delay = positive_number(matches, "retry_delay")?.map_or(DEFAULT_RETRY_DELAY, |millis| {
+        Duration::from_millis(u64::try_from(millis).unwrap_or(u64::MAX))
+    });
+    Ok(Some(Retry::new(retries, delay, timeouts.clone())))
+}
+
+/// How hosts are connected to: the connect timeouts, the known host keys, the
+/// ssh algorithms to prefer, the most hosts connected to at the same time, and
+/// how a host that can't be connected to is tried again.
+type Connect<'a> = (
+    &'a ConnectTimeouts,
+    &'a HostKeys,
+    &'a SshAlgorithms,
+    usize,
+    Option<&'a Retry>,
+);
+
+/// The most hosts run on at the same time, with `--parallel` or `parallel` in
+/// the `[options]`.
+fn max_parallel(matches: &ArgMatches<'_>, options: &Options) -> MusshResult<usize> {
+    Ok(option_number(matches, options, "parallel")?.unwrap_or(DEFAULT_MAX_PARALLEL))
+}
+
+/// Connect to every host before running anything, within its connect
+/// timeout and checking its host key, and drop the hosts that can't be
+/// connected to from the run, rather than leave libmussh hanging on them or
+/// talking to a host it can't trust.  With `--warmup`, also authenticate to
+/// each host and open a channel, and drop the hosts that fail that.  Each
+/// dropped host is reported, and its commands are returned as not run.
+///
+/// With `--retries`, a host is only dropped once it has been tried again as
+/// often as the retry policy allows.
+fn preconnect(
+    matches: &ArgMatches<'_>,
+    multiplex_maps: &mut Vec<MultiplexMapType>,
+    connect: Connect<'_>,
+    formatter: &Formatter,
+) -> Vec<HostRunResult> {
+    let warmup = matches.is_present("warmup");
+    let failures: IndexMap<String, String> = if warmup {
+        check_hosts(multiplex_maps, connect, 0)
+            .into_iter()
+            .filter_map(|(name, (_, checked))| checked.err().map(|e| (name, e)))
+            .collect()
+    } else {
+        unconnectable_hosts(multiplex_maps, connect)
+    };
+    let (failed, not_run) = if warmup {
+        ("failed the warmup", "the warmup failed")
+    } else {
+        ("couldn't be connected to", "unable to connect")
+    };
+    let mut results = Vec::new();
+
+    for multiplex_map in multiplex_maps.iter() {
+        for (name, (host, cmd_map)) in multiplex_map {
+            let Some(e) = failures.get(name) else {
+                continue;
+            };
+            for cmd_name in cmd_map.values().flat_map(IndexMap::keys) {
+                let mut result = HostRunResult::not_run(name, host.hostname(), cmd_name);
+                let _ = result.set_error(Some(format!("Not run, {not_run}: {e}")));
+                results.push(result);
+            }
+        }
+    }
+    for (name, e) in &failures {
+        status(
+            formatter,
+            &format!("'{name}' {failed}, not running on it: {e}"),
+        );
+    }
+    skip_completed(multiplex_maps, &failures.into_keys().collect());
+    results
+}
+
+/// Connect to every host run on over ssh, as many at the same time as
+/// `--parallel` allows, each within its connect timeout, check its host key unless `--insecure`, and close the
+/// connection again, trying each again as the `retry` policy allows.  Returns
+/// why each host that couldn't be connected to couldn't, in run order.
+fn unconnectable_hosts(
+    multiplex_maps: &[MultiplexMapType],
+    (timeouts, host_keys, algorithms, max_parallel, retry): Connect<'_>,
+) -> IndexMap<String, String> {
+    let (tx, rx) = mpsc::channel();
+    let slots = Arc::new(Semaphore::new(max_parallel));
+    let mut names = IndexSet::new();
+
+    for (name, (host, _)) in multiplex_maps.iter().flat_map(IndexMap::iter) {
+        if host.hostname() == local::LOCALHOST || !names.insert(name.clone()) {
+            continue;
+        }
+        let timeout = timeouts.for_host(name);
+        let algorithms = algorithms.for_host(name).clone();
+        let (name, host, tx) = (name.clone(), host.clone(), tx.clone());
+        let (host_keys, slots, retry) = (host_keys.clone(), Arc::clone(&slots), retry.cloned());
+        let _handle = thread::spawn(move || {
+            let _slot = slots.acquire();
+            let (hostname, port) = (host.hostname(), host.port().unwrap_or(22));
+            let connect = || {
+                if host_keys.checked() {
+                    auth::handshake(hostname, port, timeout, &host_keys, &algorithms).map(drop)
+                } else {
+                    connect::connect(hostname, port, timeout).map(drop)
+                }
+            };
+            let (connected, attempts) = match &retry {
+                Some(retry) => retry.attempt(&name, None, connect, Result::is_err),
+                None => (connect(), 1),
+            };
+            let _res = tx.send((name, connected.map_err(|e| with_attempts(&e, attempts))));
+        });
+    }
+
+    drop(tx);
+    let mut connected: HashMap<String, Result<(), String>> = rx.into_iter().collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let connected = connected
+                .remove(&name)
+                .unwrap_or_else(|| Err("the connect panicked".to_string()));
+            connected.err().map(|e| (name, e))
+        })
+        .collect()
+}
+
+/// The error of a host that was given up on, saying how many times it was
+/// tried if it was tried again.
+fn with_attempts(e: &MusshErr, attempts: usize) -> String {
+    if attempts > 1 {
+        format!("{e} ({attempts} attempts)")
+    } else {
+        e.to_string()
+    }
+}
+
+/// The number of hosts given up on at the `--max-runtime` deadline.
+fn timed_out_hosts(results: &[HostRunResult]) -> usize {
+    results
+        .iter()
+        .filter(|result| *result.timed_out())
+        .map(HostRunResult::name)
+        .collect::<IndexSet<_>>()
+        .len()
+}
+
+fn failed_hosts(results: &[HostRunResult]) -> usize {
+    results
+        .iter()
+        .filter(|result| !result.success())
+        .map(HostRunResult::name)
+        .collect::<IndexSet<_>>()
+        .len()
+}
+
+/// Build the resolved sync hosts and the multiplex maps to run, in order.
+///
+/// The hosts/commands given on the command line and each `--plan` are resolved
+/// independently.  They are merged into one map unless `--ordered` was given,
+/// in which case each plan gets its own map and is run after the previous one.
+/// Add the `--target` hosts and the `--exec` command to the config, and select
+/// them in the runtime config.
+fn one_off_config(
+    config: &Config,
+    matches: &ArgMatches<'_>,
+    default_cmd: Option<&str>,
+    tagged: &[String],
+) -> MusshResult<(Config, RuntimeConfig)> {
+    let mut runtime_config = RuntimeConfig::from(matches);
+    let mut hosts = targets::rejoin_ranges(matches.values_of("hosts").into_iter().flatten());
+    hosts.extend(tagged.iter().cloned());
+    let _ = runtime_config
+        .set_hosts(hosts)
+        .set_sync_hosts(targets::rejoin_ranges(
+            matches.values_of("sync_hosts").into_iter().flatten(),
+        ));
+    let mut targets = Vec::new();
+    for target in matches.values_of("target").into_iter().flatten() {
+        targets.extend(targets::expand_ranges(target)?);
+    }
+    let exec = matches.value_of("exec");
+    let no_cmds = runtime_config.cmds().is_empty() && exec.is_none();
+    if no_cmds && (!runtime_config.hosts().is_empty() || !targets.is_empty()) {
+        match default_cmd {
+            Some(cmd) => {
+                let _ = runtime_config.set_cmds(IndexSet::from_iter(vec![cmd.to_string()]));
+            }
+            None if !matches.is_present("plan") => {
+                return Err(
+                    "Nothing to run on the hosts, use --commands, --exec or a default_cmd in \
+                     the config"
+                        .into(),
+                );
+            }
+            None => {}
+        }
+    }
+    if targets.is_empty() && exec.is_none() {
+        return Ok((config.clone(), runtime_config));
+    }
+
+    let target_names: Vec<&str> = targets.iter().map(String::as_str).collect();
+    let config = targets::add_targets(config, &target_names, matches.value_of("pem"), exec)?;
+    let mut hosts = runtime_config.hosts().clone();
+    hosts.extend(targets.iter().cloned());
+    let mut cmds = runtime_config.cmds().clone();
+    if exec.is_some() {
+        let _ = cmds.insert(targets::EXEC_CMD.to_string());
+    }
+    if !targets.is_empty() && cmds.is_empty() {
+        return Err("Nothing to run on the targets, use --exec or --commands".into());
+    }
+    let _ = runtime_config.set_hosts(hosts).set_cmds(cmds);
+    Ok((config, runtime_config))
+}
+
+/// The hosts selected with `--tag`, in config order, each once.  An expression
+/// that matches no hosts is warned about.
+fn tagged_hosts(
+    extensions: &Extensions,
+    matches: &ArgMatches<'_>,
+    warnings: &mut Warnings,
+) -> MusshResult<Vec<String>> {
+    let Some(exprs) = matches.values_of("tag") else {
+        return Ok(Vec::new());
+    };
+    let host_tags = tags::host_tags(extensions)?;
+    let mut tagged = IndexSet::new();
+    for expr in exprs {
+        let selected = tags::select(&expr.parse::<TagExpr>()?, &host_tags);
+        if selected.is_empty() {
+            warnings.warn(format!("--tag '{expr}' matches no hosts"));
+        }
+        tagged.extend(selected);
+    }
+    Ok(tagged.into_iter().collect())
+}
+
+fn multiplex_maps(
+    config: &Config,
+    runtime_config: &RuntimeConfig,
+    matches: &ArgMatches<'_>,
+    warnings: &mut Warnings,
+) -> MusshResult<(IndexSet<String>, Vec<MultiplexMapType>)> {
+    let unique = !matches.is_present("no_unique");
+    let (resolved, multiplex_map) = targets::to_host_map(config, runtime_config, unique, warnings)?;
+    let mut multiplex_maps = vec![multiplex_map];
+
+    for plan in matches.values_of("plan").into_iter().flatten() {
+        let (group, cmd) = parse_plan(plan)?;
+        let mut plan_config = RuntimeConfig::default();
+        let _ = plan_config.set_hosts(IndexSet::from_iter(vec![group]));
+        let _ = plan_config.set_cmds(IndexSet::from_iter(vec![cmd]));
+        let (_, plan_map) = targets::to_host_map(config, &plan_config, unique, warnings)?;
+
+        if matches.is_present("ordered") {
+            multiplex_maps.push(plan_map);
+        } else if let Some(multiplex_map) = multiplex_maps.first_mut() {
+            merge_map(multiplex_map, plan_map);
+        }
+    }
+
+    if !unique {
+        repeat_hosts(
+            &mut multiplex_maps,
+            &targets::repeats(config, runtime_config.hosts())?,
+        );
+    }
+
+    let only: IndexSet<&str> = matches.values_of("only").into_iter().flatten().collect();
+    if !only.is_empty() {
+        only_hosts(&mut multiplex_maps, &only)?;
     }
+
+    for host in matches.values_of("host_verbose").into_iter().flatten() {
+        if !multiplex_maps
+            .iter()
+            .any(|multiplex_map| multiplex_map.contains_key(host))
+        {
+            warnings.warn(format!("--host-verbose host '{host}' isn't in the run"));
+        }
+    }
+
+    multiplex_maps.retain(|multiplex_map| !multiplex_map.is_empty());
+    Ok((resolved.sync_hosts().clone(), multiplex_maps))
 }
 
-fn create_metrics_table(conn: &Connection) -> MusshResult<()> {
-    let _rows_changed = conn.execute(
-        "CREATE TABLE IF NOT EXISTS metrics (
-          id         INTEGER PRIMARY KEY,
-          hostname   TEXT NOT NULL,
-          cmdname    TEXT NOT NULL,
-          secs       INTEGER NOT NULL,
-          micros     INTEGER NOT NULL,
-          timestamp  INTEGER NOT NULL
-        )",
-        [],
-    )?;
+/// Run each of the `repeats` hosts again, once for each time it is repeated,
+/// in maps run after the others.
+fn repeat_hosts(multiplex_maps: &mut Vec<MultiplexMapType>, repeats: &[String]) {
+    let mut rounds: Vec<MultiplexMapType> = Vec::new();
+
+    for name in repeats {
+        let entry = multiplex_maps
+            .iter()
+            .find_map(|multiplex_map| multiplex_map.get(name))
+            .cloned();
+        if let Some(entry) = entry {
+            match rounds.iter_mut().find(|round| !round.contains_key(name)) {
+                Some(round) => {
+                    let _old = round.insert(name.clone(), entry);
+                }
+                None => rounds.push(IndexMap::from_iter(vec![(name.clone(), entry)])),
+            }
+        }
+    }
+    multiplex_maps.extend(rounds);
+}
+
+/// Keep only the `--only` hosts in the multiplex maps.  Each of them must be
+/// one of the hosts selected.
+fn only_hosts(multiplex_maps: &mut [MultiplexMapType], only: &IndexSet<&str>) -> MusshResult<()> {
+    let missing: Vec<&str> = only
+        .iter()
+        .filter(|name| {
+            !multiplex_maps
+                .iter()
+                .any(|multiplex_map| multiplex_map.contains_key(**name))
+        })
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "Not among the selected hosts, for --only: {}",
+            missing.join(", ")
+        )
+        .into());
+    }
+
+    for multiplex_map in multiplex_maps.iter_mut() {
+        multiplex_map.retain(|name, _| only.contains(name.as_str()));
+    }
     Ok(())
 }
 
-fn host_file_logger(stdout: &Option<Logger>, hostname: &str) -> Option<Logger> {
-    let mut host_file_path = if let Some(mut config_dir) = dirs::config_dir() {
+/// Run the sync commands on the sync hosts, before anything else.
+///
+/// This is a canary phase: it runs to completion before anything else is
+/// started, and if a sync command failed on any sync host nothing else is run.
+/// Returns the results of the phase and the multiplex maps left to run, which
+/// no longer contain the commands it ran.  Every command left unrun by a
+/// failed phase is in the results as not run, and reported as such.
+fn preflight<F>(
+    multiplex: &Multiplex,
+    sync_hosts: &IndexSet<String>,
+    multiplex_maps: Vec<MultiplexMapType>,
+    hooks: &Hooks,
+    mut report: F,
+) -> (Vec<HostRunResult>, Vec<MultiplexMapType>)
+where
+    F: FnMut(&HostRunResult),
+{
+    let (preflight_map, multiplex_maps) = split_preflight(multiplex_maps, sync_hosts);
+    let (mut results, _) = run_waves(
+        multiplex,
+        &IndexSet::new(),
+        vec![preflight_map],
+        false,
+        hooks,
+        &mut report,
+    );
+    if results.iter().all(HostRunResult::success) {
+        return (results, multiplex_maps);
+    }
+
+    let failed = failed_hosts(&results);
+    println!("Sync commands failed on {failed} sync host(s), not running on the other hosts");
+    for multiplex_map in &multiplex_maps {
+        for (name, (host, cmd_map)) in multiplex_map {
+            for cmd_name in cmd_map.values().flat_map(IndexMap::keys) {
+                let mut result = HostRunResult::not_run(name, host.hostname(), cmd_name);
+                let _ = result.set_error(Some(format!(
+                    "Not run, the sync commands failed on {failed} sync host(s)"
+                )));
+                report(&result);
+                results.push(result);
+            }
+        }
+    }
+    (results, Vec::new())
+}
+
+/// Move the sync commands of the sync hosts out of the multiplex maps and into
+/// a map of their own.  Hosts left without commands are dropped.
+fn split_preflight(
+    multiplex_maps: Vec<MultiplexMapType>,
+    sync_hosts: &IndexSet<String>,
+) -> (MultiplexMapType, Vec<MultiplexMapType>) {
+    let mut preflight_map = MultiplexMapType::new();
+    let multiplex_maps = multiplex_maps
+        .into_iter()
+        .map(|mut multiplex_map| {
+            for (name, (host, cmd_map)) in multiplex_map
+                .iter_mut()
+                .filter(|(name, _)| sync_hosts.contains(*name))
+            {
+                let sync_cmds: IndexMap<_, _> = cmd_map
+                    .iter_mut()
+                    .filter(|(kind, cmds)| runner::is_sync_kind(*kind) && !cmds.is_empty())
+                    .map(|(kind, cmds)| (*kind, mem::take(cmds)))
+                    .collect();
+                if !sync_cmds.is_empty() {
+                    let mut host_map = MultiplexMapType::new();
+                    let _old = host_map.insert(name.clone(), (host.clone(), sync_cmds));
+                    merge_map(&mut preflight_map, host_map);
+                }
+            }
+            multiplex_map.retain(|_, (_, cmd_map)| cmd_map.values().any(|cmds| !cmds.is_empty()));
+            multiplex_map
+        })
+        .filter(|multiplex_map| !multiplex_map.is_empty())
+        .collect();
+
+    (preflight_map, multiplex_maps)
+}
+
+/// Remove the named commands from every host, dropping hosts left with nothing
+/// to run.  Returns the names that weren't run on any host.
+fn skip_commands<'a>(
+    multiplex_maps: &mut Vec<MultiplexMapType>,
+    names: &[&'a str],
+) -> Vec<&'a str> {
+    let mut found = IndexSet::new();
+
+    for multiplex_map in multiplex_maps.iter_mut() {
+        for (_, cmd_map) in multiplex_map.values_mut() {
+            for cmds in cmd_map.values_mut() {
+                for name in names {
+                    if cmds.shift_remove(*name).is_some() {
+                        let _ = found.insert(*name);
+                    }
+                }
+            }
+        }
+        multiplex_map.retain(|_, (_, cmd_map)| cmd_map.values().any(|cmds| !cmds.is_empty()));
+    }
+    multiplex_maps.retain(|multiplex_map| !multiplex_map.is_empty());
+
+    names
+        .iter()
+        .filter(|name| !found.contains(*name))
+        .copied()
+        .collect()
+}
+
+/// Wrap every command so the remote side kills it after `secs` seconds.
+fn remote_timeout(multiplex_map: &mut MultiplexMapType, secs: usize) {
+    for (_, cmd_map) in multiplex_map.values_mut() {
+        for command in cmd_map.values_mut().flat_map(IndexMap::values_mut) {
+            *command = with_timeout(command, secs);
+        }
+    }
+}
+
+/// Run the command under coreutils `timeout` if the host has it.  If it
+/// doesn't, the command is run as is, after a line saying so.
+fn with_timeout(command: &str, secs: usize) -> String {
+    format!(
+        "if command -v timeout >/dev/null 2>&1; then timeout {secs} sh -c {quoted}; \
+         else echo 'mussh: timeout not found, running without a remote deadline'; \
+         sh -c {quoted}; fi",
+        quoted = shell_quote(command)
+    )
+}
+
+/// Parse the numeric argument with the given name, if it was given.
+pub(super) fn positive_number(matches: &ArgMatches<'_>, name: &str) -> MusshResult<Option<usize>> {
+    match matches.value_of(name) {
+        Some(value) => match value.parse::<usize>() {
+            Ok(number) if number > 0 => Ok(Some(number)),
+            _ => Err(format!(
+                "Invalid value '{value}' for --{}, expected a positive number",
+                name.replace('_', "-")
+            )
+            .into()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The numeric argument with the given name, otherwise its default in the
+/// `[options]` of the config, if either is given.
+pub(super) fn option_number(
+    matches: &ArgMatches<'_>,
+    options: &Options,
+    name: &str,
+) -> MusshResult<Option<usize>> {
+    Ok(positive_number(matches, name)?.or_else(|| options.get(name)))
+}
+
+/// Split the multiplex map into waves of at most `size` hosts, in host order.
+fn waves(multiplex_map: MultiplexMapType, size: usize) -> Vec<MultiplexMapType> {
+    let mut waves: Vec<MultiplexMapType> = Vec::new();
+
+    for (hostname, entry) in multiplex_map {
+        match waves.last_mut() {
+            Some(wave) if wave.len() < size => {
+                let _old = wave.insert(hostname, entry);
+            }
+            _ => {
+                let mut wave = MultiplexMapType::new();
+                let _old = wave.insert(hostname, entry);
+                waves.push(wave);
+            }
+        }
+    }
+
+    waves
+}
+
+/// Run each wave to completion, in order, passing every result to `report` as
+/// its wave finishes.
+///
+/// If `require_success` is set and a host in a wave failed, the remaining waves
+/// are not started and the (1-based) number of the failed wave is returned
+/// alongside the results.
+fn run_waves<F>(
+    multiplex: &Multiplex,
+    sync_hosts: &IndexSet<String>,
+    waves: Vec<MultiplexMapType>,
+    require_success: bool,
+    hooks: &Hooks,
+    mut report: F,
+) -> (Vec<HostRunResult>, Option<usize>)
+where
+    F: FnMut(&HostRunResult),
+{
+    let wave_count = waves.len();
+    let mut results = Vec::new();
+
+    for (idx, wave) in waves.into_iter().enumerate() {
+        let mut wave_failed = false;
+        for result in runner::run(multiplex, sync_hosts, wave, hooks) {
+            report(&result);
+            wave_failed |= !result.success();
+            results.push(result);
+        }
+
+        if wave_failed && require_success && idx + 1 < wave_count {
+            return (results, Some(idx + 1));
+        }
+    }
+
+    (results, None)
+}
+
+/// The `--label`s of the run.
+fn labels<'a>(matches: &'a ArgMatches<'_>) -> MusshResult<Vec<(&'a str, &'a str)>> {
+    matches
+        .values_of("label")
+        .into_iter()
+        .flatten()
+        .map(parse_label)
+        .collect()
+}
+
+/// Parse a `KEY=VALUE` label into its key and value.  The value may be empty.
+fn parse_label(label: &str) -> MusshResult<(&str, &str)> {
+    match label.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key, value)),
+        _ => Err(format!("Invalid label '{label}', expected KEY=VALUE").into()),
+    }
+}
+
+/// Parse a `GROUP=CMD` plan into its group and command.
+fn parse_plan(plan: &str) -> MusshResult<(String, String)> {
+    match plan.split_once('=') {
+        Some((group, cmd)) if !group.is_empty() && !cmd.is_empty() => {
+            Ok((group.to_string(), cmd.to_string()))
+        }
+        _ => Err(format!("Invalid plan '{plan}', expected GROUP=CMD").into()),
+    }
+}
+
+/// Merge the commands in `other` into `multiplex_map`, host by host.
+fn merge_map(multiplex_map: &mut MultiplexMapType, other: MultiplexMapType) {
+    for (hostname, (host, cmd_map)) in other {
+        let entry = multiplex_map
+            .entry(hostname)
+            .or_insert_with(|| (host, IndexMap::new()));
+
+        for (cmd_type, cmds) in cmd_map {
+            entry.1.entry(cmd_type).or_default().extend(cmds);
+        }
+    }
+}
+
+/// The `--host-verbose` hosts, and the trace logger for their stdout.
+fn verbose_hosts<'a>(matches: &'a ArgMatches<'_>) -> (Vec<&'a str>, Option<Logger>) {
+    let verbose_hosts: Vec<&str> = matches
+        .values_of("host_verbose")
+        .into_iter()
+        .flatten()
+        .collect();
+    let verbose_stdout = (!verbose_hosts.is_empty()).then(|| logging::stdout_logger(Level::Trace));
+    (verbose_hosts, verbose_stdout)
+}
+
+/// The stream the `--tail` output is printed to, also written to the `--tee`
+/// file.
+fn stream(matches: &ArgMatches<'_>) -> MusshResult<Stream> {
+    match matches.value_of("tee") {
+        Some(path) => Stream::tee(Path::new(path), matches.is_present("tee_color")),
+        None => Ok(Stream::default()),
+    }
+}
+
+/// The color of each host's `--tail` prefix, if they are colored, and the
+/// width of the widest `[host]` prefix, to line up the output of every host.
+fn tail_prefixes(
+    matches: &ArgMatches<'_>,
+    multiplex_maps: &[MultiplexMapType],
+) -> (HashMap<String, u8>, usize) {
+    let hosts = run_hosts(multiplex_maps);
+    let colors = if matches.is_present("tail") && use_color(matches) {
+        host_colors(&hosts.iter().copied().collect::<Vec<_>>())
+    } else {
+        HashMap::new()
+    };
+    let width = hosts
+        .iter()
+        .map(|host| host.chars().count() + 2)
+        .max()
+        .unwrap_or_default();
+    (colors, width)
+}
+
+/// The logger writing the host's output to its log file.
+fn host_file_logger(
+    stdout: Option<&Logger>,
+    stderr: Option<&Logger>,
+    hostname: &str,
+    run_id: &str,
+    settings: &LogFile,
+) -> Option<Logger> {
+    let path = log_dir().join(log_file_name(hostname, run_id));
+    file_logger(stdout, stderr, settings.at(path))
+}
+
+/// A logger writing to the log file.  If the file can't be created, the output
+/// goes without one, with a warning.
+fn file_logger(
+    stdout: Option<&Logger>,
+    stderr: Option<&Logger>,
+    log_file: LogFile,
+) -> Option<Logger> {
+    let path = log_file.path().clone();
+    try_trace!(stdout, "Log Path: {}", path.display());
+
+    let file_drain = fs::create_dir_all(path.parent().unwrap_or(&path))
+        .map_err(MusshErr::from)
+        .and_then(|()| FileDrain::try_from(log_file));
+    match file_drain {
+        Ok(file_drain) => {
+            let async_file_drain = slog_async::Async::new(file_drain).build().fuse();
+            Some(Logger::root(async_file_drain, o!()))
+        }
+        Err(e) => {
+            try_warn!(
+                stderr,
+                "Not logging to {}, unable to create it: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// How the log files are written: their `--log-max-bytes` and `--log-keep`
+/// rotation and their timestamps.  The path of each file is set as it's made.
+fn log_file(matches: &ArgMatches<'_>) -> MusshResult<LogFile> {
+    let keep = positive_number(matches, "log_keep")?.unwrap_or(DEFAULT_LOG_KEEP);
+    let rotation = positive_number(matches, "log_max_bytes")?
+        .map(|max_bytes| Rotation::new(max_bytes as u64, keep));
+    let timestamps = if matches.is_present("no_log_timestamps") {
+        Timestamps::Off
+    } else {
+        Timestamps::new(
+            matches.value_of("log_time_format"),
+            matches.is_present("log_local_time"),
+        )?
+    };
+    Ok(LogFile::default()
+        .with_rotation(rotation)
+        .with_timestamps(timestamps))
+}
+
+/// Where the log files of the run are, for the message at the end of it.
+fn log_files(matches: &ArgMatches<'_>, run_id: &str) -> PathBuf {
+    if matches.is_present("log_per_command") {
+        log_dir()
+            .join("<host>")
+            .join(log_file_name("<cmd>", run_id))
+    } else {
+        log_dir().join(log_file_name("<host>", run_id))
+    }
+}
+
+/// The stdout logger of a `--host-verbose` host, which logs to its log file
+/// too.
+fn verbose_logger(verbose: &Logger, file_logger: Option<&Logger>) -> Logger {
+    match file_logger {
+        Some(file_logger) => Logger::root(
+            Duplicate::new(verbose.clone(), file_logger.clone()).fuse(),
+            o!(),
+        ),
+        None => verbose.clone(),
+    }
+}
+
+/// The directory the host log files are written to.
+fn log_dir() -> PathBuf {
+    if let Some(mut config_dir) = dirs::config_dir() {
         config_dir.push(env!("CARGO_PKG_NAME"));
         config_dir
     } else {
         PathBuf::new()
+    }
+}
+
+/// The log file of the host for the run, i.e. `web01.20261016T101500.123Z-4242.log`,
+/// so runs never write to the same file, and sort by when they started.
+fn log_file_name(hostname: &str, run_id: &str) -> String {
+    if run_id.is_empty() {
+        format!("{hostname}.log")
+    } else {
+        format!("{hostname}.{run_id}.log")
+    }
+}
+
+fn tail_logger<D>(file_logger: Option<Logger>, tail_drain: D) -> Logger
+where
+    D: Drain<Ok = (), Err = Never> + Send + Sync + RefUnwindSafe + UnwindSafe + 'static,
+{
+    if let Some(file_logger) = file_logger {
+        Logger::root(Duplicate::new(tail_drain, file_logger).fuse(), o!())
+    } else {
+        Logger::root(tail_drain, o!())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        block_output, failed_hosts, filter_failures, hash_report, log_file_name, multiplex_maps,
+        one_off_config, parse_label, parse_plan, positive_number, preconnect, preflight,
+        remote_timeout, run_hosts, run_waves, skip_commands, skip_completed, tagged_hosts,
+        timed_out_hosts, waves, ExitCodeMode, Run, SortOutputBy,
     };
+    use crate::algorithms::SshAlgorithms;
+    use crate::connect::ConnectTimeouts;
+    use crate::error::MusshResult;
+    use crate::extensions::Extensions;
+    use crate::format::{Formatter, Json};
+    use crate::known_hosts::{HostKeyCheck, HostKeys};
+    use crate::logging::OutputFilter;
+    use crate::runner::{self, Hooks, HostRunResult};
+    use crate::subcmd::Subcommand;
+    use crate::warnings::Warnings;
+    use indexmap::IndexMap;
+    use libmussh::{Config, Multiplex, MultiplexMapType, RuntimeConfig};
+    use slog::{o, Discard, Logger};
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
 
-    host_file_path.push(hostname);
-    let _ = host_file_path.set_extension("log");
+    const LOCALHOST_TOML: &str = r#"[hostlist.ok]
+hostnames = ["a", "b"]
+[hostlist.bad]
+hostnames = ["c"]
+[hostlist.all]
+hostnames = ["a", "b", "c"]
+[hosts.a]
+hostname = "localhost"
+username = "jozias"
+[hosts.b]
+hostname = "localhost"
+username = "jozias"
+[hosts.c]
+hostname = "localhost"
+username = "jozias"
+[cmd.pass]
+command = "true"
+[cmd.fail]
+command = "false"
+"#;
 
-    try_trace!(stdout, "Log Path: {}", host_file_path.display());
+    fn test_config() -> MusshResult<Config> {
+        Ok(Config::try_from(
+            PathBuf::from("test_cfg").join("mussh.toml"),
+        )?)
+    }
 
-    if let Ok(file_drain) = FileDrain::try_from(host_file_path) {
-        let async_file_drain = slog_async::Async::new(file_drain).build().fuse();
-        let file_logger = Logger::root(async_file_drain, o!());
-        Some(file_logger)
-    } else {
-        None
+    fn cmd_names(multiplex_map: &MultiplexMapType, hostname: &str) -> Vec<String> {
+        multiplex_map
+            .get(hostname)
+            .map(|(_, cmds)| cmds.values().flat_map(|c| c.keys().cloned()).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn plan_parses() -> MusshResult<()> {
+        assert_eq!(
+            parse_plan("db=migrate")?,
+            ("db".to_string(), "migrate".to_string())
+        );
+        assert!(parse_plan("db").is_err());
+        assert!(parse_plan("=migrate").is_err());
+        assert!(parse_plan("db=").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn label_parses() -> MusshResult<()> {
+        assert_eq!(parse_label("stage=canary")?, ("stage", "canary"));
+        assert_eq!(parse_label("note=a=b")?, ("note", "a=b"));
+        assert_eq!(parse_label("stage=")?, ("stage", ""));
+        assert!(parse_label("stage").is_err());
+        assert!(parse_label("=canary").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn plans_merge() -> MusshResult<()> {
+        let config = test_config()?;
+        let matches = Run::subcommand().get_matches_from_safe(vec![
+            "run", "--plan", "m1=ls", "--plan", "m2=uname", "--plan", "m1=uname",
+        ])?;
+        let (_, mut maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        assert_eq!(maps.len(), 1);
+        let map = maps.remove(0);
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec!["m1", "m2"]);
+        assert_eq!(cmd_names(&map, "m1"), vec!["ls", "uname"]);
+        assert_eq!(cmd_names(&map, "m2"), vec!["uname"]);
+        Ok(())
+    }
+
+    #[test]
+    fn plans_ordered() -> MusshResult<()> {
+        let config = test_config()?;
+        let matches = Run::subcommand().get_matches_from_safe(vec![
+            "run",
+            "--plan",
+            "m2=ls",
+            "--plan",
+            "m1=uname",
+            "--ordered",
+        ])?;
+        let (_, maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        assert_eq!(maps.len(), 2);
+        assert_eq!(cmd_names(&maps[0], "m2"), vec!["ls"]);
+        assert_eq!(cmd_names(&maps[1], "m1"), vec!["uname"]);
+        Ok(())
+    }
+
+    fn run_exit_code(args: &[&str]) -> MusshResult<i32> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let matches = Run::subcommand().get_matches_from_safe(args)?;
+        let (sync_hosts, maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        let results: Vec<_> = maps
+            .into_iter()
+            .flat_map(|map| runner::run(&Multiplex::default(), &sync_hosts, map, &Hooks::default()))
+            .collect();
+        Ok(ExitCodeMode::from(&matches).exit_code(&results))
+    }
+
+    #[test]
+    fn exit_code_any_fail() -> MusshResult<()> {
+        let some_fail = ["run", "--plan", "ok=pass", "--plan", "bad=fail"];
+        assert_eq!(run_exit_code(&some_fail)?, 1);
+        assert_eq!(run_exit_code(&["run", "--plan", "all=pass"])?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn output_sorts() -> MusshResult<()> {
+        let config: Config = toml::from_str(&format!(
+            "{LOCALHOST_TOML}[cmd.slow]\ncommand = \"sleep 0.3\"\n"
+        ))?;
+        let matches = Run::subcommand().get_matches_from_safe(vec![
+            "run", "--plan", "c=pass", "--plan", "b=slow", "--plan", "a=fail",
+        ])?;
+        let (sync_hosts, maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        let results: Vec<_> = maps
+            .into_iter()
+            .flat_map(|map| runner::run(&Multiplex::default(), &sync_hosts, map, &Hooks::default()))
+            .collect();
+
+        let sorted = |by: SortOutputBy| -> Vec<String> { by.hosts(&results) };
+        assert_eq!(sorted(SortOutputBy::Host), vec!["a", "b", "c"]);
+        assert_eq!(sorted(SortOutputBy::Duration)[0], "b");
+        assert_eq!(sorted(SortOutputBy::Status), vec!["a", "b", "c"]);
+
+        let matches = Run::subcommand().get_matches_from_safe(vec![
+            "run",
+            "-h",
+            "all",
+            "--tail",
+            "--sort-output-by",
+            "status",
+        ])?;
+        assert_eq!(SortOutputBy::from(&matches), SortOutputBy::Status);
+        assert!(block_output(&matches, &HashMap::new(), &Arc::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn quiet_doesnt_stream() {
+        let quiet = |streaming: &str| {
+            Run::subcommand()
+                .get_matches_from_safe(vec!["run", "-h", "all", "--quiet", streaming])
+                .is_err()
+        };
+        assert!(quiet("--tail"));
+        assert!(quiet("--prefix"));
+        assert!(quiet("--host-verbose=a"));
+        assert!(!quiet("--format=jsonl"));
+    }
+
+    #[test]
+    fn exit_code_all_fail() -> MusshResult<()> {
+        let mode = ["--exit-code-mode", "all-fail"];
+        let some_fail = ["run", "--plan", "ok=pass", "--plan", "bad=fail"];
+        assert_eq!(run_exit_code(&[&some_fail[..], &mode[..]].concat())?, 0);
+        let all_fail = ["run", "--plan", "all=fail"];
+        assert_eq!(run_exit_code(&[&all_fail[..], &mode[..]].concat())?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn exit_code_count() -> MusshResult<()> {
+        let mode = ["--exit-code-mode", "count"];
+        let some_fail = ["run", "--plan", "ok=pass", "--plan", "bad=fail"];
+        assert_eq!(run_exit_code(&[&some_fail[..], &mode[..]].concat())?, 1);
+        let all_fail = ["run", "--plan", "all=fail", "--plan", "ok=pass"];
+        assert_eq!(run_exit_code(&[&all_fail[..], &mode[..]].concat())?, 3);
+        let all_pass = ["run", "--plan", "all=pass"];
+        assert_eq!(run_exit_code(&[&all_pass[..], &mode[..]].concat())?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn waves_split() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let matches = Run::subcommand().get_matches_from_safe(vec!["run", "--plan", "all=pass"])?;
+        let (_, mut maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        let waves = waves(maps.remove(0), 2);
+        assert_eq!(waves.len(), 2);
+        assert_eq!(waves[0].keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(waves[1].keys().collect::<Vec<_>>(), vec!["c"]);
+
+        let matches = Run::subcommand().get_matches_from_safe(vec![
+            "run",
+            "-h",
+            "all",
+            "--wave-size",
+            "0",
+        ])?;
+        assert!(positive_number(&matches, "wave_size").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn waves_require_success() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let matches = Run::subcommand().get_matches_from_safe(vec!["run", "--plan", "all=fail"])?;
+        let (sync_hosts, mut maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        let map = maps.remove(0);
+        let multiplex = Multiplex::default();
+
+        let (results, failed_wave) = run_waves(
+            &multiplex,
+            &sync_hosts,
+            waves(map.clone(), 1),
+            true,
+            &Hooks::default(),
+            |_| {},
+        );
+        assert_eq!(results.len(), 1);
+        assert_eq!(failed_wave, Some(1));
+
+        let (results, failed_wave) = run_waves(
+            &multiplex,
+            &sync_hosts,
+            waves(map, 1),
+            false,
+            &Hooks::default(),
+            |_| {},
+        );
+        assert_eq!(results.len(), 3);
+        assert_eq!(failed_wave, None);
+        Ok(())
+    }
+
+    /// The hosts the preflight ran on, those it left unrun, and how many hosts
+    /// are left to run on after it.
+    fn preflight_results(args: &[&str]) -> MusshResult<(Vec<String>, Vec<String>, usize)> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let matches = Run::subcommand().get_matches_from_safe(args)?;
+        let (sync_hosts, maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        let (results, rest) = preflight(
+            &Multiplex::default(),
+            &sync_hosts,
+            maps,
+            &Hooks::default(),
+            |_| {},
+        );
+        let (not_run, ran): (Vec<_>, Vec<_>) = results.iter().partition(|result| {
+            result
+                .error()
+                .as_ref()
+                .is_some_and(|e| e.starts_with("Not run"))
+        });
+        let names = |results: Vec<&HostRunResult>| {
+            results
+                .into_iter()
+                .map(|result| result.name().clone())
+                .collect()
+        };
+        Ok((
+            names(ran),
+            names(not_run),
+            rest.iter().map(IndexMap::len).sum(),
+        ))
+    }
+
+    #[test]
+    fn group_sync_aborts_on_failure() -> MusshResult<()> {
+        let args = ["run", "-h", "ok", "-c", "pass", "-s", "bad", "-y", "fail"];
+        let (ran, mut not_run, remaining_hosts) = preflight_results(&args)?;
+        assert_eq!(ran, vec!["c"]);
+        // Each command left, the sync host's own included, is reported.
+        not_run.sort();
+        assert_eq!(not_run, vec!["a", "a", "b", "b", "c"]);
+        assert_eq!(remaining_hosts, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn group_sync_proceeds_on_success() -> MusshResult<()> {
+        let args = ["run", "-h", "bad", "-c", "pass", "-s", "ok", "-y", "pass"];
+        let (mut ran, not_run, remaining_hosts) = preflight_results(&args)?;
+        ran.sort();
+        assert_eq!(ran, vec!["a", "b"]);
+        assert!(not_run.is_empty());
+        assert!(remaining_hosts > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn remote_timeouts() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let matches = Run::subcommand().get_matches_from_safe(vec!["run", "--plan", "ok=pass"])?;
+        let (sync_hosts, mut maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        let mut map = maps.remove(0);
+        remote_timeout(&mut map, 5);
+        assert!(map
+            .values()
+            .flat_map(|(_, cmds)| cmds.values().flat_map(IndexMap::values))
+            .all(|command| command.contains("timeout 5 sh -c 'true'")));
+
+        let results = runner::run(&Multiplex::default(), &sync_hosts, map, &Hooks::default());
+        assert!(results.iter().all(HostRunResult::success));
+        Ok(())
+    }
+
+    #[test]
+    fn max_runtime_times_out_hosts() -> MusshResult<()> {
+        let mut config_toml = LOCALHOST_TOML.to_string();
+        config_toml.push_str("[cmd.slow]\ncommand = \"sleep 5\"\n");
+        let config: Config = toml::from_str(&config_toml)?;
+        let matches = Run::subcommand()
+            .get_matches_from_safe(vec!["run", "--plan", "ok=pass", "--plan", "ok=slow"])?;
+        let (sync_hosts, maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_deadline(Some(Instant::now() + Duration::from_millis(500)));
+
+        let started = Instant::now();
+        let results = runner::run(&Multiplex::default(), &sync_hosts, maps[0].clone(), &hooks);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let timed_out: Vec<(&str, &str)> = results
+            .iter()
+            .filter(|result| *result.timed_out())
+            .map(|result| (result.name().as_str(), result.cmd_name().as_str()))
+            .collect();
+        assert_eq!(results.len(), 4);
+        assert_eq!(timed_out.len(), 2);
+        assert!(timed_out.iter().all(|(_, cmd_name)| *cmd_name == "slow"));
+        assert_eq!(timed_out_hosts(&results), 2);
+
+        // Nothing is started once the deadline has passed.
+        let results = runner::run(&Multiplex::default(), &sync_hosts, maps[0].clone(), &hooks);
+        assert_eq!(timed_out_hosts(&results), 2);
+        assert!(results.iter().all(|result| *result.timed_out()));
+        Ok(())
+    }
+
+    #[test]
+    fn preconnect_drops_failed_hosts() -> MusshResult<()> {
+        let config_toml = LOCALHOST_TOML.replace(
+            "[hosts.c]\nhostname = \"localhost\"",
+            "[hosts.c]\nhostname = \"127.0.0.1\"\nport = 9",
+        );
+        let config: Config = toml::from_str(&config_toml)?;
+        let formatter: Formatter = Arc::new(Json);
+        let timeouts = ConnectTimeouts::default();
+        let host_keys = HostKeys::new(HostKeyCheck::Strict, env::temp_dir().join("known_hosts"));
+
+        for (args, error) in &[
+            (
+                vec!["run", "--plan", "all=pass"],
+                "Not run, unable to connect: ",
+            ),
+            (
+                vec!["run", "--warmup", "--plan", "all=pass"],
+                "Not run, the warmup failed: ",
+            ),
+        ] {
+            let matches = Run::subcommand().get_matches_from_safe(args)?;
+            let (_, mut maps) = multiplex_maps(
+                &config,
+                &RuntimeConfig::from(&matches),
+                &matches,
+                &mut Warnings::default(),
+            )?;
+            let results = preconnect(
+                &matches,
+                &mut maps,
+                (&timeouts, &host_keys, &SshAlgorithms::default(), 1, None),
+                &formatter,
+            );
+            assert_eq!(run_hosts(&maps).into_iter().collect::<Vec<_>>(), ["a", "b"]);
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].name(), "c");
+            assert_eq!(results[0].cmd_name(), "pass");
+            assert!(results[0]
+                .error()
+                .as_deref()
+                .is_some_and(|message| message.starts_with(error)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn hash_groups_hosts() -> MusshResult<()> {
+        let mut hooks = Hooks::default();
+        let _ = hooks.set_hash(Some("sha256".parse()?));
+        let mut results = Vec::new();
+        for (plan, command) in &[("ok=pass", "echo same"), ("bad=pass", "echo drifted")] {
+            let config_toml =
+                LOCALHOST_TOML.replace("command = \"true\"", &format!("command = \"{command}\""));
+            let config: Config = toml::from_str(&config_toml)?;
+            let matches = Run::subcommand().get_matches_from_safe(vec!["run", "--plan", plan])?;
+            let (sync_hosts, maps) = multiplex_maps(
+                &config,
+                &RuntimeConfig::from(&matches),
+                &matches,
+                &mut Warnings::default(),
+            )?;
+            for map in maps {
+                results.extend(runner::run(&Multiplex::default(), &sync_hosts, map, &hooks));
+            }
+        }
+
+        // The sha256 of the captured output, "same".
+        let same = "0967115f2813a3541eaef77de9d9d5773f1c0c04314b0bbfe4ff3b3b1c55b5d5";
+        let report = hash_report(&results);
+        assert_eq!(report.len(), 7);
+        assert_eq!(report[0], "Output hashes of 'pass':");
+        assert_eq!(report[4], "'pass' had 2 distinct output(s):");
+        assert_eq!(report[1], format!("  a: {same}"));
+        assert_eq!(report[5], format!("  {same}: a, b"));
+        assert!(report[6].ends_with(": c"));
+        assert!(hash_report(&results[..0]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn log_file_names() {
+        assert_eq!(
+            log_file_name("web01", "20261016T101500.123Z-4242"),
+            "web01.20261016T101500.123Z-4242.log"
+        );
+        assert_eq!(log_file_name("web01", ""), "web01.log");
+    }
+
+    #[test]
+    fn failed_filters_fail_hosts() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let matches = Run::subcommand().get_matches_from_safe(vec!["run", "--plan", "ok=pass"])?;
+        let (sync_hosts, mut maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        let mut results = runner::run(
+            &Multiplex::default(),
+            &sync_hosts,
+            maps.remove(0),
+            &Hooks::default(),
+        );
+        assert_eq!(failed_hosts(&results), 0);
+
+        let mut filters = HashMap::new();
+        for (host, line) in &[("a", "no match"), ("b", "ERROR")] {
+            let filter = OutputFilter::new("grep ERROR", Logger::root(Discard, o!()));
+            filter.write(line);
+            filter.finish();
+            let _old = filters.insert((*host).to_string(), Arc::new(filter));
+        }
+        filter_failures(&mut results, &filters);
+
+        let failed: Vec<&String> = results
+            .iter()
+            .filter(|result| !result.success())
+            .map(HostRunResult::name)
+            .collect();
+        assert_eq!(failed, vec!["a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn resume_skips_completed_hosts() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let matches = Run::subcommand().get_matches_from_safe(vec![
+            "run",
+            "--ordered",
+            "--plan",
+            "ok=pass",
+            "--plan",
+            "bad=fail",
+        ])?;
+        let (_, mut maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        let completed = vec!["a".to_string(), "c".to_string()].into_iter().collect();
+        skip_completed(&mut maps, &completed);
+        assert_eq!(maps.len(), 1);
+        assert_eq!(maps[0].keys().collect::<Vec<_>>(), vec!["b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn skipped_commands() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let matches =
+            Run::subcommand().get_matches_from_safe(vec!["run", "-h", "all", "-c", "pass,fail"])?;
+        let (_, mut maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        let missing = skip_commands(&mut maps, &["fail", "backup"]);
+        assert_eq!(missing, vec!["backup"]);
+
+        for name in &["a", "b", "c"] {
+            let (_, cmd_map) = maps[0].get(*name).ok_or("missing host")?;
+            let cmds: Vec<&String> = cmd_map.values().flat_map(IndexMap::keys).collect();
+            assert_eq!(cmds, vec!["pass"]);
+        }
+
+        let missing = skip_commands(&mut maps, &["pass"]);
+        assert!(missing.is_empty());
+        assert!(maps.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn tags_select_hosts() -> MusshResult<()> {
+        let config_toml = LOCALHOST_TOML
+            .replace("[hosts.b]\n", "[hosts.b]\ntags = [\"web\"]\n")
+            .replace("[hosts.c]\n", "[hosts.c]\ntags = [\"web\", \"canary\"]\n");
+        let config: Config = toml::from_str(&config_toml)?;
+        let extensions = toml::from_str(&config_toml)?;
+        let hosts = |args: Vec<&str>| -> MusshResult<(Vec<String>, Warnings)> {
+            let matches = Run::subcommand().get_matches_from_safe(args)?;
+            let mut warnings = Warnings::default();
+            let tagged = tagged_hosts(&extensions, &matches, &mut warnings)?;
+            let (_, runtime_config) = one_off_config(&config, &matches, None, &tagged)?;
+            Ok((runtime_config.hosts().iter().cloned().collect(), warnings))
+        };
+
+        let (selected, warnings) = hosts(vec!["run", "--tag", "web && !canary", "-c", "pass"])?;
+        assert_eq!(selected, ["b"]);
+        assert_eq!(warnings, Warnings::default());
+        let (selected, _) = hosts(vec!["run", "-h", "a,b", "--tag", "canary", "-c", "pass"])?;
+        assert_eq!(selected, ["a", "b", "c"]);
+        let (selected, warnings) = hosts(vec!["run", "--tag", "db", "-c", "pass"])?;
+        assert!(selected.is_empty());
+        assert!(warnings.emit(None, true).is_err());
+        assert!(hosts(vec!["run", "--tag", "web &&", "-c", "pass"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn default_cmd_when_none_given() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+
+        let cmds = |args: Vec<&str>, default: Option<&str>| -> MusshResult<Vec<String>> {
+            let matches = Run::subcommand().get_matches_from_safe(args)?;
+            let (_, runtime_config) = one_off_config(&config, &matches, default, &[])?;
+            Ok(runtime_config.cmds().iter().cloned().collect())
+        };
+        assert_eq!(cmds(vec!["run", "-h", "all"], Some("pass"))?, vec!["pass"]);
+        assert_eq!(
+            cmds(vec!["run", "-h", "all", "-c", "fail"], Some("pass"))?,
+            vec!["fail"]
+        );
+        assert!(cmds(vec!["run", "-h", "all"], None).is_err());
+        assert!(cmds(vec!["run", "--plan", "all=pass"], None)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_verbose_hosts_warn() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        for (host, warned) in &[("a", false), ("web1", true)] {
+            let matches = Run::subcommand().get_matches_from_safe(vec![
+                "run",
+                "-h",
+                "all",
+                "-c",
+                "pass",
+                "--host-verbose",
+                host,
+            ])?;
+            let mut warnings = Warnings::default();
+            let _maps = multiplex_maps(
+                &config,
+                &RuntimeConfig::from(&matches),
+                &matches,
+                &mut warnings,
+            )?;
+            assert_eq!(warnings.emit(None, true).is_err(), *warned);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn run_hosts_are_deduped() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let matches = Run::subcommand().get_matches_from_safe(vec![
+            "run",
+            "-h",
+            "all,!b",
+            "-c",
+            "pass",
+            "--print-hosts",
+        ])?;
+        let (_, mut maps) = multiplex_maps(
+            &config,
+            &RuntimeConfig::from(&matches),
+            &matches,
+            &mut Warnings::default(),
+        )?;
+        maps.push(maps[0].clone());
+        assert_eq!(
+            run_hosts(&maps).into_iter().collect::<Vec<_>>(),
+            vec!["a", "c"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unwritable_metrics_db_is_skipped() -> MusshResult<()> {
+        // A directory can't be created under a file, even by root.
+        let blocker = env::temp_dir().join(format!("mussh-blocker-{}", std::process::id()));
+        fs::write(&blocker, "")?;
+        let run = Run::new(None, None, blocker.join("mussh.db"), Extensions::default());
+
+        let plain =
+            Run::subcommand().get_matches_from_safe(vec!["run", "-h", "all", "-c", "pass"])?;
+        let labelled = Run::subcommand().get_matches_from_safe(vec![
+            "run",
+            "-h",
+            "all",
+            "-c",
+            "pass",
+            "--label",
+            "stage=canary",
+        ])?;
+        let skipped = run.metrics_writer(&plain, "run-1")?;
+        let required = run.metrics_writer(&labelled, "run-1");
+        fs::remove_file(&blocker)?;
+        assert!(skipped.is_none());
+        assert!(required.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn only_narrows_the_selection() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let maps_for = |args: Vec<&str>| -> MusshResult<Vec<String>> {
+            let matches = Run::subcommand().get_matches_from_safe(args)?;
+            let (_, maps) = multiplex_maps(
+                &config,
+                &RuntimeConfig::from(&matches),
+                &matches,
+                &mut Warnings::default(),
+            )?;
+            Ok(run_hosts(&maps).into_iter().map(str::to_string).collect())
+        };
+
+        assert_eq!(
+            maps_for(vec!["run", "-h", "all", "-c", "pass", "--only", "c,a"])?,
+            vec!["a", "c"]
+        );
+        assert!(maps_for(vec!["run", "-h", "all,!b", "-c", "pass", "--only", "a,b"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn no_unique_runs_repeats_again() -> MusshResult<()> {
+        let config: Config = toml::from_str(LOCALHOST_TOML)?;
+        let maps_for = |args: Vec<&str>| -> MusshResult<(Vec<Vec<String>>, usize)> {
+            let matches = Run::subcommand().get_matches_from_safe(args)?;
+            let mut warnings = Warnings::default();
+            let (_, maps) = multiplex_maps(
+                &config,
+                &RuntimeConfig::from(&matches),
+                &matches,
+                &mut warnings,
+            )?;
+            let hosts = maps
+                .iter()
+                .map(|multiplex_map| multiplex_map.keys().cloned().collect())
+                .collect();
+            let warned = warnings.emit(None, true).err().map_or(0, |_| 1);
+            Ok((hosts, warned))
+        };
+
+        let (hosts, warned) = maps_for(vec!["run", "-h", "ok,all,a", "-c", "pass"])?;
+        assert_eq!(hosts, vec![vec!["a", "b", "c"]]);
+        assert_eq!(warned, 1);
+        let (hosts, warned) = maps_for(vec!["run", "-h", "ok,all,a", "-c", "pass", "--no-unique"])?;
+        assert_eq!(hosts, vec![vec!["a", "b", "c"], vec!["a", "b"], vec!["a"]]);
+        assert_eq!(warned, 0);
+        let (hosts, _) = maps_for(vec![
+            "run",
+            "-h",
+            "ok,a",
+            "-c",
+            "pass",
+            "--no-unique",
+            "--unique",
+        ])?;
+        assert_eq!(hosts, vec![vec!["a", "b"]]);
+        Ok(())
     }
 }
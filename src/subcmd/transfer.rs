@@ -0,0 +1,334 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! What the push and pull subcommands share: picking the hosts, connecting to
+//! them in parallel and reporting how each copy went.
+use crate::algorithms::Algorithms;
+use crate::auth;
+use crate::connect::ConnectTimeouts;
+use crate::error::{MusshErrKind, MusshResult};
+use crate::extensions::Extensions;
+use crate::known_hosts::{self, HostKeys};
+use crate::runner::{Semaphore, DEFAULT_MAX_PARALLEL};
+use crate::session::{self, Keepalive};
+use crate::subcmd::run::option_number;
+use crate::targets::{self, ResolvedHost};
+use crate::util::{format_duration, table};
+use crate::warnings::Warnings;
+use clap::{Arg, ArgMatches};
+use libmussh::Config;
+use slog::Logger;
+use ssh2::Session;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The `--hosts` argument, with help on what is copied to or from them.
+pub(super) fn hosts_arg<'b>(help: &str) -> Arg<'_, 'b> {
+    Arg::with_name("hosts")
+        .short("h")
+        .long("hosts")
+        .value_name("HOSTS")
+        .help(help)
+        .required(true)
+        .multiple(true)
+        .number_of_values(1)
+        .use_delimiter(true)
+}
+
+/// How many hosts to copy with at the same time, and how to connect to them.
+pub(super) fn connection_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let mut args = vec![
+        Arg::with_name("parallel")
+            .long("parallel")
+            .value_name("N")
+            .help("Copy with at most N hosts at the same time, 32 by default"),
+        Arg::with_name("connect_timeout")
+            .long("connect-timeout")
+            .value_name("SECS")
+            .help(
+                "Give up connecting to a host after SECS seconds, 10 by default (a host's own \
+                 connect_timeout in the config takes precedence)",
+            ),
+    ];
+    args.extend(session::args());
+    args.extend(known_hosts::args());
+    args
+}
+
+/// The hosts given with `--hosts`, after emitting any warnings about them.
+pub(super) fn hosts(
+    config: &Config,
+    matches: &ArgMatches<'_>,
+    stderr: Option<&Logger>,
+) -> MusshResult<Vec<ResolvedHost>> {
+    let selectors = targets::rejoin_ranges(matches.values_of("hosts").into_iter().flatten());
+    let selectors: Vec<&str> = selectors.iter().map(String::as_str).collect();
+    let mut warnings = Warnings::default();
+    let hosts = targets::resolve_targets(config, &selectors, &mut warnings)?;
+    warnings.emit(stderr, false)?;
+    Ok(hosts)
+}
+
+/// How to connect to the hosts, and to how many at the same time.
+#[derive(Clone, Debug)]
+pub(super) struct Connections {
+    timeouts: ConnectTimeouts,
+    host_keys: HostKeys,
+    max_parallel: usize,
+    keepalive: Keepalive,
+}
+
+impl Connections {
+    pub(super) fn new(timeouts: ConnectTimeouts, host_keys: HostKeys, max_parallel: usize) -> Self {
+        Self {
+            timeouts,
+            host_keys,
+            max_parallel,
+            keepalive: Keepalive::default(),
+        }
+    }
+
+    pub(super) fn from_args(
+        extensions: &Extensions,
+        matches: &ArgMatches<'_>,
+    ) -> MusshResult<Self> {
+        let options = &extensions.options;
+        let mut connections = Self::new(
+            ConnectTimeouts::parse(
+                extensions,
+                option_number(matches, options, "connect_timeout")?,
+            )?,
+            HostKeys::from_args(matches)?,
+            option_number(matches, options, "parallel")?.unwrap_or(DEFAULT_MAX_PARALLEL),
+        );
+        connections.keepalive = Keepalive::new(
+            option_number(matches, options, "keepalive")?,
+            option_number(matches, options, "session_timeout")?,
+        );
+        Ok(connections)
+    }
+
+    fn for_host(&self, name: &str) -> HostConnection {
+        HostConnection {
+            connect_timeout: self.timeouts.for_host(name),
+            host_keys: self.host_keys.clone(),
+            keepalive: self.keepalive,
+        }
+    }
+}
+
+/// How to connect to one host.
+#[derive(Clone, Debug)]
+pub(super) struct HostConnection {
+    connect_timeout: Duration,
+    host_keys: HostKeys,
+    keepalive: Keepalive,
+}
+
+impl HostConnection {
+    /// Connect and authenticate to the host, with a session that sends
+    /// keepalives as it is used and, with `--session-timeout`, fails a read or
+    /// write that has stalled.
+    pub(super) fn session(&self, host: &ResolvedHost) -> MusshResult<Session> {
+        let session = auth::session(
+            host.hostname(),
+            host.username(),
+            *host.port(),
+            host.pem().as_deref(),
+            self.connect_timeout,
+            &self.host_keys,
+            &Algorithms::default(),
+        )?;
+        self.keepalive.apply(&session);
+        Ok(session)
+    }
+}
+
+/// Copy everything from `from` to `to` over the session, sending a keepalive
+/// whenever one is due, as libssh2 only sends them when asked to.
+pub(super) fn copy<R, W>(session: &Session, from: &mut R, to: &mut W) -> MusshResult<u64>
+where
+    R: Read,
+    W: Write,
+{
+    let mut buf = [0; 16 * 1024];
+    let mut bytes = 0;
+    loop {
+        let read = match from.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        to.write_all(&buf[..read])?;
+        bytes += u64::try_from(read).unwrap_or(u64::MAX);
+        let _secs = session.keepalive_send()?;
+    }
+    to.flush()?;
+    Ok(bytes)
+}
+
+/// What came of copying with a host.
+#[derive(Debug)]
+pub(super) struct Copied {
+    pub(super) name: String,
+    pub(super) hostname: String,
+    /// How many bytes were copied, or why none were.
+    pub(super) bytes: Result<u64, String>,
+    pub(super) duration: Duration,
+}
+
+/// Copy with every host, as many at the same time as the connections allow,
+/// in host order.  `copy` is given the host and how to connect to it, and
+/// returns how many bytes it copied.
+pub(super) fn copy_all<F>(hosts: &[ResolvedHost], connections: &Connections, copy: F) -> Vec<Copied>
+where
+    F: Fn(&ResolvedHost, &HostConnection) -> MusshResult<u64> + Send + Sync + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let slots = Arc::new(Semaphore::new(connections.max_parallel));
+    let copy = Arc::new(copy);
+
+    for host in hosts {
+        let connection = connections.for_host(host.name());
+        let (host, copy, tx) = (host.clone(), Arc::clone(&copy), tx.clone());
+        let slots = Arc::clone(&slots);
+        let _handle = thread::spawn(move || {
+            let _slot = slots.acquire();
+            let started = Instant::now();
+            let bytes = copy(&host, &connection).map_err(|e| e.to_string());
+            let _res = tx.send((host.name().clone(), (bytes, started.elapsed())));
+        });
+    }
+
+    drop(tx);
+    let mut copied: HashMap<String, (Result<u64, String>, Duration)> = rx.into_iter().collect();
+    hosts
+        .iter()
+        .map(|host| {
+            let (bytes, duration) = copied
+                .remove(host.name())
+                .unwrap_or_else(|| (Err("the copy panicked".to_string()), Duration::default()));
+            Copied {
+                name: host.name().clone(),
+                hostname: host.hostname().clone(),
+                bytes,
+                duration,
+            }
+        })
+        .collect()
+}
+
+/// Print a line per host, with how many bytes were copied and how long it
+/// took, or why it failed, and fail if any host did.
+pub(super) fn report(copied: &[Copied]) -> MusshResult<()> {
+    print!("{}", report_table(copied));
+    let failed = copied.iter().filter(|copied| copied.bytes.is_err()).count();
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(MusshErrKind::HostsFailed(failed, 1).into())
+    }
+}
+
+fn report_table(copied: &[Copied]) -> String {
+    let rows = copied
+        .iter()
+        .map(|copied| {
+            let (bytes, result) = match &copied.bytes {
+                Ok(bytes) => (bytes.to_string(), "ok".to_string()),
+                Err(e) => (String::new(), format!("failed: {e}")),
+            };
+            vec![
+                copied.name.clone(),
+                copied.hostname.clone(),
+                bytes,
+                format_duration(&copied.duration),
+                result,
+            ]
+        })
+        .collect();
+    table(
+        &["HOST", "HOSTNAME", "BYTES", "TIME", "RESULT"],
+        &[false, false, true, true, false],
+        rows,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connection_args, report_table, Connections, Copied};
+    use crate::error::MusshResult;
+    use crate::extensions::Extensions;
+    use crate::session::Keepalive;
+    use clap::App;
+    use std::time::Duration;
+
+    #[test]
+    fn keepalives() -> MusshResult<()> {
+        let app = App::new("push").args(&connection_args());
+        let defaults = Connections::from_args(
+            &Extensions::default(),
+            &app.clone().get_matches_from_safe(["push"])?,
+        )?;
+        assert_eq!(defaults.keepalive, Keepalive::default());
+
+        let matches = app.clone().get_matches_from_safe([
+            "push",
+            "--keepalive",
+            "5",
+            "--session-timeout",
+            "90",
+        ])?;
+        let connections = Connections::from_args(&Extensions::default(), &matches)?;
+        assert_eq!(connections.keepalive, Keepalive::new(Some(5), Some(90)));
+        assert_eq!(connections.for_host("web").keepalive, connections.keepalive);
+
+        // The flags win over the [options] of the config.
+        let extensions: Extensions =
+            toml::from_str("[options]\nkeepalive = 30\nsession_timeout = 60\nparallel = 4\n")?;
+        let matches = app
+            .clone()
+            .get_matches_from_safe(["push", "--keepalive", "5"])?;
+        let connections = Connections::from_args(&extensions, &matches)?;
+        assert_eq!(connections.keepalive, Keepalive::new(Some(5), Some(60)));
+        assert_eq!(connections.max_parallel, 4);
+
+        let matches = app.get_matches_from_safe(["push", "--keepalive", "0"])?;
+        assert!(Connections::from_args(&Extensions::default(), &matches).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reports_each_host() {
+        let copied = [
+            Copied {
+                name: "web".to_string(),
+                hostname: "10.0.0.1".to_string(),
+                bytes: Ok(1024),
+                duration: Duration::from_millis(42),
+            },
+            Copied {
+                name: "db".to_string(),
+                hostname: "10.0.0.2".to_string(),
+                bytes: Err("no such file".to_string()),
+                duration: Duration::from_millis(7),
+            },
+        ];
+        assert_eq!(
+            report_table(&copied),
+            "HOST  HOSTNAME  BYTES  TIME  RESULT\n\
+             web   10.0.0.1   1024  42ms  ok\n\
+             db    10.0.0.2          7ms  failed: no such file\n"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Exit codes that count as success
+use crate::error::MusshResult;
+use crate::extensions::Extensions;
+use std::collections::HashMap;
+
+/// The exit codes each command succeeds with, when not only `0`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct SuccessCodes {
+    /// The codes given with `--success-codes`, for commands without their own.
+    default: Option<Vec<u8>>,
+    /// The `success_codes` of the `[cmd.<name>]` tables in the config.
+    cmds: HashMap<String, Vec<u8>>,
+}
+
+impl SuccessCodes {
+    /// Check the `success_codes` of the commands in the config, and parse
+    /// `--success-codes`.
+    pub(crate) fn parse(extensions: &Extensions, default: Option<&str>) -> MusshResult<Self> {
+        let mut cmds = HashMap::new();
+
+        for (name, cmd) in &extensions.cmd {
+            match &cmd.success_codes {
+                Some(codes) if codes.is_empty() => {
+                    return Err(format!(
+                        "The success_codes of command '{name}' must be a list of exit codes, 0 \
+                         to 255"
+                    )
+                    .into())
+                }
+                Some(codes) => {
+                    let _old = cmds.insert(name.clone(), codes.clone());
+                }
+                None => {}
+            }
+        }
+
+        Ok(Self {
+            default: default.map(parse_list).transpose()?,
+            cmds,
+        })
+    }
+
+    /// The exit codes the given command succeeds with.
+    pub(crate) fn for_cmd(&self, cmd_name: &str) -> &[u8] {
+        self.cmds
+            .get(cmd_name)
+            .or(self.default.as_ref())
+            .map_or(&[0], Vec::as_slice)
+    }
+}
+
+/// Parse the comma separated codes given with `--success-codes`.
+fn parse_list(codes: &str) -> MusshResult<Vec<u8>> {
+    codes
+        .split(',')
+        .map(|code| {
+            code.trim().parse::<u8>().map_err(|_| {
+                format!("Invalid exit code '{code}' for --success-codes, expected 0 to 255").into()
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::SuccessCodes;
+    use crate::error::MusshResult;
+    use crate::extensions::Extensions;
+
+    const SUCCESS_TOML: &str = r#"[hostlist]
+[hosts]
+[cmd.diff]
+command = "diff a b"
+success_codes = [0, 1]
+[cmd.uptime]
+command = "uptime"
+"#;
+
+    #[test]
+    fn per_command_codes() -> MusshResult<()> {
+        let codes = SuccessCodes::parse(&toml::from_str(SUCCESS_TOML)?, Some("0, 3"))?;
+        assert_eq!(codes.for_cmd("diff"), &[0, 1]);
+        assert_eq!(codes.for_cmd("uptime"), &[0, 3]);
+
+        let codes = SuccessCodes::parse(&toml::from_str(SUCCESS_TOML)?, None)?;
+        assert_eq!(codes.for_cmd("uptime"), &[0]);
+        assert!(SuccessCodes::parse(&toml::from_str(SUCCESS_TOML)?, Some("0,256")).is_err());
+        assert!(SuccessCodes::parse(&toml::from_str(SUCCESS_TOML)?, Some("")).is_err());
+        assert!(
+            SuccessCodes::parse(&toml::from_str("[cmd.x]\nsuccess_codes = []\n")?, None).is_err()
+        );
+        assert!(toml::from_str::<Extensions>("[cmd.x]\nsuccess_codes = [-1]\n").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Selecting hosts by their tags
+//!
+//! A host is given tags with `tags = ["web", "prod"]`, and `run --tag` selects
+//! the hosts whose tags match an expression of tags, `&&` (or `and`), `||` (or
+//! `or`), `!` (or `not`) and parentheses, i.e. `'web && !canary'`.  `!` binds
+//! tightest, then `&&`, then `||`.
+use crate::error::{MusshErr, MusshResult};
+use crate::extensions::Extensions;
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+/// An expression over the tags of a host.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    And(Box<TagExpr>, Box<TagExpr>),
+    Or(Box<TagExpr>, Box<TagExpr>),
+}
+
+impl TagExpr {
+    /// Does a host with these tags match?
+    pub(crate) fn matches(&self, tags: &BTreeSet<String>) -> bool {
+        match self {
+            Self::Tag(tag) => tags.contains(tag),
+            Self::Not(expr) => !expr.matches(tags),
+            Self::And(left, right) => left.matches(tags) && right.matches(tags),
+            Self::Or(left, right) => left.matches(tags) || right.matches(tags),
+        }
+    }
+}
+
+impl FromStr for TagExpr {
+    type Err = MusshErr;
+
+    fn from_str(expr: &str) -> MusshResult<Self> {
+        let invalid = |message: &str| -> MusshErr {
+            format!("Invalid tag expression '{expr}': {message}").into()
+        };
+        let tokens = tokens(expr).map_err(|message| invalid(&message))?;
+        let mut parser = Parser { tokens, next: 0 };
+        let parsed = parser.or().map_err(|message| invalid(&message))?;
+        match parser.tokens.get(parser.next) {
+            None => Ok(parsed),
+            Some(token) => Err(invalid(&format!("unexpected {}", token.describe()))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Tag(String),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Self::Tag(tag) => format!("tag '{tag}'"),
+            Self::And => "'&&'".to_string(),
+            Self::Or => "'||'".to_string(),
+            Self::Not => "'!'".to_string(),
+            Self::Open => "'('".to_string(),
+            Self::Close => "')'".to_string(),
+        }
+    }
+}
+
+fn is_tag_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_-.:/=".contains(c)
+}
+
+fn tokens(expr: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '!' => Token::Not,
+            '&' | '|' => {
+                if chars.next_if(|(_, next)| *next == c).is_none() {
+                    return Err(format!("expected '{c}{c}'"));
+                }
+                if c == '&' {
+                    Token::And
+                } else {
+                    Token::Or
+                }
+            }
+            c if is_tag_char(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, next)) = chars.next_if(|(_, next)| is_tag_char(*next)) {
+                    end = i + next.len_utf8();
+                }
+                match &expr[start..end] {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    tag => Token::Tag(tag.to_string()),
+                }
+            }
+            c => return Err(format!("unexpected '{c}'")),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn eat(&mut self, token: &Token) -> bool {
+        let matched = self.tokens.get(self.next) == Some(token);
+        if matched {
+            self.next += 1;
+        }
+        matched
+    }
+
+    fn or(&mut self) -> Result<TagExpr, String> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = TagExpr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<TagExpr, String> {
+        let mut expr = self.not()?;
+        while self.eat(&Token::And) {
+            expr = TagExpr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<TagExpr, String> {
+        if self.eat(&Token::Not) {
+            return Ok(TagExpr::Not(Box::new(self.not()?)));
+        }
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        match token {
+            Some(Token::Tag(tag)) => Ok(TagExpr::Tag(tag)),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                if self.eat(&Token::Close) {
+                    Ok(expr)
+                } else {
+                    Err("expected ')'".to_string())
+                }
+            }
+            Some(token) => Err(format!("expected a tag, found {}", token.describe())),
+            None => Err("expected a tag".to_string()),
+        }
+    }
+}
+
+/// The tags of each host, its `tags` in the config, none if it has none.
+pub(crate) fn host_tags(
+    extensions: &Extensions,
+) -> MusshResult<BTreeMap<String, BTreeSet<String>>> {
+    let mut tags = BTreeMap::new();
+
+    for (name, host) in &extensions.hosts {
+        let host_tags = host.tags.clone().unwrap_or_default();
+        if !host_tags
+            .iter()
+            .all(|tag| !tag.is_empty() && tag.chars().all(is_tag_char))
+        {
+            return Err(format!("The tags of host '{name}' must be a list of tags").into());
+        }
+        let _old = tags.insert(name.clone(), host_tags.into_iter().collect());
+    }
+    Ok(tags)
+}
+
+/// The hosts whose tags match the expression, by name.
+pub(crate) fn select(expr: &TagExpr, tags: &BTreeMap<String, BTreeSet<String>>) -> Vec<String> {
+    tags.iter()
+        .filter(|(_, host_tags)| expr.matches(host_tags))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{host_tags, select, TagExpr};
+    use crate::error::MusshResult;
+    use crate::extensions::Extensions;
+
+    const TAGS_TOML: &str = r#"[hosts.web1]
+hostname = "10.0.0.1"
+username = "u"
+tags = ["web", "prod"]
+[hosts.web2]
+hostname = "10.0.0.2"
+username = "u"
+tags = ["web", "prod", "canary"]
+[hosts.db1]
+hostname = "10.0.0.3"
+username = "u"
+tags = ["db", "prod"]
+[hosts.lab]
+hostname = "10.0.0.4"
+username = "u"
+"#;
+
+    #[test]
+    fn selects_by_expression() -> MusshResult<()> {
+        let tags = host_tags(&toml::from_str(TAGS_TOML)?)?;
+        let selected =
+            |expr: &str| -> MusshResult<Vec<String>> { Ok(select(&expr.parse()?, &tags)) };
+        assert_eq!(selected("web")?, ["web1", "web2"]);
+        assert_eq!(selected("web && !canary")?, ["web1"]);
+        assert_eq!(selected("db or canary")?, ["db1", "web2"]);
+        assert_eq!(selected("not prod")?, ["lab"]);
+        assert_eq!(selected("prod && (db || canary)")?, ["db1", "web2"]);
+        // && binds tighter than ||.
+        assert_eq!(selected("db || web && canary")?, ["db1", "web2"]);
+        assert!(selected("gpu")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for bad in &[
+            "",
+            "web &&",
+            "web & prod",
+            "(web",
+            "web)",
+            "web prod",
+            "!",
+            "we$b",
+        ] {
+            assert!(bad.parse::<TagExpr>().is_err());
+        }
+        let e = "web &&".parse::<TagExpr>().err().map(|e| e.to_string());
+        assert_eq!(
+            e.as_deref(),
+            Some("Invalid tag expression 'web &&': expected a tag")
+        );
+        assert!(toml::from_str::<Extensions>("[hosts.a]\ntags = \"web\"\n").is_err());
+        assert!(
+            toml::from_str::<Extensions>("[hosts.a]\ntags = [\"a b\"]\n")
+                .is_ok_and(|extensions| host_tags(&extensions).is_err())
+        );
+    }
+}
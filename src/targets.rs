@@ -0,0 +1,777 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Target host resolution
+use crate::error::MusshResult;
+use crate::ssh_config::glob_match;
+use crate::warnings::Warnings;
+use getset::Getters;
+use indexmap::{IndexMap, IndexSet};
+use libmussh::{Config, MultiplexMapType, RuntimeConfig};
+use std::env;
+use toml::value::{Table, Value};
+
+/// A configured host selected by a set of selectors.
+#[derive(Clone, Debug, Default, Eq, Getters, PartialEq)]
+pub(crate) struct ResolvedHost {
+    /// The host key in the `hosts` table.
+    #[get = "pub(crate)"]
+    name: String,
+    /// The hostname (or address) to connect to.
+    #[get = "pub(crate)"]
+    hostname: String,
+    /// The username to connect with.
+    #[get = "pub(crate)"]
+    username: String,
+    /// The port to connect to, if not the default.
+    #[get = "pub(crate)"]
+    port: Option<u16>,
+    /// The pem file to authenticate with, if not using the agent.
+    #[get = "pub(crate)"]
+    pem: Option<String>,
+}
+
+/// The most names a range in a selector may expand to.
+const MAX_RANGE_NAMES: u64 = 10_000;
+
+/// Resolve the given selectors into the final ordered set of hosts.
+///
+/// Each selector is either a hostlist (expanded recursively), a host key, a
+/// host key pattern with ranges, i.e. `web[01-10]` or `db[1,3,5]`, see
+/// [`expand_ranges`], or a glob, i.e. `web-*` or `db??`, matched against the
+/// host keys.  Every host a range expands to must be in the config, and a glob
+/// must match at least one host.  Selectors prefixed with `!` are excluded from
+/// the result, wherever they appear in the selectors.  A host selected more
+/// than once appears only once, in the position it was first selected.
+///
+/// Hosts selected more than once, unknown names in hostlists, and a selection
+/// left empty by its exclusions are noted in `warnings`.
+pub(crate) fn resolve_targets(
+    config: &Config,
+    selectors: &[&str],
+    warnings: &mut Warnings,
+) -> MusshResult<Vec<ResolvedHost>> {
+    select_targets(config, selectors, true, warnings)
+}
+
+/// Resolve the given selectors as [`resolve_targets`] does, but unless
+/// `unique`, a host selected more than once appears each time it was selected,
+/// and isn't warned about.
+pub(crate) fn select_targets(
+    config: &Config,
+    selectors: &[&str],
+    unique: bool,
+    warnings: &mut Warnings,
+) -> MusshResult<Vec<ResolvedHost>> {
+    let mut wanted: Vec<(String, &str)> = Vec::new();
+    let mut unwanted = Vec::new();
+
+    for selector in selectors {
+        if let Some(excluded) = selector.strip_prefix('!') {
+            expand_selector(config, excluded, &mut unwanted, warnings)?;
+        } else {
+            let mut names = Vec::new();
+            expand_selector(config, selector, &mut names, warnings)?;
+            wanted.extend(names.into_iter().map(|name| (name, *selector)));
+        }
+    }
+
+    let unwanted: IndexSet<String> = unwanted.into_iter().collect();
+    let selected = !wanted.is_empty();
+    wanted.retain(|(name, _)| !unwanted.contains(name));
+    if selected && wanted.is_empty() {
+        warnings.warn(format!(
+            "Nothing is left of '{}' after its exclusions",
+            selectors.join(",")
+        ));
+    }
+
+    let names: Vec<String> = if unique {
+        dedup(wanted, warnings)
+    } else {
+        wanted.into_iter().map(|(name, _)| name).collect()
+    };
+
+    Ok(names
+        .into_iter()
+        .filter_map(|name| resolved_host(config, name))
+        .collect())
+}
+
+/// Keep the first of each host selected more than once, noting the selectors
+/// it was selected by in `warnings`.
+fn dedup(wanted: Vec<(String, &str)>, warnings: &mut Warnings) -> Vec<String> {
+    let mut selected_by: IndexMap<String, (usize, IndexSet<&str>)> = IndexMap::new();
+    for (name, selector) in wanted {
+        let (count, selectors) = selected_by.entry(name).or_default();
+        *count += 1;
+        let _new = selectors.insert(selector);
+    }
+
+    for (name, (count, selectors)) in &selected_by {
+        if *count > 1 {
+            warnings.warn(format!(
+                "Host '{name}' is selected more than once (by {}), running it once",
+                selectors.iter().copied().collect::<Vec<_>>().join(", ")
+            ));
+        }
+    }
+    selected_by.into_keys().collect()
+}
+
+/// Expand a selector, without its `!`, into the host keys it selects.  A name
+/// in the config is taken as it is, even if it looks like a range or a glob.
+fn expand_selector(
+    config: &Config,
+    selector: &str,
+    names: &mut Vec<String>,
+    warnings: &mut Warnings,
+) -> MusshResult<()> {
+    let literal = config.hostlist().contains_key(selector) || config.hosts().contains_key(selector);
+    if !literal && selector.contains(['*', '?']) {
+        return expand_glob(config, selector, names);
+    }
+    if literal || range_group(selector).is_none() {
+        return expand(config, selector, &mut Vec::new(), names, warnings);
+    }
+
+    for name in expand_ranges(selector)? {
+        if !config.hosts().contains_key(&name) {
+            return Err(format!("Unknown host '{name}', from '{selector}'").into());
+        }
+        names.push(name);
+    }
+    Ok(())
+}
+
+/// Expand a glob into the host keys it matches, in name order.  `*` matches any
+/// run of characters and `?` exactly one.  A glob may have ranges too, i.e.
+/// `web[1-2]-*`, matching the hosts of any of the globs they expand to.
+fn expand_glob(config: &Config, selector: &str, names: &mut Vec<String>) -> MusshResult<()> {
+    let globs = expand_ranges(selector)?;
+    let matched: Vec<String> = config
+        .hosts()
+        .keys()
+        .filter(|name| globs.iter().any(|glob| glob_match(glob, name)))
+        .cloned()
+        .collect();
+    if matched.is_empty() {
+        return Err(format!("No host matches '{selector}'").into());
+    }
+    names.extend(matched);
+    Ok(())
+}
+
+/// Expand the ranges in a host name pattern into the names they stand for, in
+/// order.
+///
+/// A range is a comma separated list of numbers and `start-end` spans within
+/// brackets, i.e. `web[01-10]` or `db[1,3,5]`.  The numbers keep the zero
+/// padding of the start of their span, and a pattern with more than one range
+/// expands to every combination.  Brackets around anything else, like an IPv6
+/// address, are left as they are, as is a pattern without any ranges.
+pub(crate) fn expand_ranges(pattern: &str) -> MusshResult<Vec<String>> {
+    let Some((prefix, spec, suffix)) = range_group(pattern) else {
+        return Ok(vec![pattern.to_string()]);
+    };
+    let suffixes = expand_ranges(suffix)?;
+    let mut names = Vec::new();
+    for number in range_numbers(pattern, spec)? {
+        names.extend(
+            suffixes
+                .iter()
+                .map(|suffix| format!("{prefix}{number}{suffix}")),
+        );
+    }
+    Ok(names)
+}
+
+/// Join back up the selectors split at the commas within their ranges, as clap
+/// splits `-h db[1,3],web` into `db[1`, `3]` and `web`.
+pub(crate) fn rejoin_ranges<'a, I>(values: I) -> IndexSet<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut selectors = IndexSet::new();
+    let mut partial: Option<String> = None;
+    for value in values {
+        let selector = match partial.take() {
+            Some(start) => format!("{start},{value}"),
+            None => value.to_string(),
+        };
+        if selector.matches('[').count() > selector.matches(']').count() {
+            partial = Some(selector);
+        } else {
+            let _new = selectors.insert(selector);
+        }
+    }
+    selectors.extend(partial);
+    selectors
+}
+
+/// The text before the first range in the pattern, the range without its
+/// brackets, and the text after it.
+fn range_group(pattern: &str) -> Option<(&str, &str, &str)> {
+    let mut offset = 0;
+    while let Some(start) = pattern[offset..].find('[').map(|idx| offset + idx) {
+        let end = start + pattern[start..].find(']')?;
+        let spec = &pattern[start + 1..end];
+        if spec.starts_with(|c: char| c.is_ascii_digit())
+            && spec
+                .chars()
+                .all(|c| c.is_ascii_digit() || c == ',' || c == '-')
+        {
+            return Some((&pattern[..start], spec, &pattern[end + 1..]));
+        }
+        offset = start + 1;
+    }
+    None
+}
+
+fn range_numbers(pattern: &str, spec: &str) -> MusshResult<Vec<String>> {
+    let invalid = || format!("Invalid range '[{spec}]' in '{pattern}'");
+    let mut numbers = Vec::new();
+    for span in spec.split(',') {
+        let (first, last) = span.split_once('-').unwrap_or((span, span));
+        let start = first.parse::<u64>().map_err(|_| invalid())?;
+        let end = last.parse::<u64>().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid().into());
+        }
+        if end - start >= MAX_RANGE_NAMES {
+            return Err(format!(
+                "The range '[{spec}]' in '{pattern}' is more than {MAX_RANGE_NAMES} hosts"
+            )
+            .into());
+        }
+        let width = if first.starts_with('0') {
+            first.len()
+        } else {
+            0
+        };
+        numbers.extend((start..=end).map(|number| format!("{number:0width$}")));
+    }
+    Ok(numbers)
+}
+
+fn expand(
+    config: &Config,
+    name: &str,
+    stack: &mut Vec<String>,
+    names: &mut Vec<String>,
+    warnings: &mut Warnings,
+) -> MusshResult<()> {
+    if stack.iter().any(|seen| seen == name) {
+        return Err(format!("Hostlist '{name}' includes itself").into());
+    }
+
+    if let Some(hosts) = config.hostlist().get(name) {
+        stack.push(name.to_string());
+        for hostname in hosts.hostnames() {
+            if hostname == name || !config.hostlist().contains_key(hostname) {
+                // Unknown names in a hostlist are skipped, as libmussh does.
+                if config.hosts().contains_key(hostname) {
+                    names.push(hostname.clone());
+                } else {
+                    warnings.warn(format!(
+                        "Hostlist '{name}' includes the unknown host '{hostname}'"
+                    ));
+                }
+            } else {
+                expand(config, hostname, stack, names, warnings)?;
+            }
+        }
+        let _name = stack.pop();
+        Ok(())
+    } else if config.hosts().contains_key(name) {
+        names.push(name.to_string());
+        Ok(())
+    } else {
+        Err(format!("Unknown host or hostlist '{name}'").into())
+    }
+}
+
+fn resolved_host(config: &Config, name: String) -> Option<ResolvedHost> {
+    config.hosts().get(&name).map(|host| ResolvedHost {
+        hostname: host.hostname().clone(),
+        username: host.username().clone(),
+        port: *host.port(),
+        pem: host.pem().clone(),
+        name,
+    })
+}
+
+/// Resolve the hosts and sync hosts in the runtime config and build the
+/// multiplex map for exactly those hosts, in the order they were resolved.
+///
+/// A map has each host once.  Unless `unique`, hosts selected more than once
+/// aren't warned about, see [`repeats`] for running them again.
+pub(crate) fn to_host_map(
+    config: &Config,
+    runtime_config: &RuntimeConfig,
+    unique: bool,
+    warnings: &mut Warnings,
+) -> MusshResult<(RuntimeConfig, MultiplexMapType)> {
+    let hosts = resolve_names(config, runtime_config.hosts(), unique, warnings)?;
+    let sync_hosts = resolve_names(config, runtime_config.sync_hosts(), unique, warnings)?;
+
+    // libmussh only selects hosts that are also hostlists, so give each
+    // resolved host a hostlist of its own.
+    let mut hostlist = Table::new();
+    for name in hosts.iter().chain(sync_hosts.iter()) {
+        let mut hostnames = Table::new();
+        let _old = hostnames.insert(
+            "hostnames".to_string(),
+            Value::Array(vec![Value::String(name.clone())]),
+        );
+        let _old = hostlist.insert(name.clone(), Value::Table(hostnames));
+    }
+    let mut value = Value::try_from(config)?;
+    if let Some(table) = value.as_table_mut() {
+        let _old = table.insert("hostlist".to_string(), Value::Table(hostlist));
+    }
+    let target_config: Config = value.try_into()?;
+
+    let mut resolved = runtime_config.clone();
+    let _ = resolved.set_hosts(hosts);
+    let _ = resolved.set_sync_hosts(sync_hosts);
+    let multiplex_map = target_config.to_host_map(&resolved);
+    Ok((resolved, multiplex_map))
+}
+
+/// The hosts selected again after the first time, once for each time, in the
+/// order they were selected.
+pub(crate) fn repeats(config: &Config, selectors: &IndexSet<String>) -> MusshResult<Vec<String>> {
+    let selectors: Vec<&str> = selectors.iter().map(String::as_str).collect();
+    let mut seen = IndexSet::new();
+    Ok(
+        select_targets(config, &selectors, false, &mut Warnings::default())?
+            .into_iter()
+            .map(|resolved| resolved.name)
+            .filter(|name| !seen.insert(name.clone()))
+            .collect(),
+    )
+}
+
+fn resolve_names(
+    config: &Config,
+    selectors: &IndexSet<String>,
+    unique: bool,
+    warnings: &mut Warnings,
+) -> MusshResult<IndexSet<String>> {
+    let selectors: Vec<&str> = selectors.iter().map(String::as_str).collect();
+    Ok(select_targets(config, &selectors, unique, warnings)?
+        .into_iter()
+        .map(|resolved| resolved.name)
+        .collect())
+}
+
+/// The name of the command given with `--exec`.
+pub(crate) const EXEC_CMD: &str = "exec";
+
+/// Add the one-off `user@host:port` targets, and the `--exec` command, to the
+/// config.
+///
+/// Each target becomes a host named for the target itself, so it can be
+/// selected by that name.  The username defaults to `$USER` and the port to
+/// the ssh default.
+pub(crate) fn add_targets(
+    config: &Config,
+    targets: &[&str],
+    pem: Option<&str>,
+    exec: Option<&str>,
+) -> MusshResult<Config> {
+    let mut value = Value::try_from(config)?;
+    let table = value.as_table_mut().ok_or("The config is not a table")?;
+
+    for target in targets {
+        let mut host = parse_target(target)?;
+        if let Some(pem) = pem {
+            let _old = host.insert("pem".to_string(), Value::String(pem.to_string()));
+        }
+        let hosts = table
+            .entry("hosts")
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or("The config hosts are not a table")?;
+        let _old = hosts.insert((*target).to_string(), Value::Table(host));
+    }
+
+    if let Some(exec) = exec {
+        let mut cmd = Table::new();
+        let _old = cmd.insert("command".to_string(), Value::String(exec.to_string()));
+        let cmds = table
+            .entry("cmd")
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or("The config commands are not a table")?;
+        let _old = cmds.insert(EXEC_CMD.to_string(), Value::Table(cmd));
+    }
+
+    Ok(value.try_into()?)
+}
+
+/// Parse a `[user@]host[:port]` target into a host table.  An IPv6 address
+/// with a port is written in brackets, i.e. `[::1]:22`.
+fn parse_target(target: &str) -> MusshResult<Table> {
+    let invalid = || format!("Invalid target '{target}', expected user@host:port");
+    let (username, rest) = match target.split_once('@') {
+        Some((username, rest)) => (username.to_string(), rest),
+        None => (
+            env::var("USER").map_err(|_| "Unable to determine the username, use user@host")?,
+            target,
+        ),
+    };
+
+    let (hostname, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+        let (hostname, rest) = bracketed.split_once(']').ok_or_else(invalid)?;
+        match rest.strip_prefix(':') {
+            Some(port) => (hostname, Some(port)),
+            None if rest.is_empty() => (hostname, None),
+            None => return Err(invalid().into()),
+        }
+    } else if rest.matches(':').count() == 1 {
+        let (hostname, port) = rest.split_once(':').ok_or_else(invalid)?;
+        (hostname, Some(port))
+    } else {
+        (rest, None)
+    };
+
+    if username.is_empty() || hostname.is_empty() {
+        return Err(invalid().into());
+    }
+
+    let mut host = Table::new();
+    let _old = host.insert("hostname".to_string(), Value::String(hostname.to_string()));
+    let _old = host.insert("username".to_string(), Value::String(username));
+    if let Some(port) = port {
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        let _old = host.insert("port".to_string(), Value::Integer(i64::from(port)));
+    }
+    Ok(host)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        add_targets, expand_ranges, parse_target, rejoin_ranges, repeats, resolve_targets,
+        select_targets, to_host_map, EXEC_CMD,
+    };
+    use crate::error::MusshResult;
+    use crate::warnings::Warnings;
+    use indexmap::IndexSet;
+    use libmussh::{Config, RuntimeConfig};
+    use toml::Value;
+
+    const TARGETS_TOML: &str = r#"[hostlist.all]
+hostnames = ["m1", "m2", "m3", "web"]
+[hostlist.web]
+hostnames = ["w1", "w2"]
+[hostlist.m1]
+hostnames = ["m1"]
+[hostlist.typo]
+hostnames = ["m1", "nope"]
+[hostlist.loop]
+hostnames = ["m1", "pool"]
+[hostlist.pool]
+hostnames = ["loop"]
+[hosts.m1]
+hostname = "10.0.0.1"
+username = "jozias"
+[hosts.m2]
+hostname = "10.0.0.2"
+username = "jozias"
+port = 2222
+[hosts.m3]
+hostname = "10.0.0.3"
+username = "jozias"
+pem = "id_m3"
+[hosts.w1]
+hostname = "10.0.1.1"
+username = "www"
+[hosts.w2]
+hostname = "10.0.1.2"
+username = "www"
+[cmd.ls]
+command = "ls -al"
+"#;
+
+    fn names(config: &Config, selectors: &[&str]) -> MusshResult<Vec<String>> {
+        Ok(
+            resolve_targets(config, selectors, &mut Warnings::default())?
+                .into_iter()
+                .map(|resolved| resolved.name().clone())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn nested_hostlists() -> MusshResult<()> {
+        let config: Config = toml::from_str(TARGETS_TOML)?;
+        assert_eq!(
+            names(&config, &["all"])?,
+            vec!["m1", "m2", "m3", "w1", "w2"]
+        );
+        let resolved = resolve_targets(&config, &["m2"], &mut Warnings::default())?;
+        assert_eq!(resolved[0].hostname(), "10.0.0.2");
+        assert_eq!(resolved[0].username(), "jozias");
+        assert_eq!(*resolved[0].port(), Some(2222));
+        assert_eq!(*resolved[0].pem(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn exclusions() -> MusshResult<()> {
+        let config: Config = toml::from_str(TARGETS_TOML)?;
+        assert_eq!(
+            names(&config, &["all", "!m2"])?,
+            vec!["m1", "m3", "w1", "w2"]
+        );
+        assert_eq!(names(&config, &["!web", "all"])?, vec!["m1", "m2", "m3"]);
+        Ok(())
+    }
+
+    #[test]
+    fn duplicates() -> MusshResult<()> {
+        let config: Config = toml::from_str(TARGETS_TOML)?;
+        assert_eq!(
+            names(&config, &["m3", "all", "m1"])?,
+            vec!["m3", "m1", "m2", "w1", "w2"]
+        );
+
+        // Overlapping hostlists run each host once, and still take exclusions.
+        let mut warnings = Warnings::default();
+        let resolved = resolve_targets(&config, &["all", "web", "!w2"], &mut warnings)?;
+        let resolved: Vec<&str> = resolved.iter().map(|host| host.name().as_str()).collect();
+        assert_eq!(resolved, vec!["m1", "m2", "m3", "w1"]);
+        let error = warnings.emit(None, true).err().ok_or("expected an error")?;
+        assert!(error
+            .to_string()
+            .contains("Host 'w1' is selected more than once (by all, web), running it once"));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_names() -> MusshResult<()> {
+        let config: Config = toml::from_str(TARGETS_TOML)?;
+        assert!(resolve_targets(&config, &["nope"], &mut Warnings::default()).is_err());
+        assert!(resolve_targets(&config, &["all", "!nope"], &mut Warnings::default()).is_err());
+        assert_eq!(names(&config, &["typo"])?, vec!["m1"]);
+        assert!(resolve_targets(&config, &["loop"], &mut Warnings::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn globs() -> MusshResult<()> {
+        let config: Config = toml::from_str(TARGETS_TOML)?;
+        assert_eq!(names(&config, &["m*"])?, vec!["m1", "m2", "m3"]);
+        assert_eq!(names(&config, &["all", "!m?"])?, vec!["w1", "w2"]);
+        assert_eq!(
+            names(&config, &["w*", "m[2-3]*"])?,
+            vec!["w1", "w2", "m2", "m3"]
+        );
+        assert!(resolve_targets(&config, &["db-*"], &mut Warnings::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn empty_results() -> MusshResult<()> {
+        let config: Config = toml::from_str(TARGETS_TOML)?;
+        assert!(names(&config, &[])?.is_empty());
+        assert!(names(&config, &["web", "!w1", "!w2"])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn ranges_expand() -> MusshResult<()> {
+        assert_eq!(
+            expand_ranges("web[08-11]")?,
+            vec!["web08", "web09", "web10", "web11"]
+        );
+        assert_eq!(expand_ranges("db[1,3,5]")?, vec!["db1", "db3", "db5"]);
+        assert_eq!(
+            expand_ranges("r[1-2]n[0,02-03].dc")?,
+            vec!["r1n0.dc", "r1n02.dc", "r1n03.dc", "r2n0.dc", "r2n02.dc", "r2n03.dc"]
+        );
+        assert_eq!(expand_ranges("root@[::1]:22")?, vec!["root@[::1]:22"]);
+        assert_eq!(expand_ranges("m1")?, vec!["m1"]);
+        assert!(expand_ranges("web[3-1]").is_err());
+        assert!(expand_ranges("web[1-]").is_err());
+        assert!(expand_ranges("web[0-99999]").is_err());
+
+        let selectors = rejoin_ranges(vec!["a[1", "2]", "b[1", "2]", "m1", "c[1"]);
+        assert_eq!(
+            selectors.into_iter().collect::<Vec<_>>(),
+            vec!["a[1,2]", "b[1,2]", "m1", "c[1"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn range_selectors() -> MusshResult<()> {
+        let config: Config = toml::from_str(TARGETS_TOML)?;
+        assert_eq!(names(&config, &["m[1-3]", "!m[2]"])?, vec!["m1", "m3"]);
+        assert_eq!(names(&config, &["w[2,1]"])?, vec!["w2", "w1"]);
+        assert_eq!(names(&config, &["all", "!w[1-2]"])?, vec!["m1", "m2", "m3"]);
+        let error = resolve_targets(&config, &["m[1-4]"], &mut Warnings::default())
+            .err()
+            .ok_or("expected an unknown host")?;
+        assert_eq!(error.to_string(), "Unknown host 'm4', from 'm[1-4]'");
+        Ok(())
+    }
+
+    #[test]
+    fn host_map() -> MusshResult<()> {
+        let config: Config = toml::from_str(TARGETS_TOML)?;
+        let mut runtime_config = RuntimeConfig::default();
+        let selectors: IndexSet<String> = vec!["web".to_string(), "!w2".to_string()]
+            .into_iter()
+            .collect();
+        let _ = runtime_config.set_hosts(selectors);
+        let _ = runtime_config.set_cmds(vec!["ls".to_string()].into_iter().collect());
+        let (resolved, multiplex_map) =
+            to_host_map(&config, &runtime_config, true, &mut Warnings::default())?;
+        assert_eq!(resolved.hosts().iter().collect::<Vec<_>>(), vec!["w1"]);
+        let (host, cmd_map) = multiplex_map.get("w1").ok_or("w1 not in the host map")?;
+        assert_eq!(host.username(), "www");
+        assert!(cmd_map
+            .values()
+            .any(|cmds| cmds.get("ls") == Some(&"ls -al".to_string())));
+        assert_eq!(multiplex_map.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn listed_order_is_kept() -> MusshResult<()> {
+        let config: Config = toml::from_str(&format!(
+            "{TARGETS_TOML}[hostlist.rolling]\nhostnames = [\"w2\", \"m3\", \"w1\", \"m1\"]\n"
+        ))?;
+        assert_eq!(
+            names(&config, &["rolling", "m2", "web"])?,
+            vec!["w2", "m3", "w1", "m1", "m2"]
+        );
+
+        let mut runtime_config = RuntimeConfig::default();
+        let _ = runtime_config.set_hosts(
+            vec!["m2".to_string(), "rolling".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        let _ = runtime_config.set_cmds(vec!["ls".to_string()].into_iter().collect());
+        let (_, multiplex_map) =
+            to_host_map(&config, &runtime_config, true, &mut Warnings::default())?;
+        assert_eq!(
+            multiplex_map.keys().collect::<Vec<_>>(),
+            vec!["m2", "w2", "m3", "w1", "m1"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn targets_parse() -> MusshResult<()> {
+        let host = parse_target("deploy@10.0.0.5:22")?;
+        assert_eq!(
+            host.get("hostname").and_then(Value::as_str),
+            Some("10.0.0.5")
+        );
+        assert_eq!(host.get("username").and_then(Value::as_str), Some("deploy"));
+        assert_eq!(host.get("port").and_then(Value::as_integer), Some(22));
+
+        let host = parse_target("root@[::1]:2222")?;
+        assert_eq!(host.get("hostname").and_then(Value::as_str), Some("::1"));
+        assert_eq!(host.get("port").and_then(Value::as_integer), Some(2222));
+
+        let host = parse_target("root@fe80::1")?;
+        assert_eq!(
+            host.get("hostname").and_then(Value::as_str),
+            Some("fe80::1")
+        );
+        assert!(host.get("port").is_none());
+
+        assert!(parse_target("deploy@db:ssh").is_err());
+        assert!(parse_target("@db").is_err());
+        assert!(parse_target("deploy@").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn targets_without_a_config() -> MusshResult<()> {
+        let config = add_targets(
+            &Config::default(),
+            &["deploy@10.0.0.5:22", "deploy@10.0.0.6"],
+            Some("/keys/k.pem"),
+            Some("uptime"),
+        )?;
+        let mut runtime_config = RuntimeConfig::default();
+        let _ = runtime_config.set_hosts(
+            vec![
+                "deploy@10.0.0.5:22".to_string(),
+                "deploy@10.0.0.6".to_string(),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let _ = runtime_config.set_cmds(vec![EXEC_CMD.to_string()].into_iter().collect());
+        let (_, multiplex_map) =
+            to_host_map(&config, &runtime_config, true, &mut Warnings::default())?;
+        assert_eq!(multiplex_map.len(), 2);
+
+        let (host, cmd_map) = multiplex_map
+            .get("deploy@10.0.0.5:22")
+            .ok_or("target not in the host map")?;
+        assert_eq!(host.hostname(), "10.0.0.5");
+        assert_eq!(*host.port(), Some(22));
+        assert_eq!(host.pem().as_deref(), Some("/keys/k.pem"));
+        assert!(cmd_map
+            .values()
+            .any(|cmds| cmds.get(EXEC_CMD) == Some(&"uptime".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn duplicates_unless_unique() -> MusshResult<()> {
+        let config: Config = toml::from_str(TARGETS_TOML)?;
+        let mut warnings = Warnings::default();
+        let names: Vec<String> =
+            select_targets(&config, &["m1", "web", "all"], false, &mut warnings)?
+                .into_iter()
+                .map(|resolved| resolved.name().clone())
+                .collect();
+        assert_eq!(names, vec!["m1", "w1", "w2", "m1", "m2", "m3", "w1", "w2"]);
+        warnings.emit(None, true)?;
+
+        let selectors: IndexSet<String> =
+            vec!["m1".to_string(), "web".to_string(), "all".to_string()]
+                .into_iter()
+                .collect();
+        assert_eq!(repeats(&config, &selectors)?, vec!["m1", "w1", "w2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn warnings_are_noted() -> MusshResult<()> {
+        let config: Config = toml::from_str(TARGETS_TOML)?;
+        let mut warnings = Warnings::default();
+        let _hosts = resolve_targets(&config, &["m1", "all"], &mut warnings)?;
+        let _hosts = resolve_targets(&config, &["typo"], &mut warnings)?;
+        let _hosts = resolve_targets(&config, &["web", "!w1", "!w2"], &mut warnings)?;
+        let error = warnings.emit(None, true).err().ok_or("expected an error")?;
+        assert_eq!(
+            error.to_string(),
+            "3 warning(s) with --strict:\n  \
+             Host 'm1' is selected more than once (by m1, all), running it once\n  \
+             Hostlist 'typo' includes the unknown host 'nope'\n  \
+             Nothing is left of 'web,!w1,!w2' after its exclusions"
+        );
+
+        let mut warnings = Warnings::default();
+        let _hosts = resolve_targets(&config, &["all", "!m2"], &mut warnings)?;
+        warnings.emit(None, true)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Utilities
+use chrono::{DateTime, Utc};
+use std::process;
+use std::time::Duration;
+
+/// Format a duration with units, i.e. `742ms`, `5.321s`, `1m03s` or `2h01m03s`.
+pub(crate) fn format_duration(duration: &Duration) -> String {
+    let secs = duration.as_secs();
+    let millis = duration.subsec_millis();
+
+    if secs == 0 {
+        format!("{millis}ms")
+    } else if secs < 60 {
+        format!("{secs}.{millis:03}s")
+    } else if secs < 3600 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!(
+            "{}h{:02}m{:02}s",
+            secs / 3600,
+            (secs % 3600) / 60,
+            secs % 60
+        )
+    }
+}
+
+/// Pad text with spaces on the left to the given width, in characters, to
+/// right align it in a column.  Text as wide or wider is left as it is.
+pub(crate) fn pad_left(text: &str, width: usize) -> String {
+    format!("{text:>width$}")
+}
+
+/// An aligned table with a header line, the columns marked `right` right
+/// aligned and the rest left aligned.
+pub(crate) fn table(header: &[&str], right: &[bool], rows: Vec<Vec<String>>) -> String {
+    let header: Vec<String> = header.iter().map(|title| (*title).to_string()).collect();
+    let lines: Vec<Vec<String>> = Some(header).into_iter().chain(rows).collect();
+    let widths: Vec<usize> = (0..right.len())
+        .map(|column| {
+            lines
+                .iter()
+                .map(|line| line[column].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let mut table = String::new();
+    for line in lines {
+        let cells: Vec<String> = line
+            .iter()
+            .zip(right.iter().zip(&widths))
+            .map(|(cell, (right, width))| {
+                if *right {
+                    pad_left(cell, *width)
+                } else {
+                    format!("{cell:<width$}")
+                }
+            })
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Quote text as a single POSIX shell word.
+pub(crate) fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+/// An id for this invocation of mussh: the time it started, which sorts runs
+/// in order, and the process id, which keeps two runs started together apart.
+pub(crate) fn run_id(started: DateTime<Utc>) -> String {
+    format!("{}-{}", started.format("%Y%m%dT%H%M%S%.3fZ"), process::id())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_duration, pad_left, run_id, shell_quote, table};
+    use crate::error::MusshResult;
+    use chrono::{TimeZone, Utc};
+    use std::time::Duration;
+
+    #[test]
+    fn sub_second() {
+        assert_eq!(format_duration(&Duration::from_millis(742)), "742ms");
+        assert_eq!(format_duration(&Duration::from_millis(5)), "5ms");
+        assert_eq!(format_duration(&Duration::from_micros(500)), "0ms");
+    }
+
+    #[test]
+    fn multi_second() {
+        assert_eq!(format_duration(&Duration::from_millis(5321)), "5.321s");
+        assert_eq!(format_duration(&Duration::from_millis(5030)), "5.030s");
+        assert_eq!(format_duration(&Duration::from_millis(5003)), "5.003s");
+        assert_eq!(format_duration(&Duration::from_secs(59)), "59.000s");
+    }
+
+    #[test]
+    fn multi_minute() {
+        assert_eq!(format_duration(&Duration::from_secs(63)), "1m03s");
+        assert_eq!(format_duration(&Duration::from_millis(754_999)), "12m34s");
+        assert_eq!(format_duration(&Duration::from_secs(3723)), "1h02m03s");
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(pad_left("5ms", 6), "   5ms");
+        assert_eq!(pad_left("1m03s", 3), "1m03s");
+    }
+
+    #[test]
+    fn aligns_the_table() {
+        let rows = vec![
+            vec!["web".to_string(), "5ms".to_string()],
+            vec!["database".to_string(), "1m03s".to_string()],
+        ];
+        assert_eq!(
+            table(&["HOSTNAME", "AVG"], &[false, true], rows),
+            "HOSTNAME    AVG\nweb         5ms\ndatabase  1m03s\n"
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("ls -al"), "'ls -al'");
+        assert_eq!(shell_quote("echo 'hi'"), r"'echo '\''hi'\'''");
+    }
+
+    #[test]
+    fn run_ids() -> MusshResult<()> {
+        let started = Utc
+            .timestamp_millis_opt(1_792_145_700_123)
+            .single()
+            .ok_or("bad timestamp")?;
+        assert_eq!(
+            run_id(started),
+            format!("20261016T101500.123Z-{}", std::process::id())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+// Copyright © 2016 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Warnings raised before a run
+use crate::error::{MusshErrKind, MusshResult};
+use slog::Logger;
+use slog_try::try_warn;
+
+/// The conditions worth warning about found while setting up a run.
+///
+/// They are collected rather than logged as they're found, so `--strict` can
+/// turn all of them into one error before anything is run.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Warnings {
+    messages: Vec<String>,
+}
+
+impl Warnings {
+    /// Note a condition worth warning about.
+    pub(crate) fn warn<S: Into<String>>(&mut self, message: S) {
+        self.messages.push(message.into());
+    }
+
+    /// The warnings, in the order they were noted.
+    pub(crate) fn into_messages(self) -> Vec<String> {
+        self.messages
+    }
+
+    /// Log the warnings to stderr, or if `strict`, fail with all of them.
+    pub(crate) fn emit(self, stderr: Option<&Logger>, strict: bool) -> MusshResult<()> {
+        if strict && !self.messages.is_empty() {
+            return Err(MusshErrKind::Strict(self.messages).into());
+        }
+
+        for message in &self.messages {
+            try_warn!(stderr, "{}", message);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Warnings;
+    use crate::error::MusshResult;
+
+    #[test]
+    fn strict_fails_with_every_warning() -> MusshResult<()> {
+        let mut warnings = Warnings::default();
+        warnings.clone().emit(None, true)?;
+
+        warnings.warn("one");
+        warnings.warn("two".to_string());
+        warnings.clone().emit(None, false)?;
+
+        let error = warnings.emit(None, true).err().ok_or("expected an error")?;
+        assert_eq!(
+            error.to_string(),
+            "2 warning(s) with --strict:\n  one\n  two"
+        );
+        Ok(())
+    }
+}